@@ -0,0 +1,227 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+//! Compares the decoder backpressure of the pipeline, woken by the audio callback, with sleeping while the ring buffer
+//! is full, and with the semaphore the pipeline used first. Run with `cargo bench --bench backpressure`.
+//!
+//! A thread stands for the audio callback and drains the ring buffer at the stream's rate, the decoder pushes MP3
+//! frames into it. For each strategy the bench reports the CPU time of the process, how many times the decoder woke up,
+//! and how late it pushed a frame once there was room for it.
+
+#[path = "../src/backpressure.rs"]
+mod backpressure;
+
+use ringbuf::{Producer, RingBuffer};
+
+use tokio::sync::Semaphore;
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use backpressure::SpaceNotifier;
+
+const SAMPLES_PER_SECOND: u64 = 44100 * 2;
+/// Samples in a stereo MP3 frame
+const FRAME: usize = 1152 * 2;
+const RING_BUFFER: usize = 1 << 14;
+/// Interval between two audio callbacks
+const CALLBACK_PERIOD: Duration = Duration::from_millis(10);
+const RUN_TIME: Duration = Duration::from_secs(5);
+/// The polling strategy the notifier replaced
+const MIN_BACKPRESSURE_SLEEP: Duration = Duration::from_millis(2);
+
+#[derive(Clone, Copy)]
+enum Strategy {
+    Polling,
+    /// The original design: a permit per free sample, added back by the callback for every sample it popped
+    Semaphore,
+    Notifier,
+}
+
+struct Shared {
+    start: Instant,
+    /// Nanoseconds since `start` when the ring buffer got room for a frame, 0 if it hasn't since the last push
+    room_at: AtomicU64,
+    stop: AtomicBool,
+}
+
+impl Shared {
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_nanos() as u64
+    }
+}
+
+struct Report {
+    cpu: Option<Duration>,
+    wakeups: u64,
+    latencies: Vec<Duration>,
+}
+
+async fn produce(
+    strategy: Strategy,
+    mut tx: Producer<i16>,
+    space: Arc<SpaceNotifier>,
+    permits: Arc<Semaphore>,
+    shared: Arc<Shared>,
+) -> (u64, Vec<Duration>) {
+    let frame = vec![0i16; FRAME];
+    let mut wakeups = 0;
+    let mut latencies = Vec::new();
+    while !shared.stop.load(Ordering::Relaxed) {
+        match strategy {
+            Strategy::Polling => loop {
+                wakeups += 1;
+                let free = tx.remaining();
+                if free >= FRAME {
+                    break;
+                }
+                let missing = (FRAME - free) as u64;
+                let wait = Duration::from_micros(missing * 1_000_000 / SAMPLES_PER_SECOND);
+                tokio::time::sleep(wait.max(MIN_BACKPRESSURE_SLEEP)).await;
+            },
+            Strategy::Semaphore => {
+                // The semaphore only wakes the decoder once all the permits are there
+                wakeups += 1;
+                permits
+                    .acquire_many(FRAME as u32)
+                    .await
+                    .expect("Failed to acquire ringbuffer semaphore")
+                    .forget();
+            }
+            Strategy::Notifier => {
+                let checks = AtomicU64::new(0);
+                space
+                    .wait_for_space(FRAME, || {
+                        checks.fetch_add(1, Ordering::Relaxed);
+                        tx.remaining()
+                    })
+                    .await;
+                wakeups += checks.into_inner();
+            }
+        }
+        let room_at = shared.room_at.swap(0, Ordering::AcqRel);
+        if room_at != 0 {
+            latencies.push(Duration::from_nanos(shared.now().saturating_sub(room_at)));
+        }
+        tx.push_slice(&frame);
+    }
+    (wakeups, latencies)
+}
+
+fn run(strategy: Strategy) -> Report {
+    let (tx, mut rx) = RingBuffer::<i16>::new(RING_BUFFER).split();
+    let space = Arc::new(SpaceNotifier::new());
+    let permits = Arc::new(Semaphore::new(RING_BUFFER));
+    let shared = Arc::new(Shared {
+        start: Instant::now(),
+        room_at: AtomicU64::new(0),
+        stop: AtomicBool::new(false),
+    });
+
+    // The callback keeps draining the buffer until the decoder stopped, so the decoder doesn't wait forever
+    let callback_stop = Arc::new(AtomicBool::new(false));
+    let callback_space = space.clone();
+    let callback_permits = permits.clone();
+    let callback_shared = shared.clone();
+    let stop_callback = callback_stop.clone();
+    let callback = std::thread::spawn(move || {
+        let mut data = vec![0i16; (SAMPLES_PER_SECOND * CALLBACK_PERIOD.as_millis() as u64 / 1000) as usize];
+        let mut next = Instant::now();
+        while !stop_callback.load(Ordering::Relaxed) {
+            next += CALLBACK_PERIOD;
+            std::thread::sleep(next.saturating_duration_since(Instant::now()));
+            let written = rx.pop_slice(&mut data);
+            let free = rx.remaining();
+            if free >= FRAME {
+                let now = callback_shared.now();
+                let _ = callback_shared
+                    .room_at
+                    .compare_exchange(0, now, Ordering::AcqRel, Ordering::Acquire);
+            }
+            match strategy {
+                Strategy::Polling => {}
+                Strategy::Semaphore => callback_permits.add_permits(written),
+                Strategy::Notifier => callback_space.space_available(free),
+            }
+        }
+    });
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to build runtime");
+    let cpu_before = cpu_time();
+    let stop_shared = shared.clone();
+    let (wakeups, latencies) = runtime.block_on(async move {
+        let producer = tokio::spawn(produce(strategy, tx, space, permits, shared));
+        tokio::time::sleep(RUN_TIME).await;
+        stop_shared.stop.store(true, Ordering::Relaxed);
+        producer.await.expect("Producer panicked")
+    });
+    let cpu = cpu_time().zip(cpu_before).map(|(after, before)| after - before);
+    callback_stop.store(true, Ordering::Relaxed);
+    callback.join().expect("Callback thread panicked");
+
+    Report {
+        cpu,
+        wakeups,
+        latencies,
+    }
+}
+
+/// CPU time used by the process so far, from /proc/self/stat in clock ticks of 10ms.
+#[cfg(target_os = "linux")]
+fn cpu_time() -> Option<Duration> {
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    // The fields after the command name, which may contain spaces, start with the state
+    let fields: Vec<&str> = stat.rsplit(')').next()?.split_whitespace().collect();
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    Some(Duration::from_millis((utime + stime) * 10))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cpu_time() -> Option<Duration> {
+    None
+}
+
+fn print_report(name: &str, report: &mut Report) {
+    report.latencies.sort();
+    let count = report.latencies.len().max(1);
+    let mean = report.latencies.iter().sum::<Duration>() / count as u32;
+    let percentile = |p: usize| report.latencies.get(report.latencies.len() * p / 100).copied();
+    println!(
+        "{:<9} cpu {:>9} | wakeups {:>5} | push latency mean {:?}, p50 {:?}, p99 {:?}, max {:?}",
+        name,
+        report.cpu.map_or("n/a".to_string(), |cpu| format!("{:?}", cpu)),
+        report.wakeups,
+        mean,
+        percentile(50).unwrap_or_default(),
+        percentile(99).unwrap_or_default(),
+        report.latencies.last().copied().unwrap_or_default(),
+    );
+}
+
+fn main() {
+    let strategies = [
+        ("polling", Strategy::Polling),
+        ("semaphore", Strategy::Semaphore),
+        ("notifier", Strategy::Notifier),
+    ];
+    for (name, strategy) in strategies.iter() {
+        let mut report = run(*strategy);
+        print_report(name, &mut report);
+    }
+}
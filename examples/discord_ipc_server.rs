@@ -0,0 +1,41 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+//! Fake Discord client, printing the Rich Presence updates sent by the player.
+//!
+//! Run it with `cargo run --example discord_ipc_server [socket path]` and start the player with
+//! `WAN_PLAYER_DISCORD_IPC=/tmp/wan_player-discord-ipc`. Stopping the server lets you check that the player
+//! reconnects once it is back. Unix only, the player uses named pipes on Windows.
+
+#[cfg(unix)]
+#[path = "mock_servers/discord_ipc.rs"]
+mod discord_ipc;
+
+#[cfg(unix)]
+const DEFAULT_PATH: &str = "/tmp/wan_player-discord-ipc";
+
+#[cfg(unix)]
+fn main() {
+    let path = std::env::args().nth(1).unwrap_or_else(|| DEFAULT_PATH.to_string());
+    // A previous run may have left its socket behind
+    let _ = std::fs::remove_file(&path);
+    let listener = std::os::unix::net::UnixListener::bind(&path).expect("Failed to bind the fake Discord socket");
+    println!("Listening on {}", path);
+    discord_ipc::listen(listener);
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("The fake Discord socket server only runs on Unix");
+}
@@ -0,0 +1,51 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+//! Local mock of the Gensokyo Radio account API, printing the ratings and favorites sent by the player.
+//!
+//! Run it with `cargo run --example gr_account_mock_server [address]` and start the player with
+//! `WAN_PLAYER_GR_ACCOUNT_API=http://127.0.0.1:9003/api/`, then log in with the user name and password it prints.
+
+#[path = "mock_servers/gr_account.rs"]
+mod gr_account;
+
+use hyper::service::{make_service_fn, service_fn};
+
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:9003";
+
+#[tokio::main]
+async fn main() {
+    let address = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| DEFAULT_ADDRESS.to_string())
+        .parse()
+        .expect("Invalid address");
+    let account = Arc::new(Mutex::new(gr_account::Account::default()));
+    let make_service = make_service_fn(move |_| {
+        let account = account.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| gr_account::handle(account.clone(), req))) }
+    });
+    println!("Listening on http://{}", address);
+    println!(
+        "Log in as {} with the password {}",
+        gr_account::USERNAME,
+        gr_account::PASSWORD
+    );
+    if let Err(error) = hyper::Server::bind(&address).serve(make_service).await {
+        eprintln!("Mock server failed: {}", error);
+    }
+}
@@ -0,0 +1,38 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+//! Local stand-in for the Gensokyo Radio song push websocket, see `mock_servers/gr_push.rs`.
+//!
+//! Every client gets a fake song every `SONG_DURATION` seconds. Run it with
+//! `cargo run --example gr_push_server [address]` and start the player with `WAN_PLAYER_GR_PUSH=ws://127.0.0.1:9001`.
+//! Stopping the server lets you check that the player falls back to polling, and reconnects once it is back.
+
+#[path = "mock_servers/gr_push.rs"]
+mod gr_push;
+
+use std::time::Duration;
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:9001";
+const SONG_DURATION: Duration = Duration::from_secs(30);
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+
+#[tokio::main]
+async fn main() {
+    let address = std::env::args().nth(1).unwrap_or_else(|| DEFAULT_ADDRESS.to_string());
+    let listener = tokio::net::TcpListener::bind(&address)
+        .await
+        .expect("Failed to bind the push server");
+    println!("Listening on ws://{}", address);
+    gr_push::listen(listener, SONG_DURATION, PING_INTERVAL).await;
+}
@@ -0,0 +1,52 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+//! Local mock of the Last.fm API, printing the now playing updates and the scrobbles sent by the player.
+//!
+//! Run it with `cargo run --example lastfm_mock_server [address]` and start the player with
+//! `WAN_PLAYER_LASTFM_API=http://127.0.0.1:9002/2.0/ WAN_PLAYER_LASTFM_AUTH=http://127.0.0.1:9002/api/auth/` and any
+//! `api_key` and `api_secret` in the `[lastfm]` section of the configuration. Every token is approved right away.
+//! Stopping the server lets you check that the scrobbles are queued, and sent once it is back.
+
+#[path = "mock_servers/lastfm.rs"]
+mod lastfm;
+
+use hyper::service::{make_service_fn, service_fn};
+
+use std::convert::Infallible;
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:9002";
+
+#[tokio::main]
+async fn main() {
+    let address = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| DEFAULT_ADDRESS.to_string())
+        .parse()
+        .expect("Invalid address");
+    let calls = lastfm::Calls::default();
+    let make_service = make_service_fn(move |_| {
+        let calls = calls.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| lastfm::handle(calls.clone(), req))) }
+    });
+    println!("Listening on http://{}", address);
+    println!(
+        "Every token is {}, and every session key {}",
+        lastfm::TOKEN,
+        lastfm::SESSION_KEY
+    );
+    if let Err(error) = hyper::Server::bind(&address).serve(make_service).await {
+        eprintln!("Mock server failed: {}", error);
+    }
+}
@@ -0,0 +1,47 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+//! Local stand-in for a ListenBrainz server, printing the listens submitted by the player.
+//!
+//! Run it with `cargo run --example listenbrainz_mock_server [address]` and set `server = "http://127.0.0.1:9003"` and
+//! any `token` in the `[listenbrainz]` section of the configuration. Stopping the server lets you check that the
+//! listens are queued, and imported once it is back.
+
+#[path = "mock_servers/listenbrainz.rs"]
+mod listenbrainz;
+
+use hyper::service::{make_service_fn, service_fn};
+
+use std::convert::Infallible;
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:9003";
+
+#[tokio::main]
+async fn main() {
+    let address = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| DEFAULT_ADDRESS.to_string())
+        .parse()
+        .expect("Invalid address");
+    let submissions = listenbrainz::Submissions::default();
+    let make_service = make_service_fn(move |_| {
+        let submissions = submissions.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| listenbrainz::handle(submissions.clone(), req))) }
+    });
+    println!("Listening on http://{}", address);
+    println!("Every token is accepted, except {}", listenbrainz::INVALID_TOKEN);
+    if let Err(error) = hyper::Server::bind(&address).serve(make_service).await {
+        eprintln!("Mock server failed: {}", error);
+    }
+}
@@ -0,0 +1,73 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+//! Fake Discord client, used by the `discord_ipc_server` example and the tests of the IPC client. Unix only, the
+//! player uses named pipes on Windows.
+
+use std::io::{Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+
+/// Serves every client connecting to `listener`, each on its own thread.
+pub fn listen(listener: UnixListener) {
+    for socket in listener.incoming() {
+        match socket {
+            Ok(socket) => {
+                std::thread::spawn(move || serve_client(socket));
+            }
+            Err(error) => println!("Failed to accept a client: {}", error),
+        }
+    }
+}
+
+/// Answers the handshake with READY and echoes the activity of every command, until the client disconnects.
+pub fn serve_client(mut socket: UnixStream) {
+    loop {
+        let (opcode, payload) = match read_frame(&mut socket) {
+            Ok(frame) => frame,
+            Err(error) => {
+                println!("Client disconnected: {}", error);
+                return;
+            }
+        };
+        println!("received opcode {}: {}", opcode, payload);
+        let answer = match opcode {
+            0 => serde_json::json!({ "cmd": "DISPATCH", "evt": "READY", "data": { "v": 1 } }),
+            1 => {
+                serde_json::json!({ "cmd": payload["cmd"], "data": payload["args"]["activity"], "nonce": payload["nonce"] })
+            }
+            _ => continue,
+        };
+        if let Err(error) = write_frame(&mut socket, 1, answer) {
+            println!("Client disconnected: {}", error);
+            return;
+        }
+    }
+}
+
+pub fn read_frame(socket: &mut impl Read) -> std::io::Result<(u32, serde_json::Value)> {
+    let mut header = [0; 8];
+    socket.read_exact(&mut header)?;
+    let opcode = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    let mut payload = vec![0; length as usize];
+    socket.read_exact(&mut payload)?;
+    Ok((opcode, serde_json::from_slice(&payload)?))
+}
+
+pub fn write_frame(socket: &mut impl Write, opcode: u32, payload: serde_json::Value) -> std::io::Result<()> {
+    let payload = payload.to_string();
+    socket.write_all(&opcode.to_le_bytes())?;
+    socket.write_all(&(payload.len() as u32).to_le_bytes())?;
+    socket.write_all(payload.as_bytes())
+}
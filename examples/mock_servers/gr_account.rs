@@ -0,0 +1,85 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+//! Local mock of the Gensokyo Radio account API, used by the `gr_account_mock_server` example and the tests of the API
+//! client.
+
+use hyper::{Body, Method, Request, Response, StatusCode};
+
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+
+pub const USERNAME: &str = "mock_user";
+pub const PASSWORD: &str = "mock_password";
+const USERID: u64 = 42;
+const API_KEY: &str = "mock_api_key";
+
+/// Ratings and favorites of the mock user.
+#[derive(Default, Debug)]
+pub struct Account {
+    pub ratings: HashMap<u64, u8>,
+    pub favorites: HashSet<u64>,
+}
+
+pub async fn handle(account: Arc<Mutex<Account>>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if req.method() != Method::POST {
+        let mut res = Response::new(Body::empty());
+        *res.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+        return Ok(res);
+    }
+    let path = req.uri().path().to_string();
+    let body = hyper::body::to_bytes(req.into_body()).await.unwrap_or_default();
+    let params: HashMap<String, String> = form_urlencoded::parse(&body).into_owned().collect();
+    println!("{} {:?}", path, params);
+
+    let param = |name: &str| params.get(name).map(String::as_str).unwrap_or_default();
+    let logged_in = param("userid") == USERID.to_string() && param("api_key") == API_KEY;
+    let songid = param("songid").parse::<u64>();
+    let mut account = account.lock().expect("Failed to lock account");
+    let answer = match (path.as_str(), songid) {
+        ("/api/login/", _) if param("user") == USERNAME && param("pass") == PASSWORD => {
+            serde_json::json!({ "userid": USERID, "api_key": API_KEY })
+        }
+        ("/api/login/", _) => serde_json::json!({ "error": "Invalid username or password" }),
+        ("/api/station/rating/", _) | ("/api/station/favorite/", _) if !logged_in => {
+            serde_json::json!({ "error": "Invalid API key" })
+        }
+        ("/api/station/rating/", Ok(songid)) => match param("rating").parse::<u8>() {
+            Ok(rating) if (1..=5).contains(&rating) => {
+                account.ratings.insert(songid, rating);
+                serde_json::json!({ "result": "ok" })
+            }
+            _ => serde_json::json!({ "error": "Invalid rating" }),
+        },
+        ("/api/station/favorite/", Ok(songid)) => match param("action") {
+            "add" => {
+                account.favorites.insert(songid);
+                serde_json::json!({ "result": "ok" })
+            }
+            "remove" if account.favorites.remove(&songid) => serde_json::json!({ "result": "ok" }),
+            "remove" => serde_json::json!({ "error": "Song is not a favorite" }),
+            _ => serde_json::json!({ "error": "Invalid action" }),
+        },
+        ("/api/station/rating/", Err(_)) | ("/api/station/favorite/", Err(_)) => {
+            serde_json::json!({ "error": "Invalid song id" })
+        }
+        _ => {
+            let mut res = Response::new(Body::from(serde_json::json!({ "error": "Not found" }).to_string()));
+            *res.status_mut() = StatusCode::NOT_FOUND;
+            return Ok(res);
+        }
+    };
+    Ok(Response::new(Body::from(answer.to_string())))
+}
@@ -0,0 +1,106 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+//! Local stand-in for the Gensokyo Radio song push websocket, used by the `gr_push_server` example and the tests of
+//! the song push.
+//!
+//! Every client gets a fake song every `song_duration`, alternating between a full JSON API answer and a bare change
+//! notification (for which the player fetches the real API). Each notification is preceded by a listener count,
+//! which the player must ignore. Pings are sent in between.
+
+use futures_util::{SinkExt, StreamExt};
+use tokio_tungstenite::tungstenite::Message;
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Serves every client connecting to `listener`.
+pub async fn listen(listener: tokio::net::TcpListener, song_duration: Duration, ping_interval: Duration) {
+    while let Ok((stream, peer)) = listener.accept().await {
+        tokio::spawn(async move {
+            println!("{} connected", peer);
+            if let Err(error) = serve(stream, song_duration, ping_interval).await {
+                println!("{} disconnected: {}", peer, error);
+            }
+        });
+    }
+}
+
+/// Sends the fake songs to the client of `stream` until it disconnects.
+pub async fn serve(
+    stream: tokio::net::TcpStream,
+    song_duration: Duration,
+    ping_interval: Duration,
+) -> Result<(), tokio_tungstenite::tungstenite::Error> {
+    let mut socket = tokio_tungstenite::accept_async(stream).await?;
+    socket
+        .send(Message::Text(
+            serde_json::json!({ "message": "welcome", "id": 1 }).to_string(),
+        ))
+        .await?;
+
+    let mut song = 0;
+    let mut next_song = tokio::time::Instant::now();
+    let mut ping = tokio::time::interval(ping_interval);
+    loop {
+        tokio::select! {
+            message = socket.next() => match message {
+                Some(Ok(Message::Text(text))) => println!("received {}", text),
+                Some(Ok(Message::Close(_))) | None => return Ok(()),
+                Some(Ok(_)) => {}
+                Some(Err(error)) => return Err(error),
+            },
+            _ = ping.tick() => {
+                socket.send(Message::Text(serde_json::json!({ "message": "ping" }).to_string())).await?;
+            }
+            _ = tokio::time::sleep_until(next_song) => {
+                song += 1;
+                next_song += song_duration;
+                let message = if song % 2 == 0 {
+                    let listeners = serde_json::json!({ "message": "listeners", "count": 42 });
+                    socket.send(Message::Text(listeners.to_string())).await?;
+                    serde_json::json!({ "message": "update" })
+                } else {
+                    fake_song(song, song_duration.as_secs().max(1))
+                };
+                socket.send(Message::Text(message.to_string())).await?;
+            }
+        }
+    }
+}
+
+fn fake_song(song: u64, duration: u64) -> serde_json::Value {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    serde_json::json!({
+        "SERVERINFO": { "MODE": "RADIO", "STATUS": "ONLINE", "LISTENERS": 42, "LASTUPDATE": now },
+        "SONGINFO": {
+            "TITLE": format!("Test song {}", song),
+            "ARTIST": "Test artist",
+            "ALBUM": "Test album",
+            "YEAR": "2021",
+            "CIRCLE": "Test circle",
+        },
+        "SONGTIMES": {
+            "DURATION": duration.to_string(),
+            "PLAYED": 0,
+            "REMAINING": duration,
+            "SONGSTART": now,
+            "SONGEND": now + duration,
+        },
+        "SONGDATA": { "SONGID": 0, "ALBUMID": 0, "RATING": "0", "TIMESRATED": 0 },
+        "MISC": { "CIRCLELINK": "", "ALBUMART": "", "CIRCLEART": "", "OFFSETTIME": 0, "FORCEDELAY": 0 },
+    })
+}
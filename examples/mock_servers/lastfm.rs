@@ -0,0 +1,63 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+//! Local mock of the Last.fm API, used by the `lastfm_mock_server` example and the tests of the Last.fm client.
+
+use hyper::{Body, Method, Request, Response};
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+
+pub const TOKEN: &str = "mock_token";
+pub const SESSION_KEY: &str = "mock_session_key";
+
+/// Parameters of the API calls received, oldest first.
+pub type Calls = Arc<Mutex<Vec<HashMap<String, String>>>>;
+
+pub async fn handle(calls: Calls, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if req.method() == Method::GET && req.uri().path().starts_with("/api/auth") {
+        println!("approved {}", req.uri());
+        return Ok(Response::new(Body::from(
+            "Wan Player is approved, you can go back to the player.",
+        )));
+    }
+
+    let body = hyper::body::to_bytes(req.into_body()).await.unwrap_or_default();
+    let params: HashMap<String, String> = form_urlencoded::parse(&body).into_owned().collect();
+    let method = params.get("method").cloned().unwrap_or_default();
+    println!("{} {:?}", method, params);
+
+    let param = |name: &str| params.get(name).map(String::as_str).unwrap_or_default();
+    let signed = !param("api_key").is_empty() && !param("api_sig").is_empty();
+    let answer = match method.as_str() {
+        _ if !signed => serde_json::json!({ "error": 13, "message": "Invalid method signature supplied" }),
+        "auth.getToken" => serde_json::json!({ "token": TOKEN }),
+        "auth.getSession" if param("token") == TOKEN => serde_json::json!({
+            "session": { "name": "mock_user", "key": SESSION_KEY, "subscriber": 0 }
+        }),
+        "auth.getSession" => serde_json::json!({ "error": 4, "message": "Invalid authentication token supplied" }),
+        "track.updateNowPlaying" | "track.scrobble" if param("sk") != SESSION_KEY => {
+            serde_json::json!({ "error": 9, "message": "Invalid session key - Please re-authenticate" })
+        }
+        "track.updateNowPlaying" => serde_json::json!({ "nowplaying": {} }),
+        "track.scrobble" => {
+            let accepted = params.keys().filter(|key| key.starts_with("timestamp[")).count();
+            serde_json::json!({ "scrobbles": { "@attr": { "accepted": accepted, "ignored": 0 } } })
+        }
+        _ => serde_json::json!({ "error": 3, "message": "Invalid Method - No method with that name in this package" }),
+    };
+    calls.lock().expect("Failed to lock calls").push(params);
+    Ok(Response::new(Body::from(answer.to_string())))
+}
@@ -0,0 +1,77 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+//! Local stand-in for a ListenBrainz server, used by the `listenbrainz_mock_server` example and the tests of the
+//! ListenBrainz client.
+
+use hyper::{Body, Method, Request, Response, StatusCode};
+
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+
+/// Every token is accepted, except this one
+pub const INVALID_TOKEN: &str = "invalid_token";
+
+/// Submissions received, oldest first.
+pub type Submissions = Arc<Mutex<Vec<serde_json::Value>>>;
+
+pub async fn handle(submissions: Submissions, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if req.method() != Method::POST || req.uri().path() != "/1/submit-listens" {
+        return Ok(answer(StatusCode::NOT_FOUND, "Not found"));
+    }
+    let token = req
+        .headers()
+        .get(hyper::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Token "))
+        .map(str::to_string);
+    match token.as_deref() {
+        None | Some("") => {
+            return Ok(answer(
+                StatusCode::UNAUTHORIZED,
+                "You need to provide an Authorization header.",
+            ))
+        }
+        Some(INVALID_TOKEN) => return Ok(answer(StatusCode::UNAUTHORIZED, "Invalid authorization token.")),
+        Some(_) => {}
+    }
+
+    let body = hyper::body::to_bytes(req.into_body()).await.unwrap_or_default();
+    let listens: serde_json::Value = match serde_json::from_slice(&body) {
+        Ok(listens) => listens,
+        Err(_) => return Ok(answer(StatusCode::BAD_REQUEST, "Cannot parse JSON document.")),
+    };
+    println!("{}", serde_json::to_string_pretty(&listens).unwrap_or_default());
+    let valid = match listens["listen_type"].as_str() {
+        Some("single") | Some("playing_now") => listens["payload"]
+            .as_array()
+            .map_or(false, |payload| payload.len() == 1),
+        Some("import") => listens["payload"].is_array(),
+        _ => false,
+    };
+    if !valid {
+        return Ok(answer(StatusCode::BAD_REQUEST, "Invalid listen_type or payload."));
+    }
+    submissions.lock().expect("Failed to lock submissions").push(listens);
+    Ok(Response::new(Body::from(
+        serde_json::json!({ "status": "ok" }).to_string(),
+    )))
+}
+
+fn answer(status: StatusCode, error: &str) -> Response<Body> {
+    let body = serde_json::json!({ "code": status.as_u16(), "error": error }).to_string();
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+}
@@ -0,0 +1,419 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+use iced::{widget, Command, Element};
+use serde::{Deserialize, Serialize};
+
+use std::sync::Arc;
+
+use crate::config::WebhookConfig;
+use crate::gensokyo_radio::{ApiClient, ApiError, GRApiAnswer};
+use crate::lastfm::{LastfmClient, LastfmError, Session};
+use crate::{ui, webhooks, PlayerMessage};
+
+const KEYRING_SERVICE: &str = "wan_player";
+const KEYRING_USER: &str = "gensokyoradio.net";
+
+/// Gensokyo Radio credentials, kept in the OS keyring so we can log back in on the next launch.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Credentials {
+    pub fn load() -> Option<Credentials> {
+        let entry = keyring::Entry::new(KEYRING_SERVICE, KEYRING_USER).ok()?;
+        let secret = entry.get_password().ok()?;
+        serde_json::from_str(&secret).ok()
+    }
+
+    pub fn store(&self) {
+        let result = keyring::Entry::new(KEYRING_SERVICE, KEYRING_USER).and_then(|entry| {
+            entry.set_password(&serde_json::to_string(self).expect("Failed to serialize credentials"))
+        });
+        if let Err(error) = result {
+            eprintln!("Failed to store credentials in the keyring: {}", error);
+        }
+    }
+
+    pub fn delete() {
+        let result = keyring::Entry::new(KEYRING_SERVICE, KEYRING_USER).and_then(|entry| entry.delete_password());
+        match result {
+            Ok(()) | Err(keyring::Error::NoEntry) => {}
+            Err(error) => eprintln!("Failed to remove credentials from the keyring: {}", error),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum AccountMessage {
+    UsernameChanged(String),
+    PasswordChanged(String),
+    Login,
+    /// `remember` is set when the credentials were typed by the user, and need to be stored in the keyring
+    LoggedIn {
+        result: Result<Credentials, ApiError>,
+        remember: bool,
+    },
+    Logout,
+    Rate {
+        songid: u64,
+        rating: u8,
+    },
+    Done(Result<(), ApiError>),
+    LastfmConnect,
+    LastfmToken(Result<String, LastfmError>),
+    /// The user approved the token in the browser
+    LastfmApproved,
+    LastfmConnected(Result<Session, LastfmError>),
+    LastfmDisconnect,
+    /// Handled by the player, which knows the webhooks and the current song, see `AccountPanel::preview_webhooks`
+    PreviewWebhooks,
+    /// The user confirmed the calls of the preview
+    SendTestWebhooks,
+    WebhooksTested(Vec<String>),
+}
+
+/// Logs in with `credentials` in the background, storing them in the keyring on success if `remember` is set.
+pub fn login(api_client: Arc<ApiClient>, credentials: Credentials, remember: bool) -> Command<PlayerMessage> {
+    Command::perform(
+        async move {
+            api_client
+                .login(&credentials.username, &credentials.password)
+                .await
+                .map(|_| credentials)
+        },
+        move |result| PlayerMessage::Account(AccountMessage::LoggedIn { result, remember }),
+    )
+}
+
+/// Mirrors a local favorite on the user's Gensokyo Radio account.
+pub fn sync_favorite(api_client: Arc<ApiClient>, songid: u64, favorite: bool) -> Command<PlayerMessage> {
+    Command::perform(
+        async move { api_client.set_favorite(songid, favorite).await },
+        |result| PlayerMessage::Account(AccountMessage::Done(result)),
+    )
+}
+
+/// State of the account panel.
+#[derive(Default)]
+pub struct AccountPanel {
+    username: String,
+    password: String,
+    status: Option<String>,
+    logging_in: bool,
+
+    /// Name of the connected Last.fm user
+    lastfm_user: Option<String>,
+    /// Token waiting for the user's approval
+    lastfm_token: Option<String>,
+    lastfm_status: Option<String>,
+
+    testing_webhooks: bool,
+    /// The webhooks and the song of the previewed test event, sent once the user confirms
+    webhook_test: Option<(Vec<WebhookConfig>, Option<(GRApiAnswer, String)>)>,
+    /// Calls of the previewed test event, or result of the last test, one per webhook
+    webhook_results: Vec<String>,
+
+    username_state: widget::text_input::State,
+    password_state: widget::text_input::State,
+    login_state: widget::button::State,
+    lastfm_state: widget::button::State,
+    test_webhooks_state: widget::button::State,
+    send_test_webhooks_state: widget::button::State,
+}
+
+impl AccountPanel {
+    pub fn with_lastfm_user(lastfm_user: Option<String>) -> AccountPanel {
+        AccountPanel {
+            lastfm_user,
+            ..AccountPanel::default()
+        }
+    }
+
+    /// Shows the calls a test event would make, with the current song if there is one. Nothing is sent until the user
+    /// confirms, the webhooks may e.g. control home automation.
+    pub fn preview_webhooks(&mut self, webhooks: Vec<WebhookConfig>, song: Option<(GRApiAnswer, String)>) {
+        if self.testing_webhooks {
+            return;
+        }
+        self.webhook_results = webhooks::preview(&webhooks, song.as_ref());
+        self.webhook_test = Some((webhooks, song));
+    }
+
+    pub fn update(
+        &mut self,
+        message: AccountMessage,
+        api_client: Arc<ApiClient>,
+        lastfm: Option<Arc<LastfmClient>>,
+    ) -> Command<PlayerMessage> {
+        match message {
+            AccountMessage::UsernameChanged(username) => {
+                self.username = username;
+                Command::none()
+            }
+            AccountMessage::PasswordChanged(password) => {
+                self.password = password;
+                Command::none()
+            }
+            AccountMessage::Login if self.logging_in || self.username.is_empty() || self.password.is_empty() => {
+                Command::none()
+            }
+            AccountMessage::Login => {
+                self.logging_in = true;
+                self.status = None;
+                let credentials = Credentials {
+                    username: self.username.clone(),
+                    password: self.password.clone(),
+                };
+                login(api_client, credentials, true)
+            }
+            AccountMessage::LoggedIn {
+                result: Ok(credentials),
+                remember,
+            } => {
+                self.logging_in = false;
+                self.username = credentials.username.clone();
+                self.password.clear();
+                self.status = None;
+                if remember {
+                    tokio::task::spawn_blocking(move || credentials.store());
+                }
+                Command::none()
+            }
+            AccountMessage::LoggedIn { result: Err(error), .. } => {
+                self.logging_in = false;
+                self.status = Some(error.to_string());
+                Command::none()
+            }
+            AccountMessage::Logout => {
+                api_client.logout();
+                self.status = None;
+                tokio::task::spawn_blocking(Credentials::delete);
+                Command::none()
+            }
+            AccountMessage::Rate { songid, rating } => {
+                Command::perform(async move { api_client.rate_song(songid, rating).await }, |result| {
+                    PlayerMessage::Account(AccountMessage::Done(result))
+                })
+            }
+            AccountMessage::Done(result) => {
+                self.status = result.err().map(|error| error.to_string());
+                Command::none()
+            }
+            AccountMessage::LastfmConnect => match lastfm {
+                Some(lastfm) => {
+                    self.lastfm_status = None;
+                    Command::perform(async move { lastfm.get_token().await }, |result| {
+                        PlayerMessage::Account(AccountMessage::LastfmToken(result))
+                    })
+                }
+                None => Command::none(),
+            },
+            AccountMessage::LastfmToken(Ok(token)) => match lastfm {
+                Some(lastfm) => {
+                    let url = lastfm.auth_url(&token);
+                    self.lastfm_token = Some(token);
+                    Command::perform(async move { url }, PlayerMessage::OpenUrl)
+                }
+                None => Command::none(),
+            },
+            AccountMessage::LastfmToken(Err(error)) => {
+                self.lastfm_status = Some(error.to_string());
+                Command::none()
+            }
+            AccountMessage::LastfmApproved => match (lastfm, self.lastfm_token.take()) {
+                (Some(lastfm), Some(token)) => {
+                    Command::perform(async move { lastfm.get_session(&token).await }, |result| {
+                        PlayerMessage::Account(AccountMessage::LastfmConnected(result))
+                    })
+                }
+                _ => Command::none(),
+            },
+            AccountMessage::LastfmConnected(Ok(session)) => {
+                self.lastfm_user = Some(session.name.clone());
+                self.lastfm_status = None;
+                // Shared with the scrobbler
+                if let Some(lastfm) = lastfm {
+                    tokio::task::spawn_blocking(move || lastfm.set_session(Some(session)));
+                }
+                Command::none()
+            }
+            AccountMessage::LastfmConnected(Err(error)) => {
+                self.lastfm_status = Some(error.to_string());
+                Command::none()
+            }
+            AccountMessage::LastfmDisconnect => {
+                self.lastfm_user = None;
+                self.lastfm_status = None;
+                match lastfm {
+                    Some(lastfm) => tokio::task::spawn_blocking(move || lastfm.set_session(None)),
+                    None => tokio::task::spawn_blocking(Session::delete),
+                };
+                Command::none()
+            }
+            AccountMessage::PreviewWebhooks => Command::none(),
+            AccountMessage::SendTestWebhooks => match self.webhook_test.take() {
+                Some((webhooks, song)) if !self.testing_webhooks => {
+                    self.testing_webhooks = true;
+                    self.webhook_results.clear();
+                    Command::perform(webhooks::test(webhooks, song), |results| {
+                        PlayerMessage::Account(AccountMessage::WebhooksTested(results))
+                    })
+                }
+                _ => Command::none(),
+            },
+            AccountMessage::WebhooksTested(results) => {
+                self.testing_webhooks = false;
+                self.webhook_results = results;
+                Command::none()
+            }
+        }
+    }
+
+    /// `presence_enabled` is `None` when the player is built without Discord support, `lastfm_available` and
+    /// `webhooks_available` are false when Last.fm or the webhooks aren't configured.
+    pub fn view(
+        &mut self,
+        api_client: &ApiClient,
+        presence_enabled: Option<bool>,
+        lastfm_available: bool,
+        webhooks_available: bool,
+    ) -> Element<PlayerMessage> {
+        let column = widget::Column::new().spacing(8).max_width(300);
+
+        let column = match api_client.logged_in_user() {
+            Some(username) => column
+                .push(widget::Text::new(format!("Logged in to Gensokyo Radio as {}", username)).size(16))
+                .push(
+                    widget::Button::new(&mut self.login_state, widget::Text::new("log out").size(16))
+                        .style(ui::TextButtonStyle)
+                        .on_press(PlayerMessage::Account(AccountMessage::Logout)),
+                ),
+            None => {
+                let login = widget::Button::new(&mut self.login_state, widget::Text::new("log in").size(16))
+                    .style(ui::TextButtonStyle)
+                    .on_press(PlayerMessage::Account(AccountMessage::Login));
+                column
+                    .push(widget::Text::new("Log in to Gensokyo Radio to rate songs").size(16))
+                    .push(
+                        widget::TextInput::new(&mut self.username_state, "username", &self.username, |username| {
+                            PlayerMessage::Account(AccountMessage::UsernameChanged(username))
+                        })
+                        .size(16)
+                        .padding(4),
+                    )
+                    .push(
+                        widget::TextInput::new(&mut self.password_state, "password", &self.password, |password| {
+                            PlayerMessage::Account(AccountMessage::PasswordChanged(password))
+                        })
+                        .password()
+                        .on_submit(PlayerMessage::Account(AccountMessage::Login))
+                        .size(16)
+                        .padding(4),
+                    )
+                    .push(login)
+            }
+        };
+
+        let column = match self.status {
+            Some(ref status) => column.push(widget::Text::new(status).size(16).color([1.0, 0.5, 0.5, 1.0])),
+            None => column,
+        };
+
+        let column = match presence_enabled {
+            Some(enabled) => column.push(
+                widget::Checkbox::new(
+                    enabled,
+                    "Show the current song on Discord",
+                    PlayerMessage::PresenceToggled,
+                )
+                .size(16)
+                .text_size(16),
+            ),
+            None => column,
+        };
+
+        let column = if !lastfm_available {
+            column
+        } else {
+            let (label, action, message) = match (&self.lastfm_user, &self.lastfm_token) {
+                (Some(user), _) => (
+                    format!("Scrobbling to Last.fm as {}", user),
+                    "disconnect",
+                    AccountMessage::LastfmDisconnect,
+                ),
+                (None, Some(_)) => (
+                    "Approve Wan Player on the Last.fm page opened in your browser".to_string(),
+                    "done",
+                    AccountMessage::LastfmApproved,
+                ),
+                (None, None) => (
+                    "Connect to Last.fm to scrobble the songs".to_string(),
+                    "connect",
+                    AccountMessage::LastfmConnect,
+                ),
+            };
+            let column = column.push(widget::Text::new(label).size(16)).push(
+                widget::Button::new(&mut self.lastfm_state, widget::Text::new(action).size(16))
+                    .style(ui::TextButtonStyle)
+                    .on_press(PlayerMessage::Account(message)),
+            );
+            match self.lastfm_status {
+                Some(ref status) => column.push(widget::Text::new(status).size(16).color([1.0, 0.5, 0.5, 1.0])),
+                None => column,
+            }
+        };
+
+        let column = if !webhooks_available {
+            column
+        } else {
+            let preview = widget::Button::new(
+                &mut self.test_webhooks_state,
+                widget::Text::new(if self.testing_webhooks {
+                    "testing..."
+                } else {
+                    "test webhooks"
+                })
+                .size(16),
+            )
+            .style(ui::TextButtonStyle);
+            let preview = if self.testing_webhooks {
+                preview
+            } else {
+                preview.on_press(PlayerMessage::Account(AccountMessage::PreviewWebhooks))
+            };
+            let column = self
+                .webhook_results
+                .iter()
+                .fold(column.push(preview), |column, result| {
+                    column.push(widget::Text::new(result).size(14))
+                });
+            match self.webhook_test {
+                Some(_) => column.push(
+                    widget::Button::new(
+                        &mut self.send_test_webhooks_state,
+                        widget::Text::new("send these calls").size(16),
+                    )
+                    .style(ui::TextButtonStyle)
+                    .on_press(PlayerMessage::Account(AccountMessage::SendTestWebhooks)),
+                ),
+                None => column,
+            }
+        };
+
+        column.into()
+    }
+}
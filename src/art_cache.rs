@@ -0,0 +1,251 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Least recently used disk cache for album art.
+///
+/// The order of use is tracked in memory. When the cache is opened it starts from the modification time of the files,
+/// so the files written last are kept the longest across restarts.
+#[derive(Debug, Clone)]
+pub struct ArtCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    index: Arc<Mutex<Index>>,
+}
+
+/// Sizes and order of use of the cached files.
+#[derive(Debug, Default)]
+struct Index {
+    /// Last use and size of the files, by file name
+    files: HashMap<OsString, (u64, u64)>,
+    /// File names by last use, least recently used first
+    by_use: BTreeMap<u64, OsString>,
+    next_use: u64,
+    total_bytes: u64,
+}
+
+impl Index {
+    /// Records a use of a file, adding it to the index or updating its size.
+    fn touch(&mut self, name: &OsString, len: u64) {
+        self.remove(name);
+        let used = self.next_use;
+        self.next_use += 1;
+        self.files.insert(name.clone(), (used, len));
+        self.by_use.insert(used, name.clone());
+        self.total_bytes += len;
+    }
+
+    fn remove(&mut self, name: &OsString) {
+        if let Some((used, len)) = self.files.remove(name) {
+            self.by_use.remove(&used);
+            self.total_bytes -= len;
+        }
+    }
+
+    /// Removes the least recently used file from the index and returns its name.
+    fn pop_least_recent(&mut self) -> Option<OsString> {
+        let (_, name) = self.by_use.iter().next()?;
+        let name = name.clone();
+        self.remove(&name);
+        Some(name)
+    }
+}
+
+impl ArtCache {
+    /// Opens the cache in the user's cache directory. Returns `None` if there is no cache directory or if
+    /// `max_bytes` is 0.
+    pub fn open(max_bytes: u64) -> Option<ArtCache> {
+        if max_bytes == 0 {
+            return None;
+        }
+        let mut dir = dirs::cache_dir()?;
+        dir.push("wan_player");
+        dir.push("album_art");
+        ArtCache::open_dir(dir, max_bytes)
+    }
+
+    fn open_dir(dir: PathBuf, max_bytes: u64) -> Option<ArtCache> {
+        if let Err(error) = std::fs::create_dir_all(&dir) {
+            eprintln!("Failed to create album art cache {}: {}", dir.display(), error);
+            return None;
+        }
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(error) => {
+                eprintln!("Failed to list album art cache {}: {}", dir.display(), error);
+                return None;
+            }
+        };
+        let mut files = entries
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let metadata = entry.metadata().ok().filter(|metadata| metadata.is_file())?;
+                let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                Some((modified, metadata.len(), entry.file_name()))
+            })
+            .collect::<Vec<_>>();
+        files.sort_by_key(|(modified, _, _)| *modified);
+
+        let mut index = Index::default();
+        for (_, len, name) in files {
+            index.touch(&name, len);
+        }
+        let cache = ArtCache {
+            dir,
+            max_bytes,
+            index: Arc::new(Mutex::new(index)),
+        };
+        // The limit may have been lowered since the last run
+        cache.evict();
+        Some(cache)
+    }
+
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let (name, path) = self.path(key)?;
+        let data = std::fs::read(&path);
+        let mut index = self.index.lock().unwrap();
+        match data {
+            Ok(data) => {
+                index.touch(&name, data.len() as u64);
+                Some(data)
+            }
+            Err(_) => {
+                index.remove(&name);
+                None
+            }
+        }
+    }
+
+    /// Returns the path of a cached file, for the consumers that need a file rather than its contents.
+    pub fn file_path(&self, key: &str) -> Option<PathBuf> {
+        let (name, path) = self.path(key)?;
+        let mut index = self.index.lock().unwrap();
+        let len = index.files.get(&name)?.1;
+        index.touch(&name, len);
+        Some(path)
+    }
+
+    pub fn put(&self, key: &str, data: &[u8]) {
+        let (name, path) = match self.path(key) {
+            Some(path) => path,
+            None => return,
+        };
+        if data.len() as u64 > self.max_bytes {
+            return;
+        }
+        if let Err(error) = std::fs::write(&path, data) {
+            eprintln!("Failed to cache album art {}: {}", path.display(), error);
+            return;
+        }
+        self.index.lock().unwrap().touch(&name, data.len() as u64);
+        self.evict();
+    }
+
+    /// Removes the least recently used files until the cache fits in its size limit.
+    fn evict(&self) {
+        let mut index = self.index.lock().unwrap();
+        while index.total_bytes > self.max_bytes {
+            let name = match index.pop_least_recent() {
+                Some(name) => name,
+                None => break,
+            };
+            let path = self.dir.join(name);
+            match std::fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+                Err(error) => eprintln!("Failed to remove cached album art {}: {}", path.display(), error),
+            }
+        }
+    }
+
+    /// Returns the file name and the path of a cached file.
+    fn path(&self, key: &str) -> Option<(OsString, PathBuf)> {
+        // Only keep the file name so the key can't escape the cache directory
+        let file_name = Path::new(key).file_name()?;
+        Some((file_name.to_owned(), self.dir.join(file_name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = std::env::temp_dir().join(format!("wan_player-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let dir = TempDir::new("art_cache_lru");
+        let cache = ArtCache::open_dir(dir.0.clone(), 30).unwrap();
+        cache.put("a.jpg", &[0; 10]);
+        cache.put("b.jpg", &[0; 10]);
+        cache.put("c.jpg", &[0; 10]);
+        assert!(cache.get("a.jpg").is_some());
+
+        cache.put("d.jpg", &[0; 10]);
+        assert!(cache.file_path("b.jpg").is_none());
+        assert!(!dir.0.join("b.jpg").exists());
+        for key in &["a.jpg", "c.jpg", "d.jpg"] {
+            assert!(cache.file_path(key).is_some(), "{} was evicted", key);
+        }
+    }
+
+    #[test]
+    fn running_total() {
+        let dir = TempDir::new("art_cache_total");
+        let cache = ArtCache::open_dir(dir.0.clone(), 30).unwrap();
+        cache.put("a.jpg", &[0; 20]);
+        // Replacing a file counts its new size only
+        cache.put("a.jpg", &[0; 5]);
+        cache.put("b.jpg", &[0; 20]);
+        assert_eq!(cache.index.lock().unwrap().total_bytes, 25);
+        assert_eq!(cache.get("a.jpg").unwrap().len(), 5);
+
+        // Too big to be cached at all
+        cache.put("c.jpg", &[0; 31]);
+        assert!(cache.get("c.jpg").is_none());
+        assert_eq!(cache.index.lock().unwrap().total_bytes, 25);
+    }
+
+    #[test]
+    fn reopen() {
+        let dir = TempDir::new("art_cache_reopen");
+        let cache = ArtCache::open_dir(dir.0.clone(), 100).unwrap();
+        cache.put("a.jpg", &[0; 10]);
+        cache.put("../b.jpg", &[0; 10]);
+        assert!(dir.0.join("b.jpg").exists());
+
+        let cache = ArtCache::open_dir(dir.0.clone(), 15).unwrap();
+        assert_eq!(cache.index.lock().unwrap().total_bytes, 10);
+        assert_eq!(cache.index.lock().unwrap().files.len(), 1);
+    }
+}
@@ -0,0 +1,80 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+//! Wakes the decoder when the audio callback frees room in the ring buffer.
+//!
+//! This file doesn't depend on the rest of the player, so benches/backpressure.rs can include it.
+
+use futures_util::future::poll_fn;
+use futures_util::task::AtomicWaker;
+
+use std::sync::atomic::{fence, AtomicUsize, Ordering};
+use std::task::Poll;
+
+/// Lets the decoder sleep until the output stream drained enough samples, instead of polling the ring buffer.
+///
+/// The audio callback runs on a real-time thread, so it only touches atomics: it reads one on every call, and once
+/// per wait of the decoder, when the room it waits for is available, takes its waker out of an `AtomicWaker` and wakes
+/// it. Neither takes a lock or allocates, the only work left to the runtime is scheduling the woken decoder task.
+#[derive(Default)]
+pub struct SpaceNotifier {
+    /// Free samples the decoder is waiting for, 0 when it isn't waiting
+    waiting_for: AtomicUsize,
+    waker: AtomicWaker,
+}
+
+impl SpaceNotifier {
+    pub fn new() -> SpaceNotifier {
+        SpaceNotifier::default()
+    }
+
+    /// Called by the audio callback after it popped samples, with the free room left in the ring buffer.
+    pub fn space_available(&self, free: usize) {
+        // Pairs with the fence in `wait_for_space`: either the decoder sees the samples popped before this call, or
+        // this call sees that it is waiting
+        fence(Ordering::SeqCst);
+        let needed = self.waiting_for.load(Ordering::Acquire);
+        if needed != 0
+            && free >= needed
+            && self
+                .waiting_for
+                .compare_exchange(needed, 0, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        {
+            self.waker.wake();
+        }
+    }
+
+    /// Waits until `free()` reports at least `needed` free samples.
+    pub async fn wait_for_space(&self, needed: usize, free: impl Fn() -> usize) {
+        let needed = needed.max(1);
+        poll_fn(|cx| {
+            if free() >= needed {
+                self.waiting_for.store(0, Ordering::Release);
+                return Poll::Ready(());
+            }
+            self.waker.register(cx.waker());
+            self.waiting_for.store(needed, Ordering::Release);
+            fence(Ordering::SeqCst);
+            // The callback may have drained the buffer between the check and the store
+            if free() >= needed {
+                self.waiting_for.store(0, Ordering::Release);
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
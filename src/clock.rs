@@ -0,0 +1,119 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Weight of a new measurement in the clock offset estimate.
+const OFFSET_SMOOTHING: f64 = 0.25;
+
+/// Clock following the time of a remote server.
+///
+/// The song times sent by the stations are timestamps from their own clock, which can be quite far from ours. The
+/// offset between both clocks is estimated from the `Date` header of the HTTP responses we get from the server.
+#[derive(Debug, Default)]
+pub struct ServerClock {
+    /// Server time minus local time, in milliseconds
+    offset_ms: AtomicI64,
+    synchronized: AtomicBool,
+}
+
+impl ServerClock {
+    pub fn new() -> ServerClock {
+        ServerClock::default()
+    }
+
+    /// Current server time, in seconds since the UNIX epoch.
+    pub fn now(&self) -> u64 {
+        let now = local_now_ms() + self.offset_ms.load(Ordering::Relaxed);
+        (now.max(0) / 1000) as u64
+    }
+
+    /// Updates the offset from the headers of a response to a request sent at `sent_at` (local time, in milliseconds
+    /// since the UNIX epoch, see `local_now_ms`).
+    pub fn observe(&self, sent_at: i64, headers: &hyper::HeaderMap) {
+        let received_at = local_now_ms();
+        let server_time = match headers
+            .get(hyper::header::DATE)
+            .and_then(|date| date.to_str().ok())
+            .and_then(|date| chrono::DateTime::parse_from_rfc2822(date).ok())
+        {
+            Some(server_time) => server_time,
+            None => return,
+        };
+
+        // The Date header is truncated to the second and was generated at some point during the round trip, so the
+        // middle of both intervals is our best guess.
+        let server_ms = server_time.timestamp_millis() + 500;
+        let local_ms = sent_at + (received_at - sent_at) / 2;
+        let measured = server_ms - local_ms;
+
+        let offset = if self.synchronized.swap(true, Ordering::Relaxed) {
+            let previous = self.offset_ms.load(Ordering::Relaxed);
+            previous + ((measured - previous) as f64 * OFFSET_SMOOTHING) as i64
+        } else {
+            measured
+        };
+        self.offset_ms.store(offset, Ordering::Relaxed);
+    }
+}
+
+/// Local time, in milliseconds since the UNIX epoch.
+pub fn local_now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+/// Returns a pseudo random delay between 0 and `max`, used to avoid every player hitting the API at the same time.
+///
+/// The standard library's `RandomState` is seeded randomly for each process, hashing the time and the process id with
+/// it gives values spread evenly even for players started at the same time, or on clocks with a coarse resolution.
+pub fn jitter(max: Duration) -> Duration {
+    let mut hasher = RandomState::new().build_hasher();
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .hash(&mut hasher);
+    std::process::id().hash(&mut hasher);
+    max.mul_f64((hasher.finish() >> 11) as f64 / (1u64 << 53) as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jitter_is_bounded_and_spread() {
+        let max = Duration::from_secs(5);
+        let delays: Vec<Duration> = (0..1000).map(|_| jitter(max)).collect();
+        assert!(delays.iter().all(|delay| *delay < max));
+        // Calls in the same microsecond must not give the same delay
+        let mut distinct = delays.clone();
+        distinct.sort();
+        distinct.dedup();
+        assert!(distinct.len() > 990);
+        // Roughly as many delays in each half
+        let low = delays.iter().filter(|delay| **delay < max / 2).count();
+        assert!((400..600).contains(&low), "{} delays in the lower half", low);
+    }
+
+    #[test]
+    fn jitter_zero() {
+        assert_eq!(jitter(Duration::from_secs(0)), Duration::from_secs(0));
+    }
+}
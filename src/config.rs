@@ -0,0 +1,1072 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use toml_edit::Document;
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::gensokyo_radio::RetryPolicy;
+
+/// Version of the configuration files written by this player. The files without a `version` are version 0.
+pub const CONFIG_VERSION: i64 = 2;
+/// `MIGRATIONS[n]` upgrades a configuration file from version n to version n + 1.
+const MIGRATIONS: &[fn(&mut Document)] = &[migrate_v0, migrate_v1];
+const KEYRING_SERVICE: &str = "wan_player";
+/// Options kept in the OS keyring rather than in the file, with their keyring user, see `ConfigFile::keep_secrets`.
+const SECRETS: &[(&str, &str, &str)] = &[
+    ("lastfm", "api_secret", "last.fm api_secret"),
+    ("listenbrainz", "token", "listenbrainz token"),
+];
+/// The window can't be made smaller than this, the album art and the controls wouldn't fit.
+pub const MIN_WINDOW_SIZE: (u32, u32) = (480, 340);
+/// Largest album art cache, in megabytes.
+pub const MAX_ART_CACHE_SIZE_MB: u64 = 100_000;
+
+/// User configuration, read from `config.toml` in the user's config directory.
+///
+/// Every field has a default value, so a missing file or a missing option behaves like the previous versions of the
+/// player. The `[player]` section and the options the user can change in the player are written back, see
+/// `ConfigFile::save`.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub player: PlayerConfig,
+    pub album_art: AlbumArtConfig,
+    pub api: ApiConfig,
+    pub lyrics: LyricsConfig,
+    pub enrichment: EnrichmentConfig,
+    pub discord: DiscordConfig,
+    pub mpris: MprisConfig,
+    pub lastfm: LastfmConfig,
+    pub listenbrainz: ListenBrainzConfig,
+    pub notifications: NotificationsConfig,
+    pub now_playing: NowPlayingConfig,
+    pub webhooks: Vec<WebhookConfig>,
+}
+
+/// State of the player restored on the next launch, saved by the player when it changes.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PlayerConfig {
+    /// Between 0 and 100. Wider than the player's volume so a larger value is reported and lowered to 100 rather than
+    /// replaced by the default.
+    pub volume: u32,
+    /// Start playing as soon as the player opens
+    pub play_on_start: bool,
+    /// Size of the window. iced doesn't let us restore the position.
+    pub window_width: u32,
+    pub window_height: u32,
+}
+
+impl Default for PlayerConfig {
+    fn default() -> Self {
+        PlayerConfig {
+            volume: 10,
+            play_on_start: false,
+            window_width: 640,
+            window_height: 340,
+        }
+    }
+}
+
+impl PlayerConfig {
+    pub fn volume(&self) -> u8 {
+        self.volume.min(100) as u8
+    }
+
+    pub fn window_size(&self) -> (u32, u32) {
+        (self.window_width, self.window_height)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AlbumArtConfig {
+    /// Maximum size of the album art disk cache, in megabytes. 0 disables the cache.
+    pub cache_size_mb: u64,
+    /// Fetch larger album art when the window is big enough to show it.
+    pub high_resolution: bool,
+}
+
+impl Default for AlbumArtConfig {
+    fn default() -> Self {
+        AlbumArtConfig {
+            cache_size_mb: 50,
+            high_resolution: true,
+        }
+    }
+}
+
+/// Timeout and retries of the Gensokyo Radio API requests.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ApiConfig {
+    pub timeout_secs: u64,
+    /// Number of times a request is sent before giving up
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled after every attempt up to `max_backoff_secs`
+    pub initial_backoff_secs: u64,
+    pub max_backoff_secs: u64,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        let policy = RetryPolicy::default();
+        ApiConfig {
+            timeout_secs: policy.timeout.as_secs(),
+            max_attempts: policy.max_attempts,
+            initial_backoff_secs: policy.initial_backoff.as_secs(),
+            max_backoff_secs: policy.max_backoff.as_secs(),
+        }
+    }
+}
+
+impl ApiConfig {
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            timeout: Duration::from_secs(self.timeout_secs.max(1)),
+            max_attempts: self.max_attempts.max(1),
+            initial_backoff: Duration::from_secs(self.initial_backoff_secs.min(self.max_backoff_secs)),
+            max_backoff: Duration::from_secs(self.max_backoff_secs),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct LyricsConfig {
+    /// Local lyrics library, relative to the configuration directory. `wan_player/lyrics` in the user's data directory
+    /// by default.
+    pub directory: Option<PathBuf>,
+    /// Online lyrics provider used when the local library doesn't have the song, e.g. `"lrclib"`. Disabled by default.
+    pub online_provider: Option<String>,
+}
+
+/// Lookup of the Touhou originals arranged by the songs.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct EnrichmentConfig {
+    /// Look the songs up on Touhou DB
+    pub touhoudb: bool,
+    /// Local dataset used instead of Touhou DB, relative to the configuration directory. Touhou DB is still used when
+    /// the dataset can't be loaded, if it is enabled.
+    pub dataset: Option<PathBuf>,
+}
+
+impl Default for EnrichmentConfig {
+    fn default() -> Self {
+        EnrichmentConfig {
+            touhoudb: true,
+            dataset: None,
+        }
+    }
+}
+
+/// Discord Rich Presence. The texts are templates, see `sink::template_values` for the placeholders.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct DiscordConfig {
+    /// Show the current song on the user's Discord profile, when the player is built with the `discord` feature
+    pub enabled: bool,
+    /// Discord application the presence belongs to, its name is shown as "Listening to <name>"
+    pub application_id: String,
+    pub state: String,
+    /// Replaces `state` while the player is paused
+    pub paused_state: String,
+    pub details: String,
+    /// Image keys uploaded to the Discord application, or image URLs
+    pub large_image: String,
+    pub large_text: String,
+    pub small_image: String,
+    pub small_text: String,
+    /// Used when `large_image` is empty once expanded, e.g. for the songs without album art
+    pub fallback_image: String,
+    pub timestamps: PresenceTimestamps,
+    pub on_pause: PausedPresence,
+}
+
+impl Default for DiscordConfig {
+    fn default() -> Self {
+        DiscordConfig {
+            enabled: true,
+            application_id: "808130280976023563".to_string(),
+            state: "Listening to {station}".to_string(),
+            paused_state: "Paused".to_string(),
+            details: "{artist} - {title}".to_string(),
+            large_image: "{album_art}".to_string(),
+            large_text: "{album}".to_string(),
+            small_image: String::new(),
+            small_text: String::new(),
+            fallback_image: "presence_image".to_string(),
+            timestamps: PresenceTimestamps::Remaining,
+            on_pause: PausedPresence::Show,
+        }
+    }
+}
+
+/// The time shown under the presence.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PresenceTimestamps {
+    /// Time left before the end of the song
+    Remaining,
+    /// Time since the song started
+    Elapsed,
+    None,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct MprisConfig {
+    /// Expose the player on the D-Bus session bus, for media keys and desktop widgets. Linux only.
+    pub enabled: bool,
+}
+
+impl Default for MprisConfig {
+    fn default() -> Self {
+        MprisConfig { enabled: true }
+    }
+}
+
+/// What the presence shows while the player is paused.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PausedPresence {
+    /// The song, with `paused_state` as state and no timer
+    Show,
+    /// Nothing, as if the player was closed
+    Clear,
+}
+
+/// Last.fm scrobbling, active once the API account of the application is set.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct LastfmConfig {
+    pub enabled: bool,
+    /// API account, from https://www.last.fm/api/account/create
+    pub api_key: String,
+    /// Moved from the file to the OS keyring when the player reads it
+    pub api_secret: String,
+}
+
+impl Default for LastfmConfig {
+    fn default() -> Self {
+        LastfmConfig {
+            enabled: true,
+            api_key: String::new(),
+            api_secret: String::new(),
+        }
+    }
+}
+
+/// ListenBrainz submission, active once the user token is set.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ListenBrainzConfig {
+    pub enabled: bool,
+    /// User token, from https://listenbrainz.org/profile/. Moved from the file to the OS keyring when the player reads
+    /// it.
+    pub token: String,
+    /// API root, for self-hosted instances
+    pub server: String,
+}
+
+impl Default for ListenBrainzConfig {
+    fn default() -> Self {
+        ListenBrainzConfig {
+            enabled: true,
+            token: String::new(),
+            server: crate::listenbrainz::LISTENBRAINZ_API.to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct NotificationsConfig {
+    /// Show a desktop notification when the song changes. Linux only.
+    pub enabled: bool,
+    /// Also show them while the mouse cursor is over the player window. The window focus isn't reported by iced, the
+    /// cursor is the closest we have.
+    pub when_hovered: bool,
+}
+
+impl Default for NotificationsConfig {
+    fn default() -> Self {
+        NotificationsConfig {
+            enabled: true,
+            when_hovered: false,
+        }
+    }
+}
+
+/// Now playing outputs for streaming software. Every output is off until its path or address is set.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct NowPlayingConfig {
+    pub enabled: bool,
+    /// Text file receiving `text_template`, relative to the configuration directory
+    pub text_file: Option<PathBuf>,
+    /// See `sink::template_values` for the placeholders
+    pub text_template: String,
+    /// JSON file receiving the current song, relative to the configuration directory
+    pub json_file: Option<PathBuf>,
+    /// Address of the HTML overlay server, e.g. `127.0.0.1:9090`
+    pub overlay_address: Option<String>,
+}
+
+impl Default for NowPlayingConfig {
+    fn default() -> Self {
+        NowPlayingConfig {
+            enabled: true,
+            text_file: None,
+            text_template: "{artist} - {title}".to_string(),
+            json_file: None,
+            overlay_address: None,
+        }
+    }
+}
+
+impl NowPlayingConfig {
+    pub fn has_outputs(&self) -> bool {
+        self.text_file.is_some() || self.json_file.is_some() || self.overlay_address.is_some()
+    }
+}
+
+/// A webhook, called on the events it lists. Configured as a `[[webhooks]]` array, see `webhooks` for the body.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct WebhookConfig {
+    pub url: String,
+    pub method: String,
+    /// Body template, see `webhooks::render_body` for the placeholders. A JSON object with the song by default.
+    pub body: Option<String>,
+    pub headers: HashMap<String, String>,
+    /// Among `song_changed`, `playing` and `paused`
+    pub events: Vec<String>,
+    /// Escape the placeholder values for JSON strings, turn off for bodies that aren't JSON
+    pub escape_json: bool,
+    /// Minimum time between two calls, the events in between are queued
+    pub min_interval_secs: u64,
+    /// Attempts of a call failing with a network or server error
+    pub max_attempts: u32,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            url: String::new(),
+            method: "POST".to_string(),
+            body: None,
+            headers: HashMap::new(),
+            events: vec!["song_changed".to_string(), "playing".to_string(), "paused".to_string()],
+            escape_json: true,
+            min_interval_secs: 5,
+            max_attempts: 3,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration, upgrading the file to the current version if needed.
+    ///
+    /// Falls back to the defaults if the file doesn't exist or can't be parsed. The values that are invalid or of the
+    /// wrong type are replaced by their defaults, the problems are listed in `ConfigFile::errors` to be shown to the
+    /// user. The secrets come from the OS keyring, see `ConfigFile::keep_secrets`.
+    pub fn load() -> (Config, ConfigFile) {
+        let (mut config, mut file) = Config::load_from(config_path());
+        file.keep_secrets(&mut config, keyring_get, keyring_set);
+        (config, file)
+    }
+
+    /// The value of a secret option, see `SECRETS`.
+    fn secret_mut(&mut self, section: &str) -> Option<&mut String> {
+        match section {
+            "lastfm" => Some(&mut self.lastfm.api_secret),
+            "listenbrainz" => Some(&mut self.listenbrainz.token),
+            _ => None,
+        }
+    }
+
+    fn load_from(path: Option<PathBuf>) -> (Config, ConfigFile) {
+        let mut file = ConfigFile {
+            path,
+            modified: None,
+            document: None,
+            errors: Vec::new(),
+        };
+        let path = match file.path {
+            Some(ref path) => path.clone(),
+            None => return (Config::default(), file),
+        };
+        file.modified = modified_time(&path);
+
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                // Created on the first save
+                file.document = Some(Document::new());
+                return (Config::default(), file);
+            }
+            Err(error) => {
+                file.error(format!("Failed to read config {}: {}", path.display(), error));
+                return (Config::default(), file);
+            }
+        };
+        let mut document = match contents.parse::<Document>() {
+            Ok(document) => document,
+            Err(error) => {
+                file.error(format!(
+                    "Invalid config {}, it won't be saved until it is fixed: {}",
+                    path.display(),
+                    error
+                ));
+                return (Config::default(), file);
+            }
+        };
+
+        let version = document["version"].as_integer().unwrap_or(0);
+        let migrated = version < CONFIG_VERSION;
+        if version > CONFIG_VERSION {
+            // Options we don't know would be lost, so the file is left alone
+            file.error(format!(
+                "Config {} is for a newer version of the player (version {}), it won't be saved",
+                path.display(),
+                version
+            ));
+        } else if migrated {
+            let backup = path.with_extension(format!("toml.v{}", version));
+            if let Err(error) = std::fs::copy(&path, &backup) {
+                eprintln!("Failed to back up config to {}: {}", backup.display(), error);
+            }
+            for migration in MIGRATIONS[version.max(0) as usize..].iter() {
+                migration(&mut document);
+            }
+            document["version"] = toml_edit::value(CONFIG_VERSION);
+        }
+
+        let table = match toml::from_str::<toml::value::Table>(&document.to_string()) {
+            Ok(table) => table,
+            Err(error) => {
+                file.error(format!(
+                    "Invalid config {}, it won't be saved until it is fixed: {}",
+                    path.display(),
+                    error
+                ));
+                return (Config::default(), file);
+            }
+        };
+        let mut errors = Vec::new();
+        let mut config = Config::from_table(table, &mut errors);
+        errors.extend(config.validate());
+        for error in errors {
+            file.error(error);
+        }
+        if version <= CONFIG_VERSION {
+            file.document = Some(document);
+        }
+        if migrated {
+            file.write();
+        }
+        (config, file)
+    }
+
+    /// Reads the sections of the file. An option that can't be read, e.g. a negative volume or a string instead of a
+    /// number, is reported and left to its default so the other options still apply.
+    fn from_table(mut table: toml::value::Table, errors: &mut Vec<String>) -> Config {
+        let webhooks = match table.remove("webhooks") {
+            Some(toml::Value::Array(webhooks)) => webhooks
+                .into_iter()
+                .enumerate()
+                .map(|(index, webhook)| read_section(&format!("webhooks[{}]", index), Some(webhook), errors))
+                .collect(),
+            Some(value) => {
+                errors.push(format!("webhooks must be an array of tables, not {}", value.type_str()));
+                Vec::new()
+            }
+            None => Vec::new(),
+        };
+        Config {
+            player: read_section("player", table.remove("player"), errors),
+            album_art: read_section("album_art", table.remove("album_art"), errors),
+            api: read_section("api", table.remove("api"), errors),
+            lyrics: read_section("lyrics", table.remove("lyrics"), errors),
+            enrichment: read_section("enrichment", table.remove("enrichment"), errors),
+            discord: read_section("discord", table.remove("discord"), errors),
+            mpris: read_section("mpris", table.remove("mpris"), errors),
+            lastfm: read_section("lastfm", table.remove("lastfm"), errors),
+            listenbrainz: read_section("listenbrainz", table.remove("listenbrainz"), errors),
+            notifications: read_section("notifications", table.remove("notifications"), errors),
+            now_playing: read_section("now_playing", table.remove("now_playing"), errors),
+            webhooks,
+        }
+    }
+
+    /// Replaces the invalid values by their defaults, returning what was wrong.
+    fn validate(&mut self) -> Vec<String> {
+        let mut errors = Vec::new();
+        let defaults = Config::default();
+
+        if self.player.volume > 100 {
+            errors.push(format!(
+                "player.volume must be between 0 and 100, not {}",
+                self.player.volume
+            ));
+            self.player.volume = 100;
+        }
+        if self.player.window_width < MIN_WINDOW_SIZE.0 || self.player.window_height < MIN_WINDOW_SIZE.1 {
+            errors.push(format!(
+                "The window must be at least {}x{}, not {}x{}",
+                MIN_WINDOW_SIZE.0, MIN_WINDOW_SIZE.1, self.player.window_width, self.player.window_height
+            ));
+            self.player.window_width = self.player.window_width.max(MIN_WINDOW_SIZE.0);
+            self.player.window_height = self.player.window_height.max(MIN_WINDOW_SIZE.1);
+        }
+        if self.album_art.cache_size_mb > MAX_ART_CACHE_SIZE_MB {
+            errors.push(format!(
+                "album_art.cache_size_mb must be at most {}, not {}",
+                MAX_ART_CACHE_SIZE_MB, self.album_art.cache_size_mb
+            ));
+            self.album_art.cache_size_mb = MAX_ART_CACHE_SIZE_MB;
+        }
+        if self.api.timeout_secs == 0 || self.api.max_attempts == 0 {
+            errors.push("api.timeout_secs and api.max_attempts can't be 0".to_string());
+            self.api = defaults.api;
+        }
+        if let Some(ref provider) = self.lyrics.online_provider {
+            if !crate::lyrics::PROVIDERS.contains(&provider.as_str()) {
+                errors.push(format!(
+                    "Unknown lyrics.online_provider \"{}\", expected one of {}",
+                    provider,
+                    crate::lyrics::PROVIDERS.join(", ")
+                ));
+                self.lyrics.online_provider = None;
+            }
+        }
+        if self.discord.application_id.is_empty() || !self.discord.application_id.chars().all(|c| c.is_ascii_digit()) {
+            errors.push(format!(
+                "discord.application_id must be a number, not \"{}\"",
+                self.discord.application_id
+            ));
+            self.discord.application_id = defaults.discord.application_id;
+        }
+        if let Some(ref address) = self.now_playing.overlay_address {
+            if address.parse::<std::net::SocketAddr>().is_err() {
+                errors.push(format!(
+                    "now_playing.overlay_address must be an IP address and a port, e.g. 127.0.0.1:9090, not \"{}\"",
+                    address
+                ));
+                self.now_playing.overlay_address = None;
+            }
+        }
+        self.webhooks.retain(|webhook| {
+            if !webhook.url.starts_with("http://") && !webhook.url.starts_with("https://") {
+                errors.push(format!(
+                    "Webhook URL \"{}\" must start with http:// or https://",
+                    webhook.url
+                ));
+                return false;
+            }
+            if hyper::Method::from_bytes(webhook.method.as_bytes()).is_err() {
+                errors.push(format!(
+                    "Invalid method \"{}\" of webhook {}",
+                    webhook.method, webhook.url
+                ));
+                return false;
+            }
+            true
+        });
+        for webhook in self.webhooks.iter_mut() {
+            let url = &webhook.url;
+            webhook.events.retain(|event| {
+                let known = crate::webhooks::EVENTS.contains(&event.as_str());
+                if !known {
+                    errors.push(format!(
+                        "Unknown event \"{}\" of webhook {}, expected one of {}",
+                        event,
+                        url,
+                        crate::webhooks::EVENTS.join(", ")
+                    ));
+                }
+                known
+            });
+        }
+        errors
+    }
+}
+
+/// Reads a section, or an element of an array of tables. When it can't be read as a whole, its options are added one
+/// by one and the ones that don't fit are reported and dropped.
+fn read_section<T: DeserializeOwned + Default>(name: &str, value: Option<toml::Value>, errors: &mut Vec<String>) -> T {
+    let table = match value {
+        Some(toml::Value::Table(table)) => table,
+        Some(value) => {
+            errors.push(format!("{} must be a table, not {}", name, value.type_str()));
+            return T::default();
+        }
+        None => return T::default(),
+    };
+    if let Ok(section) = toml::Value::Table(table.clone()).try_into() {
+        return section;
+    }
+    let mut valid = toml::value::Table::new();
+    for (key, value) in table {
+        let mut candidate = valid.clone();
+        candidate.insert(key.clone(), value);
+        match toml::Value::Table(candidate.clone()).try_into::<T>() {
+            Ok(_) => valid = candidate,
+            Err(error) => errors.push(format!("{}.{}: {}", name, key, error)),
+        }
+    }
+    toml::Value::Table(valid).try_into().unwrap_or_default()
+}
+
+/// Version 1 added the `version` and the `[player]` section, and reads a relative `lyrics.directory` from the
+/// configuration directory like the other paths. Version 0 opened it from the working directory of the player, which
+/// is where the migration looks for it.
+fn migrate_v0(document: &mut Document) {
+    let directory = match document["lyrics"]["directory"].as_str() {
+        Some(directory) if Path::new(directory).is_relative() => directory.to_string(),
+        _ => return,
+    };
+    let absolute = match std::env::current_dir() {
+        Ok(current_dir) => current_dir.join(directory),
+        Err(error) => {
+            eprintln!("Failed to get the working directory: {}", error);
+            return;
+        }
+    };
+    match absolute.to_str() {
+        Some(absolute) => document["lyrics"]["directory"] = toml_edit::value(absolute),
+        None => eprintln!("Can't write lyrics directory {} in the config", absolute.display()),
+    }
+}
+
+/// Version 2 renamed `notifications.when_focused` to `notifications.when_hovered`, since the player only knows
+/// whether the mouse cursor is over its window.
+fn migrate_v1(document: &mut Document) {
+    let notifications = match document.as_table_mut().get_mut("notifications") {
+        Some(notifications) => notifications,
+        None => return,
+    };
+    if let Some(table) = notifications.as_table_mut() {
+        if let Some(when_focused) = table.remove("when_focused") {
+            table["when_hovered"] = when_focused;
+        }
+    } else if let Some(table) = notifications.as_inline_table_mut() {
+        if let Some(when_focused) = table.remove("when_focused") {
+            table.get_or_insert("when_hovered", when_focused);
+        }
+    }
+}
+
+/// Removes `section.key` from the document, returns whether it was there.
+fn remove_option(document: &mut Document, section: &str, key: &str) -> bool {
+    let section = match document.as_table_mut().get_mut(section) {
+        Some(section) => section,
+        None => return false,
+    };
+    match section.as_table_mut() {
+        Some(table) => table.remove(key).is_some(),
+        None => section
+            .as_inline_table_mut()
+            .map_or(false, |table| table.remove(key).is_some()),
+    }
+}
+
+fn keyring_get(user: &str) -> Option<String> {
+    let result = keyring::Entry::new(KEYRING_SERVICE, user).and_then(|entry| entry.get_password());
+    match result {
+        Ok(secret) => Some(secret),
+        Err(keyring::Error::NoEntry) => None,
+        Err(error) => {
+            eprintln!("Failed to read {} from the keyring: {}", user, error);
+            None
+        }
+    }
+}
+
+fn keyring_set(user: &str, secret: &str) -> bool {
+    let result = keyring::Entry::new(KEYRING_SERVICE, user).and_then(|entry| entry.set_password(secret));
+    match result {
+        Ok(()) => true,
+        Err(error) => {
+            eprintln!("Failed to store {} in the keyring: {}", user, error);
+            false
+        }
+    }
+}
+
+/// The configuration file as last read or written, to save the options changed in the player and notice the user's
+/// changes.
+#[derive(Debug, Clone)]
+pub struct ConfigFile {
+    path: Option<PathBuf>,
+    /// Modification time of the file when it was last read or written
+    modified: Option<SystemTime>,
+    /// The file as read, with the user's comments and formatting. `None` if the file must not be written, because it
+    /// is invalid or comes from a newer player.
+    document: Option<Document>,
+    /// Problems found while loading the file, shown in the player
+    pub errors: Vec<String>,
+}
+
+impl ConfigFile {
+    fn error(&mut self, error: String) {
+        eprintln!("{}", error);
+        self.errors.push(error);
+    }
+
+    /// Whether the file was modified since it was last read or written.
+    pub fn has_changed(&self) -> bool {
+        match self.path {
+            Some(ref path) => modified_time(path) != self.modified,
+            None => false,
+        }
+    }
+
+    /// Saves the options that can be changed in the player, keeping the rest of the file as the user wrote it.
+    pub fn save(&mut self, config: &Config) {
+        let document = match self.document {
+            Some(ref mut document) => document,
+            None => return,
+        };
+        document["version"] = toml_edit::value(CONFIG_VERSION);
+        for section in ["player", "discord"].iter() {
+            // The user wrote something else than a table, which was reported and ignored when loading
+            if !document[*section].is_table_like() {
+                document[*section] = toml_edit::table();
+            }
+        }
+        document["player"]["volume"] = toml_edit::value(i64::from(config.player.volume));
+        document["player"]["window_width"] = toml_edit::value(i64::from(config.player.window_width));
+        document["player"]["window_height"] = toml_edit::value(i64::from(config.player.window_height));
+        document["discord"]["enabled"] = toml_edit::value(config.discord.enabled);
+        self.write();
+    }
+
+    /// Moves the secrets written in the file to the keyring, so they don't stay on the disk in clear, and fills the
+    /// missing ones from the keyring.
+    ///
+    /// `get` and `set` read and write the keyring entry of a user. `set` returns whether the secret was stored.
+    fn keep_secrets<G, S>(&mut self, config: &mut Config, get: G, set: S)
+    where
+        G: Fn(&str) -> Option<String>,
+        S: Fn(&str, &str) -> bool,
+    {
+        let mut moved = false;
+        for (section, key, user) in SECRETS.iter() {
+            let value = match config.secret_mut(section) {
+                Some(value) => value,
+                None => continue,
+            };
+            if value.is_empty() {
+                if let Some(secret) = get(user) {
+                    *value = secret;
+                }
+            } else if let Some(ref mut document) = self.document {
+                // Left in the file if the keyring doesn't work, it would be lost otherwise
+                if set(user, value) {
+                    moved |= remove_option(document, section, key);
+                }
+            }
+        }
+        if moved {
+            self.write();
+        }
+    }
+
+    fn write(&mut self) {
+        let (path, document) = match (&self.path, &self.document) {
+            (Some(path), Some(document)) => (path, document),
+            _ => return,
+        };
+        // Written next to the file then renamed, so a crash can't leave a truncated configuration
+        let temporary = path.with_extension("toml.tmp");
+        let result = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(&temporary, document.to_string()))
+            .and_then(|_| std::fs::rename(&temporary, path));
+        match result {
+            Ok(()) => self.modified = modified_time(path),
+            Err(error) => eprintln!("Failed to save config {}: {}", path.display(), error),
+        }
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+pub fn config_path() -> Option<PathBuf> {
+    let mut path = dirs::config_dir()?;
+    path.push("wan_player");
+    path.push("config.toml");
+    Some(path)
+}
+
+/// Relative paths in the configuration are relative to the configuration directory.
+pub fn resolve_path(path: &Path) -> PathBuf {
+    match config_path().as_ref().and_then(|config| config.parent()) {
+        Some(config_dir) if path.is_relative() => config_dir.join(path),
+        _ => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A configuration file in a directory of its own, removed with the directory at the end of the test
+    struct TempConfig {
+        dir: PathBuf,
+    }
+
+    impl TempConfig {
+        fn new(name: &str, contents: &str) -> TempConfig {
+            let dir = std::env::temp_dir().join(format!("wan_player-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).expect("Failed to create the config directory");
+            let config = TempConfig { dir };
+            std::fs::write(config.path(), contents).expect("Failed to write the config");
+            config
+        }
+
+        fn path(&self) -> PathBuf {
+            self.dir.join("config.toml")
+        }
+
+        fn load(&self) -> (Config, ConfigFile) {
+            Config::load_from(Some(self.path()))
+        }
+
+        fn contents(&self) -> String {
+            std::fs::read_to_string(self.path()).expect("Failed to read the config")
+        }
+    }
+
+    impl Drop for TempConfig {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn migration() {
+        let v0 = "# My lyrics\n[lyrics]\ndirectory = \"lyrics\"\n\n[discord]\nenabled = false\n";
+        let file = TempConfig::new("config-migration", v0);
+        let (config, config_file) = file.load();
+        assert!(config_file.errors.is_empty(), "{:?}", config_file.errors);
+
+        let lyrics = std::env::current_dir().unwrap().join("lyrics");
+        assert_eq!(config.lyrics.directory, Some(lyrics.clone()));
+        assert!(!config.discord.enabled);
+        let backup = std::fs::read_to_string(file.path().with_extension("toml.v0")).expect("Failed to read the backup");
+        assert_eq!(backup, v0);
+
+        // The upgraded file keeps the comments and isn't migrated again
+        let contents = file.contents();
+        assert!(contents.contains("# My lyrics"));
+        assert!(contents.contains("version = 2"));
+        let (config, _) = file.load();
+        assert_eq!(config.lyrics.directory, Some(lyrics));
+
+        let absolute = TempConfig::new("config-migration-absolute", "[lyrics]\ndirectory = \"/srv/lyrics\"\n");
+        let (config, _) = absolute.load();
+        assert_eq!(config.lyrics.directory, Some(PathBuf::from("/srv/lyrics")));
+    }
+
+    #[test]
+    fn migration_v1() {
+        let v1 = "version = 1\n\n[notifications]\nwhen_focused = true\n";
+        let file = TempConfig::new("config-migration-v1", v1);
+        let (config, config_file) = file.load();
+        assert!(config_file.errors.is_empty(), "{:?}", config_file.errors);
+        assert!(config.notifications.when_hovered);
+        let contents = file.contents();
+        assert!(contents.contains("when_hovered = true"), "{}", contents);
+        assert!(!contents.contains("when_focused"), "{}", contents);
+
+        let inline = TempConfig::new(
+            "config-migration-v1-inline",
+            "version = 1\nnotifications = { when_focused = true }\n",
+        );
+        let (config, config_file) = inline.load();
+        assert!(config_file.errors.is_empty(), "{:?}", config_file.errors);
+        assert!(config.notifications.when_hovered);
+    }
+
+    #[test]
+    fn secrets() {
+        let file = TempConfig::new(
+            "config-secrets",
+            "version = 2\n\n[lastfm]\napi_key = \"key\"\napi_secret = \"secret\"\n\n[listenbrainz]\nserver = \"http://localhost\"\n",
+        );
+        let keyring = std::cell::RefCell::new(HashMap::new());
+        keyring
+            .borrow_mut()
+            .insert("listenbrainz token".to_string(), "token".to_string());
+        let get = |user: &str| keyring.borrow().get(user).cloned();
+        let set = |user: &str, secret: &str| {
+            keyring.borrow_mut().insert(user.to_string(), secret.to_string());
+            true
+        };
+
+        let (mut config, mut config_file) = file.load();
+        config_file.keep_secrets(&mut config, get, set);
+        assert_eq!(config.lastfm.api_secret, "secret");
+        assert_eq!(config.listenbrainz.token, "token");
+        assert_eq!(keyring.borrow()["last.fm api_secret"], "secret");
+        let contents = file.contents();
+        assert!(!contents.contains("secret"), "{}", contents);
+        assert!(contents.contains("api_key = \"key\""), "{}", contents);
+        assert!(!config_file.has_changed());
+
+        // Kept in the file when the keyring fails
+        let file = TempConfig::new(
+            "config-secrets-failed",
+            "version = 2\nlistenbrainz = { token = \"token\" }\n",
+        );
+        let (mut config, mut config_file) = file.load();
+        config_file.keep_secrets(&mut config, |_| None, |_, _| false);
+        assert_eq!(config.listenbrainz.token, "token");
+        assert!(file.contents().contains("token"));
+        config_file.keep_secrets(&mut config, |_| None, |_, _| true);
+        assert!(!file.contents().contains("token"), "{}", file.contents());
+    }
+
+    #[test]
+    fn invalid_values() {
+        let file = TempConfig::new(
+            "config-invalid",
+            r#"version = 2
+notifications = true
+
+[player]
+volume = -1
+window_width = "wide"
+play_on_start = true
+
+[album_art]
+cache_size_mb = 9223372036854775807
+
+[discord]
+application_id = "abc"
+details = "{title}"
+
+[[webhooks]]
+url = "https://example.com/song"
+max_attempts = "twice"
+
+[[webhooks]]
+url = "ftp://example.com/song"
+"#,
+        );
+        let (config, config_file) = file.load();
+        let defaults = Config::default();
+
+        assert_eq!(config.player.volume, defaults.player.volume);
+        assert_eq!(config.player.window_width, defaults.player.window_width);
+        assert!(config.player.play_on_start);
+        assert_eq!(config.album_art.cache_size_mb, MAX_ART_CACHE_SIZE_MB);
+        assert_eq!(config.discord.application_id, defaults.discord.application_id);
+        assert_eq!(config.discord.details, "{title}");
+        assert_eq!(config.notifications, defaults.notifications);
+        assert_eq!(config.webhooks.len(), 1);
+        assert_eq!(config.webhooks[0].url, "https://example.com/song");
+        assert_eq!(config.webhooks[0].max_attempts, WebhookConfig::default().max_attempts);
+
+        let errors = &config_file.errors;
+        assert_eq!(errors.len(), 7, "{:?}", errors);
+        for prefix in [
+            "notifications ",
+            "player.volume:",
+            "player.window_width:",
+            "webhooks[0].max_attempts:",
+        ]
+        .iter()
+        {
+            assert!(
+                errors.iter().any(|error| error.starts_with(prefix)),
+                "No {} in {:?}",
+                prefix,
+                errors
+            );
+        }
+        assert!(errors.iter().any(|error| error.contains("cache_size_mb")));
+        assert!(errors.iter().any(|error| error.contains("application_id")));
+        assert!(errors.iter().any(|error| error.contains("ftp://")));
+
+        // Still saved
+        let mut config_file = config_file;
+        config_file.save(&config);
+        let (saved, _) = file.load();
+        assert_eq!(saved.player.volume, defaults.player.volume);
+        assert!(saved.player.play_on_start);
+    }
+
+    #[test]
+    fn save() {
+        let file = TempConfig::new(
+            "config-save",
+            "version = 2\n\n# Mine\n[player]\nvolume = 30\nplay_on_start = true\n\n[lyrics]\nonline_provider = \"lrclib\"\n",
+        );
+        let (mut config, mut config_file) = file.load();
+        assert!(!config_file.has_changed());
+        config.player.volume = 75;
+        config.player.window_width = 800;
+        config.discord.enabled = false;
+        config_file.save(&config);
+        assert!(!config_file.has_changed());
+
+        let (saved, saved_file) = file.load();
+        assert!(saved_file.errors.is_empty(), "{:?}", saved_file.errors);
+        assert_eq!(saved.player.volume, 75);
+        assert_eq!(saved.player.window_width, 800);
+        assert_eq!(saved.player.window_height, config.player.window_height);
+        assert!(saved.player.play_on_start);
+        assert!(!saved.discord.enabled);
+        assert_eq!(saved.lyrics.online_provider.as_deref(), Some("lrclib"));
+        assert!(file.contents().contains("# Mine"));
+    }
+
+    #[test]
+    fn save_over_invalid_section() {
+        let file = TempConfig::new("config-save-invalid", "version = 2\nplayer = 40\n");
+        let (mut config, mut config_file) = file.load();
+        assert_eq!(config_file.errors.len(), 1, "{:?}", config_file.errors);
+        config.player.volume = 40;
+        config_file.save(&config);
+        let (saved, saved_file) = file.load();
+        assert!(saved_file.errors.is_empty(), "{:?}", saved_file.errors);
+        assert_eq!(saved.player.volume, 40);
+    }
+
+    #[test]
+    fn syntax_error() {
+        let contents = "[player\nvolume = 30\n";
+        let file = TempConfig::new("config-syntax", contents);
+        let (config, mut config_file) = file.load();
+        assert_eq!(config.player.volume, Config::default().player.volume);
+        assert_eq!(config_file.errors.len(), 1);
+        assert!(config_file.errors[0].contains("won't be saved"));
+
+        config_file.save(&config);
+        assert_eq!(file.contents(), contents);
+    }
+}
@@ -0,0 +1,127 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+//! Helpers shared by the SQLite databases kept in the user's data directory, and their exports.
+
+use serde::Serialize;
+
+use std::path::{Path, PathBuf};
+
+/// Directory of the player's databases, created if needed.
+pub fn data_dir() -> Option<PathBuf> {
+    let mut data_dir = dirs::data_dir()?;
+    data_dir.push("wan_player");
+    std::fs::create_dir_all(&data_dir).ok()?;
+    Some(data_dir)
+}
+
+/// Directory of the player's caches, created if needed.
+pub fn cache_dir() -> Option<PathBuf> {
+    let mut cache_dir = dirs::cache_dir()?;
+    cache_dir.push("wan_player");
+    std::fs::create_dir_all(&cache_dir).ok()?;
+    Some(cache_dir)
+}
+
+/// Opens the database `file_name` of the data directory and creates its tables with `schema`.
+///
+/// `name` describes the database in the error messages.
+pub fn open(file_name: &str, name: &str, schema: &str) -> Option<rusqlite::Connection> {
+    open_in(&data_dir()?, file_name, name, schema)
+}
+
+/// Opens the database `file_name` of `directory` and creates its tables with `schema`, see `open`.
+pub fn open_in(directory: &Path, file_name: &str, name: &str, schema: &str) -> Option<rusqlite::Connection> {
+    let path = directory.join(file_name);
+    let connection = match rusqlite::Connection::open(&path) {
+        Ok(connection) => connection,
+        Err(error) => {
+            eprintln!("Failed to open {} {}: {}", name, path.display(), error);
+            return None;
+        }
+    };
+    if let Err(error) = connection.execute_batch(schema) {
+        eprintln!("Failed to create {}: {}", name, error);
+        return None;
+    }
+    Some(connection)
+}
+
+/// Runs a database task out of the UI thread. Errors are reported and replaced by the default value.
+///
+/// `action` describes the task in the error message, e.g. "list favorites".
+pub async fn run_blocking<T, F>(action: &'static str, task: F) -> T
+where
+    T: Default + Send + 'static,
+    F: FnOnce() -> rusqlite::Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(task)
+        .await
+        .expect("Failed to join database task")
+        .unwrap_or_else(|error| {
+            eprintln!("Failed to {}: {}", action, error);
+            T::default()
+        })
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+    M3u,
+}
+
+impl ExportFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Json => "json",
+            ExportFormat::M3u => "m3u",
+        }
+    }
+}
+
+/// Asks the user where to export `name`, e.g. "favorites" is suggested as favorites.csv.
+pub async fn pick_export_path(name: &str, format: ExportFormat) -> Option<PathBuf> {
+    let extension = format.extension();
+    let file = rfd::AsyncFileDialog::new()
+        .set_file_name(&format!("{}.{}", name, extension))
+        .add_filter(extension, &[extension])
+        .save_file()
+        .await?;
+    Some(file.path().to_path_buf())
+}
+
+/// Writes `rows` as CSV or JSON. M3U playlists depend on what the rows are, so they are written by their owner.
+pub fn export_rows<T: Serialize>(
+    rows: &[T],
+    format: ExportFormat,
+    path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match format {
+        ExportFormat::Csv => {
+            let mut writer = csv::Writer::from_path(path)?;
+            for row in rows {
+                writer.serialize(row)?;
+            }
+            writer.flush()?;
+        }
+        ExportFormat::Json => {
+            let file = std::fs::File::create(path)?;
+            serde_json::to_writer_pretty(file, rows)?;
+        }
+        ExportFormat::M3u => return Err("M3U exports are written by the owner of the rows".into()),
+    }
+    Ok(())
+}
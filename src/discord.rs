@@ -12,81 +12,297 @@
 //    See the License for the specific language governing permissions and
 //    limitations under the License.
 
-use discord_game_sdk::*;
+use std::time::{Duration, Instant};
 
-use std::sync::mpsc::Receiver;
+use crate::config::{DiscordConfig, PausedPresence, PresenceTimestamps};
+use crate::discord_ipc::DiscordIpc;
+use crate::gensokyo_radio::GRApiAnswer;
+use crate::sink::{expand_template, NowPlayingEvent, NowPlayingSink};
 
-pub enum DiscordControl {
-    SongInfo(super::gensokyo_radio::GRApiAnswer),
+/// Delay between two connection attempts while Discord isn't running.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(15);
+/// Discord refuses the texts outside of these lengths
+const MIN_TEXT_LENGTH: usize = 2;
+const MAX_TEXT_LENGTH: usize = 128;
+
+/// Shows the current song on the user's Discord profile.
+pub struct DiscordSink {
+    config: DiscordConfig,
+    ipc: Option<DiscordIpc>,
+    /// Current song, station and listen URL of the station
+    song: Option<(GRApiAnswer, String, Option<String>)>,
+    playing: bool,
+    /// Activity to show once Discord is reachable, `Some(None)` to clear it
+    pending: Option<Option<serde_json::Value>>,
+    last_attempt: Option<Instant>,
 }
 
-struct DiscordEventHandler;
-impl EventHandler for DiscordEventHandler {}
-
-const DISCORD_CLIENT_ID: i64 = 808130280976023563;
-
-pub fn discord_main_loop(discord_rx: Receiver<DiscordControl>) {
-    std::thread::spawn(move || {
-        let mut discord: Option<Discord<'_, _>> = None;
-        loop {
-            let result = discord_rx.recv_timeout(std::time::Duration::from_secs(1));
-            match result {
-                Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
-                    let result = match discord {
-                        None => None,
-                        Some(ref mut d) => d.run_callbacks().ok(),
-                    };
-                    // Ensure we don't keep the "bad" discord if it doesn't work anymore
-                    if result.is_none() {
-                        discord = None;
-                    }
-                }
-                Err(_) => panic!(),
-                Ok(DiscordControl::SongInfo(song_info)) => {
-                    if discord.is_none() {
-                        discord = new_discord_client(DISCORD_CLIENT_ID);
-                    }
-
-                    if let Some(ref mut discord) = discord {
-                        let activity = activity_from_song_info(&song_info);
-                        discord.update_activity(&activity, |_, result| {
-                            if let Err(err) = result {
-                                eprintln!("Error: {}", err);
-                            }
-                        });
-                    }
-                }
+impl DiscordSink {
+    pub fn new(config: DiscordConfig) -> DiscordSink {
+        DiscordSink {
+            config,
+            ipc: None,
+            song: None,
+            playing: false,
+            pending: None,
+            last_attempt: None,
+        }
+    }
+
+    /// Activity matching the current song and play state, `None` to clear it.
+    fn activity(&self) -> Option<serde_json::Value> {
+        match (&self.song, self.playing, self.config.on_pause) {
+            (None, _, _) | (Some(_), false, PausedPresence::Clear) => None,
+            (Some((song, station, listen_url)), playing, _) => Some(activity_from_song_info(
+                &self.config,
+                song,
+                station,
+                listen_url.as_deref(),
+                !playing,
+            )),
+        }
+    }
+
+    /// Queues the activity matching the current song and play state.
+    fn update_activity(&mut self) {
+        self.pending = Some(self.activity());
+        self.flush();
+    }
+
+    /// Sends the pending activity, connecting to Discord first if needed.
+    fn flush(&mut self) {
+        if self.pending.is_none() {
+            return;
+        }
+        if self.ipc.is_none() {
+            if matches!(self.last_attempt, Some(attempt) if attempt.elapsed() < RECONNECT_INTERVAL) {
+                return;
+            }
+            self.last_attempt = Some(Instant::now());
+            // Discord not running is the usual case, it isn't worth a message
+            self.ipc = DiscordIpc::connect(&self.config.application_id).ok();
+        }
+
+        if let Some(ref mut ipc) = self.ipc {
+            let activity = self.pending.take().flatten();
+            if let Err(error) = ipc.set_activity(activity.clone()) {
+                eprintln!("Failed to update Discord presence: {}", error);
+                // Retried on the next connection
+                self.pending = Some(activity);
+                self.ipc = None;
             }
         }
-    });
+    }
 }
 
-fn new_discord_client(client_id: i64) -> Option<Discord<'static, DiscordEventHandler>> {
-    let discord = Discord::with_create_flags(client_id, CreateFlags::NoRequireDiscord).ok();
-    if discord.is_none() {
-        return None;
+impl NowPlayingSink for DiscordSink {
+    fn handle(&mut self, event: NowPlayingEvent) {
+        match event {
+            NowPlayingEvent::SongChanged {
+                song,
+                station,
+                listen_url,
+            } => {
+                self.song = Some((song, station, listen_url));
+                // Try right away, even if the last attempt is recent
+                self.last_attempt = None;
+            }
+            NowPlayingEvent::Playing => self.playing = true,
+            NowPlayingEvent::Paused => self.playing = false,
+            NowPlayingEvent::Cleared => self.song = None,
+            NowPlayingEvent::VolumeChanged(_) => return,
+        }
+        self.update_activity();
     }
 
-    let mut discord = discord.unwrap();
-    discord
-        .register_launch_command("https://gensokyoradio.net/music/playing/")
-        .expect("Failed to register launch command");
-    *discord.event_handler_mut() = Some(DiscordEventHandler);
+    fn tick(&mut self) {
+        self.flush();
+    }
 
-    Some(discord)
+    fn shutdown(&mut self) {
+        // Don't let the presence linger until Discord notices the player is gone
+        if let Some(ref mut ipc) = self.ipc {
+            if let Err(error) = ipc.set_activity(None) {
+                eprintln!("Failed to clear Discord presence: {}", error);
+            }
+        }
+    }
 }
 
-fn activity_from_song_info(song_info: &super::gensokyo_radio::GRApiAnswer) -> Activity {
-    let mut activity = Activity::empty();
-    activity
-        .with_state("Listening to Gensokyo Radio")
-        .with_details(&format!(
-            "{} - {}",
-            &song_info.songinfo.artist, &song_info.songinfo.title
-        ))
-        .with_start_time(song_info.songtimes.songstart as i64)
-        .with_end_time(song_info.songtimes.songend as i64)
-        .with_large_image_key("presence_image");
+/// Builds the activity of a song. Paused songs show `paused_state` instead of `state`, and no timer. The Listen button
+/// is only shown for the stations with a `listen_url`.
+fn activity_from_song_info(
+    config: &DiscordConfig,
+    song_info: &GRApiAnswer,
+    station: &str,
+    listen_url: Option<&str>,
+    paused: bool,
+) -> serde_json::Value {
+    let text = |template: &str| {
+        let text = expand_template(template, song_info, station);
+        if text.chars().count() < MIN_TEXT_LENGTH {
+            None
+        } else {
+            Some(text.chars().take(MAX_TEXT_LENGTH).collect::<String>())
+        }
+    };
+
+    let mut activity = serde_json::json!({ "assets": {} });
+    let state = if paused { &config.paused_state } else { &config.state };
+    let fields = [("state", state), ("details", &config.details)];
+    for (field, template) in fields.iter() {
+        if let Some(text) = text(template.as_str()) {
+            activity[*field] = text.into();
+        }
+    }
+    let assets = [
+        ("large_text", &config.large_text),
+        ("small_image", &config.small_image),
+        ("small_text", &config.small_text),
+    ];
+    for (field, template) in assets.iter() {
+        if let Some(text) = text(template.as_str()) {
+            activity["assets"][*field] = text.into();
+        }
+    }
+    // Discord accepts URLs as image keys, so the actual album art can be shown
+    let large_image = expand_template(&config.large_image, song_info, station);
+    if !large_image.is_empty() {
+        activity["assets"]["large_image"] = large_image.into();
+    } else if !config.fallback_image.is_empty() {
+        activity["assets"]["large_image"] = config.fallback_image.clone().into();
+    }
+
+    let times = &song_info.songtimes;
+    if times.songstart > 0 && !paused {
+        match config.timestamps {
+            PresenceTimestamps::Remaining if times.songend > times.songstart => {
+                activity["timestamps"] = serde_json::json!({
+                    "start": times.songstart * 1000,
+                    "end": times.songend * 1000,
+                });
+            }
+            PresenceTimestamps::Remaining | PresenceTimestamps::Elapsed => {
+                activity["timestamps"] = serde_json::json!({ "start": times.songstart * 1000 });
+            }
+            PresenceTimestamps::None => {}
+        }
+    }
 
+    let mut buttons = Vec::new();
+    if let Some(listen_url) = listen_url {
+        buttons.push(serde_json::json!({ "label": "Listen", "url": listen_url }));
+    }
+    if let Some(album_url) = song_info.songdata.album_url() {
+        buttons.push(serde_json::json!({ "label": "Album page", "url": album_url }));
+    }
+    if !buttons.is_empty() {
+        activity["buttons"] = buttons.into();
+    }
     activity
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::gensokyo_radio::{Misc, SongData, SongInfo, SongTimes};
+
+    fn song(albumart: &str, albumid: u64) -> GRApiAnswer {
+        GRApiAnswer {
+            serverinfo: Default::default(),
+            songinfo: SongInfo {
+                title: "Bad Apple!!".to_string(),
+                artist: "nomico".to_string(),
+                album: "Lovelight".to_string(),
+                ..SongInfo::default()
+            },
+            songtimes: SongTimes {
+                songstart: 100,
+                songend: 300,
+                ..SongTimes::default()
+            },
+            songdata: SongData {
+                albumid,
+                ..SongData::default()
+            },
+            misc: Misc {
+                albumart: albumart.to_string(),
+                ..Misc::default()
+            },
+        }
+    }
+
+    #[test]
+    fn templates() {
+        let config = DiscordConfig::default();
+        let activity = activity_from_song_info(
+            &config,
+            &song("cover.jpg", 42),
+            "Gensokyo Radio",
+            Some("https://example.com/listen"),
+            false,
+        );
+        assert_eq!(activity["state"], "Listening to Gensokyo Radio");
+        assert_eq!(activity["details"], "nomico - Bad Apple!!");
+        assert!(activity["assets"]["large_image"]
+            .as_str()
+            .unwrap()
+            .ends_with("/cover.jpg"));
+        assert_eq!(activity["assets"]["large_text"], "Lovelight");
+        assert!(activity["assets"].get("small_image").is_none());
+        assert_eq!(
+            activity["timestamps"],
+            serde_json::json!({ "start": 100_000, "end": 300_000 })
+        );
+        assert_eq!(activity["buttons"][0]["url"], "https://example.com/listen");
+        assert_eq!(activity["buttons"][1]["label"], "Album page");
+
+        // Texts too short for Discord are left out, long ones are cut
+        let config = DiscordConfig {
+            details: "{title}".repeat(20),
+            large_text: "{year}".to_string(),
+            state: "?".to_string(),
+            ..DiscordConfig::default()
+        };
+        let activity = activity_from_song_info(&config, &song("cover.jpg", 42), "Gensokyo Radio", None, false);
+        assert_eq!(activity["details"].as_str().unwrap().chars().count(), MAX_TEXT_LENGTH);
+        assert!(activity.get("state").is_none());
+        assert!(activity["assets"].get("large_text").is_none());
+    }
+
+    #[test]
+    fn fallback_image_and_buttons() {
+        let config = DiscordConfig::default();
+        let activity = activity_from_song_info(&config, &song("", 0), "Some radio", None, false);
+        assert_eq!(activity["assets"]["large_image"], "presence_image");
+        // Only Gensokyo Radio has a listen page and album pages
+        assert!(activity.get("buttons").is_none());
+
+        let config = DiscordConfig {
+            fallback_image: String::new(),
+            ..DiscordConfig::default()
+        };
+        let activity = activity_from_song_info(&config, &song("", 0), "Some radio", None, false);
+        assert!(activity["assets"].get("large_image").is_none());
+    }
+
+    #[test]
+    fn paused() {
+        let mut sink = DiscordSink::new(DiscordConfig::default());
+        assert!(sink.activity().is_none());
+        sink.song = Some((song("cover.jpg", 42), "Gensokyo Radio".to_string(), None));
+        sink.playing = true;
+        assert!(sink.activity().unwrap().get("timestamps").is_some());
+
+        sink.playing = false;
+        let activity = sink.activity().unwrap();
+        assert_eq!(activity["state"], "Paused");
+        assert!(activity.get("timestamps").is_none());
+
+        sink.config.on_pause = PausedPresence::Clear;
+        assert!(sink.activity().is_none());
+        sink.playing = true;
+        assert!(sink.activity().is_some());
+    }
+}
@@ -0,0 +1,456 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+//! Client for the local Discord RPC socket, the protocol behind Rich Presence.
+//!
+//! Every message is a frame made of a little endian opcode, a little endian payload length and a JSON payload. The
+//! client sends a handshake with its application id, waits for the `READY` event, and then sends commands like
+//! `SET_ACTIVITY`, each answered by Discord.
+
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Discord creates the first free socket among `discord-ipc-0` to `discord-ipc-9`.
+const MAX_SOCKETS: u32 = 10;
+/// Where the Flatpak and Snap packages of Discord create their socket, relative to the usual directory
+#[cfg(unix)]
+const SANDBOX_DIRS: &[&str] = &["app/com.discordapp.Discord", "snap.discord"];
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+/// Bigger frames are certainly not from Discord
+const MAX_FRAME_SIZE: u32 = 64 * 1024;
+
+const OP_HANDSHAKE: u32 = 0;
+const OP_FRAME: u32 = 1;
+const OP_CLOSE: u32 = 2;
+const OP_PING: u32 = 3;
+const OP_PONG: u32 = 4;
+
+trait Socket: Read + Write + Send {}
+impl<T: Read + Write + Send> Socket for T {}
+
+#[derive(Debug)]
+pub enum IpcError {
+    Io(std::io::Error),
+    /// Discord closed the connection, usually because the application id is invalid
+    Closed(String),
+    InvalidFrame(String),
+}
+
+impl std::fmt::Display for IpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            IpcError::Io(error) => write!(f, "{}", error),
+            IpcError::Closed(reason) => write!(f, "Discord closed the connection: {}", reason),
+            IpcError::InvalidFrame(reason) => write!(f, "invalid frame: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for IpcError {}
+
+impl From<std::io::Error> for IpcError {
+    fn from(error: std::io::Error) -> Self {
+        IpcError::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for IpcError {
+    fn from(error: serde_json::Error) -> Self {
+        IpcError::InvalidFrame(error.to_string())
+    }
+}
+
+/// A connection to the Discord client, ready to send commands.
+pub struct DiscordIpc {
+    socket: Box<dyn Socket>,
+    nonce: u64,
+}
+
+impl DiscordIpc {
+    /// Connects to the first Discord socket that answers the handshake.
+    ///
+    /// `WAN_PLAYER_DISCORD_IPC` overrides the socket path, see examples/discord_ipc_server.rs.
+    pub fn connect(client_id: &str) -> Result<DiscordIpc, IpcError> {
+        let paths = match std::env::var_os("WAN_PLAYER_DISCORD_IPC") {
+            Some(path) => vec![PathBuf::from(path)],
+            None => socket_paths(),
+        };
+        DiscordIpc::connect_to(paths, client_id)
+    }
+
+    fn connect_to(paths: Vec<PathBuf>, client_id: &str) -> Result<DiscordIpc, IpcError> {
+        let mut last_error = IpcError::Io(std::io::ErrorKind::NotFound.into());
+        for path in paths {
+            let socket = match open_socket(&path) {
+                Ok(socket) => socket,
+                Err(error) => {
+                    last_error = error.into();
+                    continue;
+                }
+            };
+            let mut ipc = DiscordIpc { socket, nonce: 0 };
+            match ipc.handshake(client_id) {
+                Ok(()) => return Ok(ipc),
+                Err(error) => last_error = error,
+            }
+        }
+        Err(last_error)
+    }
+
+    fn handshake(&mut self, client_id: &str) -> Result<(), IpcError> {
+        let payload = serde_json::json!({ "v": 1, "client_id": client_id });
+        self.write_frame(OP_HANDSHAKE, &payload)?;
+        let ready = self.read_frame()?;
+        if ready["evt"] != "READY" {
+            return Err(IpcError::InvalidFrame(format!("expected READY, got {}", ready)));
+        }
+        Ok(())
+    }
+
+    /// Sets the activity shown on the user's profile, `None` clears it.
+    pub fn set_activity(&mut self, activity: Option<serde_json::Value>) -> Result<(), IpcError> {
+        let args = serde_json::json!({ "pid": std::process::id(), "activity": activity });
+        self.command("SET_ACTIVITY", args)
+    }
+
+    fn command(&mut self, cmd: &str, args: serde_json::Value) -> Result<(), IpcError> {
+        self.nonce += 1;
+        let nonce = self.nonce.to_string();
+        let payload = serde_json::json!({ "cmd": cmd, "args": args, "nonce": nonce });
+        self.write_frame(OP_FRAME, &payload)?;
+
+        // Discord answers every command, errors included, with the same nonce
+        loop {
+            let answer = self.read_frame()?;
+            if answer["nonce"] != nonce.as_str() {
+                continue;
+            }
+            if answer["evt"] == "ERROR" {
+                eprintln!("Discord refused {}: {}", cmd, answer["data"]["message"]);
+            }
+            return Ok(());
+        }
+    }
+
+    fn write_frame(&mut self, opcode: u32, payload: &serde_json::Value) -> Result<(), IpcError> {
+        let payload = serde_json::to_vec(payload)?;
+        let mut frame = Vec::with_capacity(8 + payload.len());
+        frame.extend_from_slice(&opcode.to_le_bytes());
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&payload);
+        self.socket.write_all(&frame)?;
+        self.socket.flush()?;
+        Ok(())
+    }
+
+    /// Reads the next data frame, answering the pings on the way.
+    fn read_frame(&mut self) -> Result<serde_json::Value, IpcError> {
+        loop {
+            let mut header = [0; 8];
+            self.socket.read_exact(&mut header)?;
+            let opcode = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+            let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+            if length > MAX_FRAME_SIZE {
+                return Err(IpcError::InvalidFrame(format!("{} bytes frame", length)));
+            }
+            let mut payload = vec![0; length as usize];
+            self.socket.read_exact(&mut payload)?;
+            let payload: serde_json::Value = serde_json::from_slice(&payload)?;
+
+            match opcode {
+                OP_FRAME => return Ok(payload),
+                OP_PING => self.write_frame(OP_PONG, &payload)?,
+                OP_PONG => {}
+                OP_CLOSE => return Err(IpcError::Closed(payload["message"].to_string())),
+                _ => return Err(IpcError::InvalidFrame(format!("unknown opcode {}", opcode))),
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn socket_paths() -> Vec<PathBuf> {
+    let dir = ["XDG_RUNTIME_DIR", "TMPDIR", "TMP", "TEMP"]
+        .iter()
+        .find_map(std::env::var_os)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("/tmp"));
+    socket_paths_in(&dir)
+}
+
+/// The sockets of a native Discord come first, then the ones of the sandboxed packages.
+#[cfg(unix)]
+fn socket_paths_in(dir: &std::path::Path) -> Vec<PathBuf> {
+    std::iter::once(dir.to_path_buf())
+        .chain(SANDBOX_DIRS.iter().map(|sandbox| dir.join(sandbox)))
+        .flat_map(|dir| (0..MAX_SOCKETS).map(move |n| dir.join(format!("discord-ipc-{}", n))))
+        .collect()
+}
+
+#[cfg(windows)]
+fn socket_paths() -> Vec<PathBuf> {
+    (0..MAX_SOCKETS)
+        .map(|n| PathBuf::from(format!(r"\\?\pipe\discord-ipc-{}", n)))
+        .collect()
+}
+
+#[cfg(unix)]
+fn open_socket(path: &std::path::Path) -> std::io::Result<Box<dyn Socket>> {
+    let socket = std::os::unix::net::UnixStream::connect(path)?;
+    socket.set_read_timeout(Some(READ_TIMEOUT))?;
+    Ok(Box::new(socket))
+}
+
+#[cfg(windows)]
+fn open_socket(path: &std::path::Path) -> std::io::Result<Box<dyn Socket>> {
+    Ok(Box::new(pipe::OverlappedPipe::open(path, READ_TIMEOUT)?))
+}
+
+/// Blocking reads on a named pipe can't time out, so the pipe is opened for overlapped I/O and the reads are
+/// cancelled when Discord doesn't answer in time.
+#[cfg(windows)]
+mod pipe {
+    use winapi::shared::minwindef::{DWORD, FALSE, TRUE};
+    use winapi::shared::winerror::{ERROR_BROKEN_PIPE, ERROR_IO_PENDING, WAIT_TIMEOUT};
+    use winapi::um::fileapi::{ReadFile, WriteFile};
+    use winapi::um::handleapi::CloseHandle;
+    use winapi::um::ioapiset::{CancelIoEx, GetOverlappedResult};
+    use winapi::um::minwinbase::OVERLAPPED;
+    use winapi::um::synchapi::{CreateEventW, WaitForSingleObject};
+    use winapi::um::winbase::{FILE_FLAG_OVERLAPPED, INFINITE};
+    use winapi::um::winnt::HANDLE;
+
+    use std::io::{Read, Write};
+    use std::os::windows::fs::OpenOptionsExt;
+    use std::os::windows::io::AsRawHandle;
+    use std::path::Path;
+    use std::time::Duration;
+
+    pub struct OverlappedPipe {
+        file: std::fs::File,
+        /// Signaled when the pending operation completes
+        event: HANDLE,
+        read_timeout: Duration,
+    }
+
+    // The event handle is only used by the thread owning the pipe
+    unsafe impl Send for OverlappedPipe {}
+
+    impl OverlappedPipe {
+        pub fn open(path: &Path, read_timeout: Duration) -> std::io::Result<OverlappedPipe> {
+            let file = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .custom_flags(FILE_FLAG_OVERLAPPED)
+                .open(path)?;
+            let event = unsafe { CreateEventW(std::ptr::null_mut(), TRUE, FALSE, std::ptr::null()) };
+            if event.is_null() {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(OverlappedPipe {
+                file,
+                event,
+                read_timeout,
+            })
+        }
+
+        /// Waits for the operation using `overlapped`, cancelling it after `timeout`. `started` is what ReadFile or
+        /// WriteFile returned, false when the operation is pending or failed. Returns the bytes transferred.
+        fn complete(
+            &self,
+            started: bool,
+            overlapped: &mut OVERLAPPED,
+            timeout: Option<Duration>,
+        ) -> std::io::Result<usize> {
+            let handle = self.file.as_raw_handle() as HANDLE;
+            if !started {
+                let error = std::io::Error::last_os_error();
+                if error.raw_os_error() != Some(ERROR_IO_PENDING as i32) {
+                    return Err(error);
+                }
+                let millis = timeout.map_or(INFINITE, |timeout| {
+                    timeout.as_millis().min(u128::from(INFINITE - 1)) as DWORD
+                });
+                if unsafe { WaitForSingleObject(self.event, millis) } == WAIT_TIMEOUT {
+                    // The kernel writes to `overlapped` until the operation is over, even once cancelled
+                    let mut transferred = 0;
+                    unsafe {
+                        CancelIoEx(handle, overlapped);
+                        GetOverlappedResult(handle, overlapped, &mut transferred, TRUE);
+                    }
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "Discord didn't answer in time",
+                    ));
+                }
+            }
+            let mut transferred: DWORD = 0;
+            if unsafe { GetOverlappedResult(handle, overlapped, &mut transferred, TRUE) } == FALSE {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(transferred as usize)
+        }
+
+        fn overlapped(&self) -> OVERLAPPED {
+            let mut overlapped: OVERLAPPED = unsafe { std::mem::zeroed() };
+            overlapped.hEvent = self.event;
+            overlapped
+        }
+    }
+
+    impl Read for OverlappedPipe {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let mut overlapped = self.overlapped();
+            let length = buf.len().min(DWORD::MAX as usize) as DWORD;
+            let handle = self.file.as_raw_handle() as HANDLE;
+            let started = unsafe {
+                ReadFile(
+                    handle,
+                    buf.as_mut_ptr() as _,
+                    length,
+                    std::ptr::null_mut(),
+                    &mut overlapped,
+                )
+            };
+            match self.complete(started != FALSE, &mut overlapped, Some(self.read_timeout)) {
+                // Discord closed its end
+                Err(ref error) if error.raw_os_error() == Some(ERROR_BROKEN_PIPE as i32) => Ok(0),
+                result => result,
+            }
+        }
+    }
+
+    impl Write for OverlappedPipe {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let mut overlapped = self.overlapped();
+            let length = buf.len().min(DWORD::MAX as usize) as DWORD;
+            let handle = self.file.as_raw_handle() as HANDLE;
+            let started =
+                unsafe { WriteFile(handle, buf.as_ptr() as _, length, std::ptr::null_mut(), &mut overlapped) };
+            self.complete(started != FALSE, &mut overlapped, None)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Drop for OverlappedPipe {
+        fn drop(&mut self) {
+            unsafe { CloseHandle(self.event) };
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    use std::os::unix::net::UnixListener;
+    use std::path::Path;
+
+    use crate::test_support;
+
+    /// A socket path in a directory of its own, removed with the directory at the end of the test
+    struct TempSocket {
+        dir: PathBuf,
+    }
+
+    impl TempSocket {
+        fn new(name: &str) -> TempSocket {
+            let dir = std::env::temp_dir().join(format!("wan_player-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).expect("Failed to create the socket directory");
+            TempSocket { dir }
+        }
+
+        fn path(&self) -> PathBuf {
+            self.dir.join("discord-ipc-0")
+        }
+    }
+
+    impl Drop for TempSocket {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn activity() {
+        let socket = TempSocket::new("discord-activity");
+        let listener = UnixListener::bind(socket.path()).expect("Failed to bind the fake Discord socket");
+        std::thread::spawn(move || test_support::discord_ipc::listen(listener));
+
+        let missing = socket.dir.join("discord-ipc-1");
+        let mut ipc = DiscordIpc::connect_to(vec![missing, socket.path()], "42").expect("Failed to connect");
+        ipc.set_activity(Some(serde_json::json!({ "details": "Song" })))
+            .expect("Failed to set the activity");
+        ipc.set_activity(None).expect("Failed to clear the activity");
+        assert_eq!(ipc.nonce, 2);
+    }
+
+    #[test]
+    fn silent_server() {
+        let socket = TempSocket::new("discord-silent");
+        let listener = UnixListener::bind(socket.path()).expect("Failed to bind the fake Discord socket");
+        std::thread::spawn(move || {
+            let (mut client, _) = listener.accept().expect("Failed to accept the client");
+            // Reads the handshake and never answers
+            let _ = test_support::discord_ipc::read_frame(&mut client);
+            std::thread::sleep(READ_TIMEOUT * 2);
+        });
+
+        match DiscordIpc::connect_to(vec![socket.path()], "42") {
+            Err(IpcError::Io(error)) => assert!(
+                matches!(
+                    error.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ),
+                "{}",
+                error
+            ),
+            Err(error) => panic!("Unexpected error: {}", error),
+            Ok(_) => panic!("Connected to a silent server"),
+        }
+    }
+
+    #[test]
+    fn closed_server() {
+        let socket = TempSocket::new("discord-closed");
+        let listener = UnixListener::bind(socket.path()).expect("Failed to bind the fake Discord socket");
+        std::thread::spawn(move || {
+            let (mut client, _) = listener.accept().expect("Failed to accept the client");
+            let _ = test_support::discord_ipc::read_frame(&mut client);
+            let close = serde_json::json!({ "code": 4000, "message": "Invalid client id" });
+            let _ = test_support::discord_ipc::write_frame(&mut client, OP_CLOSE, close);
+        });
+
+        assert!(matches!(
+            DiscordIpc::connect_to(vec![socket.path()], "42"),
+            Err(IpcError::Closed(_))
+        ));
+    }
+
+    #[test]
+    fn sandboxed_sockets() {
+        let paths = socket_paths_in(Path::new("/run/user/1000"));
+        assert_eq!(paths.len(), 3 * MAX_SOCKETS as usize);
+        assert_eq!(paths[0], Path::new("/run/user/1000/discord-ipc-0"));
+        assert!(paths.contains(&PathBuf::from(
+            "/run/user/1000/app/com.discordapp.Discord/discord-ipc-0"
+        )));
+        assert!(paths.contains(&PathBuf::from("/run/user/1000/snap.discord/discord-ipc-9")));
+    }
+}
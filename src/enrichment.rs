@@ -0,0 +1,488 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+use async_trait::async_trait;
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::config::EnrichmentConfig;
+use crate::database;
+use crate::gensokyo_radio::SongInfo;
+
+pub const TOUHOUDB_API: &str = "https://touhoudb.com/api/";
+const TOUHOUDB_TIMEOUT: Duration = Duration::from_secs(10);
+/// Arrangements of arrangements are followed up to this depth to find the ZUN original.
+const MAX_ORIGINAL_DEPTH: usize = 3;
+/// Start of the error added to the configuration errors when the dataset can't be loaded.
+pub const DATASET_ERROR: &str = "Failed to load enrichment.dataset";
+/// Songs that weren't found are looked up again after this long, in seconds, in case the database was updated.
+const NOT_FOUND_TTL: i64 = 7 * 24 * 60 * 60;
+
+/// A ZUN original an arrangement is based on.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OriginalTheme {
+    pub title: String,
+    /// Game or music collection the theme comes from, may be empty
+    pub game: String,
+}
+
+/// A database telling which originals a song arranges.
+#[async_trait]
+pub trait ArrangementDatabase: Send + Sync {
+    /// Returns the originals of `song`, empty if the song isn't in the database.
+    async fn lookup(&self, song: &SongInfo) -> Result<Vec<OriginalTheme>, String>;
+}
+
+/// [Touhou DB](https://touhoudb.com/), a VocaDB instance dedicated to Touhou music.
+pub struct TouhouDb {
+    client: hyper::client::Client<hyper_tls::HttpsConnector<hyper::client::HttpConnector>>,
+    api_root: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct SongSearch {
+    items: Vec<DbSong>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct DbSong {
+    name: String,
+    #[serde(default)]
+    artist_string: String,
+    #[serde(default)]
+    original_version_id: Option<u64>,
+    #[serde(default)]
+    albums: Vec<DbAlbum>,
+}
+
+#[derive(Deserialize, Debug)]
+struct DbAlbum {
+    name: String,
+}
+
+impl TouhouDb {
+    pub fn new(api_root: String) -> TouhouDb {
+        let https = hyper_tls::HttpsConnector::new();
+        let client = hyper::client::Client::builder().build::<_, hyper::Body>(https);
+        TouhouDb { client, api_root }
+    }
+
+    async fn get<T: serde::de::DeserializeOwned>(&self, path_and_query: &str) -> Result<T, String> {
+        let uri = format!("{}{}", self.api_root, path_and_query)
+            .parse::<hyper::Uri>()
+            .map_err(|error| error.to_string())?;
+        let request = async {
+            let res = self.client.get(uri).await.map_err(|error| error.to_string())?;
+            if !res.status().is_success() {
+                return Err(format!("Touhou DB answered {}", res.status()));
+            }
+            let data = hyper::body::to_bytes(res.into_body())
+                .await
+                .map_err(|error| error.to_string())?;
+            serde_json::from_slice(&data[..]).map_err(|error| error.to_string())
+        };
+        tokio::time::timeout(TOUHOUDB_TIMEOUT, request)
+            .await
+            .map_err(|_| "Touhou DB request timed out".to_string())?
+    }
+
+    /// Finds the arrangements in the database, see `best_arrangements`.
+    async fn find_arrangements(&self, song: &SongInfo) -> Result<Vec<DbSong>, String> {
+        let query = form_urlencoded::Serializer::new(String::new())
+            .append_pair("query", &song.title)
+            .append_pair("maxResults", "10")
+            .append_pair("fields", "Albums")
+            .finish();
+        let search: SongSearch = self.get(&format!("songs?{}", query)).await?;
+        Ok(best_arrangements(search.items, song))
+    }
+
+    /// Follows the `original_version_id` chain of `arrangement` to the ZUN original, `None` if it is too long.
+    async fn original_of(&self, arrangement: DbSong) -> Result<Option<OriginalTheme>, String> {
+        let mut current = arrangement;
+        for _ in 0..MAX_ORIGINAL_DEPTH {
+            let original_id = match current.original_version_id {
+                Some(original_id) => original_id,
+                None => break,
+            };
+            current = self.get(&format!("songs/{}?fields=Albums", original_id)).await?;
+        }
+        if current.original_version_id.is_some() {
+            return Ok(None);
+        }
+
+        Ok(Some(OriginalTheme {
+            title: current.name,
+            game: current
+                .albums
+                .into_iter()
+                .next()
+                .map(|album| album.name)
+                .unwrap_or_default(),
+        }))
+    }
+}
+
+/// Picks the search results matching `song`, keeping the entries from the same album and circle if there are any.
+///
+/// Many arrangements share a title, so a result is only accepted if it comes from the same album or circle. Several
+/// results can remain, e.g. the entries of a medley for each of its originals.
+fn best_arrangements(items: Vec<DbSong>, song: &SongInfo) -> Vec<DbSong> {
+    let album = song.album.trim().to_lowercase();
+    let circle = song.circle.trim().to_lowercase();
+    let matches: Vec<((bool, bool), DbSong)> = items
+        .into_iter()
+        .filter(|item| item.original_version_id.is_some())
+        .map(|item| {
+            let same_album = !album.is_empty() && item.albums.iter().any(|a| a.name.trim().to_lowercase() == album);
+            let same_circle = !circle.is_empty() && item.artist_string.to_lowercase().contains(&circle);
+            ((same_album, same_circle), item)
+        })
+        .filter(|((same_album, same_circle), _)| *same_album || *same_circle)
+        .collect();
+    let best = match matches.iter().map(|(matches, _)| *matches).max() {
+        Some(best) => best,
+        None => return Vec::new(),
+    };
+    matches
+        .into_iter()
+        .filter(|(matches, _)| *matches == best)
+        .map(|(_, item)| item)
+        .collect()
+}
+
+#[async_trait]
+impl ArrangementDatabase for TouhouDb {
+    async fn lookup(&self, song: &SongInfo) -> Result<Vec<OriginalTheme>, String> {
+        let mut originals: Vec<OriginalTheme> = Vec::new();
+        for arrangement in self.find_arrangements(song).await? {
+            if let Some(original) = self.original_of(arrangement).await? {
+                if !originals.contains(&original) {
+                    originals.push(original);
+                }
+            }
+        }
+        Ok(originals)
+    }
+}
+
+/// Local dump of an arrangement database, as a JSON array of `DatasetEntry`.
+pub struct LocalDataset {
+    entries: Vec<DatasetEntry>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DatasetEntry {
+    pub title: String,
+    #[serde(default)]
+    pub album: String,
+    #[serde(default)]
+    pub circle: String,
+    pub originals: Vec<OriginalTheme>,
+}
+
+impl LocalDataset {
+    pub fn open(path: &Path) -> Result<LocalDataset, String> {
+        let data = std::fs::read(path).map_err(|error| error.to_string())?;
+        let entries = serde_json::from_slice(&data).map_err(|error| error.to_string())?;
+        Ok(LocalDataset { entries })
+    }
+}
+
+#[async_trait]
+impl ArrangementDatabase for LocalDataset {
+    async fn lookup(&self, song: &SongInfo) -> Result<Vec<OriginalTheme>, String> {
+        // Empty album or circle in the dataset match any song with that title
+        let entry = self
+            .entries
+            .iter()
+            .filter(|entry| entry.title.eq_ignore_ascii_case(&song.title))
+            .filter(|entry| entry.album.is_empty() || entry.album.eq_ignore_ascii_case(&song.album))
+            .filter(|entry| entry.circle.is_empty() || entry.circle.eq_ignore_ascii_case(&song.circle))
+            .max_by_key(|entry| (!entry.album.is_empty(), !entry.circle.is_empty()));
+        Ok(entry.map(|entry| entry.originals.clone()).unwrap_or_default())
+    }
+}
+
+/// Tables of the original themes cache.
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS original_themes (
+    title TEXT NOT NULL,
+    album TEXT NOT NULL,
+    circle TEXT NOT NULL,
+    originals TEXT NOT NULL,
+    fetched_at INTEGER NOT NULL,
+    PRIMARY KEY (title, album, circle)
+);";
+
+/// Results of the lookups, stored in a SQLite database in the user's cache directory.
+pub struct EnrichmentCache {
+    connection: Mutex<rusqlite::Connection>,
+}
+
+impl EnrichmentCache {
+    pub fn open() -> Option<EnrichmentCache> {
+        let connection = database::open_in(
+            &database::cache_dir()?,
+            "original_themes.sqlite",
+            "original themes cache",
+            SCHEMA,
+        )?;
+        Some(EnrichmentCache {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Returns the cached originals of `song`, `None` if it was never looked up or if the song wasn't found for a while.
+    pub fn get(&self, song: &SongInfo) -> rusqlite::Result<Option<Vec<OriginalTheme>>> {
+        let connection = self.connection.lock().expect("Failed to lock original themes cache");
+        let row: Option<(String, i64)> = connection
+            .query_row(
+                "SELECT originals, fetched_at FROM original_themes WHERE title = ?1 AND album = ?2 AND circle = ?3",
+                params![song.title, song.album, song.circle],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+
+        Ok(row.and_then(|(originals, fetched_at)| {
+            let originals: Vec<OriginalTheme> = serde_json::from_str(&originals).ok()?;
+            if originals.is_empty() && chrono::Utc::now().timestamp() - fetched_at > NOT_FOUND_TTL {
+                None
+            } else {
+                Some(originals)
+            }
+        }))
+    }
+
+    pub fn put(&self, song: &SongInfo, originals: &[OriginalTheme]) -> rusqlite::Result<()> {
+        let connection = self.connection.lock().expect("Failed to lock original themes cache");
+        connection.execute(
+            "INSERT OR REPLACE INTO original_themes (title, album, circle, originals, fetched_at)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                song.title,
+                song.album,
+                song.circle,
+                serde_json::to_string(originals).expect("Failed to serialize original themes"),
+                chrono::Utc::now().timestamp(),
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+/// Looks up the originals of the songs, going through the cache first.
+pub struct Enricher {
+    database: Arc<dyn ArrangementDatabase>,
+    cache: Option<Arc<EnrichmentCache>>,
+}
+
+impl Enricher {
+    /// Builds the enricher described by the configuration, `None` if it is disabled.
+    ///
+    /// Touhou DB is used when the dataset can't be loaded, if it is enabled. The problem is added to `errors`.
+    pub fn from_config(config: &EnrichmentConfig, errors: &mut Vec<String>) -> Option<Enricher> {
+        let dataset = config.dataset.as_ref().and_then(|dataset| {
+            let path = crate::config::resolve_path(dataset);
+            LocalDataset::open(&path)
+                .map_err(|error| errors.push(format!("{} {}: {}", DATASET_ERROR, path.display(), error)))
+                .ok()
+        });
+        let database: Arc<dyn ArrangementDatabase> = match dataset {
+            Some(dataset) => Arc::new(dataset),
+            None if config.touhoudb => Arc::new(TouhouDb::new(TOUHOUDB_API.to_string())),
+            None => return None,
+        };
+        Some(Enricher {
+            database,
+            cache: EnrichmentCache::open().map(Arc::new),
+        })
+    }
+
+    pub async fn original_themes(&self, song: SongInfo) -> Vec<OriginalTheme> {
+        if song.title.is_empty() {
+            return Vec::new();
+        }
+
+        if let Some(cache) = self.cache.clone() {
+            let cached_song = song.clone();
+            let cached = tokio::task::spawn_blocking(move || cache.get(&cached_song))
+                .await
+                .expect("Failed to join original themes cache task");
+            match cached {
+                Ok(Some(originals)) => return originals,
+                Ok(None) => {}
+                Err(error) => eprintln!("Failed to read original themes cache: {}", error),
+            }
+        }
+
+        let originals = match self.database.lookup(&song).await {
+            Ok(originals) => originals,
+            Err(error) => {
+                // Errors aren't cached, the song will be looked up again next time
+                eprintln!("Failed to look up original themes: {}", error);
+                return Vec::new();
+            }
+        };
+
+        if let Some(cache) = self.cache.clone() {
+            let cached = originals.clone();
+            tokio::task::spawn_blocking(move || {
+                if let Err(error) = cache.put(&song, &cached) {
+                    eprintln!("Failed to cache original themes: {}", error);
+                }
+            });
+        }
+        originals
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn db_song(name: &str, artist: &str, album: &str, original: Option<u64>) -> DbSong {
+        DbSong {
+            name: name.to_string(),
+            artist_string: artist.to_string(),
+            original_version_id: original,
+            albums: vec![DbAlbum {
+                name: album.to_string(),
+            }],
+        }
+    }
+
+    fn original_ids(items: Vec<DbSong>) -> Vec<u64> {
+        items.iter().filter_map(|item| item.original_version_id).collect()
+    }
+
+    fn song(album: &str, circle: &str) -> SongInfo {
+        SongInfo {
+            title: "Bad Apple!!".to_string(),
+            album: album.to_string(),
+            circle: circle.to_string(),
+            ..SongInfo::default()
+        }
+    }
+
+    #[test]
+    fn arrangement_needs_album_or_circle() {
+        let items = || {
+            vec![
+                db_song("Bad Apple!!", "Other circle", "Other album", Some(1)),
+                db_song("Bad Apple!!", "ZUN", "Lotus Land Story", None),
+            ]
+        };
+        assert!(best_arrangements(items(), &song("Lovelight", "Alstroemeria Records")).is_empty());
+        assert!(best_arrangements(items(), &song("", "")).is_empty());
+        assert!(best_arrangements(Vec::new(), &song("Lovelight", "Alstroemeria Records")).is_empty());
+    }
+
+    #[test]
+    fn arrangement_prefers_album_and_circle() {
+        let items = vec![
+            db_song(
+                "Bad Apple!!",
+                "Alstroemeria Records feat. nomico",
+                "Other album",
+                Some(1),
+            ),
+            db_song("Bad Apple!!", "Alstroemeria Records feat. nomico", "Lovelight", Some(2)),
+            db_song("Bad Apple!!", "Other circle", "Lovelight", Some(3)),
+        ];
+        assert_eq!(
+            original_ids(best_arrangements(items, &song(" lovelight ", "Alstroemeria Records"))),
+            vec![2]
+        );
+
+        let items = vec![
+            db_song("Bad Apple!!", "Other circle", "Other album", Some(1)),
+            db_song("Bad Apple!!", "Alstroemeria Records", "Other album", Some(2)),
+        ];
+        assert_eq!(
+            original_ids(best_arrangements(items, &song("Lovelight", "alstroemeria records"))),
+            vec![2]
+        );
+
+        // The album alone is enough, e.g. for compilations credited to several circles
+        let items = vec![db_song("Bad Apple!!", "Various", "Lovelight", Some(4))];
+        assert_eq!(original_ids(best_arrangements(items, &song("Lovelight", ""))), vec![4]);
+    }
+
+    #[test]
+    fn arrangement_keeps_every_best_match() {
+        let items = vec![
+            db_song("Bad Apple!!", "Alstroemeria Records", "Lovelight", Some(1)),
+            db_song("Bad Apple!!", "Other circle", "Lovelight", Some(2)),
+            db_song("Bad Apple!!", "Alstroemeria Records", "Lovelight", Some(3)),
+        ];
+        assert_eq!(
+            original_ids(best_arrangements(items, &song("Lovelight", "Alstroemeria Records"))),
+            vec![1, 3]
+        );
+    }
+
+    #[tokio::test]
+    async fn lookup_follows_every_chain() {
+        let address = crate::test_support::serve(|req: hyper::Request<hyper::Body>| async move {
+            let body = match req.uri().path() {
+                "/api/songs" => serde_json::json!({
+                    "items": [
+                        {"name": "Bad Apple!!", "artistString": "Circle", "originalVersionId": 10,
+                         "albums": [{"name": "Medley"}]},
+                        {"name": "Bad Apple!!", "artistString": "Circle", "originalVersionId": 20,
+                         "albums": [{"name": "Medley"}]},
+                        {"name": "Bad Apple!!", "artistString": "Circle", "originalVersionId": 11,
+                         "albums": [{"name": "Medley"}]},
+                        {"name": "Bad Apple!!", "artistString": "Circle", "originalVersionId": 30,
+                         "albums": [{"name": "Medley"}]},
+                        {"name": "Bad Apple!!", "artistString": "Other", "originalVersionId": 40,
+                         "albums": [{"name": "Other album"}]},
+                    ]
+                }),
+                // An arrangement of an arrangement
+                "/api/songs/10" => serde_json::json!({"name": "Arrangement", "originalVersionId": 11}),
+                "/api/songs/11" => serde_json::json!({"name": "Bad Apple!!", "albums": [{"name": "Lotus Land Story"}]}),
+                "/api/songs/20" => {
+                    serde_json::json!({"name": "Night of Nights", "albums": [{"name": "Flowering Night"}]})
+                }
+                // Too deep, ignored
+                "/api/songs/30" => serde_json::json!({"name": "Loop", "originalVersionId": 30}),
+                _ => serde_json::json!({"name": "Wrong song", "albums": []}),
+            };
+            Ok(hyper::Response::new(hyper::Body::from(body.to_string())))
+        });
+        let database = TouhouDb::new(format!("http://{}/api/", address));
+        let song = SongInfo {
+            title: "Bad Apple!!".to_string(),
+            album: "Medley".to_string(),
+            ..SongInfo::default()
+        };
+        let original = |title: &str, game: &str| OriginalTheme {
+            title: title.to_string(),
+            game: game.to_string(),
+        };
+        assert_eq!(
+            database.lookup(&song).await.unwrap(),
+            vec![
+                original("Bad Apple!!", "Lotus Land Story"),
+                original("Night of Nights", "Flowering Night"),
+            ]
+        );
+    }
+}
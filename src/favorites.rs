@@ -0,0 +1,588 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+use iced::{widget, Command, Element};
+use rusqlite::params;
+use serde::Serialize;
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::database::{self, ExportFormat};
+use crate::gensokyo_radio::{ApiClient, GRApiAnswer, SongData};
+use crate::{account, ui, PlayerMessage};
+
+/// Tables of the favorites database.
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS favorites (
+        id INTEGER PRIMARY KEY,
+        added_at INTEGER NOT NULL,
+        title TEXT NOT NULL,
+        artist TEXT NOT NULL,
+        album TEXT NOT NULL,
+        year TEXT NOT NULL,
+        circle TEXT NOT NULL,
+        circlelink TEXT NOT NULL,
+        albumart TEXT NOT NULL,
+        songid INTEGER NOT NULL DEFAULT 0,
+        albumid INTEGER NOT NULL DEFAULT 0,
+        cover TEXT,
+        UNIQUE (title, artist, album)
+    );";
+
+/// Columns added to the favorites table after its first version, with their definition.
+const ADDED_COLUMNS: &[(&str, &str)] = &[
+    ("songid", "INTEGER NOT NULL DEFAULT 0"),
+    ("albumid", "INTEGER NOT NULL DEFAULT 0"),
+];
+
+/// A song bookmarked by the user.
+#[derive(Serialize, Debug, Clone)]
+pub struct Favorite {
+    pub id: i64,
+    /// When the song was bookmarked, in seconds since the UNIX epoch
+    pub added_at: i64,
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    pub year: String,
+    pub circle: String,
+    pub circlelink: String,
+    pub albumart: String,
+    /// Gensokyo Radio song id, 0 for the songs of other stations
+    pub songid: u64,
+    /// Gensokyo Radio album id, 0 for the songs of other stations
+    pub albumid: u64,
+    /// Path of the cached album art, if we had one when the song was bookmarked
+    pub cover: Option<PathBuf>,
+}
+
+impl Favorite {
+    pub fn is_song(&self, song: &GRApiAnswer) -> bool {
+        self.title == song.songinfo.title && self.artist == song.songinfo.artist && self.album == song.songinfo.album
+    }
+
+    /// Page of the song's album on Gensokyo Radio.
+    pub fn album_url(&self) -> Option<String> {
+        SongData {
+            albumid: self.albumid,
+            ..SongData::default()
+        }
+        .album_url()
+    }
+}
+
+/// Local favorites, stored in a SQLite database in the user's data directory. Album covers are cached next to it so
+/// the favorites browser works offline.
+pub struct Favorites {
+    connection: Mutex<rusqlite::Connection>,
+    covers_dir: PathBuf,
+}
+
+impl Favorites {
+    pub fn open() -> Option<Favorites> {
+        let covers_dir = database::data_dir()?.join("covers");
+        std::fs::create_dir_all(&covers_dir).ok()?;
+
+        let connection = database::open("favorites.sqlite", "favorites", SCHEMA)?;
+        if let Err(error) = add_missing_columns(&connection) {
+            eprintln!("Failed to update favorites: {}", error);
+            return None;
+        }
+        Some(Favorites {
+            connection: Mutex::new(connection),
+            covers_dir,
+        })
+    }
+
+    /// Bookmarks `song`, caching its album art if we have it.
+    pub fn add(&self, song: &GRApiAnswer, cover: Option<&[u8]>) -> rusqlite::Result<()> {
+        let cover = cover.and_then(|cover| self.cache_cover(&song.misc.albumart, cover));
+        let connection = self.connection.lock().expect("Failed to lock favorites");
+        connection.execute(
+            "INSERT OR IGNORE INTO favorites
+                (added_at, title, artist, album, year, circle, circlelink, albumart, songid, albumid, cover)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                chrono::Utc::now().timestamp(),
+                song.songinfo.title,
+                song.songinfo.artist,
+                song.songinfo.album,
+                song.songinfo.year,
+                song.songinfo.circle,
+                song.misc.circlelink,
+                song.misc.albumart,
+                song.songdata.songid as i64,
+                song.songdata.albumid as i64,
+                cover.map(|path| path.to_string_lossy().into_owned()),
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove(&self, song: &GRApiAnswer) -> rusqlite::Result<()> {
+        let connection = self.connection.lock().expect("Failed to lock favorites");
+        connection.execute(
+            "DELETE FROM favorites WHERE title = ?1 AND artist = ?2 AND album = ?3",
+            params![song.songinfo.title, song.songinfo.artist, song.songinfo.album],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove_id(&self, id: i64) -> rusqlite::Result<()> {
+        let connection = self.connection.lock().expect("Failed to lock favorites");
+        connection.execute("DELETE FROM favorites WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+
+    /// Returns all the favorites, most recent first.
+    pub fn list(&self) -> rusqlite::Result<Vec<Favorite>> {
+        let connection = self.connection.lock().expect("Failed to lock favorites");
+        let mut statement = connection.prepare(
+            "SELECT id, added_at, title, artist, album, year, circle, circlelink, albumart, songid, albumid, cover
+                FROM favorites
+                ORDER BY added_at DESC",
+        )?;
+        let rows = statement.query_map(params![], |row| {
+            Ok(Favorite {
+                id: row.get(0)?,
+                added_at: row.get(1)?,
+                title: row.get(2)?,
+                artist: row.get(3)?,
+                album: row.get(4)?,
+                year: row.get(5)?,
+                circle: row.get(6)?,
+                circlelink: row.get(7)?,
+                albumart: row.get(8)?,
+                songid: row.get::<_, i64>(9)? as u64,
+                albumid: row.get::<_, i64>(10)? as u64,
+                cover: row.get::<_, Option<String>>(11)?.map(PathBuf::from),
+            })
+        })?;
+
+        rows.collect()
+    }
+
+    fn cache_cover(&self, albumart: &str, cover: &[u8]) -> Option<PathBuf> {
+        // Only keep the file name so the album art id can't escape the covers directory
+        let file_name = Path::new(albumart).file_name()?;
+        let path = self.covers_dir.join(file_name);
+        if !path.exists() {
+            if let Err(error) = std::fs::write(&path, cover) {
+                eprintln!("Failed to cache cover {}: {}", path.display(), error);
+                return None;
+            }
+        }
+        Some(path)
+    }
+}
+
+/// Adds the columns of `ADDED_COLUMNS` missing from a favorites table created by an older version.
+fn add_missing_columns(connection: &rusqlite::Connection) -> rusqlite::Result<()> {
+    let columns = connection
+        .prepare("PRAGMA table_info(favorites)")?
+        .query_map(params![], |row| row.get::<_, String>(1))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    for (name, definition) in ADDED_COLUMNS {
+        if !columns.iter().any(|column| column == name) {
+            connection.execute_batch(&format!("ALTER TABLE favorites ADD COLUMN {} {};", name, definition))?;
+        }
+    }
+    Ok(())
+}
+
+fn export(favorites: &[Favorite], format: ExportFormat, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    match format {
+        ExportFormat::Csv | ExportFormat::Json => database::export_rows(favorites, format, path)?,
+        ExportFormat::M3u => {
+            // There is no file to point to, so each entry points to the page of its album on Gensokyo Radio. The songs
+            // of other stations have none, and are left out.
+            let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+            writeln!(file, "#EXTM3U")?;
+            for favorite in favorites {
+                let album_url = match favorite.album_url() {
+                    Some(album_url) => album_url,
+                    None => continue,
+                };
+                writeln!(file, "#EXTINF:-1,{} - {}", favorite.artist, favorite.title)?;
+                writeln!(file, "#EXTALB:{}", favorite.album)?;
+                writeln!(file, "#EXTART:{}", favorite.circle)?;
+                writeln!(file, "{}", album_url)?;
+            }
+            file.flush()?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub enum FavoritesMessage {
+    Loaded(Vec<Favorite>),
+    Remove(i64),
+    Export(ExportFormat),
+    Exported,
+}
+
+/// State of the favorites browser.
+#[derive(Default)]
+pub struct FavoritesPanel {
+    favorites: Vec<(Favorite, widget::button::State)>,
+
+    scrollable_state: widget::scrollable::State,
+    export_csv_state: widget::button::State,
+    export_m3u_state: widget::button::State,
+}
+
+impl FavoritesPanel {
+    pub fn contains(&self, song: &GRApiAnswer) -> bool {
+        self.favorites.iter().any(|(favorite, _)| favorite.is_song(song))
+    }
+
+    /// Reloads the favorites shown in the browser.
+    pub fn refresh(&self, favorites: Arc<Favorites>) -> Command<PlayerMessage> {
+        Command::perform(
+            database::run_blocking("list favorites", move || favorites.list()),
+            |favorites| PlayerMessage::Favorites(FavoritesMessage::Loaded(favorites)),
+        )
+    }
+
+    /// Bookmarks `song` with its album art, or removes it if it already is a favorite.
+    pub fn toggle(
+        &self,
+        favorites: Arc<Favorites>,
+        song: GRApiAnswer,
+        cover: Option<Vec<u8>>,
+    ) -> Command<PlayerMessage> {
+        let is_favorite = self.contains(&song);
+        Command::perform(
+            database::run_blocking("update favorites", move || {
+                let result = if is_favorite {
+                    favorites.remove(&song)
+                } else {
+                    favorites.add(&song, cover.as_deref())
+                };
+                if let Err(error) = result {
+                    eprintln!("Failed to update favorites: {}", error);
+                }
+                favorites.list()
+            }),
+            |favorites| PlayerMessage::Favorites(FavoritesMessage::Loaded(favorites)),
+        )
+    }
+
+    pub fn update(
+        &mut self,
+        message: FavoritesMessage,
+        favorites: Arc<Favorites>,
+        api_client: Arc<ApiClient>,
+    ) -> Command<PlayerMessage> {
+        match message {
+            FavoritesMessage::Loaded(list) => {
+                self.favorites = list
+                    .into_iter()
+                    .map(|favorite| (favorite, widget::button::State::new()))
+                    .collect();
+                Command::none()
+            }
+            FavoritesMessage::Remove(id) => {
+                let mut commands = Vec::with_capacity(2);
+                // Same as toggling the current song, the account's favorites follow the local ones
+                let songid = self
+                    .favorites
+                    .iter()
+                    .find(|(favorite, _)| favorite.id == id)
+                    .map_or(0, |(favorite, _)| favorite.songid);
+                if songid != 0 && api_client.logged_in_user().is_some() {
+                    commands.push(account::sync_favorite(api_client, songid, false));
+                }
+                commands.push(Command::perform(
+                    database::run_blocking("remove favorite", move || {
+                        if let Err(error) = favorites.remove_id(id) {
+                            eprintln!("Failed to remove favorite: {}", error);
+                        }
+                        favorites.list()
+                    }),
+                    |favorites| PlayerMessage::Favorites(FavoritesMessage::Loaded(favorites)),
+                ));
+                Command::batch(commands)
+            }
+            FavoritesMessage::Export(format) => {
+                let list = self
+                    .favorites
+                    .iter()
+                    .map(|(favorite, _)| favorite.clone())
+                    .collect::<Vec<_>>();
+                Command::perform(
+                    async move {
+                        let path = database::pick_export_path("favorites", format).await?;
+                        tokio::task::spawn_blocking(move || {
+                            if let Err(error) = export(&list, format, &path) {
+                                eprintln!("Failed to export favorites: {}", error);
+                            }
+                        })
+                        .await
+                        .ok()
+                    },
+                    |_| PlayerMessage::Favorites(FavoritesMessage::Exported),
+                )
+            }
+            FavoritesMessage::Exported => Command::none(),
+        }
+    }
+
+    pub fn view(&mut self) -> Element<PlayerMessage> {
+        let entries = self.favorites.iter_mut().fold(
+            widget::Scrollable::new(&mut self.scrollable_state)
+                .spacing(4)
+                .height(iced::Length::Fill),
+            |scrollable, (favorite, remove_state)| {
+                let cover = match favorite.cover {
+                    Some(ref path) => widget::image::Handle::from_path(path),
+                    None => widget::image::Handle::from_memory(ui::NO_IMAGE.to_vec()),
+                };
+                let row = widget::Row::new()
+                    .push(
+                        widget::Image::new(cover)
+                            .width(iced::Length::Units(48))
+                            .height(iced::Length::Units(48)),
+                    )
+                    .push(
+                        widget::Column::new()
+                            .push(widget::Text::new(&favorite.title).size(16))
+                            .push(
+                                widget::Text::new(format!("{} | {}", favorite.artist, favorite.album))
+                                    .size(14)
+                                    .color([1.0, 1.0, 1.0, 0.5]),
+                            )
+                            .push(widget::Text::new(&favorite.circle).size(14).color([1.0, 1.0, 1.0, 0.5]))
+                            .width(iced::Length::Fill),
+                    )
+                    .push(
+                        widget::Button::new(remove_state, widget::Text::new("remove").size(14))
+                            .style(ui::TextButtonStyle)
+                            .on_press(PlayerMessage::Favorites(FavoritesMessage::Remove(favorite.id))),
+                    )
+                    .spacing(8)
+                    .align_items(iced::Align::Center);
+                scrollable.push(row)
+            },
+        );
+
+        let export_row = widget::Row::new()
+            .push(
+                widget::Button::new(&mut self.export_csv_state, widget::Text::new("export csv").size(16))
+                    .style(ui::TextButtonStyle)
+                    .on_press(PlayerMessage::Favorites(FavoritesMessage::Export(ExportFormat::Csv))),
+            )
+            .push(
+                widget::Button::new(&mut self.export_m3u_state, widget::Text::new("export m3u").size(16))
+                    .style(ui::TextButtonStyle)
+                    .on_press(PlayerMessage::Favorites(FavoritesMessage::Export(ExportFormat::M3u))),
+            );
+
+        widget::Column::new().push(entries).push(export_row).spacing(4).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gensokyo_radio::{Misc, ServerInfo, SongInfo, SongTimes};
+
+    /// A directory for the covers and exports, removed at the end of the test
+    struct TempDir {
+        dir: PathBuf,
+    }
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = std::env::temp_dir().join(format!("wan_player-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).expect("Failed to create the temporary directory");
+            TempDir { dir }
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn favorites(dir: &TempDir) -> Favorites {
+        let connection = rusqlite::Connection::open_in_memory().unwrap();
+        connection.execute_batch(SCHEMA).unwrap();
+        Favorites {
+            connection: Mutex::new(connection),
+            covers_dir: dir.dir.clone(),
+        }
+    }
+
+    fn song(title: &str, albumid: u64) -> GRApiAnswer {
+        GRApiAnswer {
+            serverinfo: ServerInfo::default(),
+            songinfo: SongInfo {
+                title: title.to_string(),
+                artist: "Artist".to_string(),
+                album: "Album".to_string(),
+                circle: "Circle".to_string(),
+                ..SongInfo::default()
+            },
+            songtimes: SongTimes::default(),
+            songdata: SongData {
+                songid: albumid * 10,
+                albumid,
+                ..SongData::default()
+            },
+            misc: Misc {
+                albumart: "album.jpg".to_string(),
+                ..Misc::default()
+            },
+        }
+    }
+
+    fn titles(favorites: &Favorites) -> Vec<String> {
+        let mut titles: Vec<String> = favorites.list().unwrap().into_iter().map(|f| f.title).collect();
+        // Songs added in the same second have no order
+        titles.sort();
+        titles
+    }
+
+    #[test]
+    fn add_and_remove() {
+        let dir = TempDir::new("favorites-add");
+        let favorites = favorites(&dir);
+        favorites.add(&song("Bad Apple!!", 1), Some(b"cover")).unwrap();
+        favorites.add(&song("Bad Apple!!", 1), None).unwrap();
+        favorites.add(&song("Night of Nights", 2), None).unwrap();
+        assert_eq!(titles(&favorites), vec!["Bad Apple!!", "Night of Nights"]);
+
+        let list = favorites.list().unwrap();
+        let bad_apple = list.iter().find(|f| f.title == "Bad Apple!!").unwrap();
+        assert!(bad_apple.is_song(&song("Bad Apple!!", 1)));
+        assert_eq!((bad_apple.songid, bad_apple.albumid), (10, 1));
+        // The first time it was added, with its cover
+        let cover = bad_apple.cover.as_ref().expect("The cover wasn't cached");
+        assert_eq!(cover, &dir.dir.join("album.jpg"));
+        assert_eq!(std::fs::read(cover).unwrap(), b"cover");
+
+        favorites.remove(&song("Bad Apple!!", 1)).unwrap();
+        assert_eq!(titles(&favorites), vec!["Night of Nights"]);
+        let id = favorites.list().unwrap()[0].id;
+        favorites.remove_id(id).unwrap();
+        assert!(favorites.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn cover_stays_in_covers_dir() {
+        let dir = TempDir::new("favorites-cover");
+        let favorites = favorites(&dir);
+        let mut song = song("Bad Apple!!", 1);
+        song.misc.albumart = "../../escaped.jpg".to_string();
+        favorites.add(&song, Some(b"cover")).unwrap();
+        assert_eq!(favorites.list().unwrap()[0].cover, Some(dir.dir.join("escaped.jpg")));
+    }
+
+    #[test]
+    fn older_table() {
+        let connection = rusqlite::Connection::open_in_memory().unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE favorites (
+                    id INTEGER PRIMARY KEY,
+                    added_at INTEGER NOT NULL,
+                    title TEXT NOT NULL,
+                    artist TEXT NOT NULL,
+                    album TEXT NOT NULL,
+                    year TEXT NOT NULL,
+                    circle TEXT NOT NULL,
+                    circlelink TEXT NOT NULL,
+                    albumart TEXT NOT NULL,
+                    cover TEXT,
+                    UNIQUE (title, artist, album)
+                );
+                INSERT INTO favorites (added_at, title, artist, album, year, circle, circlelink, albumart)
+                    VALUES (0, 'Bad Apple!!', 'Artist', 'Album', '', 'Circle', '', '');",
+            )
+            .unwrap();
+        add_missing_columns(&connection).unwrap();
+        // Twice, as when the player starts again
+        add_missing_columns(&connection).unwrap();
+
+        let dir = TempDir::new("favorites-older");
+        let favorites = Favorites {
+            connection: Mutex::new(connection),
+            covers_dir: dir.dir.clone(),
+        };
+        favorites.add(&song("Night of Nights", 2), None).unwrap();
+        let list = favorites.list().unwrap();
+        assert_eq!(list.len(), 2);
+        let bad_apple = list.iter().find(|f| f.title == "Bad Apple!!").unwrap();
+        assert_eq!((bad_apple.songid, bad_apple.albumid), (0, 0));
+    }
+
+    #[test]
+    fn export_m3u() {
+        let dir = TempDir::new("favorites-m3u");
+        let favorites = favorites(&dir);
+        favorites.add(&song("Bad Apple!!", 1), None).unwrap();
+        // Not from Gensokyo Radio, there is no page to point to
+        favorites.add(&song("Icecast song", 0), None).unwrap();
+
+        let path = dir.dir.join("favorites.m3u");
+        export(&favorites.list().unwrap(), ExportFormat::M3u, &path).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "#EXTM3U\n\
+             #EXTINF:-1,Artist - Bad Apple!!\n\
+             #EXTALB:Album\n\
+             #EXTART:Circle\n\
+             https://gensokyoradio.net/music/album/1/\n"
+        );
+    }
+
+    #[test]
+    fn export_csv_and_json() {
+        let dir = TempDir::new("favorites-export");
+        let favorites = favorites(&dir);
+        favorites.add(&song("Bad Apple!!", 1), None).unwrap();
+        let list = favorites.list().unwrap();
+
+        let path = dir.dir.join("favorites.csv");
+        export(&list, ExportFormat::Csv, &path).unwrap();
+        let csv = std::fs::read_to_string(&path).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(
+            lines.next(),
+            Some("id,added_at,title,artist,album,year,circle,circlelink,albumart,songid,albumid,cover")
+        );
+        assert_eq!(
+            lines.next(),
+            Some(
+                format!(
+                    "1,{},Bad Apple!!,Artist,Album,,Circle,,album.jpg,10,1,",
+                    list[0].added_at
+                )
+                .as_str()
+            )
+        );
+
+        let path = dir.dir.join("favorites.json");
+        export(&list, ExportFormat::Json, &path).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(json[0]["title"], "Bad Apple!!");
+        assert_eq!(json[0]["albumid"], 1);
+        assert_eq!(json[0]["cover"], serde_json::Value::Null);
+    }
+}
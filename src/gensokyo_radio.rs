@@ -14,70 +14,445 @@
 //
 // I do not own the Gensokyo Radio name or any of the services provided by the website.
 
-use serde::Deserialize;
+use async_trait::async_trait;
+use serde::{Deserialize, Deserializer};
+
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::art_cache::ArtCache;
+use crate::clock::{self, ServerClock};
+use crate::station::Station;
 
 pub struct ApiClient {
     client: hyper::client::Client<hyper_tls::HttpsConnector<hyper::client::HttpConnector>>,
+    account_api: String,
+    session: Mutex<Option<Session>>,
+    art_cache: Option<ArtCache>,
+    clock: ServerClock,
+    retry_policy: RetryPolicy,
+    push_url: Option<String>,
 }
 
 pub const GR_API: &str = "https://gensokyoradio.net/json/";
 pub const GR_STREAM: &str = "https://stream.gensokyoradio.net/1/";
-pub const GR_ALBUMART_ROOT: &str = "https://gensokyoradio.net/images/albums/200/";
+/// Page with the player of the website
+pub const GR_LISTEN_PAGE: &str = "https://gensokyoradio.net/music/playing/";
+/// Websocket pushing the song changes.
+pub const GR_WEBSOCKET: &str = "wss://gensokyoradio.net/wss";
+pub const GR_ALBUMART_ROOT: &str = "https://gensokyoradio.net/images/albums/";
+/// Album art sizes available on the website, used when the window is big enough to show them.
+const GR_ALBUMART_LARGE_SIZES: &[&str] = &["500"];
+const GR_ALBUMART_SIZE: &str = "200";
+pub const GR_CIRCLEART_ROOT: &str = "https://gensokyoradio.net/images/circles/";
+pub const GR_ALBUM_PAGE_ROOT: &str = "https://gensokyoradio.net/music/album/";
+/// Root of the endpoints that need a logged in user.
+pub const GR_ACCOUNT_API: &str = "https://gensokyoradio.net/api/";
+
+/// Error returned by the Gensokyo Radio API client.
+#[derive(Debug, Clone)]
+pub enum ApiError {
+    /// The request didn't complete before the timeout
+    Timeout,
+    /// The request couldn't be sent or its answer couldn't be received
+    Http(String),
+    /// The server answered with an error status
+    Status(hyper::StatusCode),
+    /// The answer couldn't be parsed
+    InvalidAnswer(String),
+    /// The API refused the request, e.g. because of wrong credentials
+    Api(String),
+    NotLoggedIn,
+}
+
+impl ApiError {
+    /// Whether the same request may succeed if it is sent again later.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ApiError::Timeout | ApiError::Http(_) | ApiError::InvalidAnswer(_) => true,
+            ApiError::Status(status) => status.is_server_error() || *status == hyper::StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Api(_) | ApiError::NotLoggedIn => false,
+        }
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::Timeout => write!(f, "Request timed out"),
+            ApiError::Http(error) => write!(f, "Request failed: {}", error),
+            ApiError::Status(status) => write!(f, "Server answered {}", status),
+            ApiError::InvalidAnswer(error) => write!(f, "Invalid answer: {}", error),
+            ApiError::Api(error) => write!(f, "{}", error),
+            ApiError::NotLoggedIn => write!(f, "Not logged in"),
+        }
+    }
+}
 
-const RETRY_SLEEP: u64 = 5;
+impl std::error::Error for ApiError {}
+
+impl From<hyper::Error> for ApiError {
+    fn from(error: hyper::Error) -> Self {
+        ApiError::Http(error.to_string())
+    }
+}
+
+impl From<hyper::http::Error> for ApiError {
+    fn from(error: hyper::http::Error) -> Self {
+        ApiError::Http(error.to_string())
+    }
+}
+
+/// Timeout and retries applied to the API requests.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Maximum time to wait for an answer, body included
+    pub timeout: Duration,
+    /// Number of times a request is sent before giving up, at least 1
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled after every attempt
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            timeout: Duration::from_secs(10),
+            max_attempts: 3,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before sending a request again after `attempt` (starting at 0) failed.
+    fn backoff(&self, attempt: u32) -> Duration {
+        // A huge initial backoff from the configuration would overflow once doubled
+        self.initial_backoff
+            .checked_mul(2u32.pow(attempt.min(16)))
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+}
+
+/// An authenticated Gensokyo Radio session.
+#[derive(Debug, Clone)]
+struct Session {
+    username: String,
+    userid: String,
+    api_key: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct AccountAnswer {
+    #[serde(default)]
+    userid: Option<serde_json::Value>,
+    #[serde(default)]
+    api_key: Option<String>,
+    #[serde(default)]
+    error: Option<String>,
+}
 
 impl ApiClient {
     pub fn new() -> ApiClient {
         let https = hyper_tls::HttpsConnector::new();
         let client = hyper::client::Client::builder().build::<_, hyper::Body>(https);
-        ApiClient { client }
+        ApiClient {
+            client,
+            account_api: GR_ACCOUNT_API.to_string(),
+            session: Mutex::new(None),
+            art_cache: None,
+            clock: ServerClock::new(),
+            retry_policy: RetryPolicy::default(),
+            push_url: Some(GR_WEBSOCKET.to_string()),
+        }
     }
 
-    pub async fn get_song_info(&self) -> GRApiAnswer {
-        let mut response = loop {
-            let res = self
-                .client
-                .get(hyper::Uri::from_static(GR_API))
-                .await
-                .expect("Failed to request song info");
+    /// Uses another websocket for the song changes, e.g. `examples/gr_push_server.rs`. `None` disables it, and the
+    /// song info is only polled.
+    pub fn with_push_url(mut self, push_url: Option<String>) -> ApiClient {
+        self.push_url = push_url;
+        self
+    }
 
-            let data = hyper::body::to_bytes(res.into_body())
-                .await
-                .expect("Failed to collect song info request body");
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> ApiClient {
+        self.retry_policy = retry_policy;
+        self
+    }
 
-            match serde_json::from_slice::<GRApiAnswer>(&data[..]) {
-                Ok(song_info) => break song_info,
-                Err(error) => println!("{}", error),
-            }
-            tokio::time::sleep(std::time::Duration::from_secs(RETRY_SLEEP)).await;
+    /// Uses another root for the account endpoints, e.g. a local mock of the API.
+    pub fn with_account_api(mut self, account_api: String) -> ApiClient {
+        self.account_api = account_api;
+        self
+    }
+
+    /// Keeps the downloaded album art in `art_cache`.
+    pub fn with_art_cache(mut self, art_cache: Option<ArtCache>) -> ApiClient {
+        self.art_cache = art_cache;
+        self
+    }
+
+    /// Returns the name of the logged in user.
+    pub fn logged_in_user(&self) -> Option<String> {
+        let session = self.session.lock().expect("Failed to lock session");
+        session.as_ref().map(|session| session.username.clone())
+    }
+
+    pub async fn login(&self, username: &str, password: &str) -> Result<(), ApiError> {
+        let answer = self
+            .post_form("login/", &[("user", username), ("pass", password)])
+            .await?;
+        let (userid, api_key) = match (answer.userid, answer.api_key) {
+            (Some(serde_json::Value::String(userid)), Some(api_key)) => (userid, api_key),
+            (Some(serde_json::Value::Number(userid)), Some(api_key)) => (userid.to_string(), api_key),
+            _ => return Err(ApiError::InvalidAnswer("Missing user id or API key".to_string())),
         };
-        response.songtimes.duration = response
-            .songtimes
-            .duration_str
-            .parse()
-            .expect("Failed to parse duration");
-        response
-    }
-
-    pub async fn get_album_image(&self, ans: &GRApiAnswer) -> Option<Vec<u8>> {
-        let req_path = format!("{}{}", GR_ALBUMART_ROOT, ans.misc.albumart);
-        let res = self
-            .client
-            .get(
-                req_path
-                    .parse::<hyper::Uri>()
-                    .expect("Failed to parse album art as uri"),
-            )
-            .await
+
+        *self.session.lock().expect("Failed to lock session") = Some(Session {
+            username: username.to_string(),
+            userid,
+            api_key,
+        });
+        Ok(())
+    }
+
+    pub fn logout(&self) {
+        *self.session.lock().expect("Failed to lock session") = None;
+    }
+
+    /// Rates a song from 1 to 5 stars.
+    pub async fn rate_song(&self, songid: u64, rating: u8) -> Result<(), ApiError> {
+        let session = self.session()?;
+        self.post_form(
+            "station/rating/",
+            &[
+                ("userid", &session.userid),
+                ("api_key", &session.api_key),
+                ("songid", &songid.to_string()),
+                ("rating", &rating.to_string()),
+            ],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Adds a song to the user's favorites, or removes it from them.
+    pub async fn set_favorite(&self, songid: u64, favorite: bool) -> Result<(), ApiError> {
+        let session = self.session()?;
+        self.post_form(
+            "station/favorite/",
+            &[
+                ("userid", &session.userid),
+                ("api_key", &session.api_key),
+                ("songid", &songid.to_string()),
+                ("action", if favorite { "add" } else { "remove" }),
+            ],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    fn session(&self) -> Result<Session, ApiError> {
+        let session = self.session.lock().expect("Failed to lock session");
+        session.clone().ok_or(ApiError::NotLoggedIn)
+    }
+
+    async fn post_form(&self, endpoint: &str, fields: &[(&str, &str)]) -> Result<AccountAnswer, ApiError> {
+        let body = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(fields)
+            .finish();
+        let req = hyper::Request::post(format!("{}{}", self.account_api, endpoint))
+            .header(hyper::header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(hyper::Body::from(body))?;
+
+        let res = self.send(req).await?;
+        let answer = serde_json::from_slice::<AccountAnswer>(res.body());
+        match answer {
+            Ok(AccountAnswer { error: Some(error), .. }) => Err(ApiError::Api(error)),
+            _ if !res.status().is_success() => Err(ApiError::Status(res.status())),
+            Ok(answer) => Ok(answer),
+            Err(error) => Err(ApiError::InvalidAnswer(error.to_string())),
+        }
+    }
+
+    /// Sends a request and collects its answer, giving up after the policy's timeout.
+    async fn send(&self, req: hyper::Request<hyper::Body>) -> Result<hyper::Response<hyper::body::Bytes>, ApiError> {
+        let request = async {
+            let res = self.client.request(req).await?;
+            let (parts, body) = res.into_parts();
+            let data = hyper::body::to_bytes(body).await?;
+            Ok::<_, hyper::Error>(hyper::Response::from_parts(parts, data))
+        };
+        match tokio::time::timeout(self.retry_policy.timeout, request).await {
+            Ok(result) => Ok(result?),
+            Err(_) => Err(ApiError::Timeout),
+        }
+    }
+
+    /// Fetches the song currently playing, retrying according to the client's retry policy.
+    pub async fn get_song_info(&self) -> Result<GRApiAnswer, ApiError> {
+        let mut attempt = 0;
+        loop {
+            match self.fetch_song_info().await {
+                Err(error) if error.is_retryable() && attempt + 1 < self.retry_policy.max_attempts => {
+                    eprintln!("Failed to fetch song info, retrying: {}", error);
+                    tokio::time::sleep(self.retry_policy.backoff(attempt)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn fetch_song_info(&self) -> Result<GRApiAnswer, ApiError> {
+        let req = hyper::Request::get(GR_API).body(hyper::Body::empty())?;
+        let sent_at = clock::local_now_ms();
+        let res = self.send(req).await?;
+        self.clock.observe(sent_at, res.headers());
+        if !res.status().is_success() {
+            return Err(ApiError::Status(res.status()));
+        }
+
+        GRApiAnswer::from_json(res.body())
+    }
+
+    /// Fetches the album art of a song, trying the larger sizes first when `large` is set.
+    pub async fn get_album_image(&self, ans: &GRApiAnswer, large: bool) -> Option<Vec<u8>> {
+        if ans.misc.albumart.is_empty() {
+            return None;
+        }
+
+        let large_sizes = if large { GR_ALBUMART_LARGE_SIZES } else { &[] };
+        for size in large_sizes.iter().chain(std::iter::once(&GR_ALBUMART_SIZE)) {
+            let key = album_art_key(size, &ans.misc.albumart);
+            if let Some(data) = self.cached_album_image(&key).await {
+                return Some(data);
+            }
+
+            let req_path = format!("{}{}/{}", GR_ALBUMART_ROOT, size, ans.misc.albumart);
+            let res = match hyper::Request::get(req_path).body(hyper::Body::empty()) {
+                Ok(req) => self.send(req).await,
+                Err(error) => Err(error.into()),
+            };
+            let data = match res {
+                Ok(res) if res.status().is_success() && !res.body().is_empty() => res.body().to_vec(),
+                Ok(_) => continue,
+                Err(error) => {
+                    eprintln!("Failed to fetch album art: {}", error);
+                    continue;
+                }
+            };
+
+            if let Some(cache) = self.art_cache.clone() {
+                let cached = data.clone();
+                tokio::task::spawn_blocking(move || cache.put(&key, &cached));
+            }
+            return Some(data);
+        }
+
+        None
+    }
+
+    /// Fetches the artwork of the circle that made a song.
+    pub async fn get_circle_image(&self, ans: &GRApiAnswer) -> Option<Vec<u8>> {
+        if ans.misc.circleart.is_empty() {
+            return None;
+        }
+
+        let key = format!("circle_{}", ans.misc.circleart);
+        if let Some(data) = self.cached_album_image(&key).await {
+            return Some(data);
+        }
+
+        let req = hyper::Request::get(format!("{}{}", GR_CIRCLEART_ROOT, ans.misc.circleart))
+            .body(hyper::Body::empty())
             .ok()?;
+        let data = match self.send(req).await {
+            Ok(res) if res.status().is_success() && !res.body().is_empty() => res.body().to_vec(),
+            Ok(_) => return None,
+            Err(error) => {
+                eprintln!("Failed to fetch circle art: {}", error);
+                return None;
+            }
+        };
 
-        let data = hyper::body::to_bytes(res.into_body()).await.ok()?;
+        if let Some(cache) = self.art_cache.clone() {
+            let cached = data.clone();
+            tokio::task::spawn_blocking(move || cache.put(&key, &cached));
+        }
+        Some(data)
+    }
 
-        Some(data.to_vec())
+    async fn cached_album_image(&self, key: &str) -> Option<Vec<u8>> {
+        let cache = self.art_cache.clone()?;
+        let key = key.to_string();
+        tokio::task::spawn_blocking(move || cache.get(&key)).await.ok()?
     }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[async_trait]
+impl Station for ApiClient {
+    fn name(&self) -> &str {
+        "Gensokyo Radio"
+    }
+
+    fn stream_urls(&self) -> Vec<String> {
+        vec![GR_STREAM.to_string()]
+    }
+
+    fn clock(&self) -> &ServerClock {
+        &self.clock
+    }
+
+    fn push_url(&self) -> Option<String> {
+        self.push_url.clone()
+    }
+
+    fn listen_url(&self) -> Option<String> {
+        Some(GR_LISTEN_PAGE.to_string())
+    }
+
+    async fn now_playing(&self) -> Result<GRApiAnswer, ApiError> {
+        self.get_song_info().await
+    }
+
+    async fn artwork(&self, song: &GRApiAnswer, large: bool) -> Option<Vec<u8>> {
+        self.get_album_image(song, large).await
+    }
+}
+
+fn album_art_key(size: &str, albumart: &str) -> String {
+    format!("{}_{}", size, albumart)
+}
+
+/// Path of the album art of a song in the cache, the largest one if there are several.
+pub fn cached_album_art(ans: &GRApiAnswer, art_cache: &ArtCache) -> Option<PathBuf> {
+    if ans.misc.albumart.is_empty() {
+        return None;
+    }
+    GR_ALBUMART_LARGE_SIZES
+        .iter()
+        .chain(std::iter::once(&GR_ALBUMART_SIZE))
+        .find_map(|size| art_cache.file_path(&album_art_key(size, &ans.misc.albumart)))
+}
+
+/// URL of the album art of a song, pointing at the cached file when the player already downloaded it.
+pub fn album_art_url(ans: &GRApiAnswer, art_cache: Option<&ArtCache>) -> Option<String> {
+    if ans.misc.albumart.is_empty() {
+        return None;
+    }
+    match art_cache.and_then(|cache| cached_album_art(ans, cache)) {
+        Some(path) => Some(format!("file://{}", path.display())),
+        None => Some(format!(
+            "{}{}/{}",
+            GR_ALBUMART_ROOT, GR_ALBUMART_SIZE, ans.misc.albumart
+        )),
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
 #[serde(rename_all = "UPPERCASE")]
 pub struct SongInfo {
     pub title: String,
@@ -87,12 +462,11 @@ pub struct SongInfo {
     pub circle: String,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, Default)]
 #[serde(rename_all = "UPPERCASE")]
 pub struct SongTimes {
-    #[serde(rename = "DURATION")]
-    pub duration_str: String,
-    #[serde(skip)]
+    /// 0 when unknown, e.g. during live events
+    #[serde(deserialize_with = "number_or_string")]
     pub duration: u64,
     pub played: u64,
     pub remaining: u64,
@@ -100,18 +474,212 @@ pub struct SongTimes {
     pub songend: u64,
 }
 
-#[derive(Deserialize, Debug, Clone)]
-#[serde(rename_all = "UPPERCASE")]
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "UPPERCASE", default)]
 pub struct Misc {
-    circlelink: String,
-    circleart: String,
-    albumart: String,
+    /// Website of the circle, may be empty
+    pub circlelink: String,
+    /// File name of the circle artwork in `GR_CIRCLEART_ROOT`, may be empty
+    pub circleart: String,
+    /// File name of the album art in `GR_ALBUMART_ROOT`, may be empty
+    pub albumart: String,
+    #[serde(deserialize_with = "number_or_string")]
+    pub offsettime: u64,
+    #[serde(deserialize_with = "number_or_string")]
+    pub forcedelay: u64,
+}
+
+impl Misc {
+    /// Website of the circle, if the API knows it.
+    pub fn circle_url(&self) -> Option<&str> {
+        Some(self.circlelink.as_str()).filter(|link| link.starts_with("http"))
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "UPPERCASE", default)]
+pub struct SongData {
+    /// 0 when the song doesn't come from Gensokyo Radio
+    #[serde(deserialize_with = "number_or_string")]
+    pub songid: u64,
+    #[serde(deserialize_with = "number_or_string")]
+    pub albumid: u64,
+    /// Average rating of the song, out of 5
+    #[serde(deserialize_with = "number_or_string")]
+    pub rating: f32,
+    #[serde(deserialize_with = "number_or_string")]
+    pub timesrated: u64,
+}
+
+impl SongData {
+    /// Page of the song's album on the website.
+    pub fn album_url(&self) -> Option<String> {
+        if self.albumid == 0 {
+            None
+        } else {
+            Some(format!("{}{}/", GR_ALBUM_PAGE_ROOT, self.albumid))
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "UPPERCASE", default)]
+pub struct ServerInfo {
+    pub mode: String,
+    pub status: String,
+    /// Number of people listening to the station, 0 when unknown
+    #[serde(deserialize_with = "number_or_string")]
+    pub listeners: u64,
+    #[serde(deserialize_with = "number_or_string")]
+    pub lastupdate: u64,
 }
 
 #[derive(Deserialize, Debug, Clone)]
 #[serde(rename_all = "UPPERCASE")]
 pub struct GRApiAnswer {
+    #[serde(default)]
+    pub serverinfo: ServerInfo,
     pub songinfo: SongInfo,
     pub songtimes: SongTimes,
+    #[serde(default)]
+    pub songdata: SongData,
     pub misc: Misc,
 }
+
+impl GRApiAnswer {
+    /// Parses an answer of the JSON API.
+    pub fn from_json(data: &[u8]) -> Result<GRApiAnswer, ApiError> {
+        serde_json::from_slice::<GRApiAnswer>(data).map_err(|error| ApiError::InvalidAnswer(error.to_string()))
+    }
+}
+
+/// The API sends some numbers as strings, and uses empty strings or `null` when a value is unknown.
+fn number_or_string<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: std::str::FromStr + Default,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum NumberOrString {
+        Number(serde_json::Number),
+        String(String),
+    }
+
+    Ok(match Option::<NumberOrString>::deserialize(deserializer)? {
+        Some(NumberOrString::Number(number)) => number.to_string().parse().unwrap_or_default(),
+        Some(NumberOrString::String(string)) => string.trim().parse().unwrap_or_default(),
+        None => T::default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::test_support::{self, gr_account::Account};
+
+    /// Starts the account API mock on a free port, and returns a client using it.
+    fn mock_client() -> (ApiClient, Arc<Mutex<Account>>) {
+        let account = Arc::new(Mutex::new(Account::default()));
+        let server_account = account.clone();
+        let address = test_support::serve(move |req| test_support::gr_account::handle(server_account.clone(), req));
+        let client = ApiClient::new().with_account_api(format!("http://{}/api/", address));
+        (client, account)
+    }
+
+    #[tokio::test]
+    async fn login() {
+        let (client, _) = mock_client();
+        assert!(matches!(
+            client.login(test_support::gr_account::USERNAME, "wrong").await,
+            Err(ApiError::Api(_))
+        ));
+        assert_eq!(client.logged_in_user(), None);
+
+        client
+            .login(test_support::gr_account::USERNAME, test_support::gr_account::PASSWORD)
+            .await
+            .expect("Failed to log in");
+        assert_eq!(
+            client.logged_in_user().as_deref(),
+            Some(test_support::gr_account::USERNAME)
+        );
+        client.logout();
+        assert_eq!(client.logged_in_user(), None);
+    }
+
+    #[tokio::test]
+    async fn rating() {
+        let (client, account) = mock_client();
+        assert!(matches!(client.rate_song(1, 5).await, Err(ApiError::NotLoggedIn)));
+
+        client
+            .login(test_support::gr_account::USERNAME, test_support::gr_account::PASSWORD)
+            .await
+            .expect("Failed to log in");
+        client.rate_song(1, 5).await.expect("Failed to rate song");
+        client.rate_song(2, 3).await.expect("Failed to rate song");
+        assert!(matches!(client.rate_song(3, 6).await, Err(ApiError::Api(_))));
+
+        let account = account.lock().unwrap();
+        assert_eq!(account.ratings.get(&1), Some(&5));
+        assert_eq!(account.ratings.get(&2), Some(&3));
+        assert_eq!(account.ratings.get(&3), None);
+    }
+
+    #[tokio::test]
+    async fn favorite() {
+        let (client, account) = mock_client();
+        assert!(matches!(client.set_favorite(1, true).await, Err(ApiError::NotLoggedIn)));
+
+        client
+            .login(test_support::gr_account::USERNAME, test_support::gr_account::PASSWORD)
+            .await
+            .expect("Failed to log in");
+        client.set_favorite(1, true).await.expect("Failed to add favorite");
+        client.set_favorite(2, true).await.expect("Failed to add favorite");
+        client.set_favorite(1, false).await.expect("Failed to remove favorite");
+        assert!(matches!(client.set_favorite(3, false).await, Err(ApiError::Api(_))));
+
+        let favorites = &account.lock().unwrap().favorites;
+        assert!(!favorites.contains(&1));
+        assert!(favorites.contains(&2));
+    }
+
+    #[test]
+    fn backoff() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.backoff(0), Duration::from_secs(1));
+        assert_eq!(policy.backoff(2), Duration::from_secs(4));
+        assert_eq!(policy.backoff(4), Duration::from_secs(10));
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_secs(10));
+
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_secs(u64::MAX / 2),
+            max_backoff: Duration::from_secs(30),
+            ..RetryPolicy::default()
+        };
+        assert_eq!(policy.backoff(0), Duration::from_secs(30));
+        assert_eq!(policy.backoff(5), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn duration() {
+        let answer = |duration: &str| {
+            let json = format!(
+                r#"{{"SONGINFO": {{"TITLE": "", "ARTIST": "", "ALBUM": "", "YEAR": "", "CIRCLE": ""}},
+                    "SONGTIMES": {{"DURATION": {}, "PLAYED": 1, "REMAINING": 2, "SONGSTART": 10, "SONGEND": 13}},
+                    "MISC": {{}}}}"#,
+                duration
+            );
+            GRApiAnswer::from_json(json.as_bytes()).unwrap().songtimes.duration
+        };
+        assert_eq!(answer("3"), 3);
+        assert_eq!(answer(r#""3""#), 3);
+        assert_eq!(answer(r#""LIVE""#), 0);
+        assert_eq!(answer("null"), 0);
+    }
+}
@@ -0,0 +1,518 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+use chrono::TimeZone;
+use iced::{widget, Command, Element};
+use rusqlite::params;
+use serde::Serialize;
+
+use std::sync::{Arc, Mutex};
+
+use crate::database::{self, ExportFormat};
+use crate::gensokyo_radio::GRApiAnswer;
+use crate::{ui, PlayerMessage};
+
+/// Maximum number of songs shown in the history panel. Exports aren't limited.
+const HISTORY_PANEL_LIMIT: u32 = 200;
+
+/// Tables of the history database.
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS history (
+        id INTEGER PRIMARY KEY,
+        played_at INTEGER NOT NULL,
+        title TEXT NOT NULL,
+        artist TEXT NOT NULL,
+        album TEXT NOT NULL,
+        year TEXT NOT NULL,
+        circle TEXT NOT NULL,
+        duration INTEGER NOT NULL,
+        songstart INTEGER NOT NULL,
+        songend INTEGER NOT NULL,
+        albumart TEXT NOT NULL,
+        UNIQUE (songstart, title, artist)
+    );
+    CREATE INDEX IF NOT EXISTS history_played_at ON history (played_at);";
+
+/// A song that was played on the radio.
+#[derive(Serialize, Debug, Clone)]
+pub struct HistoryEntry {
+    /// When we received the song info, in seconds since the UNIX epoch
+    pub played_at: i64,
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    pub year: String,
+    pub circle: String,
+    pub duration: i64,
+    pub songstart: i64,
+    pub songend: i64,
+    pub albumart: String,
+}
+
+/// Filters applied to the history. Empty filters match everything.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HistoryQuery {
+    /// Matched against the title, artist, album and circle
+    pub text: String,
+    pub artist: String,
+    pub circle: String,
+}
+
+/// Local song history, stored in a SQLite database in the user's data directory.
+pub struct History {
+    connection: Mutex<rusqlite::Connection>,
+}
+
+impl History {
+    pub fn open() -> Option<History> {
+        let connection = database::open("history.sqlite", "song history", SCHEMA)?;
+        Some(History {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Stores a song in the history. The same song can be received several times, only the first one is kept.
+    pub fn record(&self, song: &GRApiAnswer) -> rusqlite::Result<()> {
+        let connection = self.connection.lock().expect("Failed to lock song history");
+        connection.execute(
+            "INSERT OR IGNORE INTO history
+                (played_at, title, artist, album, year, circle, duration, songstart, songend, albumart)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                chrono::Utc::now().timestamp(),
+                song.songinfo.title,
+                song.songinfo.artist,
+                song.songinfo.album,
+                song.songinfo.year,
+                song.songinfo.circle,
+                song.songtimes.duration as i64,
+                song.songtimes.songstart as i64,
+                song.songtimes.songend as i64,
+                song.misc.albumart,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Returns the songs matching `query`, most recent first.
+    pub fn search(&self, query: &HistoryQuery, limit: Option<u32>) -> rusqlite::Result<Vec<HistoryEntry>> {
+        let connection = self.connection.lock().expect("Failed to lock song history");
+        let mut statement = connection.prepare(
+            "SELECT played_at, title, artist, album, year, circle, duration, songstart, songend, albumart
+                FROM history
+                WHERE (title LIKE ?1 ESCAPE '\\' OR artist LIKE ?1 ESCAPE '\\'
+                    OR album LIKE ?1 ESCAPE '\\' OR circle LIKE ?1 ESCAPE '\\')
+                    AND artist LIKE ?2 ESCAPE '\\'
+                    AND circle LIKE ?3 ESCAPE '\\'
+                ORDER BY played_at DESC
+                LIMIT ?4",
+        )?;
+        let rows = statement.query_map(
+            params![
+                like_pattern(&query.text),
+                like_pattern(&query.artist),
+                like_pattern(&query.circle),
+                limit.map_or(-1, i64::from),
+            ],
+            |row| {
+                Ok(HistoryEntry {
+                    played_at: row.get(0)?,
+                    title: row.get(1)?,
+                    artist: row.get(2)?,
+                    album: row.get(3)?,
+                    year: row.get(4)?,
+                    circle: row.get(5)?,
+                    duration: row.get(6)?,
+                    songstart: row.get(7)?,
+                    songend: row.get(8)?,
+                    albumart: row.get(9)?,
+                })
+            },
+        )?;
+
+        rows.collect()
+    }
+}
+
+/// Builds a `LIKE` pattern matching any value containing `text`.
+fn like_pattern(text: &str) -> String {
+    let escaped = text
+        .trim()
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+#[derive(Debug, Clone)]
+pub enum HistoryMessage {
+    SearchChanged(String),
+    ArtistChanged(String),
+    CircleChanged(String),
+    /// Songs matching the query, which may have changed since they were requested
+    Loaded(HistoryQuery, Vec<HistoryEntry>),
+    Export(ExportFormat),
+    Exported,
+}
+
+/// State of the song history panel.
+#[derive(Default)]
+pub struct HistoryPanel {
+    query: HistoryQuery,
+    entries: Vec<HistoryEntry>,
+
+    search_state: widget::text_input::State,
+    artist_state: widget::text_input::State,
+    circle_state: widget::text_input::State,
+    scrollable_state: widget::scrollable::State,
+    export_csv_state: widget::button::State,
+    export_json_state: widget::button::State,
+}
+
+impl HistoryPanel {
+    /// Reloads the songs shown in the panel.
+    pub fn refresh(&self, history: Arc<History>) -> Command<PlayerMessage> {
+        self.load(history, None)
+    }
+
+    /// Stores a new song in the history, then reloads the panel.
+    pub fn record(&self, history: Arc<History>, song: GRApiAnswer) -> Command<PlayerMessage> {
+        self.load(history, Some(song))
+    }
+
+    fn load(&self, history: Arc<History>, song: Option<GRApiAnswer>) -> Command<PlayerMessage> {
+        let query = self.query.clone();
+        let loaded_query = self.query.clone();
+        Command::perform(
+            database::run_blocking("search song history", move || {
+                if let Some(song) = song {
+                    if let Err(error) = history.record(&song) {
+                        eprintln!("Failed to record song in history: {}", error);
+                    }
+                }
+                history.search(&query, Some(HISTORY_PANEL_LIMIT))
+            }),
+            move |entries| PlayerMessage::History(HistoryMessage::Loaded(loaded_query.clone(), entries)),
+        )
+    }
+
+    pub fn update(&mut self, message: HistoryMessage, history: Arc<History>) -> Command<PlayerMessage> {
+        match message {
+            HistoryMessage::SearchChanged(text) => {
+                self.query.text = text;
+                self.refresh(history)
+            }
+            HistoryMessage::ArtistChanged(artist) => {
+                self.query.artist = artist;
+                self.refresh(history)
+            }
+            HistoryMessage::CircleChanged(circle) => {
+                self.query.circle = circle;
+                self.refresh(history)
+            }
+            // Typing in the filters sends a search per key, only the answer to the last one is kept
+            HistoryMessage::Loaded(query, _) if query != self.query => Command::none(),
+            HistoryMessage::Loaded(_, entries) => {
+                self.entries = entries;
+                Command::none()
+            }
+            HistoryMessage::Export(format) => {
+                let query = self.query.clone();
+                Command::perform(
+                    async move {
+                        let path = database::pick_export_path("history", format).await?;
+                        tokio::task::spawn_blocking(move || {
+                            let result = history
+                                .search(&query, None)
+                                .map_err(|error| Box::new(error) as Box<dyn std::error::Error>)
+                                .and_then(|entries| database::export_rows(&entries, format, &path));
+                            if let Err(error) = result {
+                                eprintln!("Failed to export song history: {}", error);
+                            }
+                        })
+                        .await
+                        .ok()
+                    },
+                    |_| PlayerMessage::History(HistoryMessage::Exported),
+                )
+            }
+            HistoryMessage::Exported => Command::none(),
+        }
+    }
+
+    pub fn view(&mut self) -> Element<PlayerMessage> {
+        let filters = widget::Row::new()
+            .push(
+                widget::TextInput::new(&mut self.search_state, "search", &self.query.text, |text| {
+                    PlayerMessage::History(HistoryMessage::SearchChanged(text))
+                })
+                .size(16)
+                .padding(4),
+            )
+            .push(
+                widget::TextInput::new(&mut self.artist_state, "artist", &self.query.artist, |artist| {
+                    PlayerMessage::History(HistoryMessage::ArtistChanged(artist))
+                })
+                .size(16)
+                .padding(4)
+                .width(iced::Length::Units(100)),
+            )
+            .push(
+                widget::TextInput::new(&mut self.circle_state, "circle", &self.query.circle, |circle| {
+                    PlayerMessage::History(HistoryMessage::CircleChanged(circle))
+                })
+                .size(16)
+                .padding(4)
+                .width(iced::Length::Units(100)),
+            )
+            .spacing(4);
+
+        let entries = self.entries.iter().fold(
+            widget::Scrollable::new(&mut self.scrollable_state)
+                .spacing(4)
+                .height(iced::Length::Fill),
+            |scrollable, entry| {
+                let played_at = chrono::Local
+                    .timestamp_opt(entry.played_at, 0)
+                    .single()
+                    .map(|played_at| played_at.format("%a %d/%m %H:%M").to_string())
+                    .unwrap_or_default();
+                let row = widget::Row::new()
+                    .push(
+                        widget::Text::new(played_at)
+                            .size(14)
+                            .color([1.0, 1.0, 1.0, 0.5])
+                            .width(iced::Length::Units(90)),
+                    )
+                    .push(
+                        widget::Column::new()
+                            .push(widget::Text::new(&entry.title).size(16))
+                            .push(
+                                widget::Text::new(format!("{} | {}", entry.artist, entry.circle))
+                                    .size(14)
+                                    .color([1.0, 1.0, 1.0, 0.5]),
+                            ),
+                    )
+                    .spacing(4);
+                scrollable.push(row)
+            },
+        );
+
+        let export_row = widget::Row::new()
+            .push(
+                widget::Button::new(&mut self.export_csv_state, widget::Text::new("export csv").size(16))
+                    .style(ui::TextButtonStyle)
+                    .on_press(PlayerMessage::History(HistoryMessage::Export(ExportFormat::Csv))),
+            )
+            .push(
+                widget::Button::new(&mut self.export_json_state, widget::Text::new("export json").size(16))
+                    .style(ui::TextButtonStyle)
+                    .on_press(PlayerMessage::History(HistoryMessage::Export(ExportFormat::Json))),
+            );
+
+        widget::Column::new()
+            .push(filters)
+            .push(entries)
+            .push(export_row)
+            .spacing(4)
+            .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gensokyo_radio::{Misc, ServerInfo, SongData, SongInfo, SongTimes};
+
+    use std::path::PathBuf;
+
+    /// A directory for the exports, removed at the end of the test
+    struct TempDir {
+        dir: PathBuf,
+    }
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = std::env::temp_dir().join(format!("wan_player-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).expect("Failed to create the export directory");
+            TempDir { dir }
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn history() -> History {
+        let connection = rusqlite::Connection::open_in_memory().unwrap();
+        connection.execute_batch(SCHEMA).unwrap();
+        History {
+            connection: Mutex::new(connection),
+        }
+    }
+
+    fn song(title: &str, artist: &str, album: &str, circle: &str, songstart: u64) -> GRApiAnswer {
+        GRApiAnswer {
+            serverinfo: ServerInfo::default(),
+            songinfo: SongInfo {
+                title: title.to_string(),
+                artist: artist.to_string(),
+                album: album.to_string(),
+                circle: circle.to_string(),
+                ..SongInfo::default()
+            },
+            songtimes: SongTimes {
+                duration: 200,
+                songstart,
+                songend: songstart + 200,
+                ..SongTimes::default()
+            },
+            songdata: SongData::default(),
+            misc: Misc::default(),
+        }
+    }
+
+    /// Records a few songs, played one minute apart in this order.
+    fn filled_history() -> History {
+        let history = history();
+        let songs = [
+            song("Bad Apple!!", "nomico", "Lovelight", "Alstroemeria Records", 1000),
+            song("Night of Nights", "beatMARIO", "Toho Bossa", "COOL&CREATE", 2000),
+            song("100% Orange Juice", "Artist", "Album", "Circle_Name", 3000),
+        ];
+        for (minute, song) in songs.iter().enumerate() {
+            history.record(song).unwrap();
+            history
+                .connection
+                .lock()
+                .unwrap()
+                .execute(
+                    "UPDATE history SET played_at = ?1 WHERE songstart = ?2",
+                    params![minute as i64 * 60, song.songtimes.songstart as i64],
+                )
+                .unwrap();
+        }
+        history
+    }
+
+    fn titles(history: &History, query: HistoryQuery, limit: Option<u32>) -> Vec<String> {
+        history
+            .search(&query, limit)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.title)
+            .collect()
+    }
+
+    fn text(text: &str) -> HistoryQuery {
+        HistoryQuery {
+            text: text.to_string(),
+            ..HistoryQuery::default()
+        }
+    }
+
+    #[test]
+    fn search_most_recent_first() {
+        let history = filled_history();
+        assert_eq!(
+            titles(&history, HistoryQuery::default(), None),
+            vec!["100% Orange Juice", "Night of Nights", "Bad Apple!!"]
+        );
+        assert_eq!(
+            titles(&history, HistoryQuery::default(), Some(2)),
+            vec!["100% Orange Juice", "Night of Nights"]
+        );
+    }
+
+    #[test]
+    fn search_filters() {
+        let history = filled_history();
+        // The text is matched against the title, artist, album and circle, ignoring the case
+        assert_eq!(titles(&history, text("apple"), None), vec!["Bad Apple!!"]);
+        assert_eq!(titles(&history, text("BEATMARIO"), None), vec!["Night of Nights"]);
+        assert_eq!(titles(&history, text("lovelight"), None), vec!["Bad Apple!!"]);
+        assert_eq!(titles(&history, text(" cool&create "), None), vec!["Night of Nights"]);
+
+        let query = HistoryQuery {
+            artist: "nomi".to_string(),
+            ..HistoryQuery::default()
+        };
+        assert_eq!(titles(&history, query, None), vec!["Bad Apple!!"]);
+        let query = HistoryQuery {
+            text: "night".to_string(),
+            circle: "alstroemeria".to_string(),
+            ..HistoryQuery::default()
+        };
+        assert!(titles(&history, query, None).is_empty());
+    }
+
+    #[test]
+    fn search_wildcards() {
+        let history = filled_history();
+        // `%` and `_` are matched literally
+        assert_eq!(titles(&history, text("%"), None), vec!["100% Orange Juice"]);
+        assert_eq!(titles(&history, text("_"), None), vec!["100% Orange Juice"]);
+    }
+
+    #[test]
+    fn record_once() {
+        let history = history();
+        let song = song("Bad Apple!!", "nomico", "Lovelight", "Alstroemeria Records", 1000);
+        history.record(&song).unwrap();
+        history.record(&song).unwrap();
+        assert_eq!(titles(&history, HistoryQuery::default(), None), vec!["Bad Apple!!"]);
+    }
+
+    #[test]
+    fn export_csv_and_json() {
+        let history = filled_history();
+        let entries = history.search(&text("night"), None).unwrap();
+        let dir = TempDir::new("history-export");
+
+        let csv_path = dir.dir.join("history.csv");
+        database::export_rows(&entries, ExportFormat::Csv, &csv_path).unwrap();
+        let csv = std::fs::read_to_string(&csv_path).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(
+            lines.next(),
+            Some("played_at,title,artist,album,year,circle,duration,songstart,songend,albumart")
+        );
+        assert_eq!(
+            lines.next(),
+            Some("60,Night of Nights,beatMARIO,Toho Bossa,,COOL&CREATE,200,2000,2200,")
+        );
+        assert_eq!(lines.next(), None);
+
+        let json_path = dir.dir.join("history.json");
+        database::export_rows(&entries, ExportFormat::Json, &json_path).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&std::fs::read(&json_path).unwrap()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([{
+                "played_at": 60,
+                "title": "Night of Nights",
+                "artist": "beatMARIO",
+                "album": "Toho Bossa",
+                "year": "",
+                "circle": "COOL&CREATE",
+                "duration": 200,
+                "songstart": 2000,
+                "songend": 2200,
+                "albumart": "",
+            }])
+        );
+    }
+}
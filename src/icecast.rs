@@ -0,0 +1,389 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+use async_trait::async_trait;
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::clock::{self, ServerClock};
+use crate::gensokyo_radio::{ApiError, GRApiAnswer, Misc, ServerInfo, SongData, SongInfo, SongTimes};
+use crate::playlist::Playlist;
+use crate::station::Station;
+
+/// Time given to the server to answer the `status-json.xsl` request.
+const STATUS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Generic Icecast/Shoutcast station.
+///
+/// The now playing information is taken from the server's `status-json.xsl` page when it is available, and from the
+/// ICY metadata embedded in the stream the pipeline plays otherwise. These servers don't know when a song started or
+/// how long it lasts, so the start time is the first time we saw the song, and the duration and end time are left
+/// unset.
+///
+/// The status page is always read from the server of the first stream URL, the others are only used as fallbacks for
+/// playback.
+pub struct IcecastStation {
+    client: hyper::client::Client<hyper_tls::HttpsConnector<hyper::client::HttpConnector>>,
+    stream_urls: Vec<String>,
+    stream_url: String,
+    name: String,
+    stream_title: StreamTitle,
+    current: Mutex<Option<(String, u64)>>,
+    clock: ServerClock,
+}
+
+/// Error returned when a station is created from a playlist without any stream.
+#[derive(Debug)]
+pub struct EmptyPlaylist;
+
+impl fmt::Display for EmptyPlaylist {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the playlist doesn't contain any stream")
+    }
+}
+
+impl std::error::Error for EmptyPlaylist {}
+
+impl IcecastStation {
+    /// Creates a station from a playlist. `stream_title` is the one the pipeline fills from the ICY metadata of the
+    /// stream it plays.
+    pub fn new(playlist: Playlist, stream_title: StreamTitle) -> Result<IcecastStation, EmptyPlaylist> {
+        let https = hyper_tls::HttpsConnector::new();
+        let client = hyper::client::Client::builder().build::<_, hyper::Body>(https);
+        let stream_url = playlist.entries.first().ok_or(EmptyPlaylist)?.clone();
+        Ok(IcecastStation {
+            client,
+            name: playlist.title.unwrap_or_else(|| stream_url.clone()),
+            stream_urls: playlist.entries,
+            stream_url,
+            stream_title,
+            current: Mutex::new(None),
+            clock: ServerClock::new(),
+        })
+    }
+
+    /// Returns the artist and title of the current song, and the number of listeners.
+    async fn status_json_title(&self) -> Option<(String, String, u64)> {
+        let stream_uri = self.stream_url.parse::<hyper::Uri>().ok()?;
+        let status_uri = hyper::Uri::builder()
+            .scheme(stream_uri.scheme()?.clone())
+            .authority(stream_uri.authority()?.clone())
+            .path_and_query("/status-json.xsl")
+            .build()
+            .ok()?;
+
+        let sent_at = clock::local_now_ms();
+        let request = async {
+            let res = self.client.get(status_uri).await.ok()?;
+            self.clock.observe(sent_at, res.headers());
+            if !res.status().is_success() {
+                return None;
+            }
+            hyper::body::to_bytes(res.into_body()).await.ok()
+        };
+        let data = tokio::time::timeout(STATUS_TIMEOUT, request).await.ok()??;
+        let status: serde_json::Value = serde_json::from_slice(&data[..]).ok()?;
+
+        // Icecast sends a single object when there is only one mount point, and an array otherwise.
+        let sources = match status["icestats"]["source"] {
+            serde_json::Value::Array(ref sources) => sources.iter().collect::<Vec<_>>(),
+            ref source @ serde_json::Value::Object(_) => vec![source],
+            _ => return None,
+        };
+        let source = sources
+            .iter()
+            .find(|source| {
+                source["listenurl"]
+                    .as_str()
+                    .map_or(false, |url| url.ends_with(stream_uri.path()))
+            })
+            .or_else(|| sources.first())?;
+
+        let listeners = source["listeners"].as_u64().unwrap_or_default();
+        let artist = source["artist"].as_str().unwrap_or_default().to_string();
+        let title = source["title"].as_str()?.to_string();
+        let (artist, title) = if artist.is_empty() {
+            split_stream_title(&title)
+        } else {
+            (artist, title)
+        };
+        Some((artist, title, listeners))
+    }
+
+    /// Returns the time the song was first seen, in seconds since the UNIX epoch.
+    fn song_start(&self, key: &str) -> u64 {
+        let mut current = self.current.lock().expect("Failed to lock current Icecast song");
+        match *current {
+            Some((ref current_key, start)) if current_key == key => start,
+            _ => {
+                let start = self.clock.now();
+                *current = Some((key.to_string(), start));
+                start
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Station for IcecastStation {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn stream_urls(&self) -> Vec<String> {
+        self.stream_urls.clone()
+    }
+
+    fn clock(&self) -> &ServerClock {
+        &self.clock
+    }
+
+    async fn now_playing(&self) -> Result<GRApiAnswer, ApiError> {
+        let (artist, title, listeners) = match self.status_json_title().await {
+            Some(song) => song,
+            None => {
+                let (artist, title) = match self.stream_title.get() {
+                    Some(title) => split_stream_title(&title),
+                    None => (String::new(), self.name.clone()),
+                };
+                (artist, title, 0)
+            }
+        };
+
+        let songstart = self.song_start(&format!("{} - {}", artist, title));
+        let now = self.clock.now();
+
+        Ok(GRApiAnswer {
+            serverinfo: ServerInfo {
+                listeners,
+                ..ServerInfo::default()
+            },
+            songinfo: SongInfo {
+                title,
+                artist,
+                ..SongInfo::default()
+            },
+            songtimes: SongTimes {
+                played: now.saturating_sub(songstart),
+                songstart,
+                ..SongTimes::default()
+            },
+            songdata: SongData::default(),
+            misc: Misc::default(),
+        })
+    }
+
+    async fn artwork(&self, _song: &GRApiAnswer, _large: bool) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+/// Title of the song currently playing, as last read from the ICY metadata of the stream.
+#[derive(Clone, Default)]
+pub struct StreamTitle(Arc<Mutex<Option<String>>>);
+
+impl StreamTitle {
+    pub fn get(&self) -> Option<String> {
+        self.0.lock().expect("Failed to lock stream title").clone()
+    }
+
+    pub fn set(&self, title: Option<String>) {
+        *self.0.lock().expect("Failed to lock stream title") = title;
+    }
+}
+
+/// Position in an ICY stream, where a metadata block follows every `metaint` bytes of audio.
+enum IcyState {
+    /// Bytes of audio left before the next metadata block
+    Audio(usize),
+    /// The next byte is the length of the metadata block, in multiples of 16 bytes
+    Length,
+    /// Bytes of metadata left in the block
+    Metadata(usize),
+}
+
+/// Takes the ICY metadata blocks out of a stream requested with `Icy-MetaData: 1`, leaving only the audio.
+pub struct IcyDemuxer {
+    metaint: usize,
+    state: IcyState,
+    metadata: Vec<u8>,
+}
+
+impl IcyDemuxer {
+    /// Creates a demuxer for a stream whose `icy-metaint` header is `metaint`, which must not be 0.
+    pub fn new(metaint: usize) -> IcyDemuxer {
+        IcyDemuxer {
+            metaint,
+            state: IcyState::Audio(metaint),
+            metadata: Vec::new(),
+        }
+    }
+
+    /// Appends the audio of `chunk` to `audio`, and returns the last stream title it contained.
+    pub fn demux(&mut self, mut chunk: &[u8], audio: &mut Vec<u8>) -> Option<String> {
+        let mut title = None;
+        while !chunk.is_empty() {
+            match self.state {
+                IcyState::Audio(left) => {
+                    let len = left.min(chunk.len());
+                    audio.extend_from_slice(&chunk[..len]);
+                    chunk = &chunk[len..];
+                    self.state = if len == left {
+                        IcyState::Length
+                    } else {
+                        IcyState::Audio(left - len)
+                    };
+                }
+                IcyState::Length => {
+                    let len = chunk[0] as usize * 16;
+                    chunk = &chunk[1..];
+                    self.metadata.clear();
+                    self.state = if len == 0 {
+                        IcyState::Audio(self.metaint)
+                    } else {
+                        IcyState::Metadata(len)
+                    };
+                }
+                IcyState::Metadata(left) => {
+                    let len = left.min(chunk.len());
+                    self.metadata.extend_from_slice(&chunk[..len]);
+                    chunk = &chunk[len..];
+                    if len == left {
+                        title = parse_icy_metadata(&String::from_utf8_lossy(&self.metadata)).or(title);
+                        self.state = IcyState::Audio(self.metaint);
+                    } else {
+                        self.state = IcyState::Metadata(left - len);
+                    }
+                }
+            }
+        }
+        title
+    }
+}
+
+/// Extracts the `StreamTitle` value from an ICY metadata block, e.g. `StreamTitle='Artist - Title';StreamUrl='';`.
+fn parse_icy_metadata(metadata: &str) -> Option<String> {
+    let start = metadata.find("StreamTitle='")? + "StreamTitle='".len();
+    let rest = &metadata[start..];
+    let end = rest
+        .find("';")
+        .unwrap_or_else(|| rest.trim_end_matches('\0').trim_end_matches('\'').len());
+    Some(rest[..end].to_string())
+}
+
+/// Splits an `Artist - Title` stream title. Titles without a separator are returned with an empty artist.
+fn split_stream_title(title: &str) -> (String, String) {
+    match title.find(" - ") {
+        Some(index) => (title[..index].to_string(), title[index + 3..].to_string()),
+        None => (String::new(), title.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn icy_metadata_title() {
+        assert_eq!(
+            parse_icy_metadata("StreamTitle='ZUN - Bad Apple!!';StreamUrl='';").as_deref(),
+            Some("ZUN - Bad Apple!!")
+        );
+    }
+
+    #[test]
+    fn icy_metadata_padding() {
+        // Metadata blocks are padded with NUL bytes to a multiple of 16
+        assert_eq!(
+            parse_icy_metadata("StreamTitle='Artist - Title';\0\0\0\0").as_deref(),
+            Some("Artist - Title")
+        );
+        assert_eq!(
+            parse_icy_metadata("StreamTitle='Artist - Title'\0\0").as_deref(),
+            Some("Artist - Title")
+        );
+    }
+
+    #[test]
+    fn icy_metadata_apostrophe() {
+        assert_eq!(
+            parse_icy_metadata("StreamTitle='Don't Stop - It's Fine';").as_deref(),
+            Some("Don't Stop - It's Fine")
+        );
+    }
+
+    #[test]
+    fn icy_metadata_without_title() {
+        assert_eq!(parse_icy_metadata("StreamUrl='http://example.com';"), None);
+        assert_eq!(parse_icy_metadata("StreamTitle='';").as_deref(), Some(""));
+    }
+
+    #[test]
+    fn icy_demuxer() {
+        // 33 bytes of metadata, padded to 3 blocks of 16 bytes
+        let mut block = vec![3];
+        block.extend_from_slice(b"StreamTitle='ZUN - Bad Apple!!';");
+        block.resize(1 + 3 * 16, 0);
+        let mut stream = b"abcd".to_vec();
+        stream.extend_from_slice(&block);
+        stream.extend_from_slice(b"efgh");
+        // Empty block, the title didn't change
+        stream.push(0);
+        stream.extend_from_slice(b"ij");
+
+        // However the stream is cut into chunks, only the audio is left
+        for chunk_size in 1..=stream.len() {
+            let mut demuxer = IcyDemuxer::new(4);
+            let mut audio = Vec::new();
+            let mut titles = Vec::new();
+            for chunk in stream.chunks(chunk_size) {
+                titles.extend(demuxer.demux(chunk, &mut audio));
+            }
+            assert_eq!(audio, b"abcdefghij");
+            assert_eq!(titles, vec!["ZUN - Bad Apple!!".to_string()]);
+        }
+    }
+
+    #[test]
+    fn empty_playlist() {
+        let playlist = Playlist {
+            title: Some("Empty".to_string()),
+            entries: Vec::new(),
+        };
+        assert!(IcecastStation::new(playlist, StreamTitle::default()).is_err());
+    }
+
+    #[test]
+    fn stream_title_split() {
+        assert_eq!(
+            split_stream_title("IOSYS - Cirno's Perfect Math Class"),
+            ("IOSYS".to_string(), "Cirno's Perfect Math Class".to_string())
+        );
+        // Only the first separator splits, the title may contain more
+        assert_eq!(
+            split_stream_title("Artist - Title - Remix"),
+            ("Artist".to_string(), "Title - Remix".to_string())
+        );
+        assert_eq!(
+            split_stream_title("Station jingle"),
+            (String::new(), "Station jingle".to_string())
+        );
+        assert_eq!(
+            split_stream_title("Artist-Title"),
+            (String::new(), "Artist-Title".to_string())
+        );
+    }
+}
@@ -0,0 +1,479 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+//! [Last.fm](https://www.last.fm/api) scrobbling, see `scrobbler` for the rules.
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::config::LastfmConfig;
+use crate::scrobbler::{ScrobbleService, SubmitError, Track};
+
+pub const LASTFM_API: &str = "https://ws.audioscrobbler.com/2.0/";
+pub const LASTFM_AUTH: &str = "https://www.last.fm/api/auth/";
+const LASTFM_TIMEOUT: Duration = Duration::from_secs(10);
+
+const KEYRING_SERVICE: &str = "wan_player";
+const KEYRING_USER: &str = "last.fm";
+
+/// Last.fm accepts up to 50 scrobbles per request
+const SCROBBLE_BATCH: u32 = 50;
+/// Name of the scrobble queue in the user's data directory
+pub const QUEUE_FILE: &str = "lastfm_scrobbles.sqlite";
+
+#[derive(Debug, Clone)]
+pub enum LastfmError {
+    Http(String),
+    /// Error returned by the API, see https://www.last.fm/api/errorcodes
+    Api {
+        code: u64,
+        message: String,
+    },
+    InvalidAnswer(String),
+}
+
+impl std::fmt::Display for LastfmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LastfmError::Http(error) => write!(f, "{}", error),
+            LastfmError::Api { code, message } => write!(f, "Last.fm error {}: {}", code, message),
+            LastfmError::InvalidAnswer(error) => write!(f, "invalid answer from Last.fm: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for LastfmError {}
+
+impl LastfmError {
+    /// Whether sending the same request later could work.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            LastfmError::Http(_) | LastfmError::InvalidAnswer(_) => true,
+            // Operation failed, service offline, temporarily unavailable, rate limited
+            LastfmError::Api { code, .. } => matches!(code, 8 | 11 | 16 | 29),
+        }
+    }
+
+    /// Whether the user has to connect to Last.fm again.
+    pub fn is_invalid_session(&self) -> bool {
+        matches!(self, LastfmError::Api { code: 9, .. })
+    }
+}
+
+/// The Last.fm session of the user, kept in the OS keyring.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Session {
+    pub name: String,
+    pub key: String,
+}
+
+impl Session {
+    pub fn load() -> Option<Session> {
+        let entry = keyring::Entry::new(KEYRING_SERVICE, KEYRING_USER).ok()?;
+        let secret = entry.get_password().ok()?;
+        serde_json::from_str(&secret).ok()
+    }
+
+    pub fn store(&self) {
+        let result = keyring::Entry::new(KEYRING_SERVICE, KEYRING_USER).and_then(|entry| {
+            entry.set_password(&serde_json::to_string(self).expect("Failed to serialize Last.fm session"))
+        });
+        if let Err(error) = result {
+            eprintln!("Failed to store Last.fm session in the keyring: {}", error);
+        }
+    }
+
+    pub fn delete() {
+        let result = keyring::Entry::new(KEYRING_SERVICE, KEYRING_USER).and_then(|entry| entry.delete_password());
+        match result {
+            Ok(()) | Err(keyring::Error::NoEntry) => {}
+            Err(error) => eprintln!("Failed to remove Last.fm session from the keyring: {}", error),
+        }
+    }
+}
+
+/// The Last.fm session, read from the keyring on first use and shared by the clients of the account panel and of the
+/// scrobbler.
+#[derive(Debug, Clone, Default)]
+pub struct SessionStore(Arc<Mutex<Option<Option<Session>>>>);
+
+impl SessionStore {
+    pub fn get(&self) -> Option<Session> {
+        self.0
+            .lock()
+            .expect("Failed to lock Last.fm session")
+            .get_or_insert_with(Session::load)
+            .clone()
+    }
+
+    /// Replaces the session, in the keyring too. `None` disconnects the user.
+    pub fn set(&self, session: Option<Session>) {
+        match session {
+            Some(ref session) => session.store(),
+            None => Session::delete(),
+        }
+        *self.0.lock().expect("Failed to lock Last.fm session") = Some(session);
+    }
+}
+
+/// Client of the Last.fm API, signing the requests with the application's secret.
+pub struct LastfmClient {
+    client: hyper::client::Client<hyper_tls::HttpsConnector<hyper::client::HttpConnector>>,
+    api_root: String,
+    auth_root: String,
+    api_key: String,
+    api_secret: String,
+    session: SessionStore,
+}
+
+impl LastfmClient {
+    /// Builds the client described by the configuration, `None` if there is no API key.
+    ///
+    /// `WAN_PLAYER_LASTFM_API` and `WAN_PLAYER_LASTFM_AUTH` redirect the client to a mock of the API, see
+    /// examples/lastfm_mock_server.rs.
+    pub fn from_config(config: &LastfmConfig, session: SessionStore) -> Option<LastfmClient> {
+        if config.api_key.is_empty() || config.api_secret.is_empty() {
+            return None;
+        }
+        let https = hyper_tls::HttpsConnector::new();
+        let client = hyper::client::Client::builder().build::<_, hyper::Body>(https);
+        Some(LastfmClient {
+            client,
+            api_root: std::env::var("WAN_PLAYER_LASTFM_API").unwrap_or_else(|_| LASTFM_API.to_string()),
+            auth_root: std::env::var("WAN_PLAYER_LASTFM_AUTH").unwrap_or_else(|_| LASTFM_AUTH.to_string()),
+            api_key: config.api_key.clone(),
+            api_secret: config.api_secret.clone(),
+            session,
+        })
+    }
+
+    /// Session of the user, `None` if they aren't connected.
+    pub fn session(&self) -> Option<Session> {
+        self.session.get()
+    }
+
+    /// Connects the user with `session`, or disconnects them. Blocks while the keyring is updated.
+    pub fn set_session(&self, session: Option<Session>) {
+        self.session.set(session);
+    }
+
+    /// Calls a signed API method, with the session key of the user if `session` is set.
+    async fn call(
+        &self,
+        method: &str,
+        mut params: BTreeMap<String, String>,
+        session: Option<&Session>,
+    ) -> Result<serde_json::Value, LastfmError> {
+        params.insert("method".to_string(), method.to_string());
+        params.insert("api_key".to_string(), self.api_key.clone());
+        if let Some(session) = session {
+            params.insert("sk".to_string(), session.key.clone());
+        }
+        // The signature is the MD5 of the sorted parameters followed by the secret
+        let mut signed = params.iter().fold(String::new(), |mut signed, (key, value)| {
+            signed.push_str(key);
+            signed.push_str(value);
+            signed
+        });
+        signed.push_str(&self.api_secret);
+        params.insert("api_sig".to_string(), format!("{:x}", md5::compute(signed)));
+        params.insert("format".to_string(), "json".to_string());
+
+        let body = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(params.iter())
+            .finish();
+        let req = hyper::Request::post(self.api_root.as_str())
+            .header(hyper::header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(hyper::Body::from(body))
+            .map_err(|error| LastfmError::Http(error.to_string()))?;
+        let request = async {
+            let res = self
+                .client
+                .request(req)
+                .await
+                .map_err(|error| LastfmError::Http(error.to_string()))?;
+            let status = res.status();
+            let data = hyper::body::to_bytes(res.into_body())
+                .await
+                .map_err(|error| LastfmError::Http(error.to_string()))?;
+            // Errors come with a JSON body and an error status, so the body is checked first
+            let answer: serde_json::Value = match serde_json::from_slice(&data[..]) {
+                Ok(answer) => answer,
+                Err(_) if !status.is_success() => {
+                    return Err(LastfmError::Http(format!("Last.fm answered {}", status)))
+                }
+                Err(error) => return Err(LastfmError::InvalidAnswer(error.to_string())),
+            };
+            if let Some(code) = answer["error"].as_u64() {
+                let message = answer["message"].as_str().unwrap_or_default().to_string();
+                return Err(LastfmError::Api { code, message });
+            }
+            Ok(answer)
+        };
+        tokio::time::timeout(LASTFM_TIMEOUT, request)
+            .await
+            .map_err(|_| LastfmError::Http("Last.fm request timed out".to_string()))?
+    }
+
+    /// First step of the desktop authentication: a token the user approves in the browser, see `auth_url`.
+    pub async fn get_token(&self) -> Result<String, LastfmError> {
+        let answer = self.call("auth.getToken", BTreeMap::new(), None).await?;
+        answer["token"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| LastfmError::InvalidAnswer("missing token".to_string()))
+    }
+
+    /// Page where the user approves the token.
+    pub fn auth_url(&self, token: &str) -> String {
+        let query = form_urlencoded::Serializer::new(String::new())
+            .append_pair("api_key", &self.api_key)
+            .append_pair("token", token)
+            .finish();
+        format!("{}?{}", self.auth_root, query)
+    }
+
+    /// Last step of the desktop authentication, once the user approved the token.
+    pub async fn get_session(&self, token: &str) -> Result<Session, LastfmError> {
+        let mut params = BTreeMap::new();
+        params.insert("token".to_string(), token.to_string());
+        let answer = self.call("auth.getSession", params, None).await?;
+        serde_json::from_value(answer["session"].clone()).map_err(|error| LastfmError::InvalidAnswer(error.to_string()))
+    }
+
+    pub async fn update_now_playing(&self, track: &Track, session: &Session) -> Result<(), LastfmError> {
+        let mut params = BTreeMap::new();
+        params.insert("artist".to_string(), track.artist.clone());
+        params.insert("track".to_string(), track.title.clone());
+        if !track.album.is_empty() {
+            params.insert("album".to_string(), track.album.clone());
+        }
+        if track.duration > 0 {
+            params.insert("duration".to_string(), track.duration.to_string());
+        }
+        self.call("track.updateNowPlaying", params, Some(session)).await?;
+        Ok(())
+    }
+
+    pub async fn scrobble(&self, tracks: &[Track], session: &Session) -> Result<(), LastfmError> {
+        let mut params = BTreeMap::new();
+        for (i, track) in tracks.iter().enumerate() {
+            params.insert(format!("artist[{}]", i), track.artist.clone());
+            params.insert(format!("track[{}]", i), track.title.clone());
+            params.insert(format!("timestamp[{}]", i), track.timestamp.to_string());
+            if !track.album.is_empty() {
+                params.insert(format!("album[{}]", i), track.album.clone());
+            }
+            if track.duration > 0 {
+                params.insert(format!("duration[{}]", i), track.duration.to_string());
+            }
+        }
+        self.call("track.scrobble", params, Some(session)).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ScrobbleService for LastfmClient {
+    fn name(&self) -> &'static str {
+        "Last.fm"
+    }
+
+    fn batch_size(&self) -> u32 {
+        SCROBBLE_BATCH
+    }
+
+    async fn now_playing(&self, track: &Track) -> Result<(), SubmitError> {
+        let session = self.session().ok_or(SubmitError::NotConnected)?;
+        self.update_now_playing(track, &session).await.map_err(submit_error)
+    }
+
+    async fn submit(&self, tracks: &[Track]) -> Result<(), SubmitError> {
+        let session = self.session().ok_or(SubmitError::NotConnected)?;
+        self.scrobble(tracks, &session).await.map_err(submit_error)
+    }
+}
+
+fn submit_error(error: LastfmError) -> SubmitError {
+    // An invalid session is kept until the user connects again, the scrobbles are retried with the new one
+    if error.is_retryable() || error.is_invalid_session() {
+        SubmitError::Retry(error.to_string())
+    } else {
+        SubmitError::Rejected(error.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_support::{self, lastfm::Calls};
+
+    /// Starts the Last.fm mock on a free port, and returns a client using it.
+    fn mock_client() -> (LastfmClient, Calls) {
+        let calls = Calls::default();
+        let server_calls = calls.clone();
+        let address = test_support::serve(move |req| test_support::lastfm::handle(server_calls.clone(), req));
+
+        let config = LastfmConfig {
+            enabled: true,
+            api_key: "key".to_string(),
+            api_secret: "secret".to_string(),
+        };
+        // Connected, without touching the keyring
+        let session = SessionStore(Arc::new(Mutex::new(Some(Some(Session {
+            name: "mock_user".to_string(),
+            key: test_support::lastfm::SESSION_KEY.to_string(),
+        })))));
+        let mut client = LastfmClient::from_config(&config, session).expect("Failed to build the client");
+        client.api_root = format!("http://{}/2.0/", address);
+        client.auth_root = format!("http://{}/api/auth/", address);
+        (client, calls)
+    }
+
+    fn track(title: &str, timestamp: i64) -> Track {
+        Track {
+            artist: "Artist".to_string(),
+            title: title.to_string(),
+            album: "Album".to_string(),
+            circle: "Circle".to_string(),
+            station: "Gensokyo Radio".to_string(),
+            duration: 200,
+            timestamp,
+        }
+    }
+
+    #[tokio::test]
+    async fn desktop_auth() {
+        let (client, calls) = mock_client();
+        let token = client.get_token().await.expect("Failed to get a token");
+        assert_eq!(token, test_support::lastfm::TOKEN);
+        let url = client.auth_url(&token);
+        assert!(url.ends_with("/api/auth/?api_key=key&token=mock_token"), "{}", url);
+
+        let session = client.get_session(&token).await.expect("Failed to get a session");
+        assert_eq!(session.name, "mock_user");
+        assert_eq!(session.key, test_support::lastfm::SESSION_KEY);
+        assert!(matches!(
+            client.get_session("unapproved").await,
+            Err(LastfmError::Api { code: 4, .. })
+        ));
+
+        // The signature is the MD5 of the sorted parameters, without `format`, followed by the secret
+        let calls = calls.lock().unwrap();
+        let params = &calls[1];
+        let expected = format!(
+            "api_keykeymethodauth.getSessiontoken{}secret",
+            test_support::lastfm::TOKEN
+        );
+        assert_eq!(params["api_sig"], format!("{:x}", md5::compute(expected)));
+        assert_eq!(params["format"], "json");
+    }
+
+    #[tokio::test]
+    async fn scrobble() {
+        let (client, calls) = mock_client();
+        let session = Session {
+            name: "mock_user".to_string(),
+            key: test_support::lastfm::SESSION_KEY.to_string(),
+        };
+        let mut unknown_album = track("Second", 1_600_000_200);
+        unknown_album.album.clear();
+        client
+            .update_now_playing(&track("First", 1_600_000_000), &session)
+            .await
+            .expect("Failed to update now playing");
+        client
+            .scrobble(&[track("First", 1_600_000_000), unknown_album], &session)
+            .await
+            .expect("Failed to scrobble");
+
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls[0]["method"], "track.updateNowPlaying");
+        assert_eq!(calls[0]["track"], "First");
+        assert_eq!(calls[0]["album"], "Album");
+        assert_eq!(calls[0]["duration"], "200");
+        assert_eq!(calls[0]["sk"], test_support::lastfm::SESSION_KEY);
+        assert_eq!(calls[1]["method"], "track.scrobble");
+        assert_eq!(calls[1]["track[0]"], "First");
+        assert_eq!(calls[1]["timestamp[0]"], "1600000000");
+        assert_eq!(calls[1]["track[1]"], "Second");
+        assert_eq!(calls[1]["timestamp[1]"], "1600000200");
+        assert!(!calls[1].contains_key("album[1]"));
+    }
+
+    #[tokio::test]
+    async fn scrobble_with_stored_session() {
+        let (client, calls) = mock_client();
+        client
+            .submit(&[track("First", 1_600_000_000)])
+            .await
+            .expect("Failed to scrobble");
+        assert_eq!(calls.lock().unwrap()[0]["sk"], test_support::lastfm::SESSION_KEY);
+
+        *client.session.0.lock().unwrap() = Some(None);
+        assert!(matches!(
+            client.now_playing(&track("Second", 1_600_000_200)).await,
+            Err(SubmitError::NotConnected)
+        ));
+        assert_eq!(calls.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn errors() {
+        let (client, _) = mock_client();
+        let session = Session {
+            name: "mock_user".to_string(),
+            key: "expired".to_string(),
+        };
+        let error = client
+            .scrobble(&[track("First", 1_600_000_000)], &session)
+            .await
+            .unwrap_err();
+        assert!(error.is_invalid_session());
+        // Kept until the user connects again
+        assert!(matches!(submit_error(error), SubmitError::Retry(_)));
+
+        let error = client.call("track.unknown", BTreeMap::new(), None).await.unwrap_err();
+        assert!(matches!(error, LastfmError::Api { code: 3, .. }));
+        assert!(matches!(submit_error(error), SubmitError::Rejected(_)));
+    }
+
+    #[tokio::test]
+    async fn unreachable() {
+        let (mut client, _) = mock_client();
+        // Nothing listens on the port before the first free ones
+        client.api_root = "http://127.0.0.1:1/2.0/".to_string();
+        let error = client.get_token().await.unwrap_err();
+        assert!(matches!(error, LastfmError::Http(_)));
+        assert!(matches!(submit_error(error), SubmitError::Retry(_)));
+    }
+
+    #[test]
+    fn retryable_errors() {
+        let api_error = |code| LastfmError::Api {
+            code,
+            message: String::new(),
+        };
+        assert!(LastfmError::Http("connection refused".to_string()).is_retryable());
+        assert!(api_error(11).is_retryable());
+        assert!(api_error(29).is_retryable());
+        assert!(!api_error(6).is_retryable());
+        assert!(!api_error(9).is_retryable());
+    }
+}
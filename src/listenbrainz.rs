@@ -0,0 +1,247 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+//! [ListenBrainz](https://listenbrainz.readthedocs.io/en/latest/users/api/core.html) submission, see `scrobbler` for
+//! the rules.
+
+use async_trait::async_trait;
+
+use std::time::Duration;
+
+use crate::config::ListenBrainzConfig;
+use crate::scrobbler::{ScrobbleService, SubmitError, Track};
+
+pub const LISTENBRAINZ_API: &str = "https://api.listenbrainz.org";
+const LISTENBRAINZ_TIMEOUT: Duration = Duration::from_secs(10);
+/// ListenBrainz accepts up to 1000 listens per request, smaller batches keep the requests quick
+const LISTEN_BATCH: u32 = 100;
+/// Name of the listen queue in the user's data directory
+pub const QUEUE_FILE: &str = "listenbrainz_listens.sqlite";
+
+/// Client of the ListenBrainz API, or of a self-hosted instance.
+pub struct ListenBrainzClient {
+    client: hyper::client::Client<hyper_tls::HttpsConnector<hyper::client::HttpConnector>>,
+    api_root: String,
+    token: String,
+}
+
+impl ListenBrainzClient {
+    /// Builds the client described by the configuration, `None` if there is no token.
+    pub fn from_config(config: &ListenBrainzConfig) -> Option<ListenBrainzClient> {
+        if config.token.is_empty() {
+            return None;
+        }
+        let https = hyper_tls::HttpsConnector::new();
+        let client = hyper::client::Client::builder().build::<_, hyper::Body>(https);
+        Some(ListenBrainzClient {
+            client,
+            api_root: config.server.trim_end_matches('/').to_string(),
+            token: config.token.clone(),
+        })
+    }
+
+    async fn submit_listens(&self, listen_type: &str, payload: Vec<serde_json::Value>) -> Result<(), SubmitError> {
+        let body = serde_json::json!({ "listen_type": listen_type, "payload": payload });
+        let req = hyper::Request::post(format!("{}/1/submit-listens", self.api_root))
+            .header(hyper::header::AUTHORIZATION, format!("Token {}", self.token))
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(hyper::Body::from(body.to_string()))
+            .map_err(|error| SubmitError::Rejected(error.to_string()))?;
+        let request = async {
+            let res = self
+                .client
+                .request(req)
+                .await
+                .map_err(|error| SubmitError::Retry(error.to_string()))?;
+            let status = res.status();
+            if status.is_success() {
+                return Ok(());
+            }
+            let data = hyper::body::to_bytes(res.into_body()).await.unwrap_or_default();
+            let answer: serde_json::Value = serde_json::from_slice(&data[..]).unwrap_or_default();
+            let error = match answer["error"].as_str() {
+                Some(error) => format!("ListenBrainz answered {}: {}", status, error),
+                None => format!("ListenBrainz answered {}", status),
+            };
+            // Invalid tokens are retried, the user may fix the configuration
+            if status.is_server_error() || status.as_u16() == 401 || status.as_u16() == 429 {
+                Err(SubmitError::Retry(error))
+            } else {
+                Err(SubmitError::Rejected(error))
+            }
+        };
+        tokio::time::timeout(LISTENBRAINZ_TIMEOUT, request)
+            .await
+            .map_err(|_| SubmitError::Retry("ListenBrainz request timed out".to_string()))?
+    }
+}
+
+#[async_trait]
+impl ScrobbleService for ListenBrainzClient {
+    fn name(&self) -> &'static str {
+        "ListenBrainz"
+    }
+
+    fn batch_size(&self) -> u32 {
+        LISTEN_BATCH
+    }
+
+    async fn now_playing(&self, track: &Track) -> Result<(), SubmitError> {
+        self.submit_listens("playing_now", vec![listen(track, false)]).await
+    }
+
+    async fn submit(&self, tracks: &[Track]) -> Result<(), SubmitError> {
+        // Single listens are for the song that just played, the queued ones are imports
+        let listen_type = if tracks.len() == 1 { "single" } else { "import" };
+        let payload = tracks.iter().map(|track| listen(track, true)).collect();
+        self.submit_listens(listen_type, payload).await
+    }
+}
+
+/// Builds a listen, `playing_now` listens don't have a timestamp.
+fn listen(track: &Track, listened: bool) -> serde_json::Value {
+    let mut additional_info = serde_json::json!({
+        "media_player": "Wan Player",
+        "submission_client": "Wan Player",
+        "submission_client_version": env!("CARGO_PKG_VERSION"),
+        "radio_name": track.station,
+    });
+    if !track.circle.is_empty() {
+        additional_info["circle"] = track.circle.clone().into();
+    }
+    if track.duration > 0 {
+        additional_info["duration"] = track.duration.into();
+    }
+
+    let mut track_metadata = serde_json::json!({
+        "artist_name": track.artist,
+        "track_name": track.title,
+        "additional_info": additional_info,
+    });
+    if !track.album.is_empty() {
+        track_metadata["release_name"] = track.album.clone().into();
+    }
+
+    let mut listen = serde_json::json!({ "track_metadata": track_metadata });
+    if listened {
+        listen["listened_at"] = track.timestamp.into();
+    }
+    listen
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_support::{self, listenbrainz::Submissions};
+
+    /// Starts the ListenBrainz stand-in on a free port, and returns a client using `token` with it.
+    fn mock_client(token: &str) -> (ListenBrainzClient, Submissions) {
+        let submissions = Submissions::default();
+        let server_submissions = submissions.clone();
+        let address =
+            test_support::serve(move |req| test_support::listenbrainz::handle(server_submissions.clone(), req));
+
+        let config = ListenBrainzConfig {
+            enabled: true,
+            token: token.to_string(),
+            // Self-hosted servers may be configured with a trailing slash
+            server: format!("http://{}/", address),
+        };
+        let client = ListenBrainzClient::from_config(&config).expect("Failed to build the client");
+        (client, submissions)
+    }
+
+    fn track(title: &str, timestamp: i64) -> Track {
+        Track {
+            artist: "Artist".to_string(),
+            title: title.to_string(),
+            album: "Album".to_string(),
+            circle: "Circle".to_string(),
+            station: "Gensokyo Radio".to_string(),
+            duration: 200,
+            timestamp,
+        }
+    }
+
+    #[tokio::test]
+    async fn submit() {
+        let (client, submissions) = mock_client("token");
+        let first = track("First", 1_600_000_000);
+        let mut second = track("Second", 1_600_000_200);
+        second.album.clear();
+        second.circle.clear();
+        second.duration = 0;
+        client.now_playing(&first).await.expect("Failed to submit playing_now");
+        client
+            .submit(std::slice::from_ref(&first))
+            .await
+            .expect("Failed to submit a single listen");
+        client.submit(&[first, second]).await.expect("Failed to import listens");
+
+        let submissions = submissions.lock().unwrap();
+        let listen_types: Vec<&str> = submissions
+            .iter()
+            .map(|submission| submission["listen_type"].as_str().unwrap())
+            .collect();
+        assert_eq!(listen_types, vec!["playing_now", "single", "import"]);
+
+        let playing_now = &submissions[0]["payload"][0];
+        assert!(playing_now.get("listened_at").is_none());
+        let metadata = &playing_now["track_metadata"];
+        assert_eq!(metadata["artist_name"], "Artist");
+        assert_eq!(metadata["track_name"], "First");
+        assert_eq!(metadata["release_name"], "Album");
+        assert_eq!(metadata["additional_info"]["circle"], "Circle");
+        assert_eq!(metadata["additional_info"]["radio_name"], "Gensokyo Radio");
+        assert_eq!(metadata["additional_info"]["duration"], 200);
+
+        assert_eq!(submissions[1]["payload"][0]["listened_at"], 1_600_000_000);
+        let imported = &submissions[2]["payload"][1];
+        assert_eq!(imported["listened_at"], 1_600_000_200);
+        assert!(imported["track_metadata"].get("release_name").is_none());
+        assert!(imported["track_metadata"]["additional_info"].get("circle").is_none());
+        assert!(imported["track_metadata"]["additional_info"].get("duration").is_none());
+    }
+
+    #[tokio::test]
+    async fn invalid_token() {
+        let (client, submissions) = mock_client(test_support::listenbrainz::INVALID_TOKEN);
+        // Retried, the user may fix the token
+        assert!(matches!(
+            client.submit(&[track("First", 1_600_000_000)]).await,
+            Err(SubmitError::Retry(_))
+        ));
+        assert!(submissions.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejected() {
+        let (client, _) = mock_client("token");
+        assert!(matches!(
+            client.submit_listens("unknown", Vec::new()).await,
+            Err(SubmitError::Rejected(_))
+        ));
+    }
+
+    #[test]
+    fn no_token() {
+        let config = ListenBrainzConfig {
+            enabled: true,
+            token: String::new(),
+            server: LISTENBRAINZ_API.to_string(),
+        };
+        assert!(ListenBrainzClient::from_config(&config).is_none());
+    }
+}
@@ -0,0 +1,722 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+use async_trait::async_trait;
+use iced::{widget, Command, Element};
+use serde::Deserialize;
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::gensokyo_radio::SongInfo;
+use crate::{ui, PlayerMessage};
+
+const LYRICS_EXTENSIONS: &[&str] = &["lrc", "txt"];
+/// How deep the lyrics library is searched, e.g. `circle/album/title.lrc`.
+const LIBRARY_MAX_DEPTH: usize = 3;
+/// Lines shown before the current one in synced lyrics.
+const LINES_BEFORE: usize = 3;
+const LINES_AFTER: usize = 6;
+const ONLINE_TIMEOUT: Duration = Duration::from_secs(10);
+const LRCLIB_SEARCH: &str = "https://lrclib.net/api/search";
+/// Names of the online providers, for `lyrics.online_provider`
+pub const PROVIDERS: &[&str] = &["lrclib"];
+
+/// A line of lyrics.
+#[derive(Debug, Clone)]
+pub struct LyricLine {
+    /// When the line starts, in milliseconds since the beginning of the song. `None` for plain text lyrics.
+    pub time: Option<u64>,
+    pub text: String,
+    /// Translation or romanization given on a second line with the same timestamp
+    pub secondary: Option<String>,
+}
+
+/// One version of the lyrics of a song, e.g. the original ones or a translation.
+#[derive(Debug, Clone)]
+pub struct LyricsTrack {
+    pub name: String,
+    pub lines: Vec<LyricLine>,
+}
+
+impl LyricsTrack {
+    /// Parses LRC lyrics, or plain text lyrics if there are no timestamps.
+    pub fn parse(name: &str, contents: &str) -> LyricsTrack {
+        let mut offset: i64 = 0;
+        let mut timed = Vec::new();
+        let mut plain = Vec::new();
+
+        for line in contents.lines() {
+            let mut rest = line.trim();
+            let mut times = Vec::new();
+            let mut tagged = false;
+            while rest.starts_with('[') {
+                let end = match rest.find(']') {
+                    Some(end) => end,
+                    None => break,
+                };
+                let tag = &rest[1..end];
+                if let Some(time) = parse_timestamp(tag) {
+                    times.push(time);
+                } else if let Some(value) = tag.strip_prefix("offset:") {
+                    offset = value.trim().parse().unwrap_or_default();
+                } else if !times.is_empty() || !is_id_tag(tag) {
+                    break;
+                }
+                tagged = true;
+                rest = rest[end + 1..].trim_start();
+            }
+
+            if !times.is_empty() {
+                timed.extend(times.into_iter().map(|time| (time, rest.to_string())));
+            } else if !tagged {
+                plain.push(rest.to_string());
+            }
+        }
+
+        let lines = if timed.is_empty() {
+            plain
+                .into_iter()
+                .map(|text| LyricLine {
+                    time: None,
+                    text,
+                    secondary: None,
+                })
+                .collect()
+        } else {
+            // A positive offset makes the lyrics appear sooner
+            timed.sort_by_key(|(time, _)| *time);
+            let mut lines: Vec<LyricLine> = Vec::with_capacity(timed.len());
+            for (time, text) in timed {
+                let time = (time as i64).saturating_sub(offset).max(0) as u64;
+                match lines.last_mut() {
+                    Some(last) if last.time == Some(time) && last.secondary.is_none() => {
+                        last.secondary = Some(text).filter(|text| !text.is_empty());
+                    }
+                    _ => lines.push(LyricLine {
+                        time: Some(time),
+                        text,
+                        secondary: None,
+                    }),
+                }
+            }
+            lines
+        };
+
+        LyricsTrack {
+            name: name.to_string(),
+            lines,
+        }
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.lines.iter().any(|line| line.time.is_some())
+    }
+
+    /// Index of the line being sung `elapsed` milliseconds after the beginning of the song.
+    pub fn current_line(&self, elapsed: u64) -> Option<usize> {
+        self.lines
+            .iter()
+            .rposition(|line| line.time.map_or(false, |time| time <= elapsed))
+    }
+}
+
+/// `mm:ss`, `mm:ss.xx` or `mm:ss:xx` to milliseconds.
+fn parse_timestamp(tag: &str) -> Option<u64> {
+    let (minutes, seconds) = tag.split_at(tag.find(':')?);
+    let minutes: u64 = minutes.trim().parse().ok()?;
+    let seconds: f64 = seconds[1..].trim().replacen(':', ".", 1).parse().ok()?;
+    if !(0.0..60.0).contains(&seconds) {
+        return None;
+    }
+    minutes
+        .checked_mul(60_000)?
+        .checked_add((seconds * 1000.0).round() as u64)
+}
+
+/// ID tags like `[ar:Artist]` or `[ti:Title]` carry metadata we don't use.
+fn is_id_tag(tag: &str) -> bool {
+    match tag.find(':') {
+        Some(index) => index > 0 && tag[..index].chars().all(|c| c.is_ascii_alphabetic()),
+        None => false,
+    }
+}
+
+/// Lyrics of a song, with all the versions we found.
+#[derive(Debug, Clone)]
+pub struct Lyrics {
+    /// Where the lyrics come from, shown in the panel
+    pub source: String,
+    /// The original lyrics come first
+    pub tracks: Vec<LyricsTrack>,
+}
+
+/// Default location of the local lyrics library.
+pub fn default_directory() -> Option<PathBuf> {
+    let mut path = dirs::data_dir()?;
+    path.push("wan_player");
+    path.push("lyrics");
+    Some(path)
+}
+
+/// Searches `directory` for lyrics matching `song`.
+///
+/// Files are matched by name: `Artist - Title.lrc` is preferred over `Title.lrc`, which is preferred when it is in a
+/// directory named like the album. Other versions of the lyrics are named like the original with an additional
+/// extension, e.g. `Title.romaji.lrc` or `Title.en.txt`.
+pub fn find_local(directory: &Path, song: &SongInfo) -> Option<Lyrics> {
+    let title = normalize(&song.title);
+    if title.is_empty() {
+        return None;
+    }
+    let artist_title = normalize(&format!("{} - {}", song.artist, song.title));
+    let album = normalize(&song.album);
+
+    let mut files = Vec::new();
+    list_files(directory, LIBRARY_MAX_DEPTH, &mut files);
+
+    // Best match for every version of the lyrics, by name
+    let mut matches: BTreeMap<String, (u32, PathBuf)> = BTreeMap::new();
+    for path in files {
+        let stem = match path.file_stem() {
+            Some(stem) => stem.to_string_lossy().into_owned(),
+            None => continue,
+        };
+        let (name, variant) = match stem.rfind('.') {
+            Some(index) if normalize(&stem) != title && normalize(&stem) != artist_title => {
+                (stem[..index].to_string(), stem[index + 1..].to_string())
+            }
+            _ => (stem, String::new()),
+        };
+        let name = normalize(&name);
+        let in_album_dir = !album.is_empty()
+            && path
+                .parent()
+                .and_then(Path::file_name)
+                .map_or(false, |dir| normalize(&dir.to_string_lossy()) == album);
+        let score = if name == artist_title {
+            3
+        } else if name == title && in_album_dir {
+            2
+        } else if name == title {
+            1
+        } else {
+            continue;
+        };
+        if matches.get(&variant).map_or(true, |(best, _)| score > *best) {
+            matches.insert(variant, (score, path));
+        }
+    }
+
+    let best = matches.values().map(|(score, _)| *score).max()?;
+    let mut tracks = Vec::new();
+    for (variant, (score, path)) in matches {
+        if score < best {
+            continue;
+        }
+        let contents = match std::fs::read(&path) {
+            Ok(contents) => String::from_utf8_lossy(&contents).into_owned(),
+            Err(error) => {
+                eprintln!("Failed to read lyrics {}: {}", path.display(), error);
+                continue;
+            }
+        };
+        let track = LyricsTrack::parse(if variant.is_empty() { "original" } else { &variant }, &contents);
+        // The map is sorted, so the original lyrics come first
+        tracks.push(track);
+    }
+
+    if tracks.is_empty() {
+        None
+    } else {
+        Some(Lyrics {
+            source: directory.display().to_string(),
+            tracks,
+        })
+    }
+}
+
+fn list_files(directory: &Path, depth: usize, files: &mut Vec<PathBuf>) {
+    let entries = match std::fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(Result::ok) {
+        let path = entry.path();
+        if path.is_dir() {
+            if depth > 0 {
+                list_files(&path, depth - 1, files);
+            }
+        } else if path.extension().map_or(false, |extension| {
+            LYRICS_EXTENSIONS.iter().any(|e| extension.eq_ignore_ascii_case(e))
+        }) {
+            files.push(path);
+        }
+    }
+}
+
+/// Keeps only the letters and digits, so small differences in punctuation or case don't prevent a match.
+fn normalize(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Online source of lyrics, used when the song isn't in the local library.
+#[async_trait]
+pub trait LyricsProvider: Send + Sync {
+    async fn fetch(&self, song: &SongInfo, duration: u64) -> Option<Lyrics>;
+}
+
+/// Returns the provider configured by name, see `config::LyricsConfig`.
+pub fn provider(name: &str) -> Option<Arc<dyn LyricsProvider>> {
+    match name {
+        "lrclib" => Some(Arc::new(Lrclib::new())),
+        _ => {
+            eprintln!("Unknown lyrics provider {}", name);
+            None
+        }
+    }
+}
+
+/// [LRCLIB](https://lrclib.net/), a free database of synced lyrics.
+pub struct Lrclib {
+    client: hyper::client::Client<hyper_tls::HttpsConnector<hyper::client::HttpConnector>>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct LrclibAnswer {
+    duration: Option<f64>,
+    synced_lyrics: Option<String>,
+    plain_lyrics: Option<String>,
+}
+
+impl Lrclib {
+    pub fn new() -> Lrclib {
+        let https = hyper_tls::HttpsConnector::new();
+        let client = hyper::client::Client::builder().build::<_, hyper::Body>(https);
+        Lrclib { client }
+    }
+
+    async fn search(&self, song: &SongInfo) -> Option<Vec<LrclibAnswer>> {
+        let query = form_urlencoded::Serializer::new(String::new())
+            .append_pair("track_name", &song.title)
+            .append_pair("artist_name", &song.artist)
+            .finish();
+        let req = hyper::Request::get(format!("{}?{}", LRCLIB_SEARCH, query))
+            .header(
+                hyper::header::USER_AGENT,
+                concat!("Wan Player/", env!("CARGO_PKG_VERSION")),
+            )
+            .body(hyper::Body::empty())
+            .ok()?;
+        let res = self.client.request(req).await.ok()?;
+        if !res.status().is_success() {
+            return None;
+        }
+        let data = hyper::body::to_bytes(res.into_body()).await.ok()?;
+        serde_json::from_slice(&data[..]).ok()
+    }
+}
+
+#[async_trait]
+impl LyricsProvider for Lrclib {
+    async fn fetch(&self, song: &SongInfo, duration: u64) -> Option<Lyrics> {
+        let answers = tokio::time::timeout(ONLINE_TIMEOUT, self.search(song)).await.ok()??;
+        // Prefer synced lyrics of a recording with the same duration
+        let answer = answers
+            .iter()
+            .filter(|answer| answer.synced_lyrics.is_some() || answer.plain_lyrics.is_some())
+            .min_by_key(|answer| {
+                let duration_diff = answer.duration.map_or(u64::MAX, |d| (d - duration as f64).abs() as u64);
+                (answer.synced_lyrics.is_none(), duration_diff)
+            })?;
+        let contents = answer.synced_lyrics.as_ref().or(answer.plain_lyrics.as_ref())?;
+        Some(Lyrics {
+            source: "lrclib.net".to_string(),
+            tracks: vec![LyricsTrack::parse("original", contents)],
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum LyricsMessage {
+    /// Lyrics loaded for the song identified by the first field
+    Loaded(String, Option<Lyrics>),
+    SelectTrack(usize),
+}
+
+/// State of the lyrics panel.
+#[derive(Default)]
+pub struct LyricsPanel {
+    song: String,
+    lyrics: Option<Lyrics>,
+    loading: bool,
+    track: usize,
+
+    track_states: Vec<widget::button::State>,
+    scrollable_state: widget::scrollable::State,
+}
+
+impl LyricsPanel {
+    /// Looks for the lyrics of a new song in the local library, then online.
+    pub fn load(
+        &mut self,
+        song: SongInfo,
+        duration: u64,
+        directory: Option<PathBuf>,
+        provider: Option<Arc<dyn LyricsProvider>>,
+    ) -> Command<PlayerMessage> {
+        let key = format!("{} - {}", song.artist, song.title);
+        self.song = key.clone();
+        self.lyrics = None;
+        self.loading = true;
+        self.track = 0;
+
+        Command::perform(
+            async move {
+                let local = match directory {
+                    Some(directory) => {
+                        let local_song = song.clone();
+                        tokio::task::spawn_blocking(move || find_local(&directory, &local_song))
+                            .await
+                            .ok()
+                            .flatten()
+                    }
+                    None => None,
+                };
+                let lyrics = match (local, provider) {
+                    (Some(lyrics), _) => Some(lyrics),
+                    (None, Some(provider)) => provider.fetch(&song, duration).await,
+                    (None, None) => None,
+                };
+                (key, lyrics)
+            },
+            |(key, lyrics)| PlayerMessage::Lyrics(LyricsMessage::Loaded(key, lyrics)),
+        )
+    }
+
+    pub fn update(&mut self, message: LyricsMessage) -> Command<PlayerMessage> {
+        match message {
+            // The lyrics of a previous song
+            LyricsMessage::Loaded(song, _) if song != self.song => {}
+            LyricsMessage::Loaded(_, lyrics) => {
+                self.loading = false;
+                let tracks = lyrics.as_ref().map_or(0, |lyrics| lyrics.tracks.len());
+                self.track_states = (0..tracks).map(|_| widget::button::State::new()).collect();
+                self.lyrics = lyrics;
+            }
+            LyricsMessage::SelectTrack(track) => self.track = track,
+        }
+        Command::none()
+    }
+
+    /// Shows the lyrics, highlighting the current line of synced lyrics.
+    pub fn view(&mut self, played: u64) -> Element<PlayerMessage> {
+        let lyrics = match self.lyrics {
+            Some(ref lyrics) => lyrics,
+            None => {
+                let status = if self.loading {
+                    "Looking for lyrics..."
+                } else {
+                    "No lyrics found"
+                };
+                return widget::Text::new(status).size(16).color([1.0, 1.0, 1.0, 0.5]).into();
+            }
+        };
+        let current_track = self.track.min(lyrics.tracks.len() - 1);
+
+        let tracks_row = lyrics.tracks.iter().zip(self.track_states.iter_mut()).enumerate().fold(
+            widget::Row::new().spacing(8),
+            |row, (index, (track, state))| {
+                if index == current_track {
+                    row.push(widget::Text::new(&track.name).size(16))
+                } else {
+                    row.push(
+                        widget::Button::new(state, widget::Text::new(&track.name).size(16))
+                            .style(ui::TextButtonStyle)
+                            .padding(0)
+                            .on_press(PlayerMessage::Lyrics(LyricsMessage::SelectTrack(index))),
+                    )
+                }
+            },
+        );
+        let header = widget::Row::new()
+            .push(tracks_row.width(iced::Length::Fill))
+            .push(widget::Text::new(&lyrics.source).size(14).color([1.0, 1.0, 1.0, 0.5]));
+
+        let track = &lyrics.tracks[current_track];
+        let lines: Element<_> = if track.is_synced() {
+            // Only show the lines around the current one, so it is always visible
+            let current = track.current_line(played * 1000);
+            let first = current.map_or(0, |current| current.saturating_sub(LINES_BEFORE));
+            let last = current.map_or(0, |current| current) + LINES_AFTER;
+            track
+                .lines
+                .iter()
+                .enumerate()
+                .skip(first)
+                .take(last - first + 1)
+                .fold(widget::Column::new().spacing(4), |column, (index, line)| {
+                    let alpha = if Some(index) == current { 1.0 } else { 0.5 };
+                    let size = if Some(index) == current { 24 } else { 20 };
+                    column.push(lyric_line_widget(line, size, alpha))
+                })
+                .into()
+        } else {
+            track
+                .lines
+                .iter()
+                .fold(
+                    widget::Scrollable::new(&mut self.scrollable_state)
+                        .spacing(4)
+                        .height(iced::Length::Fill),
+                    |scrollable, line| scrollable.push(lyric_line_widget(line, 20, 1.0)),
+                )
+                .into()
+        };
+
+        widget::Column::new().push(header).push(lines).spacing(8).into()
+    }
+}
+
+fn lyric_line_widget<'a>(line: &LyricLine, size: u16, alpha: f32) -> widget::Column<'a, PlayerMessage> {
+    let column = widget::Column::new().push(widget::Text::new(&line.text).size(size).color([1.0, 1.0, 1.0, alpha]));
+    match line.secondary {
+        Some(ref secondary) => {
+            column.push(
+                widget::Text::new(secondary)
+                    .size(size - 4)
+                    .color([1.0, 1.0, 1.0, alpha * 0.7]),
+            )
+        }
+        None => column,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(parse_timestamp("01:02.50"), Some(62_500));
+        assert_eq!(parse_timestamp("01:02:50"), Some(62_500));
+        assert_eq!(parse_timestamp("00:05.123"), Some(5_123));
+        assert_eq!(parse_timestamp("1:2"), Some(62_000));
+        assert_eq!(parse_timestamp(" 1 : 05 "), Some(65_000));
+        assert_eq!(parse_timestamp("120:00.00"), Some(7_200_000));
+    }
+
+    #[test]
+    fn malformed_timestamps() {
+        for tag in [
+            "",
+            "00",
+            ":05",
+            "00:",
+            "ar:Artist",
+            "00:60.00",
+            "00:-1",
+            "-1:00",
+            "00:1x.00",
+            "00:05.5.5",
+            "00:1e2",
+            "00:NaN",
+            "00:inf",
+            "99999999999999999999:00",
+            "18446744073709551615:00",
+        ]
+        .iter()
+        {
+            assert_eq!(parse_timestamp(tag), None, "{:?}", tag);
+        }
+    }
+
+    #[test]
+    fn synced_lyrics() {
+        let track = LyricsTrack::parse(
+            "Original",
+            "[ar:Artist]\n[ti:Title]\n[00:10.00]Second\n[00:05.00]First\n[00:20.00][00:30.00]Chorus\n[00:40.00]\n",
+        );
+        assert!(track.is_synced());
+        let lines: Vec<(Option<u64>, &str)> = track.lines.iter().map(|line| (line.time, line.text.as_str())).collect();
+        assert_eq!(
+            lines,
+            vec![
+                (Some(5_000), "First"),
+                (Some(10_000), "Second"),
+                (Some(20_000), "Chorus"),
+                (Some(30_000), "Chorus"),
+                (Some(40_000), ""),
+            ]
+        );
+        assert_eq!(track.current_line(0), None);
+        assert_eq!(track.current_line(5_000), Some(0));
+        assert_eq!(track.current_line(25_000), Some(2));
+        assert_eq!(track.current_line(u64::MAX), Some(4));
+    }
+
+    #[test]
+    fn secondary_lines() {
+        let track = LyricsTrack::parse(
+            "Original",
+            "[00:01.00]\u{6771}\u{65b9}\n[00:01.00]Touhou\n[00:01.00]Third\n[00:02.00]Next\n[00:02.00]\n",
+        );
+        assert_eq!(track.lines.len(), 3);
+        assert_eq!(track.lines[0].secondary.as_deref(), Some("Touhou"));
+        // Only one secondary line per timestamp, the others are kept as lines
+        assert_eq!(track.lines[1].text, "Third");
+        assert_eq!(track.lines[1].secondary, None);
+        assert_eq!(track.lines[2].secondary, None);
+    }
+
+    #[test]
+    fn offset() {
+        let track = LyricsTrack::parse("Original", "[offset:+500]\n[00:00.20]Early\n[00:01.00]Late\n");
+        assert_eq!(track.lines[0].time, Some(0));
+        assert_eq!(track.lines[1].time, Some(500));
+
+        let track = LyricsTrack::parse("Original", "[offset:-9223372036854775808]\n[00:01.00]Line\n");
+        assert_eq!(track.lines[0].time, Some(i64::MAX as u64));
+        let track = LyricsTrack::parse("Original", "[offset:nonsense]\n[00:01.00]Line\n");
+        assert_eq!(track.lines[0].time, Some(1_000));
+    }
+
+    #[test]
+    fn malformed_lines() {
+        // Lines with a broken timestamp are plain text, dropped when the lyrics are synced
+        let track = LyricsTrack::parse("Original", "[00:1x.00]Broken\n[00:01.00]Line\n[00:02.00 Unclosed\n");
+        assert_eq!(track.lines.len(), 1);
+        assert_eq!(track.lines[0].text, "Line");
+
+        let track = LyricsTrack::parse("Original", "[ar:Artist]\nFirst line\n[Chorus]\n\n  Indented  \n");
+        assert!(!track.is_synced());
+        let lines: Vec<&str> = track.lines.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(lines, vec!["First line", "[Chorus]", "", "Indented"]);
+        assert_eq!(track.current_line(1_000), None);
+    }
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str, files: &[&str]) -> TempDir {
+            let dir = std::env::temp_dir().join(format!("wan_player-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            for file in files {
+                let path = dir.join(file);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(&path, file).unwrap();
+            }
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn song(artist: &str, title: &str, album: &str) -> SongInfo {
+        SongInfo {
+            title: title.to_string(),
+            artist: artist.to_string(),
+            album: album.to_string(),
+            ..SongInfo::default()
+        }
+    }
+
+    /// Names of the tracks found for `song`, with the file they were read from.
+    fn found(dir: &TempDir, song: &SongInfo) -> Vec<(String, String)> {
+        find_local(&dir.0, song).map_or_else(Vec::new, |lyrics| {
+            lyrics
+                .tracks
+                .into_iter()
+                .map(|track| (track.name, track.lines[0].text.clone()))
+                .collect()
+        })
+    }
+
+    #[test]
+    fn local_match_scores() {
+        let dir = TempDir::new(
+            "lyrics-scores",
+            &[
+                "Title.lrc",
+                "Some Album/Title.lrc",
+                "Circle/Artist - Title.lrc",
+                "Other Album/Other Title.lrc",
+            ],
+        );
+        let original = |file: &str| vec![("original".to_string(), file.to_string())];
+        assert_eq!(
+            found(&dir, &song("Artist", "Title", "Some Album")),
+            original("Circle/Artist - Title.lrc")
+        );
+        // Punctuation and case don't matter
+        assert_eq!(
+            found(&dir, &song("ARTIST", "title!", "Some Album")),
+            original("Circle/Artist - Title.lrc")
+        );
+        assert_eq!(
+            found(&dir, &song("Someone", "Title", "some album")),
+            original("Some Album/Title.lrc")
+        );
+        assert_eq!(
+            found(&dir, &song("Someone", "Other Title", "")),
+            original("Other Album/Other Title.lrc")
+        );
+        assert!(found(&dir, &song("Artist", "Unknown", "Some Album")).is_empty());
+        assert!(found(&dir, &song("Artist", "", "Some Album")).is_empty());
+    }
+
+    #[test]
+    fn local_variants() {
+        let dir = TempDir::new(
+            "lyrics-variants",
+            &[
+                "Title.lrc",
+                "Title.romaji.lrc",
+                "Title.en.TXT",
+                "Song.lrc",
+                "Album/Song.en.lrc",
+                "Ver. 2.lrc",
+                "Other.romaji.lrc",
+            ],
+        );
+        assert_eq!(
+            found(&dir, &song("Artist", "Title", "")),
+            vec![
+                ("original".to_string(), "Title.lrc".to_string()),
+                ("en".to_string(), "Title.en.TXT".to_string()),
+                ("romaji".to_string(), "Title.romaji.lrc".to_string()),
+            ]
+        );
+        // Only the versions of the best match are kept
+        assert_eq!(
+            found(&dir, &song("Artist", "Song", "Album")),
+            vec![("en".to_string(), "Album/Song.en.lrc".to_string())]
+        );
+        // A dot in the title isn't a version
+        assert_eq!(
+            found(&dir, &song("Artist", "Ver. 2", "")),
+            vec![("original".to_string(), "Ver. 2.lrc".to_string())]
+        );
+    }
+}
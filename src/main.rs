@@ -16,23 +16,75 @@
 #![cfg_attr(debug_assertions, windows_subsystem = "console")]
 
 use std::sync::Arc;
+use std::time::Duration;
 
+mod account;
+mod art_cache;
+mod backpressure;
+mod clock;
+mod config;
+mod database;
+#[cfg(feature = "discord")]
 mod discord;
+#[cfg(feature = "discord")]
+mod discord_ipc;
+mod enrichment;
 mod executor;
+mod favorites;
 mod gensokyo_radio;
+mod history;
+mod icecast;
+mod lastfm;
+mod listenbrainz;
+mod lyrics;
+#[cfg(target_os = "linux")]
+mod mpris;
+#[cfg(target_os = "linux")]
+mod notifications;
+mod now_playing;
 mod pipeline;
+mod playlist;
+mod push;
+mod scrobbler;
+mod sink;
+mod station;
+#[cfg(test)]
+mod test_support;
 mod ui;
+mod webhooks;
 
-use discord::{discord_main_loop, DiscordControl};
+use sink::NowPlayingEvent;
+use station::Station;
 
 #[derive(Debug, Clone)]
 pub enum PlayerMessage {
     Play,
     Pause,
     VolumeChanged(u8),
-    AlbumArt(Option<Vec<u8>>),
-    SongInfo(gensokyo_radio::GRApiAnswer),
-    IncrementElapsed,
+    AlbumArt(String, Option<Vec<u8>>),
+    CircleArt(String, Option<Vec<u8>>),
+    OriginalThemes(String, Vec<enrichment::OriginalTheme>),
+    SongInfo(Result<gensokyo_radio::GRApiAnswer, gensokyo_radio::ApiError>),
+    FetchSongInfo(u64),
+    SongPushConnected(bool),
+    Tick,
+    /// The configuration file edited by the user, read in the background
+    ConfigLoaded(Box<(config::Config, config::ConfigFile)>),
+    /// The configuration file after the player's changes were written in the background
+    ConfigSaved(Box<config::ConfigFile>),
+    OpenPlaylistDialog,
+    SourceSelected(Option<String>),
+    PlaylistLoaded(Option<playlist::Playlist>),
+    ShowPanel(Panel),
+    History(history::HistoryMessage),
+    ToggleFavorite,
+    Favorites(favorites::FavoritesMessage),
+    Account(account::AccountMessage),
+    Lyrics(lyrics::LyricsMessage),
+    WindowResized(u32, u32),
+    CursorOverWindow(bool),
+    OpenUrl(String),
+    PresenceToggled(bool),
 }
 
 #[derive(PartialEq, Eq)]
@@ -41,63 +93,206 @@ enum PlayerStatus {
     Paused,
 }
 
-use iced::{widget, Application, Command, Element, Settings};
+/// Panel shown next to the album art.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Panel {
+    Info,
+    Lyrics,
+    History,
+    Favorites,
+    Account,
+}
+
+use iced::{widget, Application, Command, Element, Settings, Subscription};
+
+/// What `main` gives the player: the configuration is loaded first because it has the window size.
+struct Flags {
+    playlist: Option<playlist::Playlist>,
+    config: config::Config,
+    config_file: config::ConfigFile,
+}
 
 struct Player {
     player_status: PlayerStatus,
     player_tx: tokio::sync::mpsc::UnboundedSender<pipeline::PlayerControl>,
-    discord_tx: std::sync::mpsc::Sender<DiscordControl>,
-    api_client: Arc<gensokyo_radio::ApiClient>,
+    /// Title the pipeline reads from the ICY metadata of the stream, for the Icecast stations
+    stream_title: icecast::StreamTitle,
+    sinks: sink::Sinks,
+    station: Arc<dyn Station>,
+    gr_client: Arc<gensokyo_radio::ApiClient>,
     volume: u8,
     album_image: Option<Vec<u8>>,
+    circle_image: Option<Vec<u8>>,
     current_song_info: Option<gensokyo_radio::GRApiAnswer>,
+    /// Incremented every time a song info fetch is scheduled, so only the latest one is performed
+    song_info_generation: u64,
+    /// Number of times in a row the station returned a song that should already be over
+    song_info_retries: u32,
+    /// Last error returned by the station, cleared once it answers again
+    song_info_error: Option<gensokyo_radio::ApiError>,
+    /// Whether the station's websocket is up, in which case the song info isn't polled
+    song_push_connected: bool,
+    history: Option<Arc<history::History>>,
+    favorites: Option<Arc<favorites::Favorites>>,
+    panel: Panel,
+    config: config::Config,
+    config_file: config::ConfigFile,
+    /// The volume, window size or presence toggle changed and the configuration has to be saved on the next tick
+    config_changed: bool,
+    /// The edited configuration file is being read
+    config_loading: bool,
+    /// The player's changes are being written to the configuration file
+    config_saving: bool,
+    art_cache: Option<art_cache::ArtCache>,
+    window_size: (u32, u32),
 
     play_pause_state: widget::button::State,
     volume_slider_state: widget::slider::State,
+    open_playlist_state: widget::button::State,
+    favorite_state: widget::button::State,
+    album_link_state: widget::button::State,
+    circle_link_state: widget::button::State,
+    info_tab_state: widget::button::State,
+    history_tab_state: widget::button::State,
+    favorites_tab_state: widget::button::State,
+    account_tab_state: widget::button::State,
+    lyrics_tab_state: widget::button::State,
+    rating_states: [widget::button::State; 5],
+    history_panel: history::HistoryPanel,
+    favorites_panel: favorites::FavoritesPanel,
+    account_panel: account::AccountPanel,
+    lyrics_panel: lyrics::LyricsPanel,
+    lyrics_provider: Option<Arc<dyn lyrics::LyricsProvider>>,
+    enricher: Option<Arc<enrichment::Enricher>>,
+    /// Used by the account panel to connect to Last.fm, the scrobbler has its own client
+    lastfm: Option<Arc<lastfm::LastfmClient>>,
+    /// Last.fm session of the user, shared by the clients
+    lastfm_session: lastfm::SessionStore,
+    original_themes: Vec<enrichment::OriginalTheme>,
 }
 
 impl Application for Player {
     type Executor = executor::TokioExecutor;
     type Message = PlayerMessage;
-    type Flags = ();
+    type Flags = Flags;
 
-    fn new(_flags: Self::Flags) -> (Self, Command<Self::Message>) {
-        let player_tx = pipeline::setup_pipeline();
+    fn new(flags: Self::Flags) -> (Self, Command<Self::Message>) {
+        let Flags {
+            playlist,
+            config,
+            mut config_file,
+        } = flags;
+        let lyrics_provider = config.lyrics.online_provider.as_deref().and_then(lyrics::provider);
+        let enricher = enrichment::Enricher::from_config(&config.enrichment, &mut config_file.errors).map(Arc::new);
+        let lastfm_session = lastfm::SessionStore::default();
+        let lastfm = lastfm::LastfmClient::from_config(&config.lastfm, lastfm_session.clone()).map(Arc::new);
+        let account_panel = match lastfm {
+            Some(ref lastfm) => account::AccountPanel::with_lastfm_user(lastfm.session().map(|session| session.name)),
+            None => account::AccountPanel::default(),
+        };
+        let art_cache = art_cache::ArtCache::open(config.album_art.cache_size_mb.saturating_mul(1024 * 1024));
+        let gr_client = gensokyo_radio::ApiClient::new()
+            .with_art_cache(art_cache.clone())
+            .with_retry_policy(config.api.retry_policy());
+        // The account endpoints can be redirected to a local mock of the API
+        let gr_client = match std::env::var("WAN_PLAYER_GR_ACCOUNT_API") {
+            Ok(account_api) => gr_client.with_account_api(account_api),
+            Err(_) => gr_client,
+        };
+        // Same for the song push websocket, see examples/gr_push_server.rs
+        let gr_client = Arc::new(match std::env::var("WAN_PLAYER_GR_PUSH") {
+            Ok(push_url) => gr_client.with_push_url(Some(push_url)),
+            Err(_) => gr_client,
+        });
+        let stream_title = icecast::StreamTitle::default();
+        let station: Arc<dyn Station> = match playlist.map(|p| icecast::IcecastStation::new(p, stream_title.clone())) {
+            Some(Ok(station)) => Arc::new(station),
+            Some(Err(error)) => {
+                eprintln!("Failed to open playlist: {}", error);
+                gr_client.clone()
+            }
+            None => gr_client.clone(),
+        };
+        let player_tx = pipeline::setup_pipeline(station.stream_urls(), stream_title.clone());
 
         let player_status = PlayerStatus::Paused;
-        let api_client = Arc::new(gensokyo_radio::ApiClient::new());
-        let fut_api_client = api_client.clone();
+        let fut_station = station.clone();
 
+        let volume = config.player.volume();
         player_tx
-            .send(pipeline::PlayerControl::Volume(DEFAULT_VOLUME))
+            .send(pipeline::PlayerControl::Volume(volume))
             .expect("Failed to set initial volume");
-        let (discord_tx, discord_rx) = std::sync::mpsc::channel();
+        let mut sinks = sink::Sinks::from_config(&config, art_cache.clone(), lastfm_session.clone());
+        sinks.send(NowPlayingEvent::VolumeChanged(volume));
 
-        discord_main_loop(discord_rx);
+        let favorites = favorites::Favorites::open().map(Arc::new);
+        let favorites_panel = favorites::FavoritesPanel::default();
 
-        let commands = vec![
-            Command::perform(
-                async move { fut_api_client.get_song_info().await },
-                PlayerMessage::SongInfo,
-            ),
-            Command::perform(
-                async move { tokio::time::sleep(std::time::Duration::from_secs(1)).await },
-                |_| PlayerMessage::IncrementElapsed,
-            ),
+        let mut commands = vec![
+            Command::perform(async move { fut_station.now_playing().await }, PlayerMessage::SongInfo),
+            Command::perform(async move { tokio::time::sleep(Duration::from_secs(1)).await }, |_| {
+                PlayerMessage::Tick
+            }),
         ];
+        // The favorites are always loaded so we know whether the current song is one of them
+        if let Some(ref favorites) = favorites {
+            commands.push(favorites_panel.refresh(favorites.clone()));
+        }
+        if let Some(credentials) = account::Credentials::load() {
+            commands.push(account::login(gr_client.clone(), credentials, false));
+        }
+        if config.player.play_on_start {
+            commands.push(Command::perform(async {}, |_| PlayerMessage::Play));
+        }
 
         (
             Player {
                 player_status,
                 player_tx,
-                discord_tx,
-                api_client,
+                stream_title,
+                sinks,
+                station,
+                gr_client,
                 album_image: None,
-                volume: DEFAULT_VOLUME,
+                circle_image: None,
+                volume,
                 current_song_info: None,
+                song_info_generation: 0,
+                song_info_retries: 0,
+                song_info_error: None,
+                song_push_connected: false,
+                history: history::History::open().map(Arc::new),
+                favorites,
+                panel: Panel::Info,
+                window_size: config.player.window_size(),
+                config,
+                config_file,
+                config_changed: false,
+                config_loading: false,
+                config_saving: false,
+                art_cache,
 
                 play_pause_state: widget::button::State::new(),
                 volume_slider_state: widget::slider::State::new(),
+                open_playlist_state: widget::button::State::new(),
+                favorite_state: widget::button::State::new(),
+                album_link_state: widget::button::State::new(),
+                circle_link_state: widget::button::State::new(),
+                info_tab_state: widget::button::State::new(),
+                history_tab_state: widget::button::State::new(),
+                favorites_tab_state: widget::button::State::new(),
+                account_tab_state: widget::button::State::new(),
+                lyrics_tab_state: widget::button::State::new(),
+                rating_states: Default::default(),
+                history_panel: history::HistoryPanel::default(),
+                favorites_panel,
+                account_panel,
+                lyrics_panel: lyrics::LyricsPanel::default(),
+                lyrics_provider,
+                enricher,
+                lastfm,
+                lastfm_session,
+                original_themes: Vec::new(),
             },
             Command::batch(commands),
         )
@@ -122,6 +317,7 @@ impl Application for Player {
                     .send(PlayerControl::Play)
                     .expect("Failed to send play command to Player");
                 self.player_status = PlayerStatus::Playing;
+                self.sinks.send(NowPlayingEvent::Playing);
                 Command::none()
             }
             PlayerMessage::Pause => {
@@ -129,6 +325,7 @@ impl Application for Player {
                     .send(PlayerControl::Pause)
                     .expect("Failed to send pause command to Player");
                 self.player_status = PlayerStatus::Paused;
+                self.sinks.send(NowPlayingEvent::Paused);
                 Command::none()
             }
             PlayerMessage::VolumeChanged(volume) => {
@@ -136,49 +333,338 @@ impl Application for Player {
                     .send(PlayerControl::Volume(volume))
                     .expect("Failed to send volume command to Player");
                 self.volume = volume;
+                self.sinks.send(NowPlayingEvent::VolumeChanged(volume));
+                self.config.player.volume = volume.into();
+                self.config_changed = true;
                 Command::none()
             }
-            PlayerMessage::AlbumArt(opt_art) => {
-                self.album_image = opt_art;
+            // Ignore the answers coming after the song changed
+            PlayerMessage::OriginalThemes(key, originals) => {
+                if self.is_current_song(&key) {
+                    self.original_themes = originals;
+                }
                 Command::none()
             }
-            PlayerMessage::SongInfo(song_info) => {
-                self.current_song_info = Some(song_info.clone());
-                self.discord_tx
-                    .send(DiscordControl::SongInfo(song_info.clone()))
-                    .expect("Failed to send song info to discord");
-                let fut_api_client = self.api_client.clone();
-                Command::perform(
-                    async move { fut_api_client.get_album_image(&song_info).await },
-                    PlayerMessage::AlbumArt,
-                )
+            PlayerMessage::CircleArt(key, opt_art) => {
+                if self.is_current_song(&key) {
+                    self.circle_image = opt_art;
+                }
+                Command::none()
+            }
+            PlayerMessage::AlbumArt(key, opt_art) => {
+                if self.is_current_song(&key) {
+                    self.album_image = opt_art;
+                }
+                Command::none()
+            }
+            PlayerMessage::SongInfo(Err(error)) => {
+                eprintln!("Failed to fetch song info: {}", error);
+                // The last known song stays displayed until the station answers again
+                self.song_info_error = Some(error);
+                self.song_info_retries += 1;
+                self.schedule_song_info()
             }
-            PlayerMessage::IncrementElapsed => {
-                let mut commands = Vec::with_capacity(2);
-                if let Some(ref mut info) = self.current_song_info {
-                    info.songtimes.played += 1;
-                    if info.songtimes.played == info.songtimes.duration {
-                        let fut_api_client = self.api_client.clone();
+            PlayerMessage::SongInfo(Ok(mut song_info)) => {
+                self.song_info_error = None;
+                let now = self.station.clock().now();
+                let is_new_song = match self.current_song_info {
+                    Some(ref current) => {
+                        current.songtimes.songstart != song_info.songtimes.songstart
+                            || current.songinfo.title != song_info.songinfo.title
+                    }
+                    None => true,
+                };
+                // The API can keep returning the previous song for a little while after it ended
+                let song_over = song_info.songtimes.duration != 0 && now >= song_info.songtimes.songend;
+                self.song_info_retries = if !is_new_song && song_over {
+                    self.song_info_retries + 1
+                } else {
+                    0
+                };
+                update_elapsed(&mut song_info, now);
+                self.current_song_info = Some(song_info.clone());
+
+                let mut commands = vec![self.schedule_song_info()];
+                if is_new_song {
+                    self.sinks.send(NowPlayingEvent::SongChanged {
+                        song: song_info.clone(),
+                        station: self.station.name().to_string(),
+                        listen_url: self.station.listen_url(),
+                    });
+                    if let Some(ref history) = self.history {
+                        commands.push(self.history_panel.record(history.clone(), song_info.clone()));
+                    }
+                    // The previous song's artwork must not be shown, or bookmarked, with this one
+                    self.album_image = None;
+                    self.circle_image = None;
+                    // Only Gensokyo Radio songs have a circle artwork
+                    if !song_info.misc.circleart.is_empty() {
+                        let gr_client = self.gr_client.clone();
+                        let fut_song_info = song_info.clone();
+                        let key = song_key(&song_info.songinfo);
                         commands.push(Command::perform(
-                            async move { fut_api_client.get_song_info().await },
-                            PlayerMessage::SongInfo,
-                        ))
+                            async move { gr_client.get_circle_image(&fut_song_info).await },
+                            move |opt_art| PlayerMessage::CircleArt(key.clone(), opt_art),
+                        ));
+                    }
+                    let lyrics_directory = match self.config.lyrics.directory {
+                        Some(ref directory) => Some(config::resolve_path(directory)),
+                        None => lyrics::default_directory(),
+                    };
+                    commands.push(self.lyrics_panel.load(
+                        song_info.songinfo.clone(),
+                        song_info.songtimes.duration,
+                        lyrics_directory,
+                        self.lyrics_provider.clone(),
+                    ));
+                    self.original_themes.clear();
+                    if let Some(ref enricher) = self.enricher {
+                        let enricher = enricher.clone();
+                        let song = song_info.songinfo.clone();
+                        let key = song_key(&song);
+                        commands.push(Command::perform(
+                            async move { enricher.original_themes(song).await },
+                            move |originals| PlayerMessage::OriginalThemes(key.clone(), originals),
+                        ));
                     }
+                    commands.push(self.fetch_artwork(song_info));
+                }
+                Command::batch(commands)
+            }
+            PlayerMessage::FetchSongInfo(generation) if generation == self.song_info_generation => {
+                let fut_station = self.station.clone();
+                Command::perform(async move { fut_station.now_playing().await }, PlayerMessage::SongInfo)
+            }
+            PlayerMessage::FetchSongInfo(_) => Command::none(),
+            PlayerMessage::SongPushConnected(connected) if connected == self.song_push_connected => Command::none(),
+            PlayerMessage::SongPushConnected(connected) => {
+                self.song_push_connected = connected;
+                // Polling takes over while the websocket is down
+                self.schedule_song_info()
+            }
+            PlayerMessage::Tick => {
+                let now = self.station.clock().now();
+                if let Some(ref mut song_info) = self.current_song_info {
+                    update_elapsed(song_info, now);
+                }
+                let mut commands = vec![Command::perform(
+                    async move { tokio::time::sleep(Duration::from_secs(1)).await },
+                    |_| PlayerMessage::Tick,
+                )];
+                // The user's edits win over the changes made in the player since the last save
+                if self.config_loading || self.config_saving {
+                } else if self.config_file.has_changed() {
+                    self.config_loading = true;
+                    commands.push(Command::perform(
+                        async {
+                            tokio::task::spawn_blocking(config::Config::load)
+                                .await
+                                .expect("Failed to join config task")
+                        },
+                        |loaded| PlayerMessage::ConfigLoaded(Box::new(loaded)),
+                    ));
+                } else if self.config_changed {
+                    self.config_saving = true;
+                    self.config_changed = false;
+                    let mut config_file = self.config_file.clone();
+                    let config = self.config.clone();
+                    commands.push(Command::perform(
+                        async move {
+                            tokio::task::spawn_blocking(move || {
+                                config_file.save(&config);
+                                config_file
+                            })
+                            .await
+                            .expect("Failed to join config task")
+                        },
+                        |config_file| PlayerMessage::ConfigSaved(Box::new(config_file)),
+                    ));
                 }
-                commands.push(Command::perform(
-                    async move { tokio::time::sleep(std::time::Duration::from_secs(1)).await },
-                    |_| PlayerMessage::IncrementElapsed,
-                ));
                 Command::batch(commands)
             }
+            PlayerMessage::ConfigLoaded(loaded) => {
+                let (config, config_file) = *loaded;
+                self.config_loading = false;
+                self.reload_config(config, config_file);
+                Command::none()
+            }
+            PlayerMessage::ConfigSaved(config_file) => {
+                self.config_file = *config_file;
+                self.config_saving = false;
+                Command::none()
+            }
+            PlayerMessage::OpenPlaylistDialog => Command::perform(
+                async {
+                    rfd::AsyncFileDialog::new()
+                        .add_filter("Playlists", playlist::PLAYLIST_EXTENSIONS)
+                        .pick_file()
+                        .await
+                        .map(|file| file.path().to_string_lossy().into_owned())
+                },
+                PlayerMessage::SourceSelected,
+            ),
+            PlayerMessage::SourceSelected(None) => Command::none(),
+            PlayerMessage::SourceSelected(Some(source)) => Command::perform(
+                async move { playlist::load(&source).await },
+                PlayerMessage::PlaylistLoaded,
+            ),
+            PlayerMessage::PlaylistLoaded(None) => Command::none(),
+            PlayerMessage::PlaylistLoaded(Some(playlist)) => {
+                self.station = match icecast::IcecastStation::new(playlist, self.stream_title.clone()) {
+                    Ok(station) => Arc::new(station),
+                    Err(error) => {
+                        eprintln!("Failed to open playlist: {}", error);
+                        return Command::none();
+                    }
+                };
+                self.current_song_info = None;
+                self.album_image = None;
+                self.circle_image = None;
+                self.original_themes.clear();
+                // Forget the fetch scheduled for the previous station
+                self.song_info_generation += 1;
+                self.song_info_retries = 0;
+                self.song_info_error = None;
+                self.song_push_connected = false;
+
+                for control in vec![
+                    PlayerControl::Pause,
+                    PlayerControl::Source(self.station.stream_urls()),
+                    PlayerControl::Play,
+                ] {
+                    self.player_tx
+                        .send(control)
+                        .expect("Failed to send new source to Player");
+                }
+                self.player_status = PlayerStatus::Playing;
+                self.sinks.send(NowPlayingEvent::Playing);
+
+                let fut_station = self.station.clone();
+                Command::perform(async move { fut_station.now_playing().await }, PlayerMessage::SongInfo)
+            }
+            PlayerMessage::ShowPanel(panel) => {
+                self.panel = panel;
+                match (panel, &self.history) {
+                    (Panel::History, Some(history)) => self.history_panel.refresh(history.clone()),
+                    _ => Command::none(),
+                }
+            }
+            PlayerMessage::History(message) => match self.history {
+                Some(ref history) => self.history_panel.update(message, history.clone()),
+                None => Command::none(),
+            },
+            PlayerMessage::ToggleFavorite => match (&self.favorites, &self.current_song_info) {
+                (Some(favorites), Some(song_info)) => {
+                    let mut commands = Vec::with_capacity(2);
+                    // Favorites are also kept on the Gensokyo Radio account when the user is logged in
+                    if song_info.songdata.songid != 0 && self.gr_client.logged_in_user().is_some() {
+                        commands.push(account::sync_favorite(
+                            self.gr_client.clone(),
+                            song_info.songdata.songid,
+                            !self.favorites_panel.contains(song_info),
+                        ));
+                    }
+                    commands.push(self.favorites_panel.toggle(
+                        favorites.clone(),
+                        song_info.clone(),
+                        self.album_image.clone(),
+                    ));
+                    Command::batch(commands)
+                }
+                _ => Command::none(),
+            },
+            PlayerMessage::Favorites(message) => match self.favorites {
+                Some(ref favorites) => self
+                    .favorites_panel
+                    .update(message, favorites.clone(), self.gr_client.clone()),
+                None => Command::none(),
+            },
+            PlayerMessage::Account(account::AccountMessage::PreviewWebhooks) => {
+                let song = self
+                    .current_song_info
+                    .clone()
+                    .map(|song_info| (song_info, self.station.name().to_string()));
+                self.account_panel.preview_webhooks(self.config.webhooks.clone(), song);
+                Command::none()
+            }
+            PlayerMessage::Account(message) => {
+                self.account_panel
+                    .update(message, self.gr_client.clone(), self.lastfm.clone())
+            }
+            PlayerMessage::PresenceToggled(enabled) => {
+                self.config.discord.enabled = enabled;
+                self.sinks.set_enabled("discord", enabled);
+                self.config_changed = true;
+                Command::none()
+            }
+            PlayerMessage::Lyrics(message) => self.lyrics_panel.update(message),
+            PlayerMessage::OpenUrl(url) => {
+                tokio::task::spawn_blocking(move || {
+                    if let Err(error) = webbrowser::open(&url) {
+                        eprintln!("Failed to open {} in the browser: {}", url, error);
+                    }
+                });
+                Command::none()
+            }
+            // Minimized windows are resized to nothing on some platforms, the user can't see the player anymore
+            PlayerMessage::WindowResized(width, height) if width == 0 || height == 0 => {
+                self.sinks.set_cursor_over_window(false);
+                Command::none()
+            }
+            PlayerMessage::WindowResized(width, height) => {
+                let was_large = self.large_artwork();
+                self.window_size = (width, height);
+                if self.config.player.window_size() != (width, height) {
+                    self.config.player.window_width = width;
+                    self.config.player.window_height = height;
+                    self.config_changed = true;
+                }
+                // Fetch the higher resolution artwork once there is room to show it
+                match self.current_song_info {
+                    Some(ref song_info) if !was_large && self.large_artwork() => self.fetch_artwork(song_info.clone()),
+                    _ => Command::none(),
+                }
+            }
+            PlayerMessage::CursorOverWindow(over_window) => {
+                self.sinks.set_cursor_over_window(over_window);
+                Command::none()
+            }
+        }
+    }
+
+    fn subscription(&self) -> Subscription<Self::Message> {
+        // Playlists and stream URLs can be dropped on the window
+        let events = iced_native::subscription::events_with(|event, _| match event {
+            iced_native::Event::Window(iced_native::window::Event::FileDropped(path)) => {
+                Some(PlayerMessage::SourceSelected(Some(path.to_string_lossy().into_owned())))
+            }
+            iced_native::Event::Window(iced_native::window::Event::Resized { width, height }) => {
+                Some(PlayerMessage::WindowResized(width, height))
+            }
+            // The window focus isn't reported, the cursor being over the window is the closest we have. The cursor
+            // starts outside, so that the notifications are shown until the user moves it over the player.
+            iced_native::Event::Mouse(iced_native::mouse::Event::CursorEntered) => {
+                Some(PlayerMessage::CursorOverWindow(true))
+            }
+            iced_native::Event::Mouse(iced_native::mouse::Event::CursorLeft) => {
+                Some(PlayerMessage::CursorOverWindow(false))
+            }
+            _ => None,
+        });
+
+        let mut subscriptions = vec![events, self.sinks.subscription()];
+        if let Some(push_url) = self.station.push_url() {
+            subscriptions.push(push::subscription(push_url, self.station.clone()));
         }
+        Subscription::batch(subscriptions)
     }
 
     fn view(&mut self) -> Element<Self::Message> {
         let player = widget::Row::new();
+        let art_size = self.art_size();
 
         let art_column = {
-            let album_image = ui::album_art_widget(&self.album_image);
+            let album_image = ui::album_art_widget(&self.album_image, art_size);
             let elapsed_row = ui::elapsed_widget(&self.current_song_info, self.volume);
 
             let (svg_source, button_message) = match self.player_status {
@@ -208,38 +694,246 @@ impl Application for Player {
 
             let progress_bar = ui::progress_widget(&self.current_song_info);
 
-            widget::Column::new()
+            let open_playlist = widget::Button::new(&mut self.open_playlist_state, widget::Text::new("open").size(16))
+                .style(ui::TextButtonStyle)
+                .on_press(PlayerMessage::OpenPlaylistDialog);
+            let station_row = widget::Row::new()
+                .push(
+                    widget::Text::new(self.station.name())
+                        .size(16)
+                        .width(iced::Length::Fill),
+                )
+                .push(open_playlist)
+                .align_items(iced::Align::Center);
+
+            let art_column = widget::Column::new()
                 .push(album_image)
                 .push(progress_bar)
                 .push(elapsed_row)
                 .push(controls)
-                .max_width(200)
+                .push(station_row)
+                .max_width(art_size as u32);
+            match self.song_info_error {
+                Some(ref error) => art_column.push(
+                    widget::Text::new(format!("Station unreachable: {}", error))
+                        .size(14)
+                        .color([1.0, 0.5, 0.5, 1.0]),
+                ),
+                None => art_column,
+            }
         };
-        let info_panel = {
-            let type_column = widget::Column::new()
-                .push(widget::Space::new(iced::Length::Shrink, iced::Length::Units(48)))
-                .push(widget::Text::new("by").size(32).color([1.0, 1.0, 1.0, 0.5]))
-                .push(widget::Text::new("album").size(32).color([1.0, 1.0, 1.0, 0.5]))
-                .push(widget::Text::new("circle").size(32).color([1.0, 1.0, 1.0, 0.5]))
-                .push(widget::Text::new("year").size(32).color([1.0, 1.0, 1.0, 0.5]))
-                .align_items(iced::Align::End);
-
-            let value_column = widget::Column::new();
-            let value_column = if let Some(ref song_info) = self.current_song_info {
-                value_column
-                    .push(widget::Text::new(&song_info.songinfo.title).size(48))
-                    .push(widget::Text::new(&song_info.songinfo.artist).size(32))
-                    .push(widget::Text::new(&song_info.songinfo.album).size(32))
-                    .push(widget::Text::new(&song_info.songinfo.circle).size(32))
-                    .push(widget::Text::new(&song_info.songinfo.year).size(32))
-            } else {
-                value_column.push(widget::Text::new("Fetching infos...").size(32))
-            };
+        let tabs = widget::Row::new()
+            .push(widget::Space::with_width(iced::Length::Fill))
+            .push(ui::tab_widget(
+                &mut self.info_tab_state,
+                "info",
+                Panel::Info,
+                self.panel,
+                true,
+            ))
+            .push(ui::tab_widget(
+                &mut self.lyrics_tab_state,
+                "lyrics",
+                Panel::Lyrics,
+                self.panel,
+                true,
+            ))
+            .push(ui::tab_widget(
+                &mut self.history_tab_state,
+                "history",
+                Panel::History,
+                self.panel,
+                self.history.is_some(),
+            ))
+            .push(ui::tab_widget(
+                &mut self.favorites_tab_state,
+                "favorites",
+                Panel::Favorites,
+                self.panel,
+                self.favorites.is_some(),
+            ))
+            .push(ui::tab_widget(
+                &mut self.account_tab_state,
+                "account",
+                Panel::Account,
+                self.panel,
+                true,
+            ));
+
+        let info_panel: Element<_> = match self.panel {
+            Panel::History => self.history_panel.view(),
+            Panel::Favorites => self.favorites_panel.view(),
+            Panel::Account => {
+                // The presence can only be toggled when it is compiled in
+                let presence_enabled = self.sinks.is_enabled("discord");
+                self.account_panel.view(
+                    &self.gr_client,
+                    presence_enabled,
+                    self.lastfm.is_some(),
+                    !self.config.webhooks.is_empty(),
+                )
+            }
+            Panel::Lyrics => {
+                let played = self
+                    .current_song_info
+                    .as_ref()
+                    .map_or(0, |song_info| song_info.songtimes.played);
+                self.lyrics_panel.view(played)
+            }
+            Panel::Info => {
+                // Only songs coming from Gensokyo Radio can be rated, by logged in users
+                let rated_songid = match self.current_song_info {
+                    Some(ref song_info) if self.gr_client.logged_in_user().is_some() => {
+                        Some(song_info.songdata.songid).filter(|songid| *songid != 0)
+                    }
+                    _ => None,
+                };
+
+                let type_column = widget::Column::new()
+                    .push(widget::Space::new(iced::Length::Shrink, iced::Length::Units(48)))
+                    .push(widget::Text::new("by").size(32).color([1.0, 1.0, 1.0, 0.5]))
+                    .push(widget::Text::new("album").size(32).color([1.0, 1.0, 1.0, 0.5]))
+                    .push(widget::Text::new("circle").size(32).color([1.0, 1.0, 1.0, 0.5]))
+                    .push(widget::Text::new("year").size(32).color([1.0, 1.0, 1.0, 0.5]))
+                    .align_items(iced::Align::End);
+                let type_column = if self.original_themes.is_empty() {
+                    type_column
+                } else {
+                    type_column
+                        .push(widget::Text::new("original").size(32).color([1.0, 1.0, 1.0, 0.5]))
+                        .push(widget::Text::new("from").size(32).color([1.0, 1.0, 1.0, 0.5]))
+                };
+                let (rating, listeners) = match self.current_song_info {
+                    Some(ref song_info) => (
+                        Some(&song_info.songdata).filter(|songdata| songdata.timesrated != 0),
+                        Some(song_info.serverinfo.listeners).filter(|listeners| *listeners != 0),
+                    ),
+                    None => (None, None),
+                };
+                let type_column = if rating.is_some() {
+                    type_column.push(widget::Text::new("rating").size(32).color([1.0, 1.0, 1.0, 0.5]))
+                } else {
+                    type_column
+                };
+                let type_column = if rated_songid.is_some() {
+                    type_column.push(widget::Text::new("rate").size(32).color([1.0, 1.0, 1.0, 0.5]))
+                } else {
+                    type_column
+                };
+                let type_column = if listeners.is_some() {
+                    type_column.push(widget::Text::new("listeners").size(32).color([1.0, 1.0, 1.0, 0.5]))
+                } else {
+                    type_column
+                };
 
-            widget::Row::new().push(type_column).push(value_column).spacing(8)
+                let value_column = widget::Column::new();
+                let value_column = if let Some(ref song_info) = self.current_song_info {
+                    let heart_svg = if self.favorites_panel.contains(song_info) {
+                        ui::HEART_FILLED_SVG
+                    } else {
+                        ui::HEART_SVG
+                    };
+                    let mut favorite = widget::Button::new(
+                        &mut self.favorite_state,
+                        widget::Svg::new(widget::svg::Handle::from_memory(heart_svg))
+                            .width(iced::Length::Units(32))
+                            .height(iced::Length::Units(32)),
+                    )
+                    .style(ui::PlayPauseStyle);
+                    if self.favorites.is_some() {
+                        favorite = favorite.on_press(PlayerMessage::ToggleFavorite);
+                    }
+                    let title_row = widget::Row::new()
+                        .push(widget::Text::new(&song_info.songinfo.title).size(48))
+                        .push(favorite)
+                        .spacing(8)
+                        .align_items(iced::Align::Center);
+                    let circle_row = widget::Row::new()
+                        .push(ui::link_widget(
+                            &mut self.circle_link_state,
+                            &song_info.songinfo.circle,
+                            song_info.misc.circle_url().map(String::from),
+                        ))
+                        .spacing(8)
+                        .align_items(iced::Align::Center);
+                    let circle_row = match self.circle_image {
+                        Some(ref circle_image) => circle_row.push(
+                            widget::Image::new(widget::image::Handle::from_memory(circle_image.clone()))
+                                .width(iced::Length::Units(32))
+                                .height(iced::Length::Units(32)),
+                        ),
+                        None => circle_row,
+                    };
+                    let value_column = value_column
+                        .push(title_row)
+                        .push(widget::Text::new(&song_info.songinfo.artist).size(32))
+                        .push(ui::link_widget(
+                            &mut self.album_link_state,
+                            &song_info.songinfo.album,
+                            song_info.songdata.album_url(),
+                        ))
+                        .push(circle_row)
+                        .push(widget::Text::new(&song_info.songinfo.year).size(32));
+                    let value_column = if self.original_themes.is_empty() {
+                        value_column
+                    } else {
+                        let mut games = Vec::new();
+                        for original in &self.original_themes {
+                            if !original.game.is_empty() && !games.contains(&original.game.as_str()) {
+                                games.push(original.game.as_str());
+                            }
+                        }
+                        let titles = self
+                            .original_themes
+                            .iter()
+                            .map(|original| original.title.as_str())
+                            .collect::<Vec<_>>();
+                        value_column
+                            .push(widget::Text::new(titles.join(" / ")).size(32))
+                            .push(widget::Text::new(games.join(" / ")).size(32))
+                    };
+                    let value_column = match rating {
+                        Some(songdata) => value_column.push(
+                            widget::Text::new(format!("{:.1} / 5 ({} votes)", songdata.rating, songdata.timesrated))
+                                .size(32),
+                        ),
+                        None => value_column,
+                    };
+                    let value_column = match rated_songid {
+                        Some(songid) => value_column.push(ui::rating_widget(&mut self.rating_states, songid)),
+                        None => value_column,
+                    };
+                    match listeners {
+                        Some(listeners) => value_column.push(widget::Text::new(listeners.to_string()).size(32)),
+                        None => value_column,
+                    }
+                } else {
+                    value_column.push(widget::Text::new("Fetching infos...").size(32))
+                };
+
+                widget::Row::new()
+                    .push(type_column)
+                    .push(value_column)
+                    .spacing(8)
+                    .into()
+            }
         };
 
-        widget::Container::new(player.push(art_column).push(info_panel).spacing(8))
+        let right_column = self
+            .config_file
+            .errors
+            .iter()
+            .fold(widget::Column::new(), |column, error| {
+                column.push(
+                    widget::Text::new(format!("Configuration: {}", error))
+                        .size(14)
+                        .color([1.0, 0.5, 0.5, 1.0]),
+                )
+            })
+            .push(tabs)
+            .push(info_panel);
+
+        widget::Container::new(player.push(art_column).push(right_column).spacing(8))
             .style(ui::PlayerStyle)
             .width(iced::Length::Fill)
             .height(iced::Length::Fill)
@@ -248,7 +942,129 @@ impl Application for Player {
     }
 }
 
-const DEFAULT_VOLUME: u8 = 10;
+impl Player {
+    /// Side of the album art, growing with the window up to the largest artwork size.
+    fn art_size(&self) -> u16 {
+        let (width, height) = self.window_size;
+        // Leave room for the controls under the art and for the info panel next to it
+        let size = std::cmp::min(height.saturating_sub(ART_COLUMN_CONTROLS_HEIGHT), width * 2 / 5);
+        size.max(MIN_ART_SIZE as u32).min(MAX_ART_SIZE as u32) as u16
+    }
+
+    fn large_artwork(&self) -> bool {
+        self.config.album_art.high_resolution && self.art_size() > MIN_ART_SIZE
+    }
+
+    /// Schedules the next song info fetch, at the end of the current song.
+    fn schedule_song_info(&mut self) -> Command<PlayerMessage> {
+        self.song_info_generation += 1;
+        let generation = self.song_info_generation;
+
+        // The websocket tells us when the song changes, unless the station is lagging or unreachable
+        if self.song_push_connected && self.song_info_retries == 0 && self.song_info_error.is_none() {
+            return Command::none();
+        }
+
+        let delay = match self.current_song_info {
+            // Stations that don't know the song duration have to be polled for changes
+            Some(ref song_info) if song_info.songtimes.duration == 0 => Duration::from_secs(UNKNOWN_DURATION_POLL),
+            Some(ref song_info) if self.song_info_retries == 0 => {
+                Duration::from_secs(song_info.songtimes.songend.saturating_sub(self.station.clock().now()))
+            }
+            _ => std::cmp::min(
+                SONG_INFO_RETRY * 2u32.pow(self.song_info_retries.saturating_sub(1).min(8)),
+                SONG_INFO_MAX_RETRY,
+            ),
+        };
+        let delay = delay + clock::jitter(SONG_INFO_JITTER);
+
+        Command::perform(
+            async move {
+                tokio::time::sleep(delay).await;
+                generation
+            },
+            PlayerMessage::FetchSongInfo,
+        )
+    }
+
+    /// Applies the configuration file edited by the user. The album art cache, the Gensokyo Radio API options and the
+    /// window size are only read on launch.
+    fn reload_config(&mut self, config: config::Config, mut config_file: config::ConfigFile) {
+        self.lyrics_provider = config.lyrics.online_provider.as_deref().and_then(lyrics::provider);
+        // Opening the dataset takes a while
+        if config.enrichment != self.config.enrichment {
+            self.enricher =
+                enrichment::Enricher::from_config(&config.enrichment, &mut config_file.errors).map(Arc::new);
+        } else {
+            let dataset_errors = self
+                .config_file
+                .errors
+                .iter()
+                .filter(|error| error.starts_with(enrichment::DATASET_ERROR))
+                .cloned();
+            config_file.errors.extend(dataset_errors);
+        }
+        self.lastfm = lastfm::LastfmClient::from_config(&config.lastfm, self.lastfm_session.clone()).map(Arc::new);
+        self.sinks.reload(&self.config, &config, self.art_cache.clone());
+
+        let volume = config.player.volume();
+        if volume != self.volume {
+            self.player_tx
+                .send(pipeline::PlayerControl::Volume(volume))
+                .expect("Failed to send volume command to Player");
+            self.volume = volume;
+            self.sinks.send(NowPlayingEvent::VolumeChanged(volume));
+        }
+        self.config = config;
+        self.config_file = config_file;
+        self.config_changed = false;
+    }
+
+    fn fetch_artwork(&self, song_info: gensokyo_radio::GRApiAnswer) -> Command<PlayerMessage> {
+        let fut_station = self.station.clone();
+        let large = self.large_artwork();
+        let key = song_key(&song_info.songinfo);
+        Command::perform(
+            async move { fut_station.artwork(&song_info, large).await },
+            move |opt_art| PlayerMessage::AlbumArt(key.clone(), opt_art),
+        )
+    }
+
+    fn is_current_song(&self, key: &str) -> bool {
+        self.current_song_info
+            .as_ref()
+            .map_or(false, |song_info| song_key(&song_info.songinfo) == key)
+    }
+}
+
+/// Identifies a song in the answers of background tasks, so they can be dropped if the song changed in the meantime.
+fn song_key(song: &gensokyo_radio::SongInfo) -> String {
+    format!("{}\n{}\n{}", song.title, song.album, song.circle)
+}
+
+/// Computes the elapsed time of a song from the station's clock.
+fn update_elapsed(song_info: &mut gensokyo_radio::GRApiAnswer, now: u64) {
+    let played = now.saturating_sub(song_info.songtimes.songstart);
+    song_info.songtimes.played = if song_info.songtimes.duration == 0 {
+        played
+    } else {
+        played.min(song_info.songtimes.duration)
+    };
+    song_info.songtimes.remaining = song_info.songtimes.duration.saturating_sub(song_info.songtimes.played);
+}
+
+const MIN_ART_SIZE: u16 = 200;
+const MAX_ART_SIZE: u16 = 500;
+/// Height taken by the progress bar, controls and station row under the album art.
+const ART_COLUMN_CONTROLS_HEIGHT: u32 = 140;
+/// Interval in seconds between song info requests when the station doesn't know the song duration.
+const UNKNOWN_DURATION_POLL: u64 = 15;
+/// Random delay added to the song info requests so the players don't all hit the API at the same time.
+const SONG_INFO_JITTER: Duration = Duration::from_secs(2);
+/// Delay before requesting the song info again when the station still returns a song that should be over. It is
+/// doubled after every attempt, up to `SONG_INFO_MAX_RETRY`.
+const SONG_INFO_RETRY: Duration = Duration::from_secs(1);
+const SONG_INFO_MAX_RETRY: Duration = Duration::from_secs(30);
 const FONT: &[u8] = include_bytes!("resources/NotoSansSC-Regular.otf");
 
 #[tokio::main]
@@ -258,14 +1074,25 @@ async fn main() {
         .to_rgba8();
     let icon_width = icon.width();
     let icon_height = icon.height();
+    // An optional playlist or stream URL can be given on the command line to listen to any Icecast/Shoutcast station.
+    let playlist = match std::env::args().nth(1) {
+        Some(source) => playlist::load(&source).await,
+        None => None,
+    };
+    let (config, config_file) = config::Config::load();
     let settings = Settings {
         default_font: Some(FONT),
         window: iced::window::Settings {
-            size: (640, 294),
+            size: config.player.window_size(),
+            min_size: Some(config::MIN_WINDOW_SIZE),
             icon: iced::window::icon::Icon::from_rgba(icon.into_raw(), icon_width, icon_height).ok(),
             ..iced::window::Settings::default()
         },
-        ..Settings::default()
+        ..Settings::with_flags(Flags {
+            playlist,
+            config,
+            config_file,
+        })
     };
     Player::run(settings).unwrap();
 }
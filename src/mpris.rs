@@ -0,0 +1,753 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+//! [MPRIS](https://specifications.freedesktop.org/mpris-spec/latest/) server, so media keys, `playerctl` and the
+//! desktop media widgets can see and control the player.
+
+use tokio::sync::mpsc::UnboundedSender;
+use zbus::dbus_interface;
+use zvariant::{ObjectPath, OwnedValue, Value};
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::hash::{Hash, Hasher};
+use std::sync::mpsc::{Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::art_cache::ArtCache;
+use crate::gensokyo_radio::{self, GRApiAnswer};
+use crate::sink::{NowPlayingEvent, NowPlayingSink, SongPosition};
+use crate::PlayerMessage;
+
+const BUS_NAME: &str = "org.mpris.MediaPlayer2.wan_player";
+const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+const PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+const NO_TRACK: &str = "/org/mpris/MediaPlayer2/TrackList/NoTrack";
+/// Interface of the signal waking the server thread up
+const WAKE_INTERFACE: &str = "org.wan_player.Server";
+/// Delay before handling requests again after an error, multiplied by the number of errors in a row
+const ERROR_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_ERROR_BACKOFF: Duration = Duration::from_secs(5);
+
+/// What the player is doing, shared between the sink and the D-Bus interfaces.
+struct State {
+    song: Option<GRApiAnswer>,
+    position: SongPosition,
+    playing: bool,
+    volume: u8,
+    art_cache: Option<ArtCache>,
+}
+
+/// MPRIS track id of `song`. The songs that don't come from Gensokyo Radio have no id, so their id is a hash of the
+/// song.
+fn track_id(song: &GRApiAnswer) -> ObjectPath<'static> {
+    let path = match song.songdata.songid {
+        0 => {
+            let mut hasher = DefaultHasher::new();
+            crate::song_key(&song.songinfo).hash(&mut hasher);
+            format!("/org/wan_player/track/key_{:016x}", hasher.finish())
+        }
+        songid => format!("/org/wan_player/track/{}", songid),
+    };
+    ObjectPath::try_from(path).unwrap_or_else(|_| ObjectPath::try_from(NO_TRACK).expect("Invalid MPRIS track id"))
+}
+
+impl State {
+    fn playback_status(&self) -> &'static str {
+        match (self.playing, &self.song) {
+            (true, _) => "Playing",
+            (false, Some(_)) => "Paused",
+            (false, None) => "Stopped",
+        }
+    }
+
+    fn metadata(&self) -> HashMap<String, Value<'static>> {
+        let mut metadata = HashMap::new();
+        let song = match self.song {
+            Some(ref song) => song,
+            None => {
+                let no_track = ObjectPath::try_from(NO_TRACK).expect("Invalid MPRIS track id");
+                metadata.insert("mpris:trackid".to_string(), Value::from(no_track));
+                return metadata;
+            }
+        };
+
+        metadata.insert("mpris:trackid".to_string(), Value::from(track_id(song)));
+        if song.songtimes.duration > 0 {
+            let length = song.songtimes.duration as i64 * 1_000_000;
+            metadata.insert("mpris:length".to_string(), Value::from(length));
+        }
+        if let Some(art_url) = gensokyo_radio::album_art_url(song, self.art_cache.as_ref()) {
+            metadata.insert("mpris:artUrl".to_string(), Value::from(art_url));
+        }
+        metadata.insert("xesam:title".to_string(), Value::from(song.songinfo.title.clone()));
+        metadata.insert(
+            "xesam:artist".to_string(),
+            Value::from(vec![song.songinfo.artist.clone()]),
+        );
+        metadata.insert("xesam:album".to_string(), Value::from(song.songinfo.album.clone()));
+        if !song.songinfo.circle.is_empty() {
+            metadata.insert(
+                "xesam:albumArtist".to_string(),
+                Value::from(vec![song.songinfo.circle.clone()]),
+            );
+        }
+        if !song.songinfo.year.is_empty() {
+            metadata.insert(
+                "xesam:contentCreated".to_string(),
+                Value::from(song.songinfo.year.clone()),
+            );
+        }
+        if let Some(album_url) = song.songdata.album_url() {
+            metadata.insert("xesam:url".to_string(), Value::from(album_url));
+        }
+        metadata
+    }
+
+    /// Position in the current song, in microseconds
+    fn position(&self) -> i64 {
+        let song = match self.song {
+            Some(ref song) => song,
+            None => return 0,
+        };
+        let played = self.position.get().as_micros() as u64;
+        match song.songtimes.duration {
+            0 => played as i64,
+            duration => played.min(duration * 1_000_000) as i64,
+        }
+    }
+}
+
+/// Publishes the player on the session bus.
+///
+/// The D-Bus requests are served by a thread of their own so media keys don't wait for the sink tick. The sink only
+/// updates the shared state and has the server tell the clients what changed.
+pub struct MprisSink {
+    state: Arc<Mutex<State>>,
+    server: Option<Server>,
+}
+
+impl MprisSink {
+    pub fn new(remote: UnboundedSender<PlayerMessage>, art_cache: Option<ArtCache>) -> MprisSink {
+        let state = Arc::new(Mutex::new(State {
+            song: None,
+            position: SongPosition::default(),
+            playing: false,
+            volume: 0,
+            art_cache,
+        }));
+        let server = match connect(state.clone(), remote) {
+            Ok(server) => Some(server),
+            Err(error) => {
+                eprintln!("Failed to start MPRIS server: {}", error);
+                None
+            }
+        };
+        MprisSink { state, server }
+    }
+}
+
+impl NowPlayingSink for MprisSink {
+    fn handle(&mut self, event: NowPlayingEvent) {
+        let changed: &'static [&'static str] = {
+            let mut state = self.state.lock().expect("Failed to lock MPRIS state");
+            match event {
+                NowPlayingEvent::SongChanged { song, .. } => {
+                    let playing = state.playing;
+                    state
+                        .position
+                        .reset(Duration::from_secs(song.songtimes.played), playing);
+                    state.song = Some(song);
+                    &["Metadata", "PlaybackStatus"]
+                }
+                NowPlayingEvent::Playing => {
+                    state.playing = true;
+                    state.position.set_playing(true);
+                    &["PlaybackStatus"]
+                }
+                NowPlayingEvent::Paused => {
+                    state.playing = false;
+                    state.position.set_playing(false);
+                    &["PlaybackStatus"]
+                }
+                NowPlayingEvent::VolumeChanged(volume) => {
+                    state.volume = volume;
+                    &["Volume"]
+                }
+                NowPlayingEvent::Cleared => {
+                    state.song = None;
+                    state.playing = false;
+                    state.position.set_playing(false);
+                    &["Metadata", "PlaybackStatus"]
+                }
+            }
+        };
+        if let Some(ref server) = self.server {
+            server.properties_changed(changed);
+        }
+    }
+}
+
+/// The thread serving the MPRIS interfaces, stopped when dropped.
+///
+/// A zbus connection can't send anything while a thread waits for a message on it, so the thread owns its connection:
+/// it sends the signals itself, and is woken up by a second connection when there is something to send or when it
+/// must stop.
+struct Server {
+    bus_name: String,
+    /// Names of the changed properties, the thread stops once this is dropped
+    changes: Option<Sender<&'static [&'static str]>>,
+    waker: zbus::Connection,
+    /// Unique name of the thread's connection, which the wake up signals are sent to
+    unique_name: String,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Server {
+    fn properties_changed(&self, names: &'static [&'static str]) {
+        let sent = self
+            .changes
+            .as_ref()
+            .map_or(false, |changes| changes.send(names).is_ok());
+        if !sent {
+            return;
+        }
+        if let Err(error) = self.wake() {
+            eprintln!("Failed to send MPRIS properties: {}", error);
+        }
+    }
+
+    fn wake(&self) -> zbus::Result<()> {
+        self.waker
+            .emit_signal(Some(&self.unique_name), OBJECT_PATH, WAKE_INTERFACE, "Wake", &())
+    }
+}
+
+impl Drop for Server {
+    /// Stops the thread, which releases the bus name for the next server.
+    fn drop(&mut self) {
+        self.changes = None;
+        if let Err(error) = self.wake() {
+            // The thread would never wake up
+            eprintln!("Failed to stop MPRIS server {}: {}", self.bus_name, error);
+            return;
+        }
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                eprintln!("The MPRIS server {} panicked", self.bus_name);
+            }
+        }
+    }
+}
+
+/// Connects to the session bus and serves the MPRIS interfaces on it.
+fn connect(
+    state: Arc<Mutex<State>>,
+    remote: UnboundedSender<PlayerMessage>,
+) -> Result<Server, Box<dyn std::error::Error>> {
+    serve(
+        zbus::Connection::new_session()?,
+        zbus::Connection::new_session()?,
+        state,
+        remote,
+    )
+}
+
+/// Registers the MPRIS interfaces on `connection` and serves them from a new thread, woken up through `waker`.
+///
+/// When another player already owns our name, e.g. a second instance of Wan Player, the MPRIS specification has us
+/// append `.instance<pid>` to it.
+fn serve(
+    connection: zbus::Connection,
+    waker: zbus::Connection,
+    state: Arc<Mutex<State>>,
+    remote: UnboundedSender<PlayerMessage>,
+) -> Result<Server, Box<dyn std::error::Error>> {
+    let unique_name = connection
+        .unique_name()
+        .ok_or("The MPRIS connection has no name")?
+        .to_string();
+    let bus = zbus::fdo::DBusProxy::new(&connection)?;
+    let flags = zbus::fdo::RequestNameFlags::DoNotQueue.into();
+    let bus_name = match bus.request_name(BUS_NAME, flags)? {
+        zbus::fdo::RequestNameReply::PrimaryOwner | zbus::fdo::RequestNameReply::AlreadyOwner => BUS_NAME.to_string(),
+        _ => {
+            let instance_name = format!("{}.instance{}", BUS_NAME, std::process::id());
+            eprintln!(
+                "{} is already owned by another player, using {}",
+                BUS_NAME, instance_name
+            );
+            match bus.request_name(&instance_name, flags)? {
+                zbus::fdo::RequestNameReply::PrimaryOwner | zbus::fdo::RequestNameReply::AlreadyOwner => instance_name,
+                _ => return Err(format!("{} is already owned", instance_name).into()),
+            }
+        }
+    };
+
+    let (changes, changed) = std::sync::mpsc::channel();
+    let served_name = bus_name.clone();
+    let thread = std::thread::Builder::new()
+        .name("MPRIS server".to_string())
+        .spawn(move || {
+            let mut server = zbus::ObjectServer::new(&connection);
+            let path = ObjectPath::try_from(OBJECT_PATH).expect("Invalid MPRIS object path");
+            let registered = server.at(&path, MediaPlayer2).and_then(|_| {
+                server.at(
+                    &path,
+                    MediaPlayer2Player {
+                        state: state.clone(),
+                        remote,
+                    },
+                )
+            });
+            if let Err(error) = registered {
+                eprintln!("Failed to register MPRIS interfaces: {}", error);
+                return;
+            }
+            let mut errors = 0;
+            loop {
+                match server.try_handle_next() {
+                    Ok(_) => errors = 0,
+                    // The bus is gone, e.g. the session ended, every read would fail right away
+                    Err(zbus::Error::Io(error)) => {
+                        eprintln!("Lost the MPRIS connection: {}", error);
+                        return;
+                    }
+                    Err(error) => {
+                        eprintln!("Failed to handle MPRIS request: {}", error);
+                        errors = (errors + 1).min(50);
+                        std::thread::sleep((ERROR_BACKOFF * errors).min(MAX_ERROR_BACKOFF));
+                    }
+                }
+                loop {
+                    match changed.try_recv() {
+                        Ok(names) => emit_properties_changed(&connection, &state, names),
+                        Err(TryRecvError::Empty) => break,
+                        // The sink is gone. The name is released before the thread ends, so the next server gets it.
+                        Err(TryRecvError::Disconnected) => {
+                            let released = zbus::fdo::DBusProxy::new(&connection)
+                                .and_then(|bus| Ok(bus.release_name(&served_name)?));
+                            if let Err(error) = released {
+                                eprintln!("Failed to release {}: {}", served_name, error);
+                            }
+                            return;
+                        }
+                    }
+                }
+            }
+        })?;
+    Ok(Server {
+        bus_name,
+        changes: Some(changes),
+        waker,
+        unique_name,
+        thread: Some(thread),
+    })
+}
+
+fn emit_properties_changed(connection: &zbus::Connection, state: &Mutex<State>, names: &[&str]) {
+    let changed = {
+        let state = state.lock().expect("Failed to lock MPRIS state");
+        names
+            .iter()
+            .map(|&name| {
+                let value = match name {
+                    "Metadata" => Value::from(state.metadata()),
+                    "PlaybackStatus" => Value::from(state.playback_status()),
+                    "Volume" => Value::from(f64::from(state.volume) / 100.0),
+                    _ => unreachable!("Unknown MPRIS property {}", name),
+                };
+                (name, value)
+            })
+            .collect::<HashMap<&str, Value>>()
+    };
+    let result = connection.emit_signal(
+        None,
+        OBJECT_PATH,
+        "org.freedesktop.DBus.Properties",
+        "PropertiesChanged",
+        &(PLAYER_INTERFACE, changed, Vec::<&str>::new()),
+    );
+    if let Err(error) = result {
+        eprintln!("Failed to send MPRIS properties: {}", error);
+    }
+}
+
+struct MediaPlayer2;
+
+#[dbus_interface(name = "org.mpris.MediaPlayer2")]
+impl MediaPlayer2 {
+    fn raise(&self) {}
+
+    fn quit(&self) {}
+
+    #[dbus_interface(property)]
+    fn can_quit(&self) -> bool {
+        false
+    }
+
+    #[dbus_interface(property)]
+    fn can_raise(&self) -> bool {
+        false
+    }
+
+    #[dbus_interface(property)]
+    fn has_track_list(&self) -> bool {
+        false
+    }
+
+    #[dbus_interface(property)]
+    fn identity(&self) -> &str {
+        "Wan Player"
+    }
+
+    #[dbus_interface(property)]
+    fn supported_uri_schemes(&self) -> Vec<String> {
+        vec!["http".to_string(), "https".to_string(), "file".to_string()]
+    }
+
+    #[dbus_interface(property)]
+    fn supported_mime_types(&self) -> Vec<String> {
+        vec![
+            "audio/mpeg".to_string(),
+            "audio/x-mpegurl".to_string(),
+            "audio/x-scpls".to_string(),
+        ]
+    }
+}
+
+struct MediaPlayer2Player {
+    state: Arc<Mutex<State>>,
+    remote: UnboundedSender<PlayerMessage>,
+}
+
+impl MediaPlayer2Player {
+    fn send(&self, message: PlayerMessage) {
+        if self.remote.send(message).is_err() {
+            eprintln!("Failed to send MPRIS command to the player");
+        }
+    }
+}
+
+#[dbus_interface(name = "org.mpris.MediaPlayer2.Player")]
+impl MediaPlayer2Player {
+    // A radio can't skip or seek, the clients hide those buttons thanks to the `Can*` properties
+    fn next(&self) {}
+
+    fn previous(&self) {}
+
+    fn seek(&self, _offset: i64) {}
+
+    fn set_position(&self, _track_id: ObjectPath, _position: i64) {}
+
+    fn pause(&self) {
+        self.send(PlayerMessage::Pause);
+    }
+
+    fn play_pause(&self) {
+        let playing = self.state.lock().expect("Failed to lock MPRIS state").playing;
+        self.send(if playing {
+            PlayerMessage::Pause
+        } else {
+            PlayerMessage::Play
+        });
+    }
+
+    /// Stopping a live stream is the same as pausing it
+    fn stop(&self) {
+        self.send(PlayerMessage::Pause);
+    }
+
+    fn play(&self) {
+        self.send(PlayerMessage::Play);
+    }
+
+    fn open_uri(&self, uri: &str) {
+        self.send(PlayerMessage::SourceSelected(Some(uri.to_string())));
+    }
+
+    #[dbus_interface(property)]
+    fn playback_status(&self) -> &str {
+        self.state.lock().expect("Failed to lock MPRIS state").playback_status()
+    }
+
+    #[dbus_interface(property)]
+    fn rate(&self) -> f64 {
+        1.0
+    }
+
+    #[dbus_interface(property)]
+    fn set_rate(&self, _rate: f64) {}
+
+    #[dbus_interface(property)]
+    fn minimum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[dbus_interface(property)]
+    fn maximum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[dbus_interface(property)]
+    fn metadata(&self) -> HashMap<String, OwnedValue> {
+        let state = self.state.lock().expect("Failed to lock MPRIS state");
+        state
+            .metadata()
+            .into_iter()
+            .map(|(key, value)| (key, OwnedValue::from(value)))
+            .collect()
+    }
+
+    #[dbus_interface(property)]
+    fn volume(&self) -> f64 {
+        f64::from(self.state.lock().expect("Failed to lock MPRIS state").volume) / 100.0
+    }
+
+    #[dbus_interface(property)]
+    fn set_volume(&self, volume: f64) {
+        let volume = (volume.clamp(0.0, 1.0) * 100.0).round() as u8;
+        self.send(PlayerMessage::VolumeChanged(volume));
+    }
+
+    #[dbus_interface(property)]
+    fn position(&self) -> i64 {
+        self.state.lock().expect("Failed to lock MPRIS state").position()
+    }
+
+    #[dbus_interface(property)]
+    fn can_go_next(&self) -> bool {
+        false
+    }
+
+    #[dbus_interface(property)]
+    fn can_go_previous(&self) -> bool {
+        false
+    }
+
+    #[dbus_interface(property)]
+    fn can_play(&self) -> bool {
+        true
+    }
+
+    #[dbus_interface(property)]
+    fn can_pause(&self) -> bool {
+        true
+    }
+
+    #[dbus_interface(property)]
+    fn can_seek(&self) -> bool {
+        false
+    }
+
+    #[dbus_interface(property)]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::BufRead;
+    use std::process::{Child, Command, Stdio};
+
+    /// Private bus, stopped when dropped.
+    struct TestBus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl TestBus {
+        /// Starts a bus. The tests need `dbus-daemon`.
+        fn start() -> TestBus {
+            let mut daemon = Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address=1"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .expect("Failed to start dbus-daemon, it is needed by the MPRIS tests");
+            let mut address = String::new();
+            let stdout = daemon.stdout.take().expect("Failed to get dbus-daemon output");
+            std::io::BufReader::new(stdout)
+                .read_line(&mut address)
+                .expect("Failed to read bus address");
+            TestBus {
+                daemon,
+                address: address.trim().to_string(),
+            }
+        }
+
+        fn connect(&self) -> zbus::Connection {
+            zbus::Connection::new_for_address(&self.address, true).expect("Failed to connect to the test bus")
+        }
+    }
+
+    impl Drop for TestBus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    fn state() -> Arc<Mutex<State>> {
+        Arc::new(Mutex::new(State {
+            song: None,
+            position: SongPosition::default(),
+            playing: false,
+            volume: 50,
+            art_cache: None,
+        }))
+    }
+
+    fn song(title: &str, songid: u64) -> GRApiAnswer {
+        GRApiAnswer {
+            serverinfo: Default::default(),
+            songinfo: gensokyo_radio::SongInfo {
+                title: title.to_string(),
+                ..Default::default()
+            },
+            songtimes: Default::default(),
+            songdata: gensokyo_radio::SongData {
+                songid,
+                ..Default::default()
+            },
+            misc: Default::default(),
+        }
+    }
+
+    #[test]
+    fn track_ids() {
+        assert_eq!(track_id(&song("Title", 42)).as_str(), "/org/wan_player/track/42");
+        // Songs from other stations have no id
+        let first = track_id(&song("Title", 0));
+        assert!(
+            first.as_str().starts_with("/org/wan_player/track/key_"),
+            "{}",
+            first.as_str()
+        );
+        assert_eq!(track_id(&song("Title", 0)), first);
+        assert_ne!(track_id(&song("Other title", 0)), first);
+    }
+
+    fn get_property(connection: &zbus::Connection, bus_name: &str, name: &str) -> OwnedValue {
+        zbus::fdo::PropertiesProxy::new_for(connection, bus_name, OBJECT_PATH)
+            .expect("Failed to create properties proxy")
+            .get(PLAYER_INTERFACE, name)
+            .expect("Failed to get MPRIS property")
+    }
+
+    fn call(connection: &zbus::Connection, bus_name: &str, method: &str) {
+        connection
+            .call_method(Some(bus_name), OBJECT_PATH, Some(PLAYER_INTERFACE), method, &())
+            .expect("Failed to call MPRIS method");
+    }
+
+    #[test]
+    fn controls() {
+        let bus = TestBus::start();
+        let state = state();
+        let (remote, mut commands) = tokio::sync::mpsc::unbounded_channel();
+        let server = serve(bus.connect(), bus.connect(), state.clone(), remote).expect("Failed to serve MPRIS");
+        assert_eq!(server.bus_name, BUS_NAME);
+
+        let client = bus.connect();
+        match *get_property(&client, BUS_NAME, "PlaybackStatus") {
+            Value::Str(ref status) => assert_eq!(status.as_str(), "Stopped"),
+            ref value => panic!("Unexpected playback status {:?}", value),
+        }
+        match *get_property(&client, BUS_NAME, "Volume") {
+            Value::F64(volume) => assert!((volume - 0.5).abs() < f64::EPSILON),
+            ref value => panic!("Unexpected volume {:?}", value),
+        }
+
+        call(&client, BUS_NAME, "PlayPause");
+        assert!(matches!(commands.try_recv(), Ok(PlayerMessage::Play)));
+        state.lock().unwrap().playing = true;
+        match *get_property(&client, BUS_NAME, "PlaybackStatus") {
+            Value::Str(ref status) => assert_eq!(status.as_str(), "Playing"),
+            ref value => panic!("Unexpected playback status {:?}", value),
+        }
+        call(&client, BUS_NAME, "PlayPause");
+        assert!(matches!(commands.try_recv(), Ok(PlayerMessage::Pause)));
+        call(&client, BUS_NAME, "Stop");
+        assert!(matches!(commands.try_recv(), Ok(PlayerMessage::Pause)));
+        // A radio can't skip
+        call(&client, BUS_NAME, "Next");
+        assert!(commands.try_recv().is_err());
+    }
+
+    #[test]
+    fn name_already_owned() {
+        let bus = TestBus::start();
+        let (remote, _commands) = tokio::sync::mpsc::unbounded_channel();
+        let first = serve(bus.connect(), bus.connect(), state(), remote.clone()).expect("Failed to serve MPRIS");
+        let second = serve(bus.connect(), bus.connect(), state(), remote).expect("Failed to serve MPRIS");
+        assert_eq!(first.bus_name, BUS_NAME);
+        assert_eq!(second.bus_name, format!("{}.instance{}", BUS_NAME, std::process::id()));
+
+        // Both players answer
+        let client = bus.connect();
+        call(&client, &first.bus_name, "Play");
+        call(&client, &second.bus_name, "Play");
+    }
+
+    #[test]
+    fn properties_changed() {
+        let bus = TestBus::start();
+        let state = state();
+        let (remote, _commands) = tokio::sync::mpsc::unbounded_channel();
+        let server = serve(bus.connect(), bus.connect(), state.clone(), remote).expect("Failed to serve MPRIS");
+
+        let client = bus.connect();
+        zbus::fdo::DBusProxy::new(&client)
+            .and_then(|dbus| Ok(dbus.add_match("type='signal',member='PropertiesChanged'")?))
+            .expect("Failed to listen to the MPRIS signals");
+        let (signals, received) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            while let Ok(message) = client.receive_message() {
+                if let Ok((_, changed, _)) = message.body::<(String, HashMap<String, OwnedValue>, Vec<String>)>() {
+                    let _ = signals.send(changed);
+                }
+            }
+        });
+
+        // Sent right away, while the server waits for requests
+        state.lock().unwrap().volume = 80;
+        server.properties_changed(&["Volume"]);
+        let changed = received
+            .recv_timeout(Duration::from_secs(5))
+            .expect("No PropertiesChanged signal");
+        match changed.get("Volume").map(|volume| &**volume) {
+            Some(Value::F64(volume)) => assert!((volume - 0.8).abs() < f64::EPSILON),
+            value => panic!("Unexpected volume {:?}", value),
+        }
+    }
+
+    #[test]
+    fn stop() {
+        let bus = TestBus::start();
+        let (remote, _commands) = tokio::sync::mpsc::unbounded_channel();
+        let first = serve(bus.connect(), bus.connect(), state(), remote.clone()).expect("Failed to serve MPRIS");
+        drop(first);
+
+        // The name was freed for the next server, e.g. after a reload of the configuration
+        let second = serve(bus.connect(), bus.connect(), state(), remote).expect("Failed to serve MPRIS");
+        assert_eq!(second.bus_name, BUS_NAME);
+    }
+}
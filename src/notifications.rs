@@ -0,0 +1,321 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+//! Desktop notifications through the
+//! [notification service](https://specifications.freedesktop.org/notification-spec/latest/) of the session bus.
+
+use tokio::sync::mpsc::UnboundedSender;
+use zvariant::Value;
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use crate::art_cache::ArtCache;
+use crate::config::NotificationsConfig;
+use crate::gensokyo_radio::{self, GRApiAnswer};
+use crate::sink::{NowPlayingEvent, NowPlayingSink};
+use crate::PlayerMessage;
+
+const NOTIFICATIONS_NAME: &str = "org.freedesktop.Notifications";
+const NOTIFICATIONS_PATH: &str = "/org/freedesktop/Notifications";
+/// Let the notification server decide how long the notifications stay
+const DEFAULT_TIMEOUT: i32 = -1;
+/// Interface of the signal waking the actions thread up
+const WAKE_INTERFACE: &str = "org.wan_player.Notifications";
+
+/// Shows a notification when the song changes, with a play/pause button.
+///
+/// There is only one notification at a time: a new song replaces the notification of the previous one, and the
+/// button is updated in place while the notification is open. The notification servers only load local images, so the
+/// album art is shown once the player has it in the album art cache, updating the open notification if needed.
+pub struct NotificationSink {
+    config: NotificationsConfig,
+    connection: Option<zbus::Connection>,
+    actions: Option<Actions>,
+    art_cache: Option<ArtCache>,
+    cursor_over_window: Arc<AtomicBool>,
+    /// Id of the open notification, 0 if there is none
+    open_id: Arc<AtomicU32>,
+    /// Whether the open notification shows the album art
+    image_shown: bool,
+    song: Option<GRApiAnswer>,
+    playing: bool,
+}
+
+impl NotificationSink {
+    pub fn new(
+        config: NotificationsConfig,
+        remote: UnboundedSender<PlayerMessage>,
+        art_cache: Option<ArtCache>,
+        cursor_over_window: Arc<AtomicBool>,
+    ) -> NotificationSink {
+        let open_id = Arc::new(AtomicU32::new(0));
+        let (connection, actions) = match listen_actions(remote, open_id.clone()) {
+            Ok((connection, actions)) => (Some(connection), Some(actions)),
+            Err(error) => {
+                eprintln!("Failed to connect to the notification service: {}", error);
+                (None, None)
+            }
+        };
+        NotificationSink {
+            config,
+            connection,
+            actions,
+            art_cache,
+            cursor_over_window,
+            open_id,
+            image_shown: false,
+            song: None,
+            playing: false,
+        }
+    }
+
+    /// Shows the notification of the current song, replacing the open one.
+    fn notify(&mut self) {
+        let (connection, song) = match (&self.connection, &self.song) {
+            (Some(connection), Some(song)) => (connection, song),
+            _ => return,
+        };
+
+        let info = &song.songinfo;
+        let body = [&info.artist, &info.album]
+            .iter()
+            .filter(|text| !text.is_empty())
+            .map(|text| escape_markup(text))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let actions = if self.playing {
+            vec!["pause", "Pause"]
+        } else {
+            vec!["play", "Play"]
+        };
+        let mut hints = HashMap::new();
+        let image = self.cached_album_art();
+        if let Some(ref path) = image {
+            hints.insert("image-path", Value::from(format!("file://{}", path.display())));
+        }
+        // Keeps the notification out of the notification center history on the servers that support it
+        hints.insert("transient", Value::from(true));
+        hints.insert("category", Value::from("x-gnome.music"));
+
+        let result = connection.call_method(
+            Some(NOTIFICATIONS_NAME),
+            NOTIFICATIONS_PATH,
+            Some(NOTIFICATIONS_NAME),
+            "Notify",
+            &(
+                "Wan Player",
+                self.open_id.load(Ordering::SeqCst),
+                "",
+                info.title.as_str(),
+                body,
+                actions,
+                hints,
+                DEFAULT_TIMEOUT,
+            ),
+        );
+        match result.and_then(|reply| Ok(reply.body::<u32>()?)) {
+            Ok(id) => {
+                self.open_id.store(id, Ordering::SeqCst);
+                self.image_shown = image.is_some();
+            }
+            Err(error) => eprintln!("Failed to show notification: {}", error),
+        }
+    }
+
+    fn cached_album_art(&self) -> Option<PathBuf> {
+        match (&self.song, &self.art_cache) {
+            (Some(song), Some(art_cache)) => gensokyo_radio::cached_album_art(song, art_cache),
+            _ => None,
+        }
+    }
+
+    fn close(&self) {
+        let connection = match self.connection {
+            Some(ref connection) => connection,
+            None => return,
+        };
+        let id = self.open_id.swap(0, Ordering::SeqCst);
+        if id == 0 {
+            return;
+        }
+        let result = connection.call_method(
+            Some(NOTIFICATIONS_NAME),
+            NOTIFICATIONS_PATH,
+            Some(NOTIFICATIONS_NAME),
+            "CloseNotification",
+            &(id),
+        );
+        if let Err(error) = result {
+            eprintln!("Failed to close notification: {}", error);
+        }
+    }
+}
+
+impl NowPlayingSink for NotificationSink {
+    fn handle(&mut self, event: NowPlayingEvent) {
+        match event {
+            NowPlayingEvent::SongChanged { song, .. } => {
+                self.song = Some(song);
+                if self.config.when_hovered || !self.cursor_over_window.load(Ordering::SeqCst) {
+                    self.notify();
+                }
+            }
+            NowPlayingEvent::Playing | NowPlayingEvent::Paused => {
+                self.playing = matches!(event, NowPlayingEvent::Playing);
+                // Only the button changes, a closed notification isn't shown again
+                if self.open_id.load(Ordering::SeqCst) != 0 {
+                    self.notify();
+                }
+            }
+            NowPlayingEvent::VolumeChanged(_) => {}
+            NowPlayingEvent::Cleared => {
+                self.song = None;
+                self.close();
+            }
+        }
+    }
+
+    fn tick(&mut self) {
+        // The album art is downloaded after the song change, the open notification gets it once it is cached
+        if !self.image_shown && self.open_id.load(Ordering::SeqCst) != 0 && self.cached_album_art().is_some() {
+            self.notify();
+        }
+    }
+
+    fn shutdown(&mut self) {
+        // The buttons wouldn't do anything once the player is gone
+        self.close();
+        self.actions = None;
+    }
+}
+
+/// The thread forwarding the buttons clicked in our notification, stopped when dropped.
+struct Actions {
+    /// Connection the thread is woken up from
+    waker: zbus::Connection,
+    /// Unique name of the thread's connection, which the wake up signal is sent to
+    signals_name: String,
+    stopping: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Actions {
+    fn drop(&mut self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        let woken = self.waker.emit_signal(
+            Some(&self.signals_name),
+            NOTIFICATIONS_PATH,
+            WAKE_INTERFACE,
+            "Wake",
+            &(),
+        );
+        if let Err(error) = woken {
+            // The thread would never wake up
+            eprintln!("Failed to stop the notification actions thread: {}", error);
+            return;
+        }
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                eprintln!("The notification actions thread panicked");
+            }
+        }
+    }
+}
+
+/// Connects to the session bus and forwards the buttons clicked in our notification to the player.
+///
+/// The signals are received on a connection of their own, `receive_message` would otherwise take the replies to the
+/// method calls made with the returned connection.
+fn listen_actions(
+    remote: UnboundedSender<PlayerMessage>,
+    open_id: Arc<AtomicU32>,
+) -> Result<(zbus::Connection, Actions), Box<dyn std::error::Error>> {
+    let connection = zbus::Connection::new_session()?;
+    let signals = zbus::Connection::new_session()?;
+    let signals_name = signals
+        .unique_name()
+        .ok_or("The notification connection has no name")?
+        .to_string();
+    let dbus = zbus::fdo::DBusProxy::new(&signals)?;
+    for member in ["ActionInvoked", "NotificationClosed"].iter() {
+        dbus.add_match(&format!(
+            "type='signal',interface='{}',member='{}'",
+            NOTIFICATIONS_NAME, member
+        ))?;
+    }
+
+    let stopping = Arc::new(AtomicBool::new(false));
+    let thread_stopping = stopping.clone();
+    let thread = std::thread::Builder::new()
+        .name("notification actions".to_string())
+        .spawn(move || loop {
+            let message = match signals.receive_message() {
+                Ok(message) => message,
+                Err(error) => {
+                    eprintln!("Failed to receive notification signals: {}", error);
+                    return;
+                }
+            };
+            if thread_stopping.load(Ordering::SeqCst) {
+                return;
+            }
+            let header = match message.header() {
+                Ok(header) => header,
+                Err(_) => continue,
+            };
+            let member = header.member().ok().flatten().map(|member| member.to_string());
+            match member.as_deref() {
+                Some("ActionInvoked") => {
+                    let (id, action) = match message.body::<(u32, String)>() {
+                        Ok(body) => body,
+                        Err(_) => continue,
+                    };
+                    if id != open_id.load(Ordering::SeqCst) {
+                        continue;
+                    }
+                    let command = match action.as_str() {
+                        "play" => PlayerMessage::Play,
+                        "pause" => PlayerMessage::Pause,
+                        _ => continue,
+                    };
+                    if remote.send(command).is_err() {
+                        return;
+                    }
+                }
+                Some("NotificationClosed") => {
+                    if let Ok((id, _reason)) = message.body::<(u32, u32)>() {
+                        let _ = open_id.compare_exchange(id, 0, Ordering::SeqCst, Ordering::SeqCst);
+                    }
+                }
+                _ => {}
+            }
+        })
+        .expect("Failed to spawn notification actions thread");
+    let actions = Actions {
+        waker: connection.clone(),
+        signals_name,
+        stopping,
+        thread: Some(thread),
+    };
+    Ok((connection, actions))
+}
+
+/// The notification bodies may be interpreted as markup.
+fn escape_markup(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
@@ -0,0 +1,306 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+//! Now playing outputs for streaming software like OBS: a text file, a JSON file, and an HTML overlay served locally.
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, StatusCode};
+
+use std::convert::Infallible;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::art_cache::ArtCache;
+use crate::config::{self, NowPlayingConfig};
+use crate::gensokyo_radio::{self, GRApiAnswer};
+use crate::sink::{expand_template, NowPlayingEvent, NowPlayingSink, SongPosition};
+
+const OVERLAY_HTML: &str = include_str!("resources/overlay.html");
+
+/// What is playing, shared with the overlay server.
+struct State {
+    song: Option<(GRApiAnswer, String)>,
+    position: SongPosition,
+    playing: bool,
+}
+
+impl State {
+    fn to_json(&self) -> serde_json::Value {
+        let (song, station) = match self.song {
+            Some((ref song, ref station)) => (song, station),
+            None => return serde_json::json!({ "playing": self.playing, "station": null, "song": null }),
+        };
+        let times = &song.songtimes;
+        let elapsed = self.position.get().as_secs();
+        let elapsed = if times.duration > 0 {
+            elapsed.min(times.duration)
+        } else {
+            elapsed
+        };
+        serde_json::json!({
+            "playing": self.playing,
+            "station": station,
+            "song": {
+                "title": song.songinfo.title,
+                "artist": song.songinfo.artist,
+                "album": song.songinfo.album,
+                "year": song.songinfo.year,
+                "circle": song.songinfo.circle,
+                "duration": times.duration,
+                "songstart": times.songstart,
+                "songend": times.songend,
+                "elapsed": elapsed,
+                "album_art": gensokyo_radio::album_art_url(song, None),
+                "album_url": song.songdata.album_url(),
+            },
+        })
+    }
+}
+
+/// Writes the current song to files, and serves it to the overlay.
+pub struct NowPlayingOutput {
+    config: NowPlayingConfig,
+    state: Arc<Mutex<State>>,
+    /// Stops the server when dropped
+    _overlay: Option<Overlay>,
+}
+
+impl NowPlayingOutput {
+    pub fn new(config: NowPlayingConfig, art_cache: Option<ArtCache>) -> NowPlayingOutput {
+        let state = Arc::new(Mutex::new(State {
+            song: None,
+            position: SongPosition::default(),
+            playing: false,
+        }));
+        let overlay = config.overlay_address.as_ref().and_then(|address| {
+            match serve_overlay(address, state.clone(), art_cache) {
+                Ok(overlay) => Some(overlay),
+                Err(error) => {
+                    eprintln!("Failed to start overlay server on {}: {}", address, error);
+                    None
+                }
+            }
+        });
+        NowPlayingOutput {
+            config,
+            state,
+            _overlay: overlay,
+        }
+    }
+
+    fn write_files(&self) {
+        let state = self.state.lock().expect("Failed to lock now playing state");
+        if let Some(ref text_file) = self.config.text_file {
+            let text = match state.song {
+                Some((ref song, ref station)) => expand_template(&self.config.text_template, song, station),
+                None => String::new(),
+            };
+            write_file(&config::resolve_path(text_file), text.as_bytes());
+        }
+        if let Some(ref json_file) = self.config.json_file {
+            let json = serde_json::to_vec_pretty(&state.to_json()).expect("Failed to serialize now playing");
+            write_file(&config::resolve_path(json_file), &json);
+        }
+    }
+}
+
+impl NowPlayingSink for NowPlayingOutput {
+    fn handle(&mut self, event: NowPlayingEvent) {
+        {
+            let mut state = self.state.lock().expect("Failed to lock now playing state");
+            match event {
+                NowPlayingEvent::SongChanged { song, station, .. } => {
+                    let playing = state.playing;
+                    state
+                        .position
+                        .reset(Duration::from_secs(song.songtimes.played), playing);
+                    state.song = Some((song, station));
+                }
+                NowPlayingEvent::Playing | NowPlayingEvent::Paused => {
+                    state.playing = matches!(event, NowPlayingEvent::Playing);
+                    let playing = state.playing;
+                    state.position.set_playing(playing);
+                }
+                NowPlayingEvent::VolumeChanged(_) => return,
+                NowPlayingEvent::Cleared => state.song = None,
+            }
+        }
+        self.write_files();
+    }
+
+    fn shutdown(&mut self) {
+        // Don't leave a stale song on the stream
+        self.state.lock().expect("Failed to lock now playing state").song = None;
+        self.write_files();
+    }
+}
+
+/// Replaces the file at once, so the streaming software never reads half of it.
+fn write_file(path: &Path, data: &[u8]) {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+    let result = std::fs::write(&temporary, data).and_then(|_| std::fs::rename(&temporary, path));
+    if let Err(error) = result {
+        eprintln!("Failed to write now playing file {}: {}", path.display(), error);
+    }
+}
+
+/// The overlay server thread, stopped when dropped so the address is free for the next server.
+struct Overlay {
+    /// Dropped to stop the server
+    stop: Option<tokio::sync::oneshot::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Overlay {
+    fn drop(&mut self) {
+        self.stop = None;
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                eprintln!("The overlay server panicked");
+            }
+        }
+    }
+}
+
+/// Serves the overlay page, the current song as JSON, and its album art, from a new thread. The address is bound
+/// right away, so the errors are returned.
+fn serve_overlay(address: &str, state: Arc<Mutex<State>>, art_cache: Option<ArtCache>) -> std::io::Result<Overlay> {
+    let listener = std::net::TcpListener::bind(address)?;
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let thread = std::thread::Builder::new()
+        .name("now playing overlay".to_string())
+        .spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("Failed to build overlay runtime");
+            runtime.block_on(async move {
+                let make_service = make_service_fn(move |_| {
+                    let state = state.clone();
+                    let art_cache = art_cache.clone();
+                    async move {
+                        Ok::<_, Infallible>(service_fn(move |req| {
+                            let response = overlay_response(req, &state, art_cache.as_ref());
+                            async move { Ok::<_, Infallible>(response) }
+                        }))
+                    }
+                });
+                let server = match hyper::Server::from_tcp(listener) {
+                    Ok(server) => server,
+                    Err(error) => {
+                        eprintln!("Failed to start overlay server: {}", error);
+                        return;
+                    }
+                };
+                // The requests being answered are completed, the idle connections are closed
+                let server = server.serve(make_service).with_graceful_shutdown(async {
+                    let _ = stopped.await;
+                });
+                if let Err(error) = server.await {
+                    eprintln!("Overlay server failed: {}", error);
+                }
+            });
+        })?;
+    Ok(Overlay {
+        stop: Some(stop),
+        thread: Some(thread),
+    })
+}
+
+fn overlay_response(req: Request<Body>, state: &Mutex<State>, art_cache: Option<&ArtCache>) -> Response<Body> {
+    let builder = Response::builder();
+    let response = match req.uri().path() {
+        "/" => builder
+            .header(hyper::header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Body::from(OVERLAY_HTML)),
+        "/now_playing.json" => builder
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .header(hyper::header::CACHE_CONTROL, "no-store")
+            .body(Body::from(
+                state
+                    .lock()
+                    .expect("Failed to lock now playing state")
+                    .to_json()
+                    .to_string(),
+            )),
+        "/art" => {
+            // The file is read once the state is unlocked, so the sink isn't blocked by the disk
+            let (cached, url) = {
+                let state = state.lock().expect("Failed to lock now playing state");
+                match state.song {
+                    Some((ref song, _)) => (
+                        art_cache.and_then(|cache| gensokyo_radio::cached_album_art(song, cache)),
+                        gensokyo_radio::album_art_url(song, None),
+                    ),
+                    None => (None, None),
+                }
+            };
+            match (cached.and_then(|path| std::fs::read(path).ok()), url) {
+                (Some(data), _) => builder.body(Body::from(data)),
+                (None, Some(url)) => builder
+                    .status(StatusCode::FOUND)
+                    .header(hyper::header::LOCATION, url)
+                    .body(Body::empty()),
+                (None, None) => builder.status(StatusCode::NOT_FOUND).body(Body::empty()),
+            }
+        }
+        _ => builder.status(StatusCode::NOT_FOUND).body(Body::empty()),
+    };
+    response.expect("Failed to build overlay response")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+
+    fn state() -> Arc<Mutex<State>> {
+        Arc::new(Mutex::new(State {
+            song: None,
+            position: SongPosition::default(),
+            playing: false,
+        }))
+    }
+
+    fn get(address: &str, path: &str) -> String {
+        let mut stream = std::net::TcpStream::connect(address).expect("Failed to connect to the overlay");
+        write!(stream, "GET {} HTTP/1.0\r\n\r\n", path).expect("Failed to send the request");
+        let mut response = String::new();
+        stream
+            .read_to_string(&mut response)
+            .expect("Failed to read the response");
+        response
+    }
+
+    #[test]
+    fn restart_overlay() {
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .expect("Failed to find a free port")
+            .to_string();
+        let overlay = serve_overlay(&address, state(), None).expect("Failed to start the overlay");
+        assert!(get(&address, "/now_playing.json").starts_with("HTTP/1.0 200 OK"));
+        assert!(serve_overlay(&address, state(), None).is_err());
+
+        // The address is free once the server is dropped, e.g. when the configuration is reloaded
+        drop(overlay);
+        let _overlay = serve_overlay(&address, state(), None).expect("Failed to restart the overlay");
+        assert!(get(&address, "/").starts_with("HTTP/1.0 200 OK"));
+    }
+}
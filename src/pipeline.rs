@@ -17,7 +17,7 @@ use hyper::body::HttpBody;
 use minimp3::{Error, Frame};
 use ringbuf::{Consumer, Producer, RingBuffer};
 use tokio::io::{duplex, AsyncWriteExt, DuplexStream};
-use tokio::sync::Semaphore;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 
 use std::sync::{
     atomic::{AtomicU8, Ordering},
@@ -25,13 +25,16 @@ use std::sync::{
     Arc,
 };
 
-use crate::gensokyo_radio::GR_STREAM;
+use crate::backpressure::SpaceNotifier;
+use crate::icecast::{IcyDemuxer, StreamTitle};
 
 #[derive(Debug)]
 pub enum PlayerControl {
     Volume(u8),
     Play,
     Pause,
+    /// Replaces the stream URLs, in order of preference. Takes effect on the next `Play`.
+    Source(Vec<String>),
 }
 
 enum PlaybackControl {
@@ -40,41 +43,132 @@ enum PlaybackControl {
     Stop,
 }
 
-async fn stream_thread(mut tx: DuplexStream) {
+const SAMPLE_RATE: u32 = 44100;
+const CHANNELS: u16 = 2;
+
+/// Streams the first working URL to the decoder, falling back to the next one when a stream can't be reached, stops,
+/// or can't be decoded.
+///
+/// The ICY metadata of the stream, when the server sends some, is taken out of the audio and its title is stored in
+/// `stream_title`.
+async fn stream_thread(
+    stream_urls: Vec<String>,
+    mut tx: DuplexStream,
+    mut decoder_errors: UnboundedReceiver<Error>,
+    stream_title: StreamTitle,
+) {
     let https = hyper_tls::HttpsConnector::new();
     let client = hyper::client::Client::builder().build::<_, hyper::Body>(https);
 
-    let mut res = client
-        .get(hyper::Uri::from_static(GR_STREAM))
-        .await
-        .expect("Failed to request stream");
+    for stream_url in stream_urls {
+        let uri = match stream_url.parse::<hyper::Uri>() {
+            Ok(uri) => uri,
+            Err(error) => {
+                eprintln!("Invalid stream url {}: {}", stream_url, error);
+                continue;
+            }
+        };
+        let req = match hyper::Request::get(uri)
+            .header("Icy-MetaData", "1")
+            .body(hyper::Body::empty())
+        {
+            Ok(req) => req,
+            Err(error) => {
+                eprintln!("Invalid stream url {}: {}", stream_url, error);
+                continue;
+            }
+        };
+        stream_title.set(None);
+        let mut res = match client.request(req).await {
+            Ok(res) if res.status().is_success() => res,
+            Ok(res) => {
+                eprintln!("Failed to request stream {}: {}", stream_url, res.status());
+                continue;
+            }
+            Err(error) => {
+                eprintln!("Failed to request stream {}: {}", stream_url, error);
+                continue;
+            }
+        };
+        let mut demuxer = res
+            .headers()
+            .get("icy-metaint")
+            .and_then(|metaint| metaint.to_str().ok()?.parse().ok())
+            .filter(|&metaint| metaint != 0)
+            .map(IcyDemuxer::new);
+        let mut audio = Vec::new();
 
-    while let Some(chunk) = res.data().await {
-        let slice = &chunk.expect("Failed to convert response body to byte chunk")[..];
-        tx.write_all(slice).await.expect("Failed to send stream to decoder");
+        loop {
+            let chunk = tokio::select! {
+                chunk = res.data() => chunk,
+                Some(error) = decoder_errors.recv() => {
+                    eprintln!("Failed to decode stream {}: {}", stream_url, error);
+                    break;
+                }
+            };
+            let chunk = match chunk {
+                Some(Ok(chunk)) => chunk,
+                Some(Err(error)) => {
+                    eprintln!("Stream {} failed: {}", stream_url, error);
+                    break;
+                }
+                None => break,
+            };
+            let audio = match demuxer {
+                Some(ref mut demuxer) => {
+                    audio.clear();
+                    if let Some(title) = demuxer.demux(&chunk[..], &mut audio) {
+                        stream_title.set(Some(title));
+                    }
+                    &audio[..]
+                }
+                None => &chunk[..],
+            };
+            tx.write_all(audio).await.expect("Failed to send stream to decoder");
+        }
     }
+
+    stream_title.set(None);
+    eprintln!("No stream left to play");
 }
 
-async fn decoder_thread(mut tx: Producer<i16>, rx: DuplexStream, sem: Arc<Semaphore>) {
+/// Decodes the streamed MP3 into the ring buffer until the stream thread is done. Decoding errors are sent back to the
+/// stream thread, which moves on to the next URL.
+async fn decoder_thread(
+    mut tx: Producer<i16>,
+    rx: DuplexStream,
+    space: Arc<SpaceNotifier>,
+    errors: UnboundedSender<Error>,
+) {
     let mut decoder = minimp3::Decoder::new(rx);
 
     loop {
         match decoder.next_frame_future().await {
             Ok(Frame { data, .. }) => {
-                let permit = sem
-                    .acquire_many(data.len() as u32)
-                    .await
-                    .expect("Failed to acquire ringbuffer semaphore");
-                tx.push_iter(&mut data.into_iter());
-                permit.forget();
+                let mut pushed = 0;
+                while pushed < data.len() {
+                    let needed = (data.len() - pushed).min(tx.capacity());
+                    space.wait_for_space(needed, || tx.remaining()).await;
+                    pushed += tx.push_slice(&data[pushed..]);
+                }
+            }
+            Err(Error::Eof) => return,
+            Err(error) => {
+                // Drops what the decoder buffered from the failed stream
+                decoder = minimp3::Decoder::new(decoder.into_inner());
+                if errors.send(error).is_err() {
+                    return;
+                }
             }
-            Err(Error::Eof) => {}
-            Err(e) => panic!("An error happened while waiting for the next frame in decoder: {}", e),
         }
     }
 }
 
-fn playback_init(mut data_rx: Consumer<i16>, playback_control_rx: Receiver<PlaybackControl>, sem: Arc<Semaphore>) {
+fn playback_init(
+    mut data_rx: Consumer<i16>,
+    space: Arc<SpaceNotifier>,
+    playback_control_rx: Receiver<PlaybackControl>,
+) {
     let host = cpal::default_host();
     let device = host
         .default_output_device()
@@ -82,11 +176,11 @@ fn playback_init(mut data_rx: Consumer<i16>, playback_control_rx: Receiver<Playb
     let mut configs = device
         .supported_output_configs()
         .expect("Failed to list supported output configs")
-        .filter(|c| c.channels() == 2);
+        .filter(|c| c.channels() == CHANNELS);
     let config = configs
         .next()
         .expect("Failed to get output config")
-        .with_sample_rate(cpal::SampleRate(44100))
+        .with_sample_rate(cpal::SampleRate(SAMPLE_RATE))
         .config();
 
     let volume = Arc::new(AtomicU8::new(10));
@@ -95,11 +189,15 @@ fn playback_init(mut data_rx: Consumer<i16>, playback_control_rx: Receiver<Playb
     let stream = device
         .build_output_stream(
             &config,
+            // Runs on the real-time audio thread: only atomics and the lock-free ring buffer may be touched here, the
+            // decoder is only notified once the room it waits for is available.
             move |data: &mut [i16], _: &cpal::OutputCallbackInfo| {
                 let factor = cpal_volume.load(Ordering::Relaxed) as f32 / 100.0;
                 let written = data_rx.pop_slice(data);
-                sem.add_permits(written);
-                data.iter_mut().for_each(|d| *d = (*d as f32 * factor) as i16);
+                let (samples, underrun) = data.split_at_mut(written);
+                samples.iter_mut().for_each(|d| *d = (*d as f32 * factor) as i16);
+                underrun.iter_mut().for_each(|d| *d = 0);
+                space.space_available(data_rx.remaining());
             },
             move |err| {
                 println!("{}", err);
@@ -118,7 +216,11 @@ fn playback_init(mut data_rx: Consumer<i16>, playback_control_rx: Receiver<Playb
     }
 }
 
-pub fn setup_pipeline() -> tokio::sync::mpsc::UnboundedSender<PlayerControl> {
+/// Starts the player task. The title in the ICY metadata of the stream playing is kept in `stream_title`.
+pub fn setup_pipeline(
+    mut stream_urls: Vec<String>,
+    stream_title: StreamTitle,
+) -> tokio::sync::mpsc::UnboundedSender<PlayerControl> {
     let (player_tx, mut player_rx) = tokio::sync::mpsc::unbounded_channel();
 
     tokio::spawn(async move {
@@ -142,11 +244,22 @@ pub fn setup_pipeline() -> tokio::sync::mpsc::UnboundedSender<PlayerControl> {
                     let (stream_rx, stream_tx) = duplex(2usize.pow(12));
                     let rb_size = 2usize.pow(14);
                     let (decoder_tx, decoder_rx) = RingBuffer::new(rb_size).split();
-                    let sem = Arc::new(Semaphore::new(rb_size));
+                    let space = Arc::new(SpaceNotifier::new());
+                    let (errors_tx, errors_rx) = unbounded_channel();
 
-                    stream = Some(tokio::spawn(stream_thread(stream_tx)));
-                    decoder = Some(tokio::spawn(decoder_thread(decoder_tx, stream_rx, sem.clone())));
-                    std::thread::spawn(move || playback_init(decoder_rx, playback_control_rx, sem));
+                    stream = Some(tokio::spawn(stream_thread(
+                        stream_urls.clone(),
+                        stream_tx,
+                        errors_rx,
+                        stream_title.clone(),
+                    )));
+                    decoder = Some(tokio::spawn(decoder_thread(
+                        decoder_tx,
+                        stream_rx,
+                        space.clone(),
+                        errors_tx,
+                    )));
+                    std::thread::spawn(move || playback_init(decoder_rx, space, playback_control_rx));
 
                     if let Some(ref pctx) = playback_control_tx {
                         pctx.send(PlaybackControl::Volume(volume))
@@ -164,11 +277,13 @@ pub fn setup_pipeline() -> tokio::sync::mpsc::UnboundedSender<PlayerControl> {
                         stream.abort();
                     }
                     stream = None;
+                    stream_title.set(None);
                     if let Some(ref decoder) = decoder {
                         decoder.abort();
                     }
                     decoder = None;
                 }
+                PlayerControl::Source(urls) => stream_urls = urls,
             }
         }
     });
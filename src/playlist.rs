@@ -0,0 +1,427 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+use std::time::Duration;
+
+/// Extensions of the playlist files we know how to read.
+pub const PLAYLIST_EXTENSIONS: &[&str] = &["m3u", "m3u8", "pls", "xspf"];
+
+/// Largest playlist we accept to download, to avoid reading a stream mistaken for a playlist forever.
+const MAX_PLAYLIST_SIZE: usize = 2usize.pow(20);
+/// Time given to the server to send the whole playlist, redirections included.
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(10);
+/// Redirections followed before giving up on a playlist URL.
+const MAX_REDIRECTS: u32 = 5;
+
+/// Stream entries read from a playlist file or URL.
+#[derive(Debug, Clone)]
+pub struct Playlist {
+    pub title: Option<String>,
+    pub entries: Vec<String>,
+}
+
+enum Format {
+    M3u,
+    Pls,
+    Xspf,
+}
+
+/// Loads the stream entries from `source`.
+///
+/// `source` can be a local playlist file, the URL of a playlist, or the URL of a stream, in which case the playlist
+/// only contains this URL. Only HTTP(S) entries are kept, since the player can only play web streams.
+pub async fn load(source: &str) -> Option<Playlist> {
+    let source = source.trim();
+    let is_url = source.starts_with("http://") || source.starts_with("https://");
+    let format = format_from_extension(source);
+
+    let contents = match (is_url, format.is_some()) {
+        (true, false) => {
+            return Some(Playlist {
+                title: None,
+                entries: vec![source.to_string()],
+            })
+        }
+        (true, true) => download(source).await?,
+        (false, _) => match tokio::fs::read(source).await {
+            Ok(contents) => String::from_utf8_lossy(&contents).into_owned(),
+            Err(error) => {
+                eprintln!("Failed to read playlist {}: {}", source, error);
+                return None;
+            }
+        },
+    };
+
+    let playlist = match format.or_else(|| format_from_contents(&contents))? {
+        Format::M3u => parse_m3u(&contents),
+        Format::Pls => parse_pls(&contents),
+        Format::Xspf => parse_xspf(&contents),
+    };
+
+    if playlist.entries.is_empty() {
+        eprintln!("Playlist {} doesn't contain any stream", source);
+        None
+    } else {
+        Some(playlist)
+    }
+}
+
+async fn download(url: &str) -> Option<String> {
+    match tokio::time::timeout(DOWNLOAD_TIMEOUT, download_following_redirects(url)).await {
+        Ok(contents) => contents,
+        Err(_) => {
+            eprintln!("Failed to download playlist {}: timed out", url);
+            None
+        }
+    }
+}
+
+async fn download_following_redirects(url: &str) -> Option<String> {
+    use hyper::body::HttpBody;
+
+    let https = hyper_tls::HttpsConnector::new();
+    let client = hyper::client::Client::builder().build::<_, hyper::Body>(https);
+    let mut uri = match url.parse::<hyper::Uri>() {
+        Ok(uri) => uri,
+        Err(error) => {
+            eprintln!("Invalid playlist url {}: {}", url, error);
+            return None;
+        }
+    };
+
+    let mut redirects = 0;
+    let mut res = loop {
+        let res = match client.get(uri.clone()).await {
+            Ok(res) => res,
+            Err(error) => {
+                eprintln!("Failed to download playlist {}: {}", url, error);
+                return None;
+            }
+        };
+        if res.status().is_redirection() && redirects < MAX_REDIRECTS {
+            let location = res
+                .headers()
+                .get(hyper::header::LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .and_then(|location| redirect_uri(&uri, location));
+            if let Some(location) = location {
+                redirects += 1;
+                uri = location;
+                continue;
+            }
+        }
+        if !res.status().is_success() {
+            eprintln!("Failed to download playlist {}: {}", url, res.status());
+            return None;
+        }
+        break res;
+    };
+
+    let mut data = Vec::new();
+    while let Some(chunk) = res.data().await {
+        data.extend_from_slice(&chunk.ok()?[..]);
+        if data.len() > MAX_PLAYLIST_SIZE {
+            eprintln!("Playlist {} is too large", url);
+            return None;
+        }
+    }
+
+    Some(String::from_utf8_lossy(&data).into_owned())
+}
+
+/// Resolves the `Location` of a redirection, which may be relative to the URI that was requested.
+fn redirect_uri(uri: &hyper::Uri, location: &str) -> Option<hyper::Uri> {
+    if is_stream_url(location) {
+        return location.parse().ok();
+    }
+    let path = if location.starts_with('/') {
+        location.to_string()
+    } else {
+        let base = uri.path();
+        format!("{}{}", &base[..=base.rfind('/')?], location)
+    };
+    hyper::Uri::builder()
+        .scheme(uri.scheme()?.clone())
+        .authority(uri.authority()?.clone())
+        .path_and_query(path)
+        .build()
+        .ok()
+}
+
+fn format_from_extension(source: &str) -> Option<Format> {
+    let path = source.split(['?', '#']).next().unwrap_or(source);
+    let extension = path.rsplit('.').next()?.to_lowercase();
+    match extension.as_str() {
+        "m3u" | "m3u8" => Some(Format::M3u),
+        "pls" => Some(Format::Pls),
+        "xspf" => Some(Format::Xspf),
+        _ => None,
+    }
+}
+
+fn format_from_contents(contents: &str) -> Option<Format> {
+    let start = contents.trim_start_matches('\u{feff}').trim_start();
+    if start.starts_with("[playlist]") {
+        Some(Format::Pls)
+    } else if start.starts_with("<?xml") || start.starts_with("<playlist") {
+        Some(Format::Xspf)
+    } else if start.starts_with("#EXTM3U") || start.starts_with("http") {
+        Some(Format::M3u)
+    } else {
+        None
+    }
+}
+
+fn is_stream_url(entry: &str) -> bool {
+    entry.starts_with("http://") || entry.starts_with("https://")
+}
+
+fn parse_m3u(contents: &str) -> Playlist {
+    let mut title = None;
+    let mut entries = Vec::new();
+
+    // Files saved by Windows editors may start with a byte order mark, which `trim` keeps
+    for line in contents.trim_start_matches('\u{feff}').lines().map(str::trim) {
+        if let Some(playlist_title) = line.strip_prefix("#PLAYLIST:") {
+            title = Some(playlist_title.trim().to_string());
+        } else if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+            // #EXTINF:<duration>,<title>
+            if title.is_none() {
+                title = extinf.split_once(',').map(|(_, t)| t.trim().to_string());
+            }
+        } else if is_stream_url(line) {
+            entries.push(line.to_string());
+        }
+    }
+
+    Playlist { title, entries }
+}
+
+fn parse_pls(contents: &str) -> Playlist {
+    let mut title = None;
+    let mut entries = Vec::new();
+
+    for line in contents.lines().map(str::trim) {
+        let mut parts = line.splitn(2, '=');
+        let (key, value) = match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => (key.trim().to_lowercase(), value.trim()),
+            _ => continue,
+        };
+        if let Some(index) = key.strip_prefix("file").and_then(|i| i.parse::<u32>().ok()) {
+            if is_stream_url(value) {
+                entries.push((index, value.to_string()));
+            }
+        } else if key.starts_with("title") && title.is_none() {
+            title = Some(value.to_string());
+        }
+    }
+
+    entries.sort_by_key(|(index, _)| *index);
+    Playlist {
+        title,
+        entries: entries.into_iter().map(|(_, entry)| entry).collect(),
+    }
+}
+
+fn parse_xspf(contents: &str) -> Playlist {
+    // The playlist title is the first <title> before any <track>
+    let header = contents.split("<track").next().unwrap_or("");
+    let title = xml_elements(header, "title").next();
+    let entries = xml_elements(contents, "location")
+        .filter(|l| is_stream_url(l))
+        .collect();
+
+    Playlist { title, entries }
+}
+
+/// Iterates over the unescaped text of every `<tag>` element. XSPF playlists are simple enough that we don't need a
+/// full XML parser.
+fn xml_elements<'a>(contents: &'a str, tag: &'a str) -> impl Iterator<Item = String> + 'a {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let mut rest = contents;
+
+    std::iter::from_fn(move || {
+        let start = rest.find(&open)? + open.len();
+        let end = rest[start..].find(&close)? + start;
+        let text = &rest[start..end];
+        rest = &rest[end + close.len()..];
+        Some(unescape_xml(text.trim()))
+    })
+}
+
+/// Replaces the predefined entities and the character references of an XML text.
+fn unescape_xml(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest.find(';') {
+            Some(end) => end,
+            None => break,
+        };
+        let entity = &rest[1..end];
+        let character = match entity {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "amp" => Some('&'),
+            _ => entity
+                .strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse))
+                .and_then(Result::ok)
+                .and_then(std::char::from_u32),
+        };
+        match character {
+            Some(character) => {
+                unescaped.push(character);
+                rest = &rest[end + 1..];
+            }
+            // Not an entity, kept as is
+            None => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redirect_locations() {
+        let uri = "https://example.com/radio/listen.m3u?format=mp3".parse().unwrap();
+        let redirect = |location| redirect_uri(&uri, location).map(|uri| uri.to_string());
+        assert_eq!(
+            redirect("http://mirror.example.org/listen.pls").as_deref(),
+            Some("http://mirror.example.org/listen.pls")
+        );
+        assert_eq!(
+            redirect("/playlists/listen.m3u").as_deref(),
+            Some("https://example.com/playlists/listen.m3u")
+        );
+        assert_eq!(
+            redirect("listen.xspf?v=2").as_deref(),
+            Some("https://example.com/radio/listen.xspf?v=2")
+        );
+    }
+
+    #[test]
+    fn m3u_entries_and_title() {
+        let playlist = parse_m3u(
+            "#EXTM3U\n#EXTINF:-1,Gensokyo Radio\nhttps://stream.gensokyoradio.net/1/\n\n# comment\nfile.mp3\n\
+             http://backup.example.com/stream\n",
+        );
+        assert_eq!(playlist.title.as_deref(), Some("Gensokyo Radio"));
+        assert_eq!(
+            playlist.entries,
+            vec![
+                "https://stream.gensokyoradio.net/1/",
+                "http://backup.example.com/stream"
+            ]
+        );
+    }
+
+    #[test]
+    fn m3u_playlist_title_wins() {
+        let playlist = parse_m3u("#EXTM3U\n#PLAYLIST:My Station\n#EXTINF:-1,Track\nhttp://example.com/a\n");
+        assert_eq!(playlist.title.as_deref(), Some("My Station"));
+    }
+
+    #[test]
+    fn m3u_byte_order_mark_and_crlf() {
+        let playlist = parse_m3u("\u{feff}http://example.com/a\r\nhttp://example.com/b\r\n");
+        assert_eq!(playlist.title, None);
+        assert_eq!(playlist.entries, vec!["http://example.com/a", "http://example.com/b"]);
+    }
+
+    #[test]
+    fn pls_out_of_order_indexes() {
+        let playlist = parse_pls(
+            "[playlist]\nFile10=http://example.com/10\nTitle2=Second\nFile2=http://example.com/2\n\
+             File1 = http://example.com/1\nFile3=local.mp3\nNumberOfEntries=4\nVersion=2\n",
+        );
+        assert_eq!(playlist.title.as_deref(), Some("Second"));
+        assert_eq!(
+            playlist.entries,
+            vec!["http://example.com/1", "http://example.com/2", "http://example.com/10"]
+        );
+    }
+
+    #[test]
+    fn pls_keys_are_case_insensitive() {
+        let playlist = parse_pls("[Playlist]\r\nfile1=http://example.com/stream?a=b\r\nTITLE1=Radio\r\n");
+        assert_eq!(playlist.title.as_deref(), Some("Radio"));
+        assert_eq!(playlist.entries, vec!["http://example.com/stream?a=b"]);
+    }
+
+    #[test]
+    fn xspf_entities() {
+        let playlist = parse_xspf(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<playlist version="1" xmlns="http://xspf.org/ns/0/">
+  <title>Rock &amp; Roll &#x2665;</title>
+  <trackList>
+    <track>
+      <title>Not the playlist title</title>
+      <location>http://example.com/stream?a=1&amp;b=2</location>
+    </track>
+    <track><location> https://example.com/&#38;second </location></track>
+    <track><location>file:///music/local.mp3</location></track>
+  </trackList>
+</playlist>"#,
+        );
+        assert_eq!(playlist.title.as_deref(), Some("Rock & Roll \u{2665}"));
+        assert_eq!(
+            playlist.entries,
+            vec!["http://example.com/stream?a=1&b=2", "https://example.com/&second"]
+        );
+    }
+
+    #[test]
+    fn xml_unknown_entities_are_kept() {
+        assert_eq!(unescape_xml("a &amp;lt; b"), "a &lt; b");
+        assert_eq!(unescape_xml("AT&T & co"), "AT&T & co");
+        assert_eq!(unescape_xml("&nbsp;&#xZZ;"), "&nbsp;&#xZZ;");
+    }
+
+    #[test]
+    fn format_detection() {
+        assert!(matches!(
+            format_from_extension("http://example.com/listen.pls?sid=1"),
+            Some(Format::Pls)
+        ));
+        assert!(matches!(
+            format_from_extension("C:\\Music\\radio.M3U8"),
+            Some(Format::M3u)
+        ));
+        assert!(format_from_extension("http://example.com/stream").is_none());
+        assert!(matches!(
+            format_from_contents("\u{feff}[playlist]\n"),
+            Some(Format::Pls)
+        ));
+        assert!(matches!(
+            format_from_contents("  <?xml version=\"1.0\"?>"),
+            Some(Format::Xspf)
+        ));
+        assert!(format_from_contents("ID3").is_none());
+    }
+}
@@ -0,0 +1,258 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+use futures_util::stream::BoxStream;
+use futures_util::{SinkExt, StreamExt};
+use tokio_tungstenite::tungstenite::Message;
+
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::gensokyo_radio::GRApiAnswer;
+use crate::station::Station;
+use crate::PlayerMessage;
+
+/// Delay before reconnecting after the first failure, doubled after every failed attempt.
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(120);
+/// Songs rarely last this long, if nothing came through the socket it is probably dead.
+const FEED_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+/// Messages telling that the song changed, without its info
+const SONG_CHANGED_MESSAGES: &[&str] = &["update"];
+
+type WebSocket = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// Listens to the song changes pushed by a station.
+///
+/// The subscription emits `PlayerMessage::SongPushConnected` when the socket goes up or down, so the player can fall
+/// back to polling, and `PlayerMessage::SongInfo` on every song change. Most messages only tell us that the song
+/// changed, the song info is then fetched from the station. Messages containing a full JSON API answer are used as is.
+pub fn subscription(url: String, station: Arc<dyn Station>) -> iced::Subscription<PlayerMessage> {
+    iced::Subscription::from_recipe(SongPush { url, station })
+}
+
+struct SongPush {
+    url: String,
+    station: Arc<dyn Station>,
+}
+
+enum State {
+    Disconnected {
+        attempt: u32,
+    },
+    Connected {
+        socket: Box<WebSocket>,
+        id: Option<serde_json::Value>,
+    },
+}
+
+impl<H: Hasher, I> iced_native::subscription::Recipe<H, I> for SongPush {
+    type Output = PlayerMessage;
+
+    fn hash(&self, state: &mut H) {
+        std::any::TypeId::of::<Self>().hash(state);
+        self.url.hash(state);
+    }
+
+    fn stream(self: Box<Self>, _input: BoxStream<'static, I>) -> BoxStream<'static, Self::Output> {
+        let push = Arc::new(*self);
+        futures_util::stream::unfold(State::Disconnected { attempt: 0 }, move |state| {
+            let push = push.clone();
+            async move { Some(push.next_message(state).await) }
+        })
+        .boxed()
+    }
+}
+
+impl SongPush {
+    async fn next_message(&self, mut state: State) -> (PlayerMessage, State) {
+        loop {
+            state = match state {
+                State::Disconnected { attempt } => {
+                    if attempt > 0 {
+                        let delay = RECONNECT_DELAY * 2u32.pow((attempt - 1).min(16));
+                        tokio::time::sleep(delay.min(MAX_RECONNECT_DELAY)).await;
+                    }
+                    match tokio_tungstenite::connect_async(self.url.as_str()).await {
+                        Ok((mut socket, _)) => {
+                            let hello = serde_json::json!({ "message": "grInitialConnection" }).to_string();
+                            if let Err(error) = socket.send(Message::Text(hello)).await {
+                                eprintln!("Failed to greet song push {}: {}", self.url, error);
+                            }
+                            let state = State::Connected {
+                                socket: Box::new(socket),
+                                id: None,
+                            };
+                            return (PlayerMessage::SongPushConnected(true), state);
+                        }
+                        Err(error) => {
+                            eprintln!("Failed to connect to song push {}: {}", self.url, error);
+                            let state = State::Disconnected { attempt: attempt + 1 };
+                            return (PlayerMessage::SongPushConnected(false), state);
+                        }
+                    }
+                }
+                State::Connected { mut socket, mut id } => {
+                    let text = match tokio::time::timeout(FEED_TIMEOUT, socket.next()).await {
+                        Ok(Some(Ok(Message::Text(text)))) => text,
+                        Ok(Some(Ok(Message::Close(_)))) | Ok(Some(Err(_))) | Ok(None) | Err(_) => {
+                            let state = State::Disconnected { attempt: 1 };
+                            return (PlayerMessage::SongPushConnected(false), state);
+                        }
+                        // Websocket pings are answered by tungstenite
+                        Ok(Some(Ok(_))) => {
+                            state = State::Connected { socket, id };
+                            continue;
+                        }
+                    };
+
+                    let message: serde_json::Value = match serde_json::from_str(&text) {
+                        Ok(message) => message,
+                        Err(_) => {
+                            state = State::Connected { socket, id };
+                            continue;
+                        }
+                    };
+                    if message.get("SONGINFO").is_some() {
+                        let song_info = GRApiAnswer::from_json(text.as_bytes());
+                        return (PlayerMessage::SongInfo(song_info), State::Connected { socket, id });
+                    }
+                    match message["message"].as_str() {
+                        Some("welcome") => id = Some(message["id"].clone()),
+                        Some("ping") => {
+                            let pong = serde_json::json!({ "message": "pong", "id": id }).to_string();
+                            if socket.send(Message::Text(pong)).await.is_err() {
+                                let state = State::Disconnected { attempt: 1 };
+                                return (PlayerMessage::SongPushConnected(false), state);
+                            }
+                        }
+                        Some(kind) if SONG_CHANGED_MESSAGES.contains(&kind) => {
+                            let song_info = self.station.now_playing().await;
+                            return (PlayerMessage::SongInfo(song_info), State::Connected { socket, id });
+                        }
+                        // Other messages, e.g. the listener count, don't mean that the song changed
+                        _ => {}
+                    }
+                    State::Connected { socket, id }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_trait::async_trait;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::clock::ServerClock;
+    use crate::gensokyo_radio::ApiError;
+    use crate::test_support;
+
+    const SONG_DURATION: Duration = Duration::from_millis(300);
+    const PING_INTERVAL: Duration = Duration::from_millis(100);
+
+    /// Station counting the song info fetches, which always fail.
+    #[derive(Default)]
+    struct FakeStation {
+        fetches: AtomicUsize,
+        clock: ServerClock,
+    }
+
+    #[async_trait]
+    impl Station for FakeStation {
+        fn name(&self) -> &str {
+            "fake"
+        }
+
+        fn stream_urls(&self) -> Vec<String> {
+            Vec::new()
+        }
+
+        fn clock(&self) -> &ServerClock {
+            &self.clock
+        }
+
+        async fn now_playing(&self) -> Result<GRApiAnswer, ApiError> {
+            self.fetches.fetch_add(1, Ordering::SeqCst);
+            Err(ApiError::Api("fetched".to_string()))
+        }
+
+        async fn artwork(&self, _song: &GRApiAnswer, _large: bool) -> Option<Vec<u8>> {
+            None
+        }
+    }
+
+    /// Starts the push websocket stand-in on a free port, and returns its URL.
+    async fn start_server() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to bind the push server");
+        let address = listener.local_addr().expect("Failed to get the push server address");
+        tokio::spawn(test_support::gr_push::listen(listener, SONG_DURATION, PING_INTERVAL));
+        format!("ws://{}", address)
+    }
+
+    #[tokio::test]
+    async fn song_changes() {
+        let station = Arc::new(FakeStation::default());
+        let push = SongPush {
+            url: start_server().await,
+            station: station.clone(),
+        };
+
+        let (message, state) = push.next_message(State::Disconnected { attempt: 0 }).await;
+        assert!(matches!(message, PlayerMessage::SongPushConnected(true)));
+
+        // The first song is sent whole, and used as is
+        let (message, state) = push.next_message(state).await;
+        match message {
+            PlayerMessage::SongInfo(Ok(song)) => assert_eq!(song.songinfo.title, "Test song 1"),
+            message => panic!("Unexpected message {:?}", message),
+        }
+        assert_eq!(station.fetches.load(Ordering::SeqCst), 0);
+
+        // The second one is only notified, after a listener count and pings which don't fetch anything
+        let (message, state) = push.next_message(state).await;
+        assert!(matches!(message, PlayerMessage::SongInfo(Err(ApiError::Api(_)))));
+        assert_eq!(station.fetches.load(Ordering::SeqCst), 1);
+
+        let (message, _) = push.next_message(state).await;
+        match message {
+            PlayerMessage::SongInfo(Ok(song)) => assert_eq!(song.songinfo.title, "Test song 3"),
+            message => panic!("Unexpected message {:?}", message),
+        }
+        assert_eq!(station.fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn connection_failure() {
+        // Nothing listens on the port of a dropped listener
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("Failed to bind");
+        let address = listener.local_addr().expect("Failed to get the address");
+        drop(listener);
+
+        let push = SongPush {
+            url: format!("ws://{}", address),
+            station: Arc::new(FakeStation::default()),
+        };
+        let (message, state) = push.next_message(State::Disconnected { attempt: 0 }).await;
+        assert!(matches!(message, PlayerMessage::SongPushConnected(false)));
+        assert!(matches!(state, State::Disconnected { attempt: 1 }));
+    }
+}
@@ -0,0 +1,560 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+//! Scrobbling, shared by the Last.fm and ListenBrainz sinks.
+//!
+//! Songs are scrobbled once they were audible for half of their duration or 4 minutes, whichever comes first, as
+//! required by the [Last.fm scrobbling rules](https://www.last.fm/api/scrobbling), which ListenBrainz follows too. The
+//! scrobbles go through a queue stored on disk, so the songs played while the service is unreachable are sent later.
+
+use async_trait::async_trait;
+use rusqlite::params;
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::database;
+use crate::gensokyo_radio::GRApiAnswer;
+use crate::sink::{NowPlayingEvent, NowPlayingSink};
+
+/// Songs shorter than this can't be scrobbled
+const MIN_DURATION: u64 = 30;
+/// Songs are scrobbled after half of their duration, or after this long for the long songs and unknown durations
+const MAX_LISTEN_TIME: Duration = Duration::from_secs(4 * 60);
+/// Delay before sending the queue again after the first failure, doubled after every failed attempt.
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30 * 60);
+
+/// A song the user listened to.
+#[derive(Debug, Clone)]
+pub struct Track {
+    pub artist: String,
+    pub title: String,
+    pub album: String,
+    pub circle: String,
+    /// Name of the station that played the song
+    pub station: String,
+    /// In seconds, 0 if unknown
+    pub duration: u64,
+    /// When the song started, in seconds since the UNIX epoch
+    pub timestamp: i64,
+}
+
+impl Track {
+    fn from_song(song: &GRApiAnswer, station: &str) -> Option<Track> {
+        if song.songinfo.artist.is_empty() || song.songinfo.title.is_empty() {
+            return None;
+        }
+        let timestamp = match song.songtimes.songstart {
+            0 => chrono::Utc::now().timestamp(),
+            songstart => songstart as i64,
+        };
+        Some(Track {
+            artist: song.songinfo.artist.clone(),
+            title: song.songinfo.title.clone(),
+            album: song.songinfo.album.clone(),
+            circle: song.songinfo.circle.clone(),
+            station: station.to_string(),
+            duration: song.songtimes.duration,
+            timestamp,
+        })
+    }
+
+    /// How long the song must be heard before it is scrobbled, `None` if it is too short to be scrobbled.
+    fn scrobble_after(&self) -> Option<Duration> {
+        match self.duration {
+            0 => Some(MAX_LISTEN_TIME),
+            duration if duration <= MIN_DURATION => None,
+            duration => Some(Duration::from_secs(duration / 2).min(MAX_LISTEN_TIME)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum SubmitError {
+    /// Nothing was sent because the user isn't connected to the service
+    NotConnected,
+    /// Sending the same scrobbles later could work
+    Retry(String),
+    /// The service will never accept these scrobbles
+    Rejected(String),
+}
+
+/// A service the songs are scrobbled to.
+#[async_trait]
+pub trait ScrobbleService: Send + Sync {
+    /// Name of the service, for the error messages
+    fn name(&self) -> &'static str;
+
+    /// Maximum number of scrobbles sent in one request
+    fn batch_size(&self) -> u32;
+
+    async fn now_playing(&self, track: &Track) -> Result<(), SubmitError>;
+
+    async fn submit(&self, tracks: &[Track]) -> Result<(), SubmitError>;
+}
+
+/// Tables of the scrobble queue database.
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS scrobbles (
+    id INTEGER PRIMARY KEY,
+    artist TEXT NOT NULL,
+    title TEXT NOT NULL,
+    album TEXT NOT NULL,
+    circle TEXT NOT NULL,
+    station TEXT NOT NULL,
+    duration INTEGER NOT NULL,
+    timestamp INTEGER NOT NULL,
+    UNIQUE (timestamp, artist, title)
+);";
+
+/// Scrobbles waiting to be sent, stored in a SQLite database in the user's data directory.
+pub struct ScrobbleQueue {
+    connection: Mutex<rusqlite::Connection>,
+}
+
+impl ScrobbleQueue {
+    /// Opens the queue stored in `file_name`, every service has its own.
+    pub fn open(file_name: &str) -> Option<ScrobbleQueue> {
+        let connection = database::open(file_name, "scrobble queue", SCHEMA)?;
+        Some(ScrobbleQueue {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    pub fn push(&self, track: &Track) -> rusqlite::Result<()> {
+        let connection = self.connection.lock().expect("Failed to lock scrobble queue");
+        connection.execute(
+            "INSERT OR IGNORE INTO scrobbles (artist, title, album, circle, station, duration, timestamp)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                track.artist,
+                track.title,
+                track.album,
+                track.circle,
+                track.station,
+                track.duration as i64,
+                track.timestamp,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Returns the oldest scrobbles with their ids.
+    pub fn oldest(&self, limit: u32) -> rusqlite::Result<Vec<(i64, Track)>> {
+        let connection = self.connection.lock().expect("Failed to lock scrobble queue");
+        let mut statement = connection.prepare(
+            "SELECT id, artist, title, album, circle, station, duration, timestamp FROM scrobbles
+                ORDER BY timestamp LIMIT ?1",
+        )?;
+        let rows = statement.query_map(params![limit], |row| {
+            let duration: i64 = row.get(6)?;
+            Ok((
+                row.get(0)?,
+                Track {
+                    artist: row.get(1)?,
+                    title: row.get(2)?,
+                    album: row.get(3)?,
+                    circle: row.get(4)?,
+                    station: row.get(5)?,
+                    duration: duration as u64,
+                    timestamp: row.get(7)?,
+                },
+            ))
+        })?;
+        rows.collect()
+    }
+
+    pub fn remove(&self, ids: &[i64]) -> rusqlite::Result<()> {
+        let mut connection = self.connection.lock().expect("Failed to lock scrobble queue");
+        let transaction = connection.transaction()?;
+        for id in ids {
+            transaction.execute("DELETE FROM scrobbles WHERE id = ?1", params![id])?;
+        }
+        transaction.commit()
+    }
+}
+
+/// The song being listened to.
+struct Listening {
+    track: Track,
+    /// Audible time before `audible_since`
+    listened: Duration,
+    audible_since: Option<Instant>,
+    announced: bool,
+    queued: bool,
+}
+
+impl Listening {
+    fn listened(&self) -> Duration {
+        self.listened + self.audible_since.map(|since| since.elapsed()).unwrap_or_default()
+    }
+}
+
+/// Sends the songs the user listens to to a scrobbling service.
+pub struct ScrobbleSink<S: ScrobbleService> {
+    service: S,
+    runtime: tokio::runtime::Runtime,
+    queue: Option<ScrobbleQueue>,
+    current: Option<Listening>,
+    playing: bool,
+    volume: u8,
+    failures: u32,
+    next_flush: Instant,
+}
+
+impl<S: ScrobbleService> ScrobbleSink<S> {
+    /// `queue_file` is the name of the queue of the service in the user's data directory.
+    pub fn new(service: S, queue_file: &str) -> ScrobbleSink<S> {
+        ScrobbleSink::with_queue(service, ScrobbleQueue::open(queue_file))
+    }
+
+    fn with_queue(service: S, queue: Option<ScrobbleQueue>) -> ScrobbleSink<S> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("Failed to build scrobbler runtime");
+        ScrobbleSink {
+            service,
+            runtime,
+            queue,
+            current: None,
+            playing: false,
+            volume: 0,
+            failures: 0,
+            next_flush: Instant::now(),
+        }
+    }
+
+    /// Only the time the song can actually be heard counts towards the scrobble.
+    fn audible(&self) -> bool {
+        self.playing && self.volume > 0
+    }
+
+    /// Starts or stops counting the listening time after the play state or the volume changed.
+    fn update_clock(&mut self) {
+        let audible = self.audible();
+        if let Some(ref mut current) = self.current {
+            match (current.audible_since, audible) {
+                (Some(since), false) => {
+                    current.listened += since.elapsed();
+                    current.audible_since = None;
+                }
+                (None, true) => current.audible_since = Some(Instant::now()),
+                _ => {}
+            }
+        }
+    }
+
+    fn announce(&mut self) {
+        let current = match self.current {
+            Some(ref mut current) if !current.announced && self.playing => current,
+            _ => return,
+        };
+        current.announced = true;
+        match self.runtime.block_on(self.service.now_playing(&current.track)) {
+            Ok(()) | Err(SubmitError::NotConnected) => {}
+            Err(SubmitError::Retry(error)) | Err(SubmitError::Rejected(error)) => {
+                eprintln!("Failed to update {} now playing: {}", self.service.name(), error);
+            }
+        }
+    }
+
+    /// Queues the current song once it was heard long enough.
+    fn check_scrobble(&mut self) {
+        let current = match self.current {
+            Some(ref mut current) if !current.queued => current,
+            _ => return,
+        };
+        match current.track.scrobble_after() {
+            Some(after) if current.listened() >= after => {}
+            _ => return,
+        }
+        current.queued = true;
+        if let Some(ref queue) = self.queue {
+            if let Err(error) = queue.push(&current.track) {
+                eprintln!("Failed to queue scrobble: {}", error);
+            }
+            self.next_flush = Instant::now();
+        }
+    }
+
+    /// Sends the queued scrobbles, backing off while the service is unreachable.
+    fn flush(&mut self) {
+        if Instant::now() < self.next_flush {
+            return;
+        }
+        let queue = match self.queue {
+            Some(ref queue) => queue,
+            None => return,
+        };
+        let scrobbles = match queue.oldest(self.service.batch_size()) {
+            Ok(scrobbles) if scrobbles.is_empty() => return,
+            Ok(scrobbles) => scrobbles,
+            Err(error) => {
+                eprintln!("Failed to read scrobble queue: {}", error);
+                return;
+            }
+        };
+
+        let (ids, tracks): (Vec<i64>, Vec<Track>) = scrobbles.into_iter().unzip();
+        let remove = match self.runtime.block_on(self.service.submit(&tracks)) {
+            Ok(()) => true,
+            // The scrobbles wait for the user to connect
+            Err(SubmitError::NotConnected) => {
+                self.next_flush = Instant::now() + RETRY_DELAY;
+                return;
+            }
+            Err(SubmitError::Retry(error)) => {
+                eprintln!("Failed to scrobble to {}, will retry: {}", self.service.name(), error);
+                false
+            }
+            Err(SubmitError::Rejected(error)) => {
+                // Keeping them would block the queue
+                eprintln!("{} refused {} scrobbles: {}", self.service.name(), ids.len(), error);
+                true
+            }
+        };
+        if remove {
+            self.failures = 0;
+            if let Err(error) = queue.remove(&ids) {
+                eprintln!("Failed to remove sent scrobbles: {}", error);
+            }
+        } else {
+            self.failures += 1;
+            let delay = RETRY_DELAY * 2u32.pow((self.failures - 1).min(16));
+            self.next_flush = Instant::now() + delay.min(MAX_RETRY_DELAY);
+        }
+    }
+}
+
+impl<S: ScrobbleService> NowPlayingSink for ScrobbleSink<S> {
+    fn handle(&mut self, event: NowPlayingEvent) {
+        match event {
+            NowPlayingEvent::SongChanged { song, station, .. } => {
+                self.check_scrobble();
+                self.current = Track::from_song(&song, &station).map(|track| Listening {
+                    track,
+                    listened: Duration::default(),
+                    audible_since: None,
+                    announced: false,
+                    queued: false,
+                });
+            }
+            NowPlayingEvent::Playing => self.playing = true,
+            NowPlayingEvent::Paused => self.playing = false,
+            NowPlayingEvent::VolumeChanged(volume) => self.volume = volume,
+            NowPlayingEvent::Cleared => {
+                self.check_scrobble();
+                self.current = None;
+            }
+        }
+        self.update_clock();
+        self.announce();
+        self.check_scrobble();
+        self.flush();
+    }
+
+    fn tick(&mut self) {
+        self.check_scrobble();
+        self.flush();
+    }
+
+    fn shutdown(&mut self) {
+        self.check_scrobble();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::gensokyo_radio::{Misc, ServerInfo, SongData, SongInfo, SongTimes};
+
+    /// Records what the sink sends
+    #[derive(Default, Clone)]
+    struct FakeService {
+        now_playing: Arc<Mutex<Vec<String>>>,
+        submitted: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl ScrobbleService for FakeService {
+        fn name(&self) -> &'static str {
+            "Fake"
+        }
+
+        fn batch_size(&self) -> u32 {
+            10
+        }
+
+        async fn now_playing(&self, track: &Track) -> Result<(), SubmitError> {
+            self.now_playing.lock().unwrap().push(track.title.clone());
+            Ok(())
+        }
+
+        async fn submit(&self, tracks: &[Track]) -> Result<(), SubmitError> {
+            let mut submitted = self.submitted.lock().unwrap();
+            submitted.extend(tracks.iter().map(|track| track.title.clone()));
+            Ok(())
+        }
+    }
+
+    fn track(duration: u64) -> Track {
+        Track {
+            artist: "Artist".to_string(),
+            title: "Title".to_string(),
+            album: String::new(),
+            circle: String::new(),
+            station: "Gensokyo Radio".to_string(),
+            duration,
+            timestamp: 1_600_000_000,
+        }
+    }
+
+    fn song(title: &str, duration: u64) -> NowPlayingEvent {
+        let song = GRApiAnswer {
+            serverinfo: ServerInfo::default(),
+            songinfo: SongInfo {
+                title: title.to_string(),
+                artist: "Artist".to_string(),
+                ..SongInfo::default()
+            },
+            songtimes: SongTimes {
+                duration,
+                songstart: 1_600_000_000,
+                ..SongTimes::default()
+            },
+            songdata: SongData::default(),
+            misc: Misc::default(),
+        };
+        NowPlayingEvent::SongChanged {
+            song,
+            station: "Gensokyo Radio".to_string(),
+            listen_url: None,
+        }
+    }
+
+    fn memory_queue() -> ScrobbleQueue {
+        let connection = rusqlite::Connection::open_in_memory().unwrap();
+        connection.execute_batch(SCHEMA).unwrap();
+        ScrobbleQueue {
+            connection: Mutex::new(connection),
+        }
+    }
+
+    fn sink() -> (ScrobbleSink<FakeService>, FakeService) {
+        let service = FakeService::default();
+        let queue = memory_queue();
+        (ScrobbleSink::with_queue(service.clone(), Some(queue)), service)
+    }
+
+    /// Pretends the current song was heard for `listened` before the last play or volume change.
+    fn listened_for(sink: &mut ScrobbleSink<FakeService>, listened: Duration) {
+        sink.current.as_mut().expect("No current song").listened = listened;
+    }
+
+    #[test]
+    fn scrobble_after() {
+        assert_eq!(track(0).scrobble_after(), Some(MAX_LISTEN_TIME));
+        assert_eq!(track(20).scrobble_after(), None);
+        assert_eq!(track(MIN_DURATION).scrobble_after(), None);
+        assert_eq!(track(31).scrobble_after(), Some(Duration::from_secs(15)));
+        assert_eq!(track(200).scrobble_after(), Some(Duration::from_secs(100)));
+        assert_eq!(track(480).scrobble_after(), Some(MAX_LISTEN_TIME));
+        assert_eq!(track(3600).scrobble_after(), Some(MAX_LISTEN_TIME));
+    }
+
+    #[test]
+    fn listened() {
+        let mut listening = Listening {
+            track: track(200),
+            listened: Duration::from_secs(10),
+            audible_since: None,
+            announced: false,
+            queued: false,
+        };
+        assert_eq!(listening.listened(), Duration::from_secs(10));
+        listening.audible_since = Instant::now().checked_sub(Duration::from_secs(5));
+        assert!(listening.listened() >= Duration::from_secs(15));
+    }
+
+    #[test]
+    fn audible_time() {
+        let (mut sink, service) = sink();
+        sink.handle(song("Song", 200));
+        // Paused and muted time doesn't count
+        assert!(sink.current.as_ref().unwrap().audible_since.is_none());
+        sink.handle(NowPlayingEvent::Playing);
+        assert!(sink.current.as_ref().unwrap().audible_since.is_none());
+        assert_eq!(*service.now_playing.lock().unwrap(), vec!["Song"]);
+
+        sink.handle(NowPlayingEvent::VolumeChanged(50));
+        assert!(sink.current.as_ref().unwrap().audible_since.is_some());
+        sink.handle(NowPlayingEvent::Paused);
+        assert!(sink.current.as_ref().unwrap().audible_since.is_none());
+
+        listened_for(&mut sink, Duration::from_secs(99));
+        sink.tick();
+        assert!(service.submitted.lock().unwrap().is_empty());
+        listened_for(&mut sink, Duration::from_secs(100));
+        sink.tick();
+        assert_eq!(*service.submitted.lock().unwrap(), vec!["Song"]);
+        // Only once per song
+        sink.tick();
+        assert_eq!(service.submitted.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn song_change() {
+        let (mut sink, service) = sink();
+        sink.handle(NowPlayingEvent::VolumeChanged(50));
+        sink.handle(NowPlayingEvent::Playing);
+        sink.handle(song("Skipped", 200));
+        listened_for(&mut sink, Duration::from_secs(50));
+        sink.handle(song("Too short", 20));
+        listened_for(&mut sink, Duration::from_secs(20));
+        sink.handle(song("Heard", 0));
+        listened_for(&mut sink, MAX_LISTEN_TIME);
+        sink.handle(NowPlayingEvent::Cleared);
+
+        assert_eq!(
+            *service.now_playing.lock().unwrap(),
+            vec!["Skipped", "Too short", "Heard"]
+        );
+        assert_eq!(*service.submitted.lock().unwrap(), vec!["Heard"]);
+    }
+
+    #[test]
+    fn queue() {
+        let queue = memory_queue();
+        let mut later = track(200);
+        later.timestamp += 200;
+        queue.push(&later).unwrap();
+        queue.push(&track(200)).unwrap();
+        // The same play is only queued once
+        queue.push(&track(200)).unwrap();
+
+        let oldest = queue.oldest(10).unwrap();
+        let timestamps: Vec<i64> = oldest.iter().map(|(_, track)| track.timestamp).collect();
+        assert_eq!(timestamps, vec![1_600_000_000, 1_600_000_200]);
+        assert_eq!(queue.oldest(1).unwrap().len(), 1);
+
+        queue.remove(&[oldest[0].0]).unwrap();
+        let left = queue.oldest(10).unwrap();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].1.timestamp, 1_600_000_200);
+    }
+}
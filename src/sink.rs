@@ -0,0 +1,494 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+use futures_util::stream::BoxStream;
+use futures_util::StreamExt;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::art_cache::ArtCache;
+use crate::config::Config;
+use crate::gensokyo_radio::{self, GRApiAnswer};
+use crate::PlayerMessage;
+
+/// Interval between two calls to `NowPlayingSink::tick`.
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What the player is doing, as seen by the sinks.
+#[derive(Debug, Clone)]
+pub enum NowPlayingEvent {
+    /// A new song started on the station
+    SongChanged {
+        song: GRApiAnswer,
+        station: String,
+        /// Page where other people can listen to the station, see `Station::listen_url`. Only the Discord presence
+        /// uses it.
+        #[cfg_attr(not(feature = "discord"), allow(dead_code))]
+        listen_url: Option<String>,
+    },
+    Playing,
+    Paused,
+    /// Volume of the player, between 0 and 100
+    VolumeChanged(u8),
+    /// There is no current song anymore, e.g. because the sink was disabled
+    Cleared,
+}
+
+/// Something that wants to know what is playing, like Discord Rich Presence or a scrobbler.
+///
+/// Every sink runs on its own thread, so `handle` can block (e.g. on a network request) without slowing the player or
+/// the other sinks down. Sinks that are not `Send` are created on their thread, see `Sinks::spawn`.
+pub trait NowPlayingSink {
+    fn handle(&mut self, event: NowPlayingEvent);
+
+    /// Called regularly, for sinks that need to poll a connection.
+    fn tick(&mut self) {}
+
+    /// Called when the player exits, to clean up what the sink published.
+    fn shutdown(&mut self) {}
+}
+
+struct SinkHandle {
+    name: &'static str,
+    enabled: bool,
+    tx: Sender<NowPlayingEvent>,
+    thread: std::thread::JoinHandle<()>,
+}
+
+/// The sinks enabled by the user.
+pub struct Sinks {
+    sinks: Vec<SinkHandle>,
+    /// Threads of the sinks stopped by a reload, waited for by the sink replacing them or when the player exits
+    stopping: Vec<(&'static str, std::thread::JoinHandle<()>)>,
+    /// Replayed to the sinks when they are enabled
+    song: Option<NowPlayingEvent>,
+    playing: bool,
+    volume: Option<u8>,
+    /// Sinks that can control the player, like MPRIS, send their commands through this channel
+    remote_tx: UnboundedSender<PlayerMessage>,
+    remote_rx: Arc<Mutex<Option<UnboundedReceiver<PlayerMessage>>>>,
+    cursor_over_window: Arc<AtomicBool>,
+    lastfm_session: crate::lastfm::SessionStore,
+}
+
+impl Sinks {
+    fn new(lastfm_session: crate::lastfm::SessionStore) -> Sinks {
+        let (remote_tx, remote_rx) = tokio::sync::mpsc::unbounded_channel();
+        Sinks {
+            sinks: Vec::new(),
+            stopping: Vec::new(),
+            song: None,
+            playing: false,
+            volume: None,
+            remote_tx,
+            remote_rx: Arc::new(Mutex::new(Some(remote_rx))),
+            cursor_over_window: Arc::new(AtomicBool::new(false)),
+            lastfm_session,
+        }
+    }
+
+    /// Starts the sinks compiled in the player, according to the configuration.
+    ///
+    /// `art_cache` lets the sinks point at the album art downloaded by the player instead of fetching it again, and
+    /// the scrobbler shares `lastfm_session` with the account panel.
+    pub fn from_config(
+        config: &Config,
+        art_cache: Option<ArtCache>,
+        lastfm_session: crate::lastfm::SessionStore,
+    ) -> Sinks {
+        let mut sinks = Sinks::new(lastfm_session);
+        sinks.spawn_configured(config, art_cache);
+        sinks
+    }
+
+    /// Applies a new configuration, e.g. after the user edited the configuration file. Only the sinks whose section
+    /// changed are restarted, the others keep running as they are, e.g. with the progress of the current scrobble.
+    /// The new sinks get the current song, state and volume.
+    ///
+    /// Doesn't wait for the old sinks: the sink replacing one waits for it on its own thread, e.g. so the overlay port
+    /// is free again before the new server binds it.
+    pub fn reload(&mut self, previous: &Config, config: &Config, art_cache: Option<ArtCache>) {
+        let changed = |name: &str| match name {
+            "discord" => previous.discord != config.discord,
+            "lastfm" => previous.lastfm != config.lastfm,
+            "listenbrainz" => previous.listenbrainz != config.listenbrainz,
+            "now_playing" => previous.now_playing != config.now_playing,
+            "webhooks" => previous.webhooks != config.webhooks,
+            "notifications" => previous.notifications != config.notifications,
+            "mpris" => previous.mpris != config.mpris,
+            _ => true,
+        };
+        let (stopped, kept) = self.sinks.drain(..).partition::<Vec<_>, _>(|sink| changed(sink.name));
+        // Dropping the channel lets the sink shut down
+        self.stopping
+            .extend(stopped.into_iter().map(|sink| (sink.name, sink.thread)));
+        self.sinks = kept;
+        let kept = self.sinks.iter().map(|sink| sink.name).collect::<Vec<_>>();
+
+        self.spawn_configured(config, art_cache);
+        let events = self.current_state();
+        for sink in self
+            .sinks
+            .iter()
+            .filter(|sink| sink.enabled && !kept.contains(&sink.name))
+        {
+            for event in events.iter() {
+                if sink.tx.send(event.clone()).is_err() {
+                    eprintln!("The {} sink stopped", sink.name);
+                }
+            }
+        }
+    }
+
+    fn spawn_configured(&mut self, config: &Config, art_cache: Option<ArtCache>) {
+        #[cfg(feature = "discord")]
+        {
+            let discord = config.discord.clone();
+            self.spawn("discord", discord.enabled, move || {
+                crate::discord::DiscordSink::new(discord)
+            });
+        }
+        if let Some(client) = crate::lastfm::LastfmClient::from_config(&config.lastfm, self.lastfm_session.clone()) {
+            self.spawn("lastfm", config.lastfm.enabled, move || {
+                crate::scrobbler::ScrobbleSink::new(client, crate::lastfm::QUEUE_FILE)
+            });
+        }
+        if let Some(client) = crate::listenbrainz::ListenBrainzClient::from_config(&config.listenbrainz) {
+            self.spawn("listenbrainz", config.listenbrainz.enabled, move || {
+                crate::scrobbler::ScrobbleSink::new(client, crate::listenbrainz::QUEUE_FILE)
+            });
+        }
+        if config.now_playing.has_outputs() {
+            let now_playing = config.now_playing.clone();
+            let now_playing_art_cache = art_cache.clone();
+            self.spawn("now_playing", now_playing.enabled, move || {
+                crate::now_playing::NowPlayingOutput::new(now_playing, now_playing_art_cache)
+            });
+        }
+        if !config.webhooks.is_empty() {
+            let webhooks = config.webhooks.clone();
+            self.spawn("webhooks", true, move || crate::webhooks::WebhookSink::new(webhooks));
+        }
+        #[cfg(target_os = "linux")]
+        {
+            let notifications = config.notifications.clone();
+            let remote = self.remote_tx.clone();
+            let notifications_art_cache = art_cache.clone();
+            let cursor_over_window = self.cursor_over_window.clone();
+            self.spawn("notifications", notifications.enabled, move || {
+                crate::notifications::NotificationSink::new(
+                    notifications,
+                    remote,
+                    notifications_art_cache,
+                    cursor_over_window,
+                )
+            });
+
+            let remote = self.remote_tx.clone();
+            self.spawn("mpris", config.mpris.enabled, move || {
+                crate::mpris::MprisSink::new(remote, art_cache)
+            });
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = art_cache;
+    }
+
+    /// Lets the sinks shut down, so e.g. the Discord presence is cleared.
+    fn stop(&mut self) {
+        let threads = self
+            .sinks
+            .drain(..)
+            .map(|sink| (sink.name, sink.thread))
+            .chain(self.stopping.drain(..))
+            .collect::<Vec<_>>();
+        for (name, thread) in threads {
+            join(name, thread);
+        }
+    }
+
+    /// Emits the commands sent by the sinks, e.g. when a media key is pressed.
+    pub fn subscription(&self) -> iced::Subscription<PlayerMessage> {
+        iced::Subscription::from_recipe(Remote {
+            rx: self.remote_rx.clone(),
+        })
+    }
+
+    /// Starts a sink on its own thread, unless it was kept by a reload. `new_sink` is called on that thread, once the
+    /// sink it replaces is stopped.
+    fn spawn<S, F>(&mut self, name: &'static str, enabled: bool, new_sink: F)
+    where
+        S: NowPlayingSink,
+        F: FnOnce() -> S + Send + 'static,
+    {
+        if self.sinks.iter().any(|sink| sink.name == name) {
+            return;
+        }
+        let replaced = self
+            .stopping
+            .iter()
+            .position(|(stopping, _)| *stopping == name)
+            .map(|index| self.stopping.remove(index).1);
+        let (tx, rx) = std::sync::mpsc::channel();
+        let thread = std::thread::Builder::new()
+            .name(format!("{} sink", name))
+            .spawn(move || {
+                if let Some(replaced) = replaced {
+                    join(name, replaced);
+                }
+                let mut sink = new_sink();
+                loop {
+                    match rx.recv_timeout(TICK_INTERVAL) {
+                        Ok(event) => sink.handle(event),
+                        Err(RecvTimeoutError::Timeout) => sink.tick(),
+                        // The player is exiting
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                sink.shutdown();
+            })
+            .expect("Failed to spawn now playing sink thread");
+        self.sinks.push(SinkHandle {
+            name,
+            enabled,
+            tx,
+            thread,
+        });
+    }
+
+    /// Tells the sinks whether the mouse cursor is over the player window, so the user is likely looking at it, e.g. to
+    /// skip the notifications.
+    pub fn set_cursor_over_window(&self, over_window: bool) {
+        self.cursor_over_window.store(over_window, Ordering::SeqCst);
+    }
+
+    pub fn send(&mut self, event: NowPlayingEvent) {
+        match event {
+            NowPlayingEvent::SongChanged { .. } => self.song = Some(event.clone()),
+            NowPlayingEvent::Playing => self.playing = true,
+            NowPlayingEvent::Paused => self.playing = false,
+            NowPlayingEvent::VolumeChanged(volume) => self.volume = Some(volume),
+            NowPlayingEvent::Cleared => self.song = None,
+        }
+        for sink in self.sinks.iter().filter(|sink| sink.enabled) {
+            if sink.tx.send(event.clone()).is_err() {
+                eprintln!("The {} sink stopped", sink.name);
+            }
+        }
+    }
+
+    pub fn is_enabled(&self, name: &str) -> Option<bool> {
+        self.sinks
+            .iter()
+            .find(|sink| sink.name == name)
+            .map(|sink| sink.enabled)
+    }
+
+    /// Events bringing a new sink up to date with the player.
+    fn current_state(&self) -> Vec<NowPlayingEvent> {
+        let state = if self.playing {
+            NowPlayingEvent::Playing
+        } else {
+            NowPlayingEvent::Paused
+        };
+        let volume = self.volume.map(NowPlayingEvent::VolumeChanged);
+        self.song
+            .iter()
+            .cloned()
+            .chain(std::iter::once(state))
+            .chain(volume)
+            .collect()
+    }
+
+    /// Enables or disables a sink at runtime. Disabled sinks are cleared, enabled ones get the current song.
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) {
+        let events = if enabled {
+            self.current_state()
+        } else {
+            vec![NowPlayingEvent::Cleared]
+        };
+        let sink = match self.sinks.iter_mut().find(|sink| sink.name == name) {
+            Some(sink) if sink.enabled != enabled => sink,
+            _ => return,
+        };
+        sink.enabled = enabled;
+        for event in events {
+            if sink.tx.send(event).is_err() {
+                eprintln!("The {} sink stopped", sink.name);
+            }
+        }
+    }
+}
+
+fn join(name: &str, thread: std::thread::JoinHandle<()>) {
+    if thread.join().is_err() {
+        eprintln!("The {} sink panicked", name);
+    }
+}
+
+/// Position in the current song as heard by the user, frozen while the player is paused.
+#[derive(Debug, Clone, Default)]
+pub struct SongPosition {
+    /// Position when `playing_since` was set, or while paused
+    played: Duration,
+    playing_since: Option<Instant>,
+}
+
+impl SongPosition {
+    /// Starts over at `played`, when a new song is received.
+    pub fn reset(&mut self, played: Duration, playing: bool) {
+        self.played = played;
+        self.playing_since = if playing { Some(Instant::now()) } else { None };
+    }
+
+    pub fn set_playing(&mut self, playing: bool) {
+        match (self.playing_since, playing) {
+            (Some(since), false) => {
+                self.played += since.elapsed();
+                self.playing_since = None;
+            }
+            (None, true) => self.playing_since = Some(Instant::now()),
+            _ => {}
+        }
+    }
+
+    pub fn get(&self) -> Duration {
+        self.played
+            + self
+                .playing_since
+                .map_or_else(Duration::default, |since| since.elapsed())
+    }
+}
+
+/// Values of the placeholders of the text templates, e.g. of the Discord presence: `{title}`, `{artist}`, `{album}`,
+/// `{year}`, `{circle}`, `{station}` and `{album_art}` (the URL of the album art, empty if the song has none).
+pub fn template_values(song_info: &GRApiAnswer, station: &str) -> Vec<(&'static str, String)> {
+    let song = &song_info.songinfo;
+    vec![
+        ("{title}", song.title.clone()),
+        ("{artist}", song.artist.clone()),
+        ("{album}", song.album.clone()),
+        ("{year}", song.year.clone()),
+        ("{circle}", song.circle.clone()),
+        ("{station}", station.to_string()),
+        (
+            "{album_art}",
+            gensokyo_radio::album_art_url(song_info, None).unwrap_or_default(),
+        ),
+    ]
+}
+
+/// Replaces the placeholders of `template` with their values, in a single pass so that the values aren't expanded.
+pub fn fill_template(template: &str, values: &[(&str, String)]) -> String {
+    let mut text = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        text.push_str(&rest[..start]);
+        rest = &rest[start..];
+        match values.iter().find(|(placeholder, _)| rest.starts_with(placeholder)) {
+            Some((placeholder, value)) => {
+                text.push_str(value);
+                rest = &rest[placeholder.len()..];
+            }
+            None => {
+                text.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    text.push_str(rest);
+    text
+}
+
+/// Fills a text template with the values of `template_values`.
+pub fn expand_template(template: &str, song_info: &GRApiAnswer, station: &str) -> String {
+    fill_template(template, &template_values(song_info, station))
+        .trim()
+        .to_string()
+}
+
+impl Drop for Sinks {
+    /// Lets the sinks shut down before the player exits, so e.g. the Discord presence is cleared.
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+struct Remote {
+    rx: Arc<Mutex<Option<UnboundedReceiver<PlayerMessage>>>>,
+}
+
+impl<H: Hasher, I> iced_native::subscription::Recipe<H, I> for Remote {
+    type Output = PlayerMessage;
+
+    fn hash(&self, state: &mut H) {
+        std::any::TypeId::of::<Self>().hash(state);
+    }
+
+    fn stream(self: Box<Self>, _input: BoxStream<'static, I>) -> BoxStream<'static, Self::Output> {
+        // The subscription is never dropped, so the receiver is only taken once
+        let rx = self.rx.lock().expect("Failed to lock sinks remote").take();
+        match rx {
+            Some(rx) => futures_util::stream::unfold(rx, |mut rx| async move {
+                let message = rx.recv().await?;
+                Some((message, rx))
+            })
+            .boxed(),
+            None => futures_util::stream::empty().boxed(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn song_position() {
+        let mut position = SongPosition::default();
+        position.reset(Duration::from_secs(30), false);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(position.get(), Duration::from_secs(30));
+
+        position.set_playing(true);
+        std::thread::sleep(Duration::from_millis(20));
+        position.set_playing(false);
+        let paused_at = position.get();
+        assert!(paused_at >= Duration::from_millis(30_020));
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(position.get(), paused_at);
+
+        position.set_playing(true);
+        position.set_playing(true);
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(position.get() >= paused_at + Duration::from_millis(20));
+
+        position.reset(Duration::from_secs(5), true);
+        assert!(position.get() < Duration::from_secs(6));
+    }
+
+    #[test]
+    fn templates() {
+        let values = [("{title}", "{artist}".to_string()), ("{artist}", "Artist".to_string())];
+        assert_eq!(fill_template("{artist} - {title}", &values), "Artist - {artist}");
+        assert_eq!(fill_template("{title}{title}", &values), "{artist}{artist}");
+        assert_eq!(fill_template("{{artist}} {unknown} {", &values), "{Artist} {unknown} {");
+        assert_eq!(fill_template("東方 {artist}", &values), "東方 Artist");
+        assert_eq!(fill_template("", &values), "");
+    }
+}
@@ -0,0 +1,52 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+use async_trait::async_trait;
+
+use crate::clock::ServerClock;
+use crate::gensokyo_radio::{ApiError, GRApiAnswer};
+
+/// A web radio the player can listen to.
+///
+/// Song metadata is always reported in the Gensokyo Radio format, since it is the richest one we support. Stations that
+/// can't fill some fields leave them empty, and report a `duration` and `songend` of 0 when they don't know how long
+/// the current song lasts.
+#[async_trait]
+pub trait Station: Send + Sync {
+    /// Human readable name of the station.
+    fn name(&self) -> &str;
+
+    /// URLs of the MP3 stream, in order of preference. The player falls back to the next URL when one fails.
+    fn stream_urls(&self) -> Vec<String>;
+
+    /// Clock of the station's server, which the song times are based on.
+    fn clock(&self) -> &ServerClock;
+
+    /// Websocket notifying the song changes, see `push::subscription`. Stations without one are polled.
+    fn push_url(&self) -> Option<String> {
+        None
+    }
+
+    /// Web page where other people can listen to the station, e.g. for a button on the Discord presence.
+    fn listen_url(&self) -> Option<String> {
+        None
+    }
+
+    /// Fetches the song currently playing on the station.
+    async fn now_playing(&self) -> Result<GRApiAnswer, ApiError>;
+
+    /// Fetches the artwork of a song previously returned by `now_playing`. When `large` is set, the station should
+    /// try to return a higher resolution artwork, falling back to the usual one.
+    async fn artwork(&self, song: &GRApiAnswer, large: bool) -> Option<Vec<u8>>;
+}
@@ -0,0 +1,51 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+//! Helpers shared by the tests: the local stand-ins of the web services from the examples, and a server for them.
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response};
+
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+
+#[cfg(all(unix, feature = "discord"))]
+#[path = "../examples/mock_servers/discord_ipc.rs"]
+pub mod discord_ipc;
+#[path = "../examples/mock_servers/gr_account.rs"]
+pub mod gr_account;
+#[path = "../examples/mock_servers/gr_push.rs"]
+pub mod gr_push;
+#[path = "../examples/mock_servers/lastfm.rs"]
+pub mod lastfm;
+#[path = "../examples/mock_servers/listenbrainz.rs"]
+pub mod listenbrainz;
+
+/// Serves the requests with `handler` on a free local port, until the end of the test's runtime, and returns the
+/// address of the server.
+pub fn serve<H, F>(handler: H) -> SocketAddr
+where
+    H: Fn(Request<Body>) -> F + Clone + Send + 'static,
+    F: Future<Output = Result<Response<Body>, Infallible>> + Send + 'static,
+{
+    let make_service = make_service_fn(move |_| {
+        let handler = handler.clone();
+        async move { Ok::<_, Infallible>(service_fn(handler)) }
+    });
+    let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+    let address = server.local_addr();
+    tokio::spawn(server);
+    address
+}
@@ -18,6 +18,8 @@ use super::PlayerMessage;
 
 pub const PLAY_SVG: &str = include_str!("resources/play.svg");
 pub const PAUSE_SVG: &str = include_str!("resources/pause.svg");
+pub const HEART_SVG: &str = include_str!("resources/heart.svg");
+pub const HEART_FILLED_SVG: &str = include_str!("resources/heart_filled.svg");
 pub const ICON: &[u8] = include_bytes!("resources/wan_player.ico");
 pub const NO_IMAGE: &[u8] = include_bytes!("resources/not_found.png");
 
@@ -35,6 +37,60 @@ impl widget::button::StyleSheet for PlayPauseStyle {
     }
 }
 
+pub struct TextButtonStyle;
+impl widget::button::StyleSheet for TextButtonStyle {
+    fn active(&self) -> widget::button::Style {
+        widget::button::Style {
+            shadow_offset: Vector::new(0.0, 0.0),
+            background: None,
+            border_radius: 0.0,
+            border_width: 0.0,
+            border_color: Color::new(0.0, 0.0, 0.0, 0.0),
+            text_color: Color::new(1.0, 1.0, 1.0, 0.5),
+        }
+    }
+
+    fn hovered(&self) -> widget::button::Style {
+        widget::button::Style {
+            text_color: Color::new(1.0, 1.0, 1.0, 1.0),
+            ..self.active()
+        }
+    }
+}
+
+pub struct LinkStyle;
+impl widget::button::StyleSheet for LinkStyle {
+    fn active(&self) -> widget::button::Style {
+        widget::button::Style {
+            text_color: Color::new(1.0, 1.0, 1.0, 1.0),
+            ..widget::button::StyleSheet::active(&TextButtonStyle)
+        }
+    }
+
+    fn hovered(&self) -> widget::button::Style {
+        widget::button::Style {
+            text_color: Color::new(0.6, 0.8, 1.0, 1.0),
+            ..self.active()
+        }
+    }
+}
+
+pub struct TabStyle {
+    selected: bool,
+}
+impl widget::button::StyleSheet for TabStyle {
+    fn active(&self) -> widget::button::Style {
+        widget::button::Style {
+            text_color: Color::new(1.0, 1.0, 1.0, if self.selected { 1.0 } else { 0.5 }),
+            ..widget::button::StyleSheet::active(&TextButtonStyle)
+        }
+    }
+
+    fn hovered(&self) -> widget::button::Style {
+        widget::button::StyleSheet::hovered(&TextButtonStyle)
+    }
+}
+
 pub struct VolumeSliderStyle;
 impl widget::slider::StyleSheet for VolumeSliderStyle {
     fn active(&self) -> widget::slider::Style {
@@ -90,14 +146,14 @@ impl widget::container::StyleSheet for PlayerStyle {
     }
 }
 
-pub fn album_art_widget(album_image: &Option<Vec<u8>>) -> widget::Image {
+pub fn album_art_widget(album_image: &Option<Vec<u8>>, size: u16) -> widget::Image {
     widget::Image::new(widget::image::Handle::from_memory(if let Some(ref art) = album_image {
         art.clone()
     } else {
         NO_IMAGE.to_vec()
     }))
-    .height(iced::Length::Units(200))
-    .width(iced::Length::Units(200))
+    .height(iced::Length::Units(size))
+    .width(iced::Length::Units(size))
 }
 
 pub fn elapsed_widget(
@@ -121,11 +177,11 @@ pub fn elapsed_widget(
                     .horizontal_alignment(iced::HorizontalAlignment::Center),
             )
             .push(
-                widget::Text::new(format!(
-                    "{}:{:02}",
-                    song_info.songtimes.duration / 60,
-                    song_info.songtimes.duration % 60
-                ))
+                // Icecast stations don't know how long the songs last
+                widget::Text::new(match song_info.songtimes.duration {
+                    0 => "--:--".to_string(),
+                    duration => format!("{}:{:02}", duration / 60, duration % 60),
+                })
                 .width(iced::Length::Shrink),
             )
     } else {
@@ -143,14 +199,64 @@ pub fn elapsed_widget(
 }
 
 pub fn progress_widget(song_info: &Option<super::gensokyo_radio::GRApiAnswer>) -> widget::ProgressBar {
-    if let Some(ref song_info) = song_info {
-        widget::ProgressBar::new(
+    match song_info {
+        Some(ref song_info) if song_info.songtimes.duration != 0 => widget::ProgressBar::new(
             0.0..=song_info.songtimes.duration as f32,
             song_info.songtimes.played as f32,
-        )
-    } else {
-        widget::ProgressBar::new(0.0..=100.0, 0.0)
+        ),
+        _ => widget::ProgressBar::new(0.0..=100.0, 0.0),
     }
     .style(SongProgressStyle)
     .height(iced::Length::Units(8))
 }
+
+pub fn tab_widget<'a>(
+    state: &'a mut widget::button::State,
+    label: &str,
+    panel: super::Panel,
+    current_panel: super::Panel,
+    enabled: bool,
+) -> widget::Button<'a, PlayerMessage> {
+    let tab = widget::Button::new(state, widget::Text::new(label).size(16)).style(TabStyle {
+        selected: panel == current_panel,
+    });
+    if enabled {
+        tab.on_press(PlayerMessage::ShowPanel(panel))
+    } else {
+        tab
+    }
+}
+
+/// Text opening `url` in the default browser when clicked. Shown as plain text when there is no URL.
+pub fn link_widget<'a>(
+    state: &'a mut widget::button::State,
+    label: &str,
+    url: Option<String>,
+) -> Element<'a, PlayerMessage> {
+    match url {
+        Some(url) => widget::Button::new(state, widget::Text::new(label).size(32))
+            .style(LinkStyle)
+            .padding(0)
+            .on_press(PlayerMessage::OpenUrl(url))
+            .into(),
+        None => widget::Text::new(label).size(32).into(),
+    }
+}
+
+pub fn rating_widget(states: &mut [widget::button::State; 5], songid: u64) -> widget::Row<PlayerMessage> {
+    states
+        .iter_mut()
+        .enumerate()
+        .fold(widget::Row::new().spacing(8), |row, (index, state)| {
+            let rating = index as u8 + 1;
+            row.push(
+                widget::Button::new(state, widget::Text::new(rating.to_string()).size(32))
+                    .style(TextButtonStyle)
+                    .padding(0)
+                    .on_press(PlayerMessage::Account(super::account::AccountMessage::Rate {
+                        songid,
+                        rating,
+                    })),
+            )
+        })
+}
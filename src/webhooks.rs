@@ -0,0 +1,397 @@
+// Copyright 2021 Flat Bartender <flat.bartender@gmail.com>
+//
+//    Licensed under the Apache License, Version 2.0 (the "License");
+//    you may not use this file except in compliance with the License.
+//    You may obtain a copy of the License at
+//
+//        http://www.apache.org/licenses/LICENSE-2.0
+//
+//    Unless required by applicable law or agreed to in writing, software
+//    distributed under the License is distributed on an "AS IS" BASIS,
+//    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//    See the License for the specific language governing permissions and
+//    limitations under the License.
+
+//! Webhooks called when the song changes and when the player is played or paused, e.g. to post the songs to a chat.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::config::WebhookConfig;
+use crate::gensokyo_radio::GRApiAnswer;
+use crate::sink::{fill_template, template_values, NowPlayingEvent, NowPlayingSink};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Delay before the first retry, doubled after every failed attempt.
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+/// Calls waiting for the rate limit beyond this are dropped, oldest first
+const MAX_PENDING: usize = 10;
+/// Events a webhook can be called on, see `event_name`
+pub const EVENTS: &[&str] = &["song_changed", "playing", "paused"];
+
+type HttpClient = hyper::client::Client<hyper_tls::HttpsConnector<hyper::client::HttpConnector>>;
+
+fn new_client() -> HttpClient {
+    let https = hyper_tls::HttpsConnector::new();
+    hyper::client::Client::builder().build::<_, hyper::Body>(https)
+}
+
+/// Name of the events in the configuration and in the `{event}` placeholder
+fn event_name(event: &NowPlayingEvent) -> Option<&'static str> {
+    match event {
+        NowPlayingEvent::SongChanged { .. } => Some("song_changed"),
+        NowPlayingEvent::Playing => Some("playing"),
+        NowPlayingEvent::Paused => Some("paused"),
+        NowPlayingEvent::VolumeChanged(_) | NowPlayingEvent::Cleared => None,
+    }
+}
+
+/// Builds the body of a call. Without a template, the body is a JSON object with the event and the song.
+///
+/// The templates can use the placeholders of `sink::template_values`, plus `{event}`, `{duration}`, `{songstart}` and
+/// `{songend}`. The values are escaped for JSON strings unless `escape_json` is off.
+fn render_body(webhook: &WebhookConfig, event: &str, song: Option<&(GRApiAnswer, String)>) -> String {
+    let template = match webhook.body {
+        Some(ref template) => template,
+        None => {
+            let song = song.map(|(song, station)| {
+                serde_json::json!({
+                    "station": station,
+                    "title": song.songinfo.title,
+                    "artist": song.songinfo.artist,
+                    "album": song.songinfo.album,
+                    "year": song.songinfo.year,
+                    "circle": song.songinfo.circle,
+                    "duration": song.songtimes.duration,
+                    "songstart": song.songtimes.songstart,
+                    "songend": song.songtimes.songend,
+                })
+            });
+            return serde_json::json!({ "event": event, "song": song }).to_string();
+        }
+    };
+
+    let mut values = match song {
+        Some((song, station)) => {
+            let mut values = template_values(song, station);
+            values.push(("{duration}", song.songtimes.duration.to_string()));
+            values.push(("{songstart}", song.songtimes.songstart.to_string()));
+            values.push(("{songend}", song.songtimes.songend.to_string()));
+            values
+        }
+        None => Vec::new(),
+    };
+    values.push(("{event}", event.to_string()));
+    if webhook.escape_json {
+        for (_, value) in values.iter_mut() {
+            let quoted = serde_json::Value::from(value.as_str()).to_string();
+            *value = quoted[1..quoted.len() - 1].to_string();
+        }
+    }
+    fill_template(template, &values)
+}
+
+/// Error of a call, retryable when the server or the network failed.
+struct CallError {
+    message: String,
+    retryable: bool,
+}
+
+/// Headers of the calls to `webhook`, the configured ones last.
+fn headers(webhook: &WebhookConfig) -> Vec<(String, String)> {
+    let mut headers = vec![(
+        hyper::header::USER_AGENT.to_string(),
+        concat!("Wan Player/", env!("CARGO_PKG_VERSION")).to_string(),
+    )];
+    if webhook.body.is_none() || webhook.escape_json {
+        headers.push((hyper::header::CONTENT_TYPE.to_string(), "application/json".to_string()));
+    }
+    let mut configured: Vec<(String, String)> = webhook
+        .headers
+        .iter()
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+    configured.sort();
+    headers.extend(configured);
+    headers
+}
+
+async fn call(client: &HttpClient, webhook: &WebhookConfig, body: String) -> Result<hyper::StatusCode, CallError> {
+    let req = headers(webhook).into_iter().fold(
+        hyper::Request::builder()
+            .method(webhook.method.as_str())
+            .uri(webhook.url.as_str()),
+        |req, (name, value)| req.header(name.as_str(), value.as_str()),
+    );
+    let req = req.body(hyper::Body::from(body)).map_err(|error| CallError {
+        message: error.to_string(),
+        retryable: false,
+    })?;
+
+    let res = match tokio::time::timeout(WEBHOOK_TIMEOUT, client.request(req)).await {
+        Ok(Ok(res)) => res,
+        Ok(Err(error)) => {
+            return Err(CallError {
+                message: error.to_string(),
+                retryable: true,
+            })
+        }
+        Err(_) => {
+            return Err(CallError {
+                message: "request timed out".to_string(),
+                retryable: true,
+            })
+        }
+    };
+    let status = res.status();
+    if status.is_success() {
+        Ok(status)
+    } else {
+        Err(CallError {
+            message: format!("answered {}", status),
+            retryable: status.is_server_error() || status == hyper::StatusCode::TOO_MANY_REQUESTS,
+        })
+    }
+}
+
+/// Shows the calls a `test` event would make, without sending anything: the method, the URL, the headers and the body
+/// of every webhook.
+pub fn preview(webhooks: &[WebhookConfig], song: Option<&(GRApiAnswer, String)>) -> Vec<String> {
+    webhooks
+        .iter()
+        .filter(|webhook| !webhook.url.is_empty())
+        .map(|webhook| {
+            let headers: String = headers(webhook)
+                .iter()
+                .map(|(name, value)| format!("{}: {}\n", name, value))
+                .collect();
+            format!(
+                "{} {}\n{}\n{}",
+                webhook.method,
+                webhook.url,
+                headers,
+                render_body(webhook, "test", song)
+            )
+        })
+        .collect()
+}
+
+/// Sends a `test` event to every webhook right away, ignoring their events filter and rate limit. Only called once
+/// the user confirmed the calls shown by `preview`.
+///
+/// Returns a line per webhook telling how it went, for the settings panel.
+pub async fn test(webhooks: Vec<WebhookConfig>, song: Option<(GRApiAnswer, String)>) -> Vec<String> {
+    let client = new_client();
+    let mut results = Vec::new();
+    for webhook in webhooks.iter().filter(|webhook| !webhook.url.is_empty()) {
+        let body = render_body(webhook, "test", song.as_ref());
+        let result = match call(&client, webhook, body).await {
+            Ok(status) => format!("{}: {}", webhook.url, status),
+            Err(error) => format!("{}: {}", webhook.url, error.message),
+        };
+        results.push(result);
+    }
+    results
+}
+
+struct PendingCall {
+    body: String,
+    attempt: u32,
+    not_before: Instant,
+}
+
+struct Webhook {
+    config: WebhookConfig,
+    pending: VecDeque<PendingCall>,
+    last_call: Option<Instant>,
+}
+
+/// Calls the configured webhooks, one at a time per webhook, respecting their rate limit.
+pub struct WebhookSink {
+    client: HttpClient,
+    runtime: tokio::runtime::Runtime,
+    webhooks: Vec<Webhook>,
+    /// Current song and station, for the play and pause events
+    song: Option<(GRApiAnswer, String)>,
+}
+
+impl WebhookSink {
+    pub fn new(webhooks: Vec<WebhookConfig>) -> WebhookSink {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("Failed to build webhooks runtime");
+        WebhookSink {
+            client: new_client(),
+            runtime,
+            webhooks: webhooks
+                .into_iter()
+                .filter(|webhook| !webhook.url.is_empty())
+                .map(|config| Webhook {
+                    config,
+                    pending: VecDeque::new(),
+                    last_call: None,
+                })
+                .collect(),
+            song: None,
+        }
+    }
+
+    /// Sends the calls that are due.
+    fn flush(&mut self) {
+        let now = Instant::now();
+        for webhook in self.webhooks.iter_mut() {
+            let min_interval = Duration::from_secs(webhook.config.min_interval_secs);
+            if matches!(webhook.last_call, Some(last_call) if now < last_call + min_interval) {
+                continue;
+            }
+            let mut pending = match webhook.pending.pop_front() {
+                Some(pending) if pending.not_before <= now => pending,
+                Some(pending) => {
+                    webhook.pending.push_front(pending);
+                    continue;
+                }
+                None => continue,
+            };
+
+            webhook.last_call = Some(now);
+            let result = self
+                .runtime
+                .block_on(call(&self.client, &webhook.config, pending.body.clone()));
+            match result {
+                Ok(_) => {}
+                Err(error) if error.retryable && pending.attempt < webhook.config.max_attempts => {
+                    eprintln!(
+                        "Failed to call webhook {}, will retry: {}",
+                        webhook.config.url, error.message
+                    );
+                    pending.not_before = now + RETRY_DELAY * 2u32.pow((pending.attempt - 1).min(16));
+                    pending.attempt += 1;
+                    webhook.pending.push_front(pending);
+                }
+                Err(error) => eprintln!("Failed to call webhook {}: {}", webhook.config.url, error.message),
+            }
+        }
+    }
+}
+
+impl NowPlayingSink for WebhookSink {
+    fn handle(&mut self, event: NowPlayingEvent) {
+        if let NowPlayingEvent::SongChanged {
+            ref song, ref station, ..
+        } = event
+        {
+            self.song = Some((song.clone(), station.clone()));
+        }
+        if let NowPlayingEvent::Cleared = event {
+            self.song = None;
+        }
+        let name = match event_name(&event) {
+            Some(name) => name,
+            None => return,
+        };
+
+        let song = self.song.as_ref();
+        for webhook in self.webhooks.iter_mut() {
+            if !webhook.config.events.iter().any(|event| event == name) {
+                continue;
+            }
+            if webhook.pending.len() >= MAX_PENDING {
+                webhook.pending.pop_front();
+            }
+            webhook.pending.push_back(PendingCall {
+                body: render_body(&webhook.config, name, song),
+                attempt: 1,
+                not_before: Instant::now(),
+            });
+        }
+        self.flush();
+    }
+
+    fn tick(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::gensokyo_radio::{Misc, ServerInfo, SongData, SongInfo, SongTimes};
+
+    fn song(title: &str) -> (GRApiAnswer, String) {
+        let song = GRApiAnswer {
+            serverinfo: ServerInfo::default(),
+            songinfo: SongInfo {
+                title: title.to_string(),
+                artist: "Artist".to_string(),
+                ..SongInfo::default()
+            },
+            songtimes: SongTimes {
+                duration: 200,
+                ..SongTimes::default()
+            },
+            songdata: SongData::default(),
+            misc: Misc::default(),
+        };
+        (song, "Gensokyo Radio".to_string())
+    }
+
+    #[test]
+    fn body() {
+        let mut webhook = WebhookConfig {
+            url: "http://127.0.0.1/hook".to_string(),
+            body: Some(
+                r#"{"content": "{artist} - {title} on {station} ({duration}s)", "event": "{event}"}"#.to_string(),
+            ),
+            ..WebhookConfig::default()
+        };
+        // The values are inserted once, even when they look like placeholders
+        let song = song(r#"Say "{station}" {album_art}"#);
+        let body = render_body(&webhook, "song_changed", Some(&song));
+        let body: serde_json::Value = serde_json::from_str(&body).expect("Invalid JSON body");
+        assert_eq!(
+            body["content"],
+            r#"Artist - Say "{station}" {album_art} on Gensokyo Radio (200s)"#
+        );
+        assert_eq!(body["event"], "song_changed");
+
+        webhook.body = Some("{title}".to_string());
+        webhook.escape_json = false;
+        assert_eq!(
+            render_body(&webhook, "test", Some(&song)),
+            r#"Say "{station}" {album_art}"#
+        );
+        assert_eq!(render_body(&webhook, "test", None), "{title}");
+
+        webhook.body = None;
+        let body: serde_json::Value = serde_json::from_str(&render_body(&webhook, "paused", None)).unwrap();
+        assert_eq!(body, serde_json::json!({ "event": "paused", "song": null }));
+    }
+
+    #[test]
+    fn preview_calls() {
+        let mut webhook = WebhookConfig {
+            url: "http://127.0.0.1/hook".to_string(),
+            method: "PUT".to_string(),
+            body: Some("{title}".to_string()),
+            escape_json: false,
+            ..WebhookConfig::default()
+        };
+        webhook
+            .headers
+            .insert("Authorization".to_string(), "Bearer token".to_string());
+        let disabled = WebhookConfig::default();
+        let previews = preview(&[webhook, disabled], Some(&song("Title")));
+        assert_eq!(previews.len(), 1);
+        assert_eq!(
+            previews[0],
+            concat!(
+                "PUT http://127.0.0.1/hook\n",
+                "user-agent: Wan Player/",
+                env!("CARGO_PKG_VERSION"),
+                "\nAuthorization: Bearer token\n\nTitle"
+            )
+        );
+    }
+}